@@ -0,0 +1,47 @@
+-- migrations/0001_initial.sql
+-- Tables the bot has always written to. IF NOT EXISTS so existing deployments are left alone.
+--
+-- The bot never wrote messages.id/created_at or metrics.id/created_at itself, so a deployment whose tables predate
+-- this file may lack them; the ALTERs at the end add them. Nothing records when such historical rows were written:
+-- they get created_at = the time of this migration, and ids in table storage order, which for these append-only
+-- tables is the order they were inserted in. That is why the admin API pages messages and metrics by id rather than
+-- by created_at.
+
+CREATE TABLE IF NOT EXISTS users (
+    user_id     BIGINT PRIMARY KEY,
+    first_name  TEXT,
+    last_name   TEXT,
+    username    TEXT
+);
+
+CREATE TABLE IF NOT EXISTS threads (
+    thread_id         TEXT PRIMARY KEY,
+    user_id           BIGINT NOT NULL REFERENCES users (user_id),
+    openai_thread_id  TEXT NOT NULL,
+    assistant_id      TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS messages (
+    id            BIGSERIAL PRIMARY KEY,
+    thread_id     TEXT NOT NULL,
+    sender        TEXT NOT NULL,
+    content       TEXT NOT NULL,
+    message_type  TEXT NOT NULL,
+    assistant_id  TEXT NOT NULL,
+    created_at    TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS metrics (
+    id                  BIGSERIAL PRIMARY KEY,
+    user_id             BIGINT NOT NULL,
+    thread_id           TEXT NOT NULL,
+    interest            INTEGER NOT NULL,
+    user_response_time  INTEGER,
+    response_cue        INTEGER,
+    created_at          TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS id BIGSERIAL;
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+ALTER TABLE metrics ADD COLUMN IF NOT EXISTS id BIGSERIAL;
+ALTER TABLE metrics ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT now();
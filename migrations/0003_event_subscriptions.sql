@@ -0,0 +1,26 @@
+-- migrations/0003_event_subscriptions.sql
+-- Outbound event webhooks: who wants which events, and every delivery attempt we made.
+
+CREATE TABLE IF NOT EXISTS event_subscriptions (
+    id           BIGSERIAL PRIMARY KEY,
+    url          TEXT NOT NULL,
+    secret       TEXT NOT NULL,
+    event_types  TEXT[] NOT NULL,
+    active       BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at   TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS event_deliveries (
+    id               BIGSERIAL PRIMARY KEY,
+    subscription_id  BIGINT NOT NULL REFERENCES event_subscriptions (id) ON DELETE CASCADE,
+    event_id         TEXT NOT NULL,
+    event_type       TEXT NOT NULL,
+    payload          JSONB NOT NULL,
+    attempt          INTEGER NOT NULL,
+    status_code      INTEGER,
+    error            TEXT,
+    succeeded        BOOLEAN NOT NULL,
+    created_at       TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS event_deliveries_subscription_idx ON event_deliveries (subscription_id, created_at DESC);
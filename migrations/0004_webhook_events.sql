@@ -0,0 +1,15 @@
+-- migrations/0004_webhook_events.sql
+-- Raw archive of every inbound provider webhook, stored before the handler runs.
+
+CREATE TABLE IF NOT EXISTS webhook_events (
+    id                  BIGSERIAL PRIMARY KEY,
+    provider            TEXT NOT NULL,
+    path                TEXT NOT NULL,
+    headers             JSONB NOT NULL,
+    body                BYTEA NOT NULL,
+    verified            BOOLEAN NOT NULL,
+    verification_error  TEXT,
+    received_at         TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS webhook_events_provider_idx ON webhook_events (provider, received_at DESC);
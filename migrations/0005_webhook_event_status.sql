@@ -0,0 +1,11 @@
+-- migrations/0005_webhook_event_status.sql
+-- Processing state for archived webhooks. Failed handlers leave the event in dead_letter until replayed.
+
+ALTER TABLE webhook_events ADD COLUMN IF NOT EXISTS status TEXT NOT NULL DEFAULT 'received';
+ALTER TABLE webhook_events ADD COLUMN IF NOT EXISTS attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE webhook_events ADD COLUMN IF NOT EXISTS last_error TEXT;
+ALTER TABLE webhook_events ADD COLUMN IF NOT EXISTS processed_at TIMESTAMPTZ;
+
+UPDATE webhook_events SET status = 'rejected' WHERE NOT verified;
+
+CREATE INDEX IF NOT EXISTS webhook_events_status_idx ON webhook_events (status, received_at);
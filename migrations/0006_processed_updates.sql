@@ -0,0 +1,10 @@
+-- migrations/0006_processed_updates.sql
+-- Telegram updates/messages we've already handled, so retries and restarts don't trigger a second AI run.
+-- Rows older than DEDUP_TTL_HOURS are pruned by the bot.
+
+CREATE TABLE IF NOT EXISTS processed_updates (
+    key           TEXT PRIMARY KEY,
+    processed_at  TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS processed_updates_processed_at_idx ON processed_updates (processed_at);
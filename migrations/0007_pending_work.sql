@@ -0,0 +1,23 @@
+-- migrations/0007_pending_work.sql
+-- Work that was still in memory when the process shut down: debounce buffers that were never
+-- processed, and generated replies still waiting out their response cue. Restored by the bot on startup.
+
+CREATE TABLE IF NOT EXISTS pending_buffers (
+    user_id     BIGINT PRIMARY KEY,
+    chat_id     BIGINT NOT NULL,
+    messages    JSONB NOT NULL,
+    created_at  TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS pending_replies (
+    id            BIGSERIAL PRIMARY KEY,
+    user_id       BIGINT NOT NULL,
+    chat_id       BIGINT NOT NULL,
+    thread_id     TEXT NOT NULL,
+    assistant_id  TEXT NOT NULL,
+    text          TEXT NOT NULL,
+    send_at       TIMESTAMPTZ NOT NULL,
+    created_at    TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS pending_replies_send_at_idx ON pending_replies (send_at);
@@ -0,0 +1,12 @@
+-- migrations/0008_quiet_hours.sql
+-- Per-user timezone for quiet hours, and replies deferred out of them. timezone is an IANA name; timezone_source
+-- says where it came from (configured, phone or language) so a better source is never overwritten by a guess.
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS language_code TEXT;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS timezone TEXT;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS timezone_source TEXT;
+
+-- deferred_from is when the reply would have gone out had it not fallen in quiet hours; claimed_at marks a reply
+-- a bot process is sending right now
+ALTER TABLE pending_replies ADD COLUMN IF NOT EXISTS deferred_from TIMESTAMPTZ;
+ALTER TABLE pending_replies ADD COLUMN IF NOT EXISTS claimed_at TIMESTAMPTZ;
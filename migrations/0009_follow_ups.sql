@@ -0,0 +1,19 @@
+-- migrations/0009_follow_ups.sql
+-- Proactive follow-ups sent to users who stopped replying. status is sent, failed or blocked (the user blocked the
+-- bot); only sent ones count towards FOLLOW_UP_MAX_PER_USER, and a blocked one stops the campaign for that user.
+
+CREATE TABLE IF NOT EXISTS follow_ups (
+    id            BIGSERIAL PRIMARY KEY,
+    user_id       BIGINT NOT NULL,
+    thread_id     TEXT NOT NULL,
+    assistant_id  TEXT NOT NULL,
+    text          TEXT NOT NULL,
+    status        TEXT NOT NULL,
+    error         TEXT,
+    created_at    TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS follow_ups_user_idx ON follow_ups (user_id, created_at DESC);
+
+-- For finding each thread's last inbound message
+CREATE INDEX IF NOT EXISTS messages_thread_created_idx ON messages (thread_id, created_at DESC);
@@ -0,0 +1,31 @@
+-- migrations/0010_broadcasts.sql
+-- Announcements sent through the bot to a segment of users, and how delivery went for each recipient. Recipients
+-- are resolved when the broadcast is created; the bot sends to them while status is queued or sending.
+
+CREATE TABLE IF NOT EXISTS broadcasts (
+    id           BIGSERIAL PRIMARY KEY,
+    text         TEXT NOT NULL,
+    segment      JSONB NOT NULL,
+    -- queued, sending, done or cancelled
+    status       TEXT NOT NULL DEFAULT 'queued',
+    created_by   TEXT NOT NULL,
+    created_at   TIMESTAMPTZ NOT NULL DEFAULT now(),
+    started_at   TIMESTAMPTZ,
+    finished_at  TIMESTAMPTZ
+);
+
+CREATE TABLE IF NOT EXISTS broadcast_recipients (
+    broadcast_id  BIGINT NOT NULL REFERENCES broadcasts (id) ON DELETE CASCADE,
+    user_id       BIGINT NOT NULL,
+    -- pending, sent, failed or unreachable
+    status        TEXT NOT NULL DEFAULT 'pending',
+    attempts      INTEGER NOT NULL DEFAULT 0,
+    error         TEXT,
+    sent_at       TIMESTAMPTZ,
+    PRIMARY KEY (broadcast_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS broadcast_recipients_status_idx ON broadcast_recipients (broadcast_id, status);
+
+-- Set when Telegram says the user blocked the bot or deleted their account; cleared when they write again
+ALTER TABLE users ADD COLUMN IF NOT EXISTS unreachable_at TIMESTAMPTZ;
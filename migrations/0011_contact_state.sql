@@ -0,0 +1,13 @@
+-- migrations/0011_contact_state.sql
+-- Whether the bot may message a user: active, opted_out (they sent /stop, or an admin recorded it) or blocked
+-- (Telegram says they blocked the bot or deleted their account). Only active users get follow-ups, broadcasts and
+-- delayed replies. A blocked user who writes again is active again; an opted-out one only after /start.
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS contact_state TEXT NOT NULL DEFAULT 'active';
+ALTER TABLE users ADD COLUMN IF NOT EXISTS contact_state_at TIMESTAMPTZ;
+
+-- Replaces unreachable_at
+UPDATE users SET contact_state = 'blocked', contact_state_at = unreachable_at WHERE unreachable_at IS NOT NULL;
+ALTER TABLE users DROP COLUMN IF EXISTS unreachable_at;
+
+-- broadcast_recipients.status can also be opted_out now, for a recipient who opted out before their turn
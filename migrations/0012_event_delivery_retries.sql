@@ -0,0 +1,7 @@
+-- migrations/0012_event_delivery_retries.sql
+-- When a failed delivery attempt is to be retried. Set on the attempt's row and cleared once the next attempt is
+-- logged, so retries still go out after a restart.
+
+ALTER TABLE event_deliveries ADD COLUMN IF NOT EXISTS next_attempt_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS event_deliveries_next_attempt_idx ON event_deliveries (next_attempt_at) WHERE next_attempt_at IS NOT NULL;
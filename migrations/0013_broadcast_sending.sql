@@ -0,0 +1,4 @@
+-- migrations/0013_broadcast_sending.sql
+-- broadcast_recipients.status can also be sending: set just before the message goes out, so a recipient whose
+-- outcome couldn't be recorded (the database failing, a crash) is never sent the broadcast twice. Still sending once
+-- the broadcast is done, they end up failed, as it is unknown whether they got it.
@@ -0,0 +1,6 @@
+-- migrations/0014_processed_update_claims.sql
+-- Keys are now claimed before their update is handled and completed after it, so two deliveries of the same update
+-- can't both get past the check. A claim that is neither completed nor released (the process died) lapses after a
+-- lease and the update can be handled again. Rows from before were all written once handled.
+
+ALTER TABLE processed_updates ADD COLUMN IF NOT EXISTS completed BOOLEAN NOT NULL DEFAULT true;
@@ -0,0 +1,166 @@
+-- migrations/sqlite/0001_initial.sql
+-- The schema of migrations/0001 to 0014 for the sqlite backend (database_sqlite.rs). Schema changes after those
+-- need a migration here too.
+--
+-- Timestamps are TEXT in the format rusqlite writes chrono::DateTime<Utc> in (2024-01-31 12:00:00.123+00:00), so
+-- they compare correctly as strings. Arrays and JSONB are JSON TEXT, BYTEA is a BLOB and booleans are 0 or 1.
+
+CREATE TABLE IF NOT EXISTS users (
+    user_id           INTEGER PRIMARY KEY,
+    first_name        TEXT,
+    last_name         TEXT,
+    username          TEXT,
+    language_code     TEXT,
+    timezone          TEXT,
+    timezone_source   TEXT,
+    contact_state     TEXT NOT NULL DEFAULT 'active',
+    contact_state_at  TEXT
+);
+
+CREATE TABLE IF NOT EXISTS threads (
+    thread_id         TEXT PRIMARY KEY,
+    user_id           INTEGER NOT NULL REFERENCES users (user_id),
+    openai_thread_id  TEXT NOT NULL,
+    assistant_id      TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS messages (
+    id            INTEGER PRIMARY KEY,
+    thread_id     TEXT NOT NULL,
+    sender        TEXT NOT NULL,
+    content       TEXT NOT NULL,
+    message_type  TEXT NOT NULL,
+    assistant_id  TEXT NOT NULL,
+    created_at    TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f+00:00', 'now'))
+);
+
+CREATE INDEX IF NOT EXISTS messages_thread_created_idx ON messages (thread_id, created_at DESC);
+
+CREATE TABLE IF NOT EXISTS metrics (
+    id                  INTEGER PRIMARY KEY,
+    user_id             INTEGER NOT NULL,
+    thread_id           TEXT NOT NULL,
+    interest            INTEGER NOT NULL,
+    user_response_time  INTEGER,
+    response_cue        INTEGER,
+    created_at          TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f+00:00', 'now'))
+);
+
+CREATE TABLE IF NOT EXISTS api_keys (
+    id            INTEGER PRIMARY KEY,
+    name          TEXT NOT NULL,
+    key_hash      TEXT NOT NULL UNIQUE,
+    created_at    TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f+00:00', 'now')),
+    last_used_at  TEXT,
+    revoked_at    TEXT
+);
+
+CREATE TABLE IF NOT EXISTS event_subscriptions (
+    id           INTEGER PRIMARY KEY,
+    url          TEXT NOT NULL,
+    secret       TEXT NOT NULL,
+    event_types  TEXT NOT NULL,
+    active       INTEGER NOT NULL DEFAULT 1,
+    created_at   TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f+00:00', 'now'))
+);
+
+CREATE TABLE IF NOT EXISTS event_deliveries (
+    id               INTEGER PRIMARY KEY,
+    subscription_id  INTEGER NOT NULL REFERENCES event_subscriptions (id) ON DELETE CASCADE,
+    event_id         TEXT NOT NULL,
+    event_type       TEXT NOT NULL,
+    payload          TEXT NOT NULL,
+    attempt          INTEGER NOT NULL,
+    status_code      INTEGER,
+    error            TEXT,
+    succeeded        INTEGER NOT NULL,
+    next_attempt_at  TEXT,
+    created_at       TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f+00:00', 'now'))
+);
+
+CREATE INDEX IF NOT EXISTS event_deliveries_subscription_idx ON event_deliveries (subscription_id, created_at DESC);
+CREATE INDEX IF NOT EXISTS event_deliveries_next_attempt_idx ON event_deliveries (next_attempt_at) WHERE next_attempt_at IS NOT NULL;
+
+CREATE TABLE IF NOT EXISTS webhook_events (
+    id                  INTEGER PRIMARY KEY,
+    provider            TEXT NOT NULL,
+    path                TEXT NOT NULL,
+    headers             TEXT NOT NULL,
+    body                BLOB NOT NULL,
+    verified            INTEGER NOT NULL,
+    verification_error  TEXT,
+    status              TEXT NOT NULL DEFAULT 'received',
+    attempts            INTEGER NOT NULL DEFAULT 0,
+    last_error          TEXT,
+    received_at         TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f+00:00', 'now')),
+    processed_at        TEXT
+);
+
+CREATE INDEX IF NOT EXISTS webhook_events_provider_idx ON webhook_events (provider, received_at DESC);
+CREATE INDEX IF NOT EXISTS webhook_events_status_idx ON webhook_events (status, received_at);
+
+CREATE TABLE IF NOT EXISTS processed_updates (
+    key           TEXT PRIMARY KEY,
+    completed     INTEGER NOT NULL DEFAULT 1,
+    processed_at  TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f+00:00', 'now'))
+);
+
+CREATE INDEX IF NOT EXISTS processed_updates_processed_at_idx ON processed_updates (processed_at);
+
+CREATE TABLE IF NOT EXISTS pending_buffers (
+    user_id     INTEGER PRIMARY KEY,
+    chat_id     INTEGER NOT NULL,
+    messages    TEXT NOT NULL,
+    created_at  TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f+00:00', 'now'))
+);
+
+CREATE TABLE IF NOT EXISTS pending_replies (
+    id             INTEGER PRIMARY KEY,
+    user_id        INTEGER NOT NULL,
+    chat_id        INTEGER NOT NULL,
+    thread_id      TEXT NOT NULL,
+    assistant_id   TEXT NOT NULL,
+    text           TEXT NOT NULL,
+    send_at        TEXT NOT NULL,
+    deferred_from  TEXT,
+    claimed_at     TEXT,
+    created_at     TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f+00:00', 'now'))
+);
+
+CREATE INDEX IF NOT EXISTS pending_replies_send_at_idx ON pending_replies (send_at);
+
+CREATE TABLE IF NOT EXISTS follow_ups (
+    id            INTEGER PRIMARY KEY,
+    user_id       INTEGER NOT NULL,
+    thread_id     TEXT NOT NULL,
+    assistant_id  TEXT NOT NULL,
+    text          TEXT NOT NULL,
+    status        TEXT NOT NULL,
+    error         TEXT,
+    created_at    TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f+00:00', 'now'))
+);
+
+CREATE INDEX IF NOT EXISTS follow_ups_user_idx ON follow_ups (user_id, created_at DESC);
+
+CREATE TABLE IF NOT EXISTS broadcasts (
+    id           INTEGER PRIMARY KEY,
+    text         TEXT NOT NULL,
+    segment      TEXT NOT NULL,
+    status       TEXT NOT NULL DEFAULT 'queued',
+    created_by   TEXT NOT NULL,
+    created_at   TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f+00:00', 'now')),
+    started_at   TEXT,
+    finished_at  TEXT
+);
+
+CREATE TABLE IF NOT EXISTS broadcast_recipients (
+    broadcast_id  INTEGER NOT NULL REFERENCES broadcasts (id) ON DELETE CASCADE,
+    user_id       INTEGER NOT NULL,
+    status        TEXT NOT NULL DEFAULT 'pending',
+    attempts      INTEGER NOT NULL DEFAULT 0,
+    error         TEXT,
+    sent_at       TEXT,
+    PRIMARY KEY (broadcast_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS broadcast_recipients_status_idx ON broadcast_recipients (broadcast_id, status);
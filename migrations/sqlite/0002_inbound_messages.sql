@@ -0,0 +1,14 @@
+-- migrations/sqlite/0002_inbound_messages.sql
+-- Same as migrations/0015_inbound_messages.sql.
+-- Each batch of messages a user sends is now stored as a sender = 'user' row on their Convo AI thread, which
+-- follow-ups go by to tell when the user last wrote. Until now the only trace of a batch was the Analyzing AI's notes
+-- on it (assistant_id = telegram::ANALYZING_AI_ID); those stand in for the batches sent before this migration, with
+-- empty content as their text was never stored.
+
+INSERT INTO messages (thread_id, sender, content, message_type, assistant_id, created_at)
+SELECT m.thread_id, 'user', '', m.message_type, t.assistant_id, m.created_at
+FROM messages m
+JOIN threads t ON t.thread_id = m.thread_id
+WHERE m.sender = 'assistant' AND m.assistant_id = 'asst_JjoQ4OUjIgdhTgA9fiAIeRQu'
+  AND NOT EXISTS (SELECT 1 FROM messages u WHERE u.thread_id = m.thread_id AND u.sender = 'user')
+ORDER BY m.id;
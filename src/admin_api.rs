@@ -0,0 +1,796 @@
+// src/admin_api.rs
+
+use warp::{Filter, Reply};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use rand::RngCore;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+// Rejection carried through warp so every failure ends up as the same JSON error body
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: warp::http::StatusCode,
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl warp::reject::Reject for ApiError {}
+
+impl ApiError {
+    pub fn new(status: warp::http::StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError { status, code, message: message.into() }
+    }
+
+    pub fn unauthorized() -> Self {
+        ApiError::new(warp::http::StatusCode::UNAUTHORIZED, "unauthorized", "Missing or invalid API key")
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        ApiError::new(warp::http::StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    pub fn internal(e: anyhow::Error) -> Self {
+        log::error!("admin api: internal error: {:?}", e);
+        ApiError::new(warp::http::StatusCode::INTERNAL_SERVER_ERROR, "internal_error", "Internal server error")
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    pub error: ErrorDetail,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorDetail {
+    pub code: &'static str,
+    pub message: String,
+}
+
+pub fn error_reply(status: warp::http::StatusCode, code: &'static str, message: &str) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(
+        warp::reply::json(&ErrorBody { error: ErrorDetail { code, message: message.to_string() } }),
+        status,
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Pagination {
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+impl Pagination {
+    fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+    }
+
+    fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0).max(0)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserSearch {
+    pub q: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ThreadFilter {
+    pub assistant_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TimezoneUpdate {
+    // IANA name, e.g. Europe/Berlin
+    pub timezone: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ContactStateUpdate {
+    // active or opted_out; blocked is only ever set from Telegram's answers
+    pub state: String,
+    // Needed to make an opted-out user active again; otherwise only their own /start does
+    #[serde(default)]
+    pub force: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PendingReplyFilter {
+    pub user_id: Option<i64>,
+    // Only replies deferred out of quiet hours
+    pub deferred: Option<bool>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewBroadcast {
+    pub text: String,
+    #[serde(default)]
+    pub segment: crate::BroadcastSegment,
+    // Only count the recipients
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BroadcastPreview {
+    pub recipients: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RecipientFilter {
+    pub status: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewSubscription {
+    pub url: String,
+    pub event_types: Vec<String>,
+    pub secret: Option<String>,
+}
+
+// The signing secret is only ever returned in the response to the create call
+#[derive(Debug, Serialize)]
+pub struct CreatedSubscription {
+    #[serde(flatten)]
+    pub subscription: crate::DBEventSubscription,
+    pub secret: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebhookEventFilter {
+    pub status: Option<String>,
+    pub provider: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookEventDetail {
+    #[serde(flatten)]
+    pub event: crate::DBWebhookEvent,
+    pub body: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    pub data: Vec<T>,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+// Keys are handed out once in plain text; only the SHA-256 hex digest is stored
+pub fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+pub fn generate_api_key() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    format!("whk_{}", hex::encode(bytes))
+}
+
+pub async fn create_api_key(pool: &crate::database::Pool, name: &str) -> Result<String, anyhow::Error> {
+    let key = generate_api_key();
+    crate::database::insert_api_key(pool, name, &hash_api_key(&key)).await?;
+    Ok(key)
+}
+
+fn with_pool(pool: crate::database::Pool) -> impl Filter<Extract = (crate::database::Pool,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || pool.clone())
+}
+
+// Accepts either `Authorization: Bearer <key>` or `X-Api-Key: <key>`
+pub fn with_auth(pool: crate::database::Pool) -> impl Filter<Extract = (crate::DBApiKey,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::header::optional::<String>("x-api-key"))
+        .and(with_pool(pool.clone()))
+        .and_then(|authorization: Option<String>, api_key: Option<String>, pool: crate::database::Pool| async move {
+            let token = api_key.or_else(|| {
+                authorization.and_then(|value| value.strip_prefix("Bearer ").map(|t| t.trim().to_string()))
+            });
+            let token = match token {
+                Some(token) if !token.is_empty() => token,
+                _ => return Err(warp::reject::custom(ApiError::unauthorized())),
+            };
+
+            // ADMIN_API_KEY works without being stored, so a fresh deployment has a way in
+            let token_hash = hash_api_key(&token);
+            if std::env::var("ADMIN_API_KEY").is_ok_and(|key| !key.is_empty() && hash_api_key(&key) == token_hash) {
+                return Ok(crate::DBApiKey { id: 0, name: "ADMIN_API_KEY".to_string() });
+            }
+
+            match crate::database::get_api_key_by_hash(&pool, &token_hash).await {
+                Ok(Some(key)) => Ok(key),
+                Ok(None) => Err(warp::reject::custom(ApiError::unauthorized())),
+                Err(e) => Err(warp::reject::custom(ApiError::internal(e))),
+            }
+        })
+}
+
+pub fn routes(pool: crate::database::Pool, receivers: std::sync::Arc<crate::receivers::ReceiverRegistry>) -> warp::filters::BoxedFilter<(warp::reply::Response,)> {
+    let api = warp::path("api").and(warp::path("v1"));
+    let auth = with_auth(pool.clone());
+
+    // GET /api/v1/users?q=&limit=&offset=
+    let list_users = api
+        .and(warp::path("users"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth.clone())
+        .and(warp::query::<UserSearch>())
+        .and(with_pool(pool.clone()))
+        .and_then(list_users);
+
+    // GET /api/v1/users/{id}
+    let get_user = api
+        .and(warp::path("users"))
+        .and(warp::path::param::<i64>())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth.clone())
+        .and(with_pool(pool.clone()))
+        .and_then(get_user);
+
+    // GET /api/v1/users/{id}/threads?assistant_id=
+    let user_threads = api
+        .and(warp::path("users"))
+        .and(warp::path::param::<i64>())
+        .and(warp::path("threads"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth.clone())
+        .and(warp::query::<ThreadFilter>())
+        .and(with_pool(pool.clone()))
+        .and_then(user_threads);
+
+    // GET /api/v1/users/{id}/metrics?limit=&offset=
+    let user_metrics = api
+        .and(warp::path("users"))
+        .and(warp::path::param::<i64>())
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth.clone())
+        .and(warp::query::<Pagination>())
+        .and(with_pool(pool.clone()))
+        .and_then(user_metrics);
+
+    // PUT /api/v1/users/{id}/timezone
+    let set_user_timezone = api
+        .and(warp::path("users"))
+        .and(warp::path::param::<i64>())
+        .and(warp::path("timezone"))
+        .and(warp::path::end())
+        .and(warp::put())
+        .and(auth.clone())
+        .and(warp::body::json::<TimezoneUpdate>())
+        .and(with_pool(pool.clone()))
+        .and_then(set_user_timezone);
+
+    // PUT /api/v1/users/{id}/contact-state
+    let set_contact_state = api
+        .and(warp::path("users"))
+        .and(warp::path::param::<i64>())
+        .and(warp::path("contact-state"))
+        .and(warp::path::end())
+        .and(warp::put())
+        .and(auth.clone())
+        .and(warp::body::json::<ContactStateUpdate>())
+        .and(with_pool(pool.clone()))
+        .and_then(set_contact_state);
+
+    // DELETE /api/v1/users/{id}
+    let delete_user = api
+        .and(warp::path("users"))
+        .and(warp::path::param::<i64>())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(auth.clone())
+        .and(with_pool(pool.clone()))
+        .and_then(delete_user);
+
+    // GET /api/v1/threads/{thread_id}/messages?limit=&offset=
+    let thread_messages = api
+        .and(warp::path("threads"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("messages"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth.clone())
+        .and(warp::query::<Pagination>())
+        .and(with_pool(pool.clone()))
+        .and_then(thread_messages);
+
+    // GET /api/v1/pending-replies?user_id=&deferred=&limit=&offset=
+    let list_pending_replies = api
+        .and(warp::path("pending-replies"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth.clone())
+        .and(warp::query::<PendingReplyFilter>())
+        .and(with_pool(pool.clone()))
+        .and_then(list_pending_replies);
+
+    // GET /api/v1/broadcasts?limit=&offset=
+    let list_broadcasts = api
+        .and(warp::path("broadcasts"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth.clone())
+        .and(warp::query::<Pagination>())
+        .and(with_pool(pool.clone()))
+        .and_then(list_broadcasts);
+
+    // POST /api/v1/broadcasts
+    let create_broadcast = api
+        .and(warp::path("broadcasts"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(auth.clone())
+        .and(warp::body::json::<NewBroadcast>())
+        .and(with_pool(pool.clone()))
+        .and_then(create_broadcast);
+
+    // GET /api/v1/broadcasts/{id}
+    let get_broadcast = api
+        .and(warp::path("broadcasts"))
+        .and(warp::path::param::<i64>())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth.clone())
+        .and(with_pool(pool.clone()))
+        .and_then(get_broadcast);
+
+    // DELETE /api/v1/broadcasts/{id} - cancels it
+    let cancel_broadcast = api
+        .and(warp::path("broadcasts"))
+        .and(warp::path::param::<i64>())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(auth.clone())
+        .and(with_pool(pool.clone()))
+        .and_then(cancel_broadcast);
+
+    // GET /api/v1/broadcasts/{id}/recipients?status=&limit=&offset=
+    let broadcast_recipients = api
+        .and(warp::path("broadcasts"))
+        .and(warp::path::param::<i64>())
+        .and(warp::path("recipients"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth.clone())
+        .and(warp::query::<RecipientFilter>())
+        .and(with_pool(pool.clone()))
+        .and_then(broadcast_recipients);
+
+    // GET /api/v1/subscriptions
+    let list_subscriptions = api
+        .and(warp::path("subscriptions"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth.clone())
+        .and(with_pool(pool.clone()))
+        .and_then(list_subscriptions);
+
+    // POST /api/v1/subscriptions
+    let create_subscription = api
+        .and(warp::path("subscriptions"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(auth.clone())
+        .and(warp::body::json::<NewSubscription>())
+        .and(with_pool(pool.clone()))
+        .and_then(create_subscription);
+
+    // DELETE /api/v1/subscriptions/{id}
+    let delete_subscription = api
+        .and(warp::path("subscriptions"))
+        .and(warp::path::param::<i64>())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(auth.clone())
+        .and(with_pool(pool.clone()))
+        .and_then(delete_subscription);
+
+    // GET /api/v1/subscriptions/{id}/deliveries?limit=&offset=
+    let subscription_deliveries = api
+        .and(warp::path("subscriptions"))
+        .and(warp::path::param::<i64>())
+        .and(warp::path("deliveries"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth.clone())
+        .and(warp::query::<Pagination>())
+        .and(with_pool(pool.clone()))
+        .and_then(subscription_deliveries);
+
+    // GET /api/v1/webhook-events?status=&provider=&limit=&offset=
+    let list_webhook_events = api
+        .and(warp::path("webhook-events"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth.clone())
+        .and(warp::query::<WebhookEventFilter>())
+        .and(with_pool(pool.clone()))
+        .and_then(list_webhook_events);
+
+    // GET /api/v1/webhook-events/{id}
+    let get_webhook_event = api
+        .and(warp::path("webhook-events"))
+        .and(warp::path::param::<i64>())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth.clone())
+        .and(with_pool(pool.clone()))
+        .and_then(get_webhook_event);
+
+    // GET /api/v1/webhook-events/{id}/body - the exact bytes we received, handy as a test fixture
+    let webhook_event_body = api
+        .and(warp::path("webhook-events"))
+        .and(warp::path::param::<i64>())
+        .and(warp::path("body"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth.clone())
+        .and(with_pool(pool.clone()))
+        .and_then(webhook_event_body);
+
+    // POST /api/v1/webhook-events/{id}/replay
+    let receivers_for_replay = receivers.clone();
+    let replay_webhook_event = api
+        .and(warp::path("webhook-events"))
+        .and(warp::path::param::<i64>())
+        .and(warp::path("replay"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(auth.clone())
+        .and(warp::any().map(move || receivers_for_replay.clone()))
+        .and(with_pool(pool.clone()))
+        .and_then(replay_webhook_event);
+
+    // POST /api/v1/webhook-events/replay?status=dead_letter&provider=&limit=
+    let replay_webhook_events = api
+        .and(warp::path("webhook-events"))
+        .and(warp::path("replay"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(auth.clone())
+        .and(warp::query::<WebhookEventFilter>())
+        .and(warp::any().map(move || receivers.clone()))
+        .and(with_pool(pool.clone()))
+        .and_then(replay_webhook_events);
+
+    list_users
+        .or(get_user)
+        .unify()
+        .or(user_threads)
+        .unify()
+        .or(user_metrics)
+        .unify()
+        .or(set_user_timezone)
+        .unify()
+        .or(set_contact_state)
+        .unify()
+        .or(delete_user)
+        .unify()
+        .or(thread_messages)
+        .unify()
+        .or(list_pending_replies)
+        .unify()
+        .or(list_broadcasts)
+        .unify()
+        .or(create_broadcast)
+        .unify()
+        .or(get_broadcast)
+        .unify()
+        .or(cancel_broadcast)
+        .unify()
+        .or(broadcast_recipients)
+        .unify()
+        .or(list_subscriptions)
+        .unify()
+        .or(create_subscription)
+        .unify()
+        .or(delete_subscription)
+        .unify()
+        .or(subscription_deliveries)
+        .unify()
+        .or(list_webhook_events)
+        .unify()
+        .or(get_webhook_event)
+        .unify()
+        .or(webhook_event_body)
+        .unify()
+        .or(replay_webhook_event)
+        .unify()
+        .or(replay_webhook_events)
+        .unify()
+        .boxed()
+}
+
+async fn list_users(key: crate::DBApiKey, search: UserSearch, pool: crate::database::Pool) -> Result<warp::reply::Response, warp::Rejection> {
+    log::info!("admin api: key {} listing users", key.name);
+    let page = Pagination { limit: search.limit, offset: search.offset };
+    let search_term = search.q.as_deref().map(str::trim).filter(|q| !q.is_empty());
+    let users = crate::database::search_users(&pool, search_term, page.limit(), page.offset())
+        .await
+        .map_err(|e| warp::reject::custom(ApiError::internal(e)))?;
+    Ok(warp::reply::json(&Page { data: users, limit: page.limit(), offset: page.offset() }).into_response())
+}
+
+async fn get_user(user_id: i64, key: crate::DBApiKey, pool: crate::database::Pool) -> Result<warp::reply::Response, warp::Rejection> {
+    log::info!("admin api: key {} fetching user {}", key.name, user_id);
+    match crate::database::get_user(&pool, user_id).await {
+        Ok(Some(user)) => Ok(warp::reply::json(&user).into_response()),
+        Ok(None) => Err(warp::reject::custom(ApiError::not_found(format!("User {} not found", user_id)))),
+        Err(e) => Err(warp::reject::custom(ApiError::internal(e))),
+    }
+}
+
+async fn user_threads(user_id: i64, key: crate::DBApiKey, filter: ThreadFilter, pool: crate::database::Pool) -> Result<warp::reply::Response, warp::Rejection> {
+    log::info!("admin api: key {} fetching threads for user {}", key.name, user_id);
+    let threads = crate::database::get_threads_by_user_id(&pool, user_id, filter.assistant_id.as_deref())
+        .await
+        .map_err(|e| warp::reject::custom(ApiError::internal(e)))?;
+    Ok(warp::reply::json(&threads).into_response())
+}
+
+async fn user_metrics(user_id: i64, key: crate::DBApiKey, page: Pagination, pool: crate::database::Pool) -> Result<warp::reply::Response, warp::Rejection> {
+    log::info!("admin api: key {} fetching metrics for user {}", key.name, user_id);
+    let metrics = crate::database::get_metrics_by_user_id(&pool, user_id, page.limit(), page.offset())
+        .await
+        .map_err(|e| warp::reject::custom(ApiError::internal(e)))?;
+    Ok(warp::reply::json(&Page { data: metrics, limit: page.limit(), offset: page.offset() }).into_response())
+}
+
+async fn set_user_timezone(user_id: i64, key: crate::DBApiKey, update: TimezoneUpdate, pool: crate::database::Pool) -> Result<warp::reply::Response, warp::Rejection> {
+    log::info!("admin api: key {} setting timezone of user {} to {}", key.name, user_id, update.timezone);
+    let Ok(timezone) = update.timezone.trim().parse::<chrono_tz::Tz>() else {
+        return Err(warp::reject::custom(ApiError::new(
+            warp::http::StatusCode::BAD_REQUEST,
+            "invalid_timezone",
+            format!("Unknown timezone {}, expected an IANA name like Europe/Berlin", update.timezone),
+        )));
+    };
+    crate::database::set_user_timezone(&pool, user_id, timezone.name(), "configured")
+        .await
+        .map_err(|e| warp::reject::custom(ApiError::internal(e)))?;
+    get_user(user_id, key, pool).await
+}
+
+async fn set_contact_state(user_id: i64, key: crate::DBApiKey, update: ContactStateUpdate, pool: crate::database::Pool) -> Result<warp::reply::Response, warp::Rejection> {
+    log::info!("admin api: key {} setting contact state of user {} to {}", key.name, user_id, update.state);
+    if update.state != crate::CONTACT_ACTIVE && update.state != crate::CONTACT_OPTED_OUT {
+        return Err(warp::reject::custom(ApiError::new(
+            warp::http::StatusCode::BAD_REQUEST,
+            "invalid_state",
+            format!("state must be {} or {}", crate::CONTACT_ACTIVE, crate::CONTACT_OPTED_OUT),
+        )));
+    }
+    let user = crate::database::get_user(&pool, user_id)
+        .await
+        .map_err(|e| warp::reject::custom(ApiError::internal(e)))?
+        .ok_or_else(|| warp::reject::custom(ApiError::not_found(format!("User {} not found", user_id))))?;
+    if user.contact_state == crate::CONTACT_OPTED_OUT && update.state == crate::CONTACT_ACTIVE {
+        if !update.force {
+            return Err(warp::reject::custom(ApiError::new(
+                warp::http::StatusCode::CONFLICT,
+                "opted_out",
+                format!("User {} opted out; they can re-subscribe with /start, or set force to override", user_id),
+            )));
+        }
+        log::warn!("admin api: key {} overriding the opt-out of user {}", key.name, user_id);
+    }
+    crate::database::set_contact_state(&pool, user_id, &update.state)
+        .await
+        .map_err(|e| warp::reject::custom(ApiError::internal(e)))?;
+    get_user(user_id, key, pool).await
+}
+
+async fn delete_user(user_id: i64, key: crate::DBApiKey, pool: crate::database::Pool) -> Result<warp::reply::Response, warp::Rejection> {
+    log::warn!("admin api: key {} deleting user {}", key.name, user_id);
+    match crate::database::delete_user(&pool, user_id).await {
+        Ok(true) => Ok(warp::reply::with_status(warp::reply(), warp::http::StatusCode::NO_CONTENT).into_response()),
+        Ok(false) => Err(warp::reject::custom(ApiError::not_found(format!("User {} not found", user_id)))),
+        Err(e) => Err(warp::reject::custom(ApiError::internal(e))),
+    }
+}
+
+async fn thread_messages(thread_id: String, key: crate::DBApiKey, page: Pagination, pool: crate::database::Pool) -> Result<warp::reply::Response, warp::Rejection> {
+    log::info!("admin api: key {} fetching messages for thread {}", key.name, thread_id);
+    let messages = crate::database::get_messages_by_thread_id(&pool, &thread_id, page.limit(), page.offset())
+        .await
+        .map_err(|e| warp::reject::custom(ApiError::internal(e)))?;
+    Ok(warp::reply::json(&Page { data: messages, limit: page.limit(), offset: page.offset() }).into_response())
+}
+
+async fn list_pending_replies(key: crate::DBApiKey, filter: PendingReplyFilter, pool: crate::database::Pool) -> Result<warp::reply::Response, warp::Rejection> {
+    log::info!("admin api: key {} listing pending replies", key.name);
+    let page = Pagination { limit: filter.limit, offset: filter.offset };
+    let replies = crate::database::list_pending_replies(&pool, filter.user_id, filter.deferred.unwrap_or(false), page.limit(), page.offset())
+        .await
+        .map_err(|e| warp::reject::custom(ApiError::internal(e)))?;
+    Ok(warp::reply::json(&Page { data: replies, limit: page.limit(), offset: page.offset() }).into_response())
+}
+
+async fn list_broadcasts(key: crate::DBApiKey, page: Pagination, pool: crate::database::Pool) -> Result<warp::reply::Response, warp::Rejection> {
+    log::info!("admin api: key {} listing broadcasts", key.name);
+    let broadcasts = crate::database::list_broadcasts(&pool, page.limit(), page.offset())
+        .await
+        .map_err(|e| warp::reject::custom(ApiError::internal(e)))?;
+    Ok(warp::reply::json(&Page { data: broadcasts, limit: page.limit(), offset: page.offset() }).into_response())
+}
+
+async fn create_broadcast(key: crate::DBApiKey, new_broadcast: NewBroadcast, pool: crate::database::Pool) -> Result<warp::reply::Response, warp::Rejection> {
+    log::info!("admin api: key {} creating broadcast for segment {:?} (dry run: {})", key.name, new_broadcast.segment, new_broadcast.dry_run);
+    let text = new_broadcast.text.trim();
+    if text.is_empty() {
+        return Err(warp::reject::custom(ApiError::new(warp::http::StatusCode::BAD_REQUEST, "invalid_text", "text must not be empty")));
+    }
+    if text.chars().count() > crate::pacing::TELEGRAM_MAX_MESSAGE_CHARS {
+        return Err(warp::reject::custom(ApiError::new(
+            warp::http::StatusCode::BAD_REQUEST,
+            "invalid_text",
+            format!("text must fit in one Telegram message of {} characters", crate::pacing::TELEGRAM_MAX_MESSAGE_CHARS),
+        )));
+    }
+
+    if new_broadcast.dry_run {
+        let recipients = crate::database::count_segment(&pool, &new_broadcast.segment)
+            .await
+            .map_err(|e| warp::reject::custom(ApiError::internal(e)))?;
+        return Ok(warp::reply::json(&BroadcastPreview { recipients }).into_response());
+    }
+    let broadcast = crate::database::insert_broadcast(&pool, text, &new_broadcast.segment, &key.name)
+        .await
+        .map_err(|e| warp::reject::custom(ApiError::internal(e)))?;
+    Ok(warp::reply::with_status(warp::reply::json(&broadcast), warp::http::StatusCode::CREATED).into_response())
+}
+
+async fn get_broadcast(broadcast_id: i64, key: crate::DBApiKey, pool: crate::database::Pool) -> Result<warp::reply::Response, warp::Rejection> {
+    log::info!("admin api: key {} fetching broadcast {}", key.name, broadcast_id);
+    match crate::database::get_broadcast(&pool, broadcast_id).await {
+        Ok(Some(broadcast)) => Ok(warp::reply::json(&broadcast).into_response()),
+        Ok(None) => Err(warp::reject::custom(ApiError::not_found(format!("Broadcast {} not found", broadcast_id)))),
+        Err(e) => Err(warp::reject::custom(ApiError::internal(e))),
+    }
+}
+
+async fn cancel_broadcast(broadcast_id: i64, key: crate::DBApiKey, pool: crate::database::Pool) -> Result<warp::reply::Response, warp::Rejection> {
+    log::info!("admin api: key {} cancelling broadcast {}", key.name, broadcast_id);
+    match crate::database::cancel_broadcast(&pool, broadcast_id).await {
+        Ok(true) => Ok(warp::reply::with_status(warp::reply(), warp::http::StatusCode::NO_CONTENT).into_response()),
+        Ok(false) => Err(warp::reject::custom(ApiError::not_found(format!("Broadcast {} not found or already finished", broadcast_id)))),
+        Err(e) => Err(warp::reject::custom(ApiError::internal(e))),
+    }
+}
+
+async fn broadcast_recipients(broadcast_id: i64, key: crate::DBApiKey, filter: RecipientFilter, pool: crate::database::Pool) -> Result<warp::reply::Response, warp::Rejection> {
+    log::info!("admin api: key {} fetching recipients of broadcast {}", key.name, broadcast_id);
+    let page = Pagination { limit: filter.limit, offset: filter.offset };
+    let recipients = crate::database::list_broadcast_recipients(&pool, broadcast_id, filter.status.as_deref(), page.limit(), page.offset())
+        .await
+        .map_err(|e| warp::reject::custom(ApiError::internal(e)))?;
+    Ok(warp::reply::json(&Page { data: recipients, limit: page.limit(), offset: page.offset() }).into_response())
+}
+
+async fn list_subscriptions(key: crate::DBApiKey, pool: crate::database::Pool) -> Result<warp::reply::Response, warp::Rejection> {
+    log::info!("admin api: key {} listing event subscriptions", key.name);
+    let subscriptions = crate::database::get_event_subscriptions(&pool)
+        .await
+        .map_err(|e| warp::reject::custom(ApiError::internal(e)))?;
+    Ok(warp::reply::json(&subscriptions).into_response())
+}
+
+async fn create_subscription(key: crate::DBApiKey, new_subscription: NewSubscription, pool: crate::database::Pool) -> Result<warp::reply::Response, warp::Rejection> {
+    log::info!("admin api: key {} creating event subscription for {}", key.name, new_subscription.url);
+    if let Err(e) = crate::events::check_target(&new_subscription.url).await {
+        return Err(warp::reject::custom(ApiError::new(warp::http::StatusCode::BAD_REQUEST, "invalid_url", e)));
+    }
+    if new_subscription.event_types.is_empty() {
+        return Err(warp::reject::custom(ApiError::new(warp::http::StatusCode::BAD_REQUEST, "invalid_event_types", "event_types must not be empty")));
+    }
+    if let Some(unknown) = new_subscription.event_types.iter().find(|t| !crate::events::EVENT_TYPES.contains(&t.as_str())) {
+        return Err(warp::reject::custom(ApiError::new(
+            warp::http::StatusCode::BAD_REQUEST,
+            "invalid_event_types",
+            format!("Unknown event type {}. Expected one of: {}", unknown, crate::events::EVENT_TYPES.join(", ")),
+        )));
+    }
+
+    let secret = new_subscription.secret.unwrap_or_else(generate_api_key);
+    let subscription = crate::database::insert_event_subscription(&pool, &new_subscription.url, &secret, &new_subscription.event_types)
+        .await
+        .map_err(|e| warp::reject::custom(ApiError::internal(e)))?;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&CreatedSubscription { subscription, secret }),
+        warp::http::StatusCode::CREATED,
+    ).into_response())
+}
+
+async fn delete_subscription(subscription_id: i64, key: crate::DBApiKey, pool: crate::database::Pool) -> Result<warp::reply::Response, warp::Rejection> {
+    log::info!("admin api: key {} deleting event subscription {}", key.name, subscription_id);
+    match crate::database::delete_event_subscription(&pool, subscription_id).await {
+        Ok(true) => Ok(warp::reply::with_status(warp::reply(), warp::http::StatusCode::NO_CONTENT).into_response()),
+        Ok(false) => Err(warp::reject::custom(ApiError::not_found(format!("Subscription {} not found", subscription_id)))),
+        Err(e) => Err(warp::reject::custom(ApiError::internal(e))),
+    }
+}
+
+async fn subscription_deliveries(subscription_id: i64, key: crate::DBApiKey, page: Pagination, pool: crate::database::Pool) -> Result<warp::reply::Response, warp::Rejection> {
+    log::info!("admin api: key {} fetching deliveries for subscription {}", key.name, subscription_id);
+    let deliveries = crate::database::get_event_deliveries(&pool, subscription_id, page.limit(), page.offset())
+        .await
+        .map_err(|e| warp::reject::custom(ApiError::internal(e)))?;
+    Ok(warp::reply::json(&Page { data: deliveries, limit: page.limit(), offset: page.offset() }).into_response())
+}
+
+async fn list_webhook_events(key: crate::DBApiKey, filter: WebhookEventFilter, pool: crate::database::Pool) -> Result<warp::reply::Response, warp::Rejection> {
+    log::info!("admin api: key {} listing webhook events", key.name);
+    let page = Pagination { limit: filter.limit, offset: filter.offset };
+    let mut events = crate::database::list_webhook_events(&pool, filter.status.as_deref(), filter.provider.as_deref(), page.limit(), page.offset())
+        .await
+        .map_err(|e| warp::reject::custom(ApiError::internal(e)))?;
+    for event in &mut events {
+        crate::receivers::redact_archived_headers(&mut event.headers);
+    }
+    Ok(warp::reply::json(&Page { data: events, limit: page.limit(), offset: page.offset() }).into_response())
+}
+
+async fn find_webhook_event(pool: &crate::database::Pool, event_id: i64) -> Result<crate::DBWebhookEvent, warp::Rejection> {
+    match crate::database::get_webhook_event(pool, event_id).await {
+        Ok(Some(event)) => Ok(event),
+        Ok(None) => Err(warp::reject::custom(ApiError::not_found(format!("Webhook event {} not found", event_id)))),
+        Err(e) => Err(warp::reject::custom(ApiError::internal(e))),
+    }
+}
+
+async fn get_webhook_event(event_id: i64, key: crate::DBApiKey, pool: crate::database::Pool) -> Result<warp::reply::Response, warp::Rejection> {
+    log::info!("admin api: key {} fetching webhook event {}", key.name, event_id);
+    let mut event = find_webhook_event(&pool, event_id).await?;
+    crate::receivers::redact_archived_headers(&mut event.headers);
+    let body = String::from_utf8_lossy(&event.body).into_owned();
+    Ok(warp::reply::json(&WebhookEventDetail { event, body }).into_response())
+}
+
+async fn webhook_event_body(event_id: i64, key: crate::DBApiKey, pool: crate::database::Pool) -> Result<warp::reply::Response, warp::Rejection> {
+    log::info!("admin api: key {} downloading body of webhook event {}", key.name, event_id);
+    let event = find_webhook_event(&pool, event_id).await?;
+    let content_type = event.headers["content-type"].as_str().unwrap_or("application/octet-stream").to_string();
+    Ok(warp::reply::with_header(event.body, "content-type", content_type).into_response())
+}
+
+async fn replay_webhook_event(
+    event_id: i64,
+    key: crate::DBApiKey,
+    receivers: std::sync::Arc<crate::receivers::ReceiverRegistry>,
+    pool: crate::database::Pool,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    log::info!("admin api: key {} replaying webhook event {}", key.name, event_id);
+    let event = find_webhook_event(&pool, event_id).await?;
+    let outcome = crate::receivers::replay_event(&receivers, &pool, &event).await;
+    Ok(warp::reply::json(&outcome).into_response())
+}
+
+async fn replay_webhook_events(
+    key: crate::DBApiKey,
+    filter: WebhookEventFilter,
+    receivers: std::sync::Arc<crate::receivers::ReceiverRegistry>,
+    pool: crate::database::Pool,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let status = filter.status.as_deref().unwrap_or("dead_letter");
+    log::info!("admin api: key {} replaying {} webhook events", key.name, status);
+    let page = Pagination { limit: filter.limit, offset: None };
+    let outcomes = crate::receivers::replay_events(&receivers, &pool, status, filter.provider.as_deref(), page.limit())
+        .await
+        .map_err(|e| warp::reject::custom(ApiError::internal(e)))?;
+    Ok(warp::reply::json(&outcomes).into_response())
+}
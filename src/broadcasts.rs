@@ -0,0 +1,190 @@
+// src/broadcasts.rs
+
+// Sends the broadcasts created through the admin API, one at a time and oldest first, each recipient getting the
+// text as a single message. Telegram allows about 30 messages a second across chats and one a second per chat:
+// sends are spaced to BROADCAST_MESSAGES_PER_SEC (default 25, leaving room for the bot's replies), a chat gets one
+// message per broadcast, and a RetryAfter pauses the broadcast for as long as Telegram asks. A recipient who blocked
+// the bot or deleted their account is marked unreachable, and blocked on the users row so later segments leave them
+// out; one who opted out or was found blocked since the broadcast was created is skipped. A recipient is marked
+// sending before the message goes out and never sent it again from there, even if their outcome can't be recorded.
+
+use tokio::time::Duration;
+
+// Recipients loaded at a time; a cancel through the admin API takes effect between pages
+const RECIPIENTS_PER_PAGE: i64 = 100;
+// Tries per recipient on errors other than blocked, not counting RetryAfter waits
+const MAX_ATTEMPTS: i32 = 3;
+// Tries to record a recipient's outcome, a second apart and then doubling
+const RECORD_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, Clone)]
+pub struct BroadcastConfig {
+    pub messages_per_sec: f64,
+    // How often to look for a new broadcast when there is nothing to send
+    pub poll_interval: Duration,
+}
+
+impl BroadcastConfig {
+    // BROADCAST_MESSAGES_PER_SEC (default 25) and BROADCAST_POLL_SECS (default 5)
+    pub fn from_env() -> Self {
+        let poll_secs = std::env::var("BROADCAST_POLL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(5);
+        BroadcastConfig {
+            messages_per_sec: std::env::var("BROADCAST_MESSAGES_PER_SEC").ok().and_then(|v| v.parse().ok()).unwrap_or(25.0),
+            poll_interval: Duration::from_secs(poll_secs),
+        }
+    }
+}
+
+// Runs until shutdown; started once per process next to send_due_replies. A broadcast interrupted by a shutdown
+// carries on with its pending recipients on the next start.
+pub async fn run(pool: crate::database::Pool, bot: teloxide::Bot) {
+    let config = BroadcastConfig::from_env();
+    while !crate::shutdown::is_shutting_down() {
+        match crate::database::start_next_broadcast(&pool).await {
+            Ok(Some(broadcast)) => {
+                send_broadcast(&pool, &bot, &config, &broadcast).await;
+                continue;
+            }
+            Ok(None) => {}
+            Err(e) => log::error!("broadcasts: failed to load the next broadcast: {:?}", e),
+        }
+        crate::shutdown::sleep_or_shutdown(config.poll_interval).await;
+    }
+}
+
+async fn send_broadcast(pool: &crate::database::Pool, bot: &teloxide::Bot, config: &BroadcastConfig, broadcast: &crate::DBBroadcast) {
+    log::info!("broadcasts: sending broadcast {} to {} pending of {} recipients", broadcast.id, broadcast.pending, broadcast.recipients);
+    let mut pace = tokio::time::interval(Duration::from_secs_f64(1.0 / config.messages_per_sec.max(0.1)));
+    pace.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        match crate::database::get_broadcast_status(pool, broadcast.id).await {
+            Ok(Some(status)) if status == "sending" => {}
+            Ok(status) => {
+                log::info!("broadcasts: broadcast {} is {:?}, stopping", broadcast.id, status);
+                return;
+            }
+            Err(e) => {
+                log::error!("broadcasts: failed to check status of broadcast {}: {:?}", broadcast.id, e);
+                return;
+            }
+        }
+        let recipients = match crate::database::get_pending_broadcast_recipients(pool, broadcast.id, RECIPIENTS_PER_PAGE).await {
+            Ok(recipients) => recipients,
+            Err(e) => {
+                log::error!("broadcasts: failed to load recipients of broadcast {}: {:?}", broadcast.id, e);
+                return;
+            }
+        };
+        if recipients.is_empty() {
+            match crate::database::finish_broadcast(pool, broadcast.id).await {
+                Ok(_) => log::info!("broadcasts: broadcast {} done", broadcast.id),
+                Err(e) => log::error!("broadcasts: failed to mark broadcast {} done: {:?}", broadcast.id, e),
+            }
+            return;
+        }
+        for (user_id, contact_state) in recipients {
+            if crate::shutdown::is_shutting_down() {
+                return;
+            }
+            let log_context = crate::logging::LogContext::for_chat(Some(user_id), user_id);
+            let delivered = crate::logging::with_context(log_context, deliver(pool, bot, &mut pace, broadcast, user_id, &contact_state)).await;
+            if !delivered {
+                // Left pending; the page would come back with the same recipient
+                return;
+            }
+        }
+    }
+}
+
+// Sends to one recipient and records the outcome; false if the broadcast should stop here (a shutdown, or the
+// database failing)
+async fn deliver(
+    pool: &crate::database::Pool,
+    bot: &teloxide::Bot,
+    pace: &mut tokio::time::Interval,
+    broadcast: &crate::DBBroadcast,
+    user_id: i64,
+    contact_state: &str,
+) -> bool {
+    let (status, error, attempts) = if contact_state == crate::CONTACT_ACTIVE {
+        match crate::database::mark_broadcast_recipient_sending(pool, broadcast.id, user_id).await {
+            Ok(true) => {}
+            Ok(false) => return true,
+            Err(e) => {
+                log::error!("broadcasts: failed to mark user_id {} as sending for broadcast {}: {:?}", user_id, broadcast.id, e);
+                return false;
+            }
+        }
+        match send(pool, bot, pace, broadcast, user_id).await {
+            Some(outcome) => outcome,
+            None => {
+                // Nothing went out, so they can have it on the next start
+                record(pool, broadcast, user_id, "pending", None, 0).await;
+                return false;
+            }
+        }
+    } else {
+        log::info!("broadcasts: user_id {} is {}, skipping", user_id, contact_state);
+        (if contact_state == crate::CONTACT_OPTED_OUT { "opted_out" } else { "unreachable" }, None, 0)
+    };
+
+    crate::metrics::BROADCAST_MESSAGES.with_label_values(&[status]).inc();
+    record(pool, broadcast, user_id, status, error.as_deref(), attempts).await
+}
+
+// A recipient left sending after the last try is failed when the broadcast finishes
+async fn record(pool: &crate::database::Pool, broadcast: &crate::DBBroadcast, user_id: i64, status: &str, error: Option<&str>, attempts: i32) -> bool {
+    for attempt in 1..=RECORD_ATTEMPTS {
+        match crate::database::update_broadcast_recipient(pool, broadcast.id, user_id, status, error, attempts).await {
+            Ok(()) => return true,
+            Err(e) => {
+                log::error!("broadcasts: attempt {} to record {} for user_id {} in broadcast {} failed: {:?}", attempt, status, user_id, broadcast.id, e);
+                if attempt < RECORD_ATTEMPTS {
+                    tokio::time::sleep(Duration::from_secs(1 << (attempt - 1))).await;
+                }
+            }
+        }
+    }
+    false
+}
+
+// (status, error, attempts), or None if shutdown came during a RetryAfter wait
+async fn send(
+    pool: &crate::database::Pool,
+    bot: &teloxide::Bot,
+    pace: &mut tokio::time::Interval,
+    broadcast: &crate::DBBroadcast,
+    user_id: i64,
+) -> Option<(&'static str, Option<String>, i32)> {
+    // Private chats share the user's id
+    let chat_id = teloxide::types::ChatId(user_id);
+    let mut attempts = 0;
+    loop {
+        pace.tick().await;
+        attempts += 1;
+        match teloxide::prelude::Requester::send_message(bot, chat_id, broadcast.text.clone()).await {
+            Ok(_) => return Some(("sent", None, attempts)),
+            Err(teloxide::RequestError::RetryAfter(duration)) => {
+                log::warn!("broadcasts: Telegram asked to wait {:?} before sending more", duration);
+                attempts -= 1;
+                crate::shutdown::sleep_or_shutdown(duration).await;
+                if crate::shutdown::is_shutting_down() {
+                    return None;
+                }
+            }
+            Err(e) if crate::telegram::is_blocked_error(&e) => {
+                log::info!("broadcasts: user_id {} can't be reached: {}", user_id, e);
+                crate::telegram::note_send_error(pool, user_id, &e).await;
+                return Some(("unreachable", Some(e.to_string()), attempts));
+            }
+            Err(e @ (teloxide::RequestError::Network(_) | teloxide::RequestError::Io(_))) if attempts < MAX_ATTEMPTS => {
+                log::warn!("broadcasts: attempt {} to user_id {} failed, retrying: {}", attempts, user_id, e);
+            }
+            Err(e) => {
+                log::error!("broadcasts: failed to send broadcast {} to user_id {}: {}", broadcast.id, user_id, e);
+                return Some(("failed", Some(e.to_string()), attempts));
+            }
+        }
+    }
+}
@@ -0,0 +1,251 @@
+// src/cli.rs
+
+use clap::{Args, Parser, Subcommand};
+
+#[derive(Debug, Parser)]
+#[command(name = "webhooks_server", about = "Telegram sales bot, webhook receivers and admin API")]
+pub struct Cli {
+    // No subcommand means `serve`
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the Telegram bot and the webhook server
+    Serve(ServeArgs),
+    /// Apply pending database migrations and exit
+    Migrate,
+    /// Manage OpenAI assistants
+    #[cfg(feature = "telegram")]
+    #[command(subcommand)]
+    Assistants(AssistantsCommand),
+    /// Export data as JSON to stdout
+    #[command(subcommand)]
+    Export(ExportCommand),
+    /// Re-run archived inbound webhooks through their handlers
+    #[cfg(feature = "webhook-server")]
+    ReplayWebhooks(ReplayWebhooksArgs),
+    /// Manage admin API keys
+    #[cfg(feature = "webhook-server")]
+    #[command(subcommand)]
+    ApiKeys(ApiKeysCommand),
+    /// Validate env, TLS files, database, Telegram and OpenAI access without starting anything
+    CheckConfig,
+    /// Play a scripted conversation through the bot on a virtual clock and print what it does
+    #[cfg(all(feature = "telegram", feature = "test-support"))]
+    Simulate(SimulateArgs),
+}
+
+#[derive(Debug, Args, Default)]
+pub struct ServeArgs {
+    /// Only poll Telegram; no webhook server or admin API
+    #[arg(long, conflicts_with = "webhooks_only")]
+    pub bot_only: bool,
+    /// Only run the webhook server and admin API; no Telegram polling
+    #[arg(long)]
+    pub webhooks_only: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AssistantsCommand {
+    /// List assistants on the OpenAI account
+    List,
+    /// Create an assistant with the given name
+    Create { name: String },
+    /// Print an assistant's full configuration
+    Show { assistant_id: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ExportCommand {
+    /// A user with their threads, messages and metrics
+    User { user_id: i64 },
+}
+
+#[derive(Debug, Args)]
+pub struct ReplayWebhooksArgs {
+    /// Which events to replay: dead_letter, or received for ones a crash never finished
+    #[arg(long, default_value = "dead_letter")]
+    pub status: String,
+    /// Only replay events for this provider
+    #[arg(long)]
+    pub provider: Option<String>,
+    #[arg(long, default_value_t = 100)]
+    pub limit: i64,
+}
+
+#[cfg(all(feature = "telegram", feature = "test-support"))]
+#[derive(Debug, Args)]
+pub struct SimulateArgs {
+    /// Transcript of "+<offset> <message>" lines, see src/simulator.rs for the format
+    pub transcript: std::path::PathBuf,
+    /// Answer from the local mock OpenAI instead of the real API; needed for !analysis and !reply lines
+    #[arg(long)]
+    pub mock_openai: bool,
+    /// Telegram id of the simulated user; random by default so old threads don't get in the way
+    #[arg(long)]
+    pub user_id: Option<i64>,
+    /// Virtual seconds to keep going after the last line while the bot is quiet
+    #[arg(long, default_value_t = 600)]
+    pub settle_secs: u64,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ApiKeysCommand {
+    /// Create a key; it is printed once and only its hash is stored
+    Create { name: String },
+}
+
+pub async fn migrate() -> Result<(), anyhow::Error> {
+    let pool = crate::database::create_pool()?;
+    crate::database::run_migrations(&pool).await?;
+    println!("Migrations applied");
+    Ok(())
+}
+
+#[cfg(feature = "telegram")]
+pub async fn assistants(command: AssistantsCommand) -> Result<(), anyhow::Error> {
+    match command {
+        AssistantsCommand::List => print!("{}", crate::telegram::list_assistants().await.map_err(|e| anyhow::anyhow!(e))?),
+        AssistantsCommand::Create { name } => println!("{}", crate::telegram::create_assistant(&name).await.map_err(|e| anyhow::anyhow!(e))?),
+        AssistantsCommand::Show { assistant_id } => {
+            let assistant = crate::telegram::get_assistant(&assistant_id).await.map_err(|e| anyhow::anyhow!(e))?;
+            println!("{}", serde_json::to_string_pretty(&assistant)?);
+        }
+    }
+    Ok(())
+}
+
+pub async fn export(command: ExportCommand) -> Result<(), anyhow::Error> {
+    let pool = crate::database::create_pool()?;
+    match command {
+        ExportCommand::User { user_id } => {
+            let user = crate::database::get_user(&pool, user_id)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("User {} not found", user_id))?;
+
+            let mut threads = Vec::new();
+            for thread in crate::database::get_threads_by_user_id(&pool, user_id, None).await? {
+                let messages = crate::database::get_messages_by_thread_id(&pool, &thread.thread_id, i64::MAX, 0).await?;
+                threads.push(serde_json::json!({ "thread": thread, "messages": messages }));
+            }
+            let metrics = crate::database::get_metrics_by_user_id(&pool, user_id, i64::MAX, 0).await?;
+
+            let export = serde_json::json!({
+                "user": user,
+                "threads": threads,
+                "metrics": metrics,
+                "exported_at": chrono::Utc::now(),
+            });
+            println!("{}", serde_json::to_string_pretty(&export)?);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "webhook-server")]
+pub async fn replay_webhooks(args: ReplayWebhooksArgs) -> Result<(), anyhow::Error> {
+    let pool = crate::database::create_pool()?;
+    let registry = crate::receivers::default_registry();
+    let outcomes = crate::receivers::replay_events(&registry, &pool, &args.status, args.provider.as_deref(), args.limit).await?;
+
+    let failed = outcomes.iter().filter(|outcome| !outcome.succeeded).count();
+    for outcome in &outcomes {
+        match &outcome.error {
+            None => println!("{} {}: ok", outcome.event_id, outcome.provider),
+            Some(error) => println!("{} {}: failed: {}", outcome.event_id, outcome.provider, error),
+        }
+    }
+    println!("Replayed {} event(s), {} failed", outcomes.len(), failed);
+
+    if failed > 0 {
+        anyhow::bail!("{} event(s) failed to replay", failed);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "webhook-server")]
+pub async fn api_keys(command: ApiKeysCommand) -> Result<(), anyhow::Error> {
+    let pool = crate::database::create_pool()?;
+    match command {
+        ApiKeysCommand::Create { name } => {
+            let key = crate::admin_api::create_api_key(&pool, &name).await?;
+            println!("{}", key);
+        }
+    }
+    Ok(())
+}
+
+fn report(check: &str, result: Result<String, String>) -> bool {
+    match result {
+        Ok(detail) => {
+            println!("ok    {}: {}", check, detail);
+            true
+        }
+        Err(detail) => {
+            println!("FAIL  {}: {}", check, detail);
+            false
+        }
+    }
+}
+
+#[cfg(all(feature = "telegram", feature = "test-support"))]
+pub async fn simulate(args: SimulateArgs) -> Result<(), anyhow::Error> {
+    let transcript = std::fs::read_to_string(&args.transcript)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", args.transcript.display(), e))?;
+    let lines = crate::simulator::parse_transcript(&transcript)?;
+    let options = crate::simulator::Options {
+        mock_openai: args.mock_openai,
+        user_id: args.user_id.unwrap_or_else(|| rand::Rng::gen_range(&mut rand::thread_rng(), 8_000_000_000..9_000_000_000)),
+        settle: std::time::Duration::from_secs(args.settle_secs),
+    };
+
+    tokio::task::spawn_blocking(move || crate::simulator::run_on_virtual_clock(lines, options)).await?
+}
+
+pub async fn check_config() -> Result<(), anyhow::Error> {
+    let mut ok = true;
+
+    for name in ["OPENAI_KEY", "TELOXIDE_TOKEN"] {
+        let result = std::env::var(name).map(|_| "set".to_string()).map_err(|_| "not set".to_string());
+        ok &= report(name, result);
+    }
+
+    #[cfg(feature = "webhook-server")]
+    {
+        let listener = crate::listener::ListenerConfig::from_env();
+        ok &= report("listener", match &listener {
+            Ok(config) => config.validate().map(|_| format!("{:?}", config)).map_err(|e| format!("{:#}", e)),
+            Err(e) => Err(format!("{:#}", e)),
+        });
+
+        // Logs warnings for providers whose secrets are missing
+        let registry = crate::receivers::default_registry();
+        ok &= report("webhook receivers", Ok(registry.provider_names().join(", ")));
+    }
+
+    match crate::database::create_pool() {
+        Ok(pool) => {
+            let readiness = crate::health::readiness(&pool).await;
+            ok &= report("database", if readiness.database.ok { Ok(readiness.database.detail) } else { Err(readiness.database.detail) });
+            ok &= report("openai", if readiness.openai.ok { Ok(readiness.openai.detail) } else { Err(readiness.openai.detail) });
+        }
+        Err(e) => ok &= report("database", Err(format!("{:#}", e))),
+    }
+
+    #[cfg(feature = "telegram")]
+    if std::env::var("TELOXIDE_TOKEN").is_ok() {
+        let result = teloxide::requests::Requester::get_me(&crate::telegram::bot_from_env())
+            .await
+            .map(|me| format!("@{}", me.username()))
+            .map_err(|e| e.to_string());
+        ok &= report("telegram", result);
+    }
+
+    if !ok {
+        anyhow::bail!("Configuration check failed");
+    }
+    println!("Configuration looks good");
+    Ok(())
+}
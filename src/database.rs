@@ -1,19 +1,110 @@
 // src/database.rs
 
+// Embedded schema migrations, applied in order. Each version is recorded in schema_migrations once applied.
+const MIGRATIONS: &[(&str, &str)] = &[
+    ("0001_initial", include_str!("../migrations/0001_initial.sql")),
+    ("0002_api_keys", include_str!("../migrations/0002_api_keys.sql")),
+    ("0003_event_subscriptions", include_str!("../migrations/0003_event_subscriptions.sql")),
+    ("0004_webhook_events", include_str!("../migrations/0004_webhook_events.sql")),
+    ("0005_webhook_event_status", include_str!("../migrations/0005_webhook_event_status.sql")),
+    ("0006_processed_updates", include_str!("../migrations/0006_processed_updates.sql")),
+    ("0007_pending_work", include_str!("../migrations/0007_pending_work.sql")),
+    ("0008_quiet_hours", include_str!("../migrations/0008_quiet_hours.sql")),
+    ("0009_follow_ups", include_str!("../migrations/0009_follow_ups.sql")),
+    ("0010_broadcasts", include_str!("../migrations/0010_broadcasts.sql")),
+    ("0011_contact_state", include_str!("../migrations/0011_contact_state.sql")),
+    ("0012_event_delivery_retries", include_str!("../migrations/0012_event_delivery_retries.sql")),
+    ("0013_broadcast_sending", include_str!("../migrations/0013_broadcast_sending.sql")),
+    ("0014_processed_update_claims", include_str!("../migrations/0014_processed_update_claims.sql")),
+    ("0015_inbound_messages", include_str!("../migrations/0015_inbound_messages.sql")),
+];
+
+// What the rest of the crate passes around; database_sqlite.rs has its own
+pub type Pool = deadpool_postgres::Pool;
+
+// Connection settings come from the TELEGRAM_DATABASE_* env vars
+pub fn create_pool() -> Result<deadpool_postgres::Pool, anyhow::Error> {
+    let var = |name: &str| std::env::var(name).map_err(|_| anyhow::anyhow!("{} not set", name));
+
+    let mut cfg = deadpool_postgres::Config::new();
+    cfg.host = Some(var("TELEGRAM_DATABASE_HOST")?);
+    cfg.port = Some(var("TELEGRAM_DATABASE_PORT")?.parse().map_err(|_| anyhow::anyhow!("Invalid port"))?);
+    cfg.user = Some(var("TELEGRAM_DATABASE_USER")?);
+    cfg.password = Some(var("TELEGRAM_DATABASE_PASSWORD")?);
+    cfg.dbname = Some(var("TELEGRAM_DATABASE_NAME")?);
+    cfg.manager = Some(deadpool_postgres::ManagerConfig { recycling_method: deadpool_postgres::RecyclingMethod::Fast });
+
+    Ok(cfg.create_pool(None, tokio_postgres::NoTls)?)
+}
+
+pub async fn ping(pool: &deadpool_postgres::Pool) -> Result<(), anyhow::Error> {
+    let client = pool.get().await?;
+    client.query_one("SELECT 1", &[]).await?;
+    Ok(())
+}
+
+pub async fn run_migrations(pool: &deadpool_postgres::Pool) -> Result<(), anyhow::Error> {
+    let mut client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    client.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (version TEXT PRIMARY KEY, applied_at TIMESTAMPTZ NOT NULL DEFAULT now())"
+    ).await?;
+
+    for (version, sql) in MIGRATIONS {
+        let transaction = client.transaction().await?;
+        let applied = transaction.query_opt("SELECT 1 FROM schema_migrations WHERE version = $1", &[version]).await?;
+        if applied.is_some() {
+            continue;
+        }
+        transaction.batch_execute(sql).await?;
+        transaction.execute("INSERT INTO schema_migrations (version) VALUES ($1)", &[version]).await?;
+        transaction.commit().await?;
+        log::info!("Applied migration {}", version);
+    }
+
+    Ok(())
+}
+
 pub async fn insert_user(pool: deadpool_postgres::Pool, user: crate::DBUser) -> Result<(), anyhow::Error> {
     // Get a client from the pool, handling the pool error explicitly
     let client = pool.get().await.map_err(|e| {
         log::error!("Failed to get client from pool: {:?}", e);
         anyhow::Error::new(e)
     })?;
+    // Writing to the bot means they unblocked it; an opt-out stays until /start
     client.execute(
-        "INSERT INTO users (user_id, first_name, last_name, username) VALUES ($1, $2, $3, $4)
-         ON CONFLICT (user_id) DO UPDATE SET first_name = EXCLUDED.first_name, last_name = EXCLUDED.last_name, username = EXCLUDED.username",
-        &[&user.id, &user.first_name, &user.last_name, &user.username]
+        "INSERT INTO users (user_id, first_name, last_name, username, language_code) VALUES ($1, $2, $3, $4, $5)
+         ON CONFLICT (user_id) DO UPDATE SET first_name = EXCLUDED.first_name, last_name = EXCLUDED.last_name, username = EXCLUDED.username,
+             language_code = COALESCE(EXCLUDED.language_code, users.language_code),
+             contact_state = CASE WHEN users.contact_state = 'blocked' THEN 'active' ELSE users.contact_state END,
+             contact_state_at = CASE WHEN users.contact_state = 'blocked' THEN now() ELSE users.contact_state_at END",
+        &[&user.id, &user.first_name, &user.last_name, &user.username, &user.language_code]
     ).await?;
     Ok(())
 }
 
+// Sets the user's timezone unless the one they have came from a stronger source (see quiet_hours::TIMEZONE_SOURCES).
+// Returns whether anything changed.
+pub async fn set_user_timezone(pool: &deadpool_postgres::Pool, user_id: i64, timezone: &str, source: &str) -> Result<bool, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let sources: Vec<String> = crate::quiet_hours::TIMEZONE_SOURCES.iter().map(|source| source.to_string()).collect();
+    let updated = client.execute(
+        "UPDATE users SET timezone = $2, timezone_source = $3
+         WHERE user_id = $1
+           AND (timezone IS DISTINCT FROM $2 OR timezone_source IS DISTINCT FROM $3)
+           AND COALESCE(array_position($4::TEXT[], timezone_source), 0) <= array_position($4::TEXT[], $3)",
+        &[&user_id, &timezone, &source, &sources]
+    ).await?;
+    Ok(updated > 0)
+}
+
 pub async fn insert_thread(pool: deadpool_postgres::Pool, thread_id: &str, user_id: i64, openai_thread_id: &str, assistant_id: &str) -> Result<(), anyhow::Error> {
     let client = pool.get().await.map_err(|e| {
         log::error!("Failed to get client from pool: {:?}", e);
@@ -92,6 +183,1035 @@ pub async fn insert_pre_processing_results(
 }
 
 
+//      DEDUPLICATION RELATED
+//          ---     --  --
+// Claims all of `keys` in one transaction, or none of them if any is completed or claimed within the last
+// `lease_secs`. Returns whether they were claimed.
+pub async fn claim_processed_updates(pool: &deadpool_postgres::Pool, keys: &[String], lease_secs: i64) -> Result<bool, anyhow::Error> {
+    let mut client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let transaction = client.transaction().await?;
+    for key in keys {
+        // A concurrent claim of the same key waits here until the first one commits, then finds it taken
+        let claimed = transaction.query_opt(
+            "INSERT INTO processed_updates (key, completed) VALUES ($1, false)
+             ON CONFLICT (key) DO UPDATE SET processed_at = now()
+             WHERE NOT processed_updates.completed AND processed_updates.processed_at < now() - $2::INT * INTERVAL '1 second'
+             RETURNING key",
+            &[key, &(lease_secs as i32)]
+        ).await?;
+        if claimed.is_none() {
+            transaction.rollback().await?;
+            return Ok(false);
+        }
+    }
+    transaction.commit().await?;
+    Ok(true)
+}
+
+pub async fn complete_processed_updates(pool: &deadpool_postgres::Pool, keys: &[String]) -> Result<(), anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    client.execute(
+        "UPDATE processed_updates SET completed = true, processed_at = now() WHERE key = ANY($1)",
+        &[&keys]
+    ).await?;
+    Ok(())
+}
+
+// Gives up claims that were never completed, so a redelivery is handled
+pub async fn release_processed_updates(pool: &deadpool_postgres::Pool, keys: &[String]) -> Result<(), anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    client.execute(
+        "DELETE FROM processed_updates WHERE key = ANY($1) AND NOT completed",
+        &[&keys]
+    ).await?;
+    Ok(())
+}
+
+pub async fn delete_expired_processed_updates(pool: &deadpool_postgres::Pool, ttl_hours: i64) -> Result<u64, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let deleted = client.execute(
+        "DELETE FROM processed_updates WHERE processed_at < now() - make_interval(hours => $1::INT)",
+        &[&(ttl_hours as i32)]
+    ).await?;
+
+    Ok(deleted)
+}
+
+//      ADMIN API RELATED
+//          ---     --  --
+pub async fn search_users(pool: &deadpool_postgres::Pool, search: Option<&str>, limit: i64, offset: i64) -> Result<Vec<crate::DBUser>, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    // Matches on names/username, or on the exact user_id when the search term is numeric
+    let pattern = search.map(|s| format!("%{}%", s));
+    let rows = client.query(
+        "SELECT user_id, first_name, last_name, username, language_code, timezone, timezone_source, contact_state, contact_state_at FROM users
+         WHERE $1::TEXT IS NULL
+            OR username ILIKE $1 OR first_name ILIKE $1 OR last_name ILIKE $1
+            OR user_id::TEXT = $2
+         ORDER BY user_id
+         LIMIT $3 OFFSET $4",
+        &[&pattern, &search, &limit, &offset]
+    ).await?;
+
+    Ok(rows.iter().map(row_to_user).collect())
+}
+
+pub async fn get_user(pool: &deadpool_postgres::Pool, user_id: i64) -> Result<Option<crate::DBUser>, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let row = client.query_opt(
+        "SELECT user_id, first_name, last_name, username, language_code, timezone, timezone_source, contact_state, contact_state_at FROM users WHERE user_id = $1",
+        &[&user_id]
+    ).await?;
+
+    Ok(row.as_ref().map(row_to_user))
+}
+
+pub async fn get_threads_by_user_id(pool: &deadpool_postgres::Pool, user_id: i64, assistant_id: Option<&str>) -> Result<Vec<crate::DBThread>, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let rows = client.query(
+        "SELECT thread_id, user_id, openai_thread_id, assistant_id FROM threads
+         WHERE user_id = $1 AND ($2::TEXT IS NULL OR assistant_id = $2)
+         ORDER BY assistant_id",
+        &[&user_id, &assistant_id]
+    ).await?;
+
+    Ok(rows.iter().map(|row| crate::DBThread {
+        thread_id: row.get("thread_id"),
+        user_id: row.get("user_id"),
+        openai_thread_id: row.get("openai_thread_id"),
+        assistant_id: row.get("assistant_id"),
+    }).collect())
+}
+
+pub async fn get_messages_by_thread_id(pool: &deadpool_postgres::Pool, thread_id: &str, limit: i64, offset: i64) -> Result<Vec<crate::DBMessage>, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let rows = client.query(
+        "SELECT id, thread_id, sender, content, message_type, assistant_id, created_at FROM messages
+         WHERE thread_id = $1
+         ORDER BY id
+         LIMIT $2 OFFSET $3",
+        &[&thread_id, &limit, &offset]
+    ).await?;
+
+    Ok(rows.iter().map(|row| crate::DBMessage {
+        id: row.get("id"),
+        thread_id: row.get("thread_id"),
+        sender: row.get("sender"),
+        content: row.get("content"),
+        message_type: row.get("message_type"),
+        assistant_id: row.get("assistant_id"),
+        created_at: row.get("created_at"),
+    }).collect())
+}
+
+pub async fn get_metrics_by_user_id(pool: &deadpool_postgres::Pool, user_id: i64, limit: i64, offset: i64) -> Result<Vec<crate::DBMetric>, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let rows = client.query(
+        "SELECT id, user_id, thread_id, interest, user_response_time, response_cue, created_at FROM metrics
+         WHERE user_id = $1
+         ORDER BY id DESC
+         LIMIT $2 OFFSET $3",
+        &[&user_id, &limit, &offset]
+    ).await?;
+
+    Ok(rows.iter().map(|row| crate::DBMetric {
+        id: row.get("id"),
+        user_id: row.get("user_id"),
+        thread_id: row.get("thread_id"),
+        interest: row.get("interest"),
+        user_response_time: row.get("user_response_time"),
+        response_cue: row.get("response_cue"),
+        created_at: row.get("created_at"),
+    }).collect())
+}
+
+// Deletes the user along with their threads, messages, metrics and unsent replies. Returns false if the user didn't exist.
+pub async fn delete_user(pool: &deadpool_postgres::Pool, user_id: i64) -> Result<bool, anyhow::Error> {
+    let mut client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let transaction = client.transaction().await?;
+    transaction.execute(
+        "DELETE FROM messages WHERE thread_id IN (SELECT thread_id FROM threads WHERE user_id = $1)",
+        &[&user_id]
+    ).await?;
+    transaction.execute("DELETE FROM metrics WHERE user_id = $1", &[&user_id]).await?;
+    transaction.execute("DELETE FROM pending_replies WHERE user_id = $1", &[&user_id]).await?;
+    transaction.execute("DELETE FROM pending_buffers WHERE user_id = $1", &[&user_id]).await?;
+    transaction.execute("DELETE FROM follow_ups WHERE user_id = $1", &[&user_id]).await?;
+    transaction.execute("DELETE FROM broadcast_recipients WHERE user_id = $1", &[&user_id]).await?;
+    transaction.execute("DELETE FROM threads WHERE user_id = $1", &[&user_id]).await?;
+    let deleted = transaction.execute("DELETE FROM users WHERE user_id = $1", &[&user_id]).await?;
+    transaction.commit().await?;
+
+    Ok(deleted > 0)
+}
+
+pub async fn insert_api_key(pool: &deadpool_postgres::Pool, name: &str, key_hash: &str) -> Result<i64, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let row = client.query_one(
+        "INSERT INTO api_keys (name, key_hash) VALUES ($1, $2) RETURNING id",
+        &[&name, &key_hash]
+    ).await?;
+
+    Ok(row.get("id"))
+}
+
+// Looks up a non-revoked key by its hash and bumps last_used_at in the same statement
+pub async fn get_api_key_by_hash(pool: &deadpool_postgres::Pool, key_hash: &str) -> Result<Option<crate::DBApiKey>, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let row = client.query_opt(
+        "UPDATE api_keys SET last_used_at = now() WHERE key_hash = $1 AND revoked_at IS NULL RETURNING id, name",
+        &[&key_hash]
+    ).await?;
+
+    Ok(row.map(|row| crate::DBApiKey {
+        id: row.get("id"),
+        name: row.get("name"),
+    }))
+}
+
+//      EVENT WEBHOOKS RELATED
+//          ---     --  --
+pub async fn insert_event_subscription(pool: &deadpool_postgres::Pool, url: &str, secret: &str, event_types: &[String]) -> Result<crate::DBEventSubscription, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let row = client.query_one(
+        "INSERT INTO event_subscriptions (url, secret, event_types) VALUES ($1, $2, $3)
+         RETURNING id, url, secret, event_types, active, created_at",
+        &[&url, &secret, &event_types]
+    ).await?;
+
+    Ok(row_to_event_subscription(&row))
+}
+
+pub async fn get_event_subscriptions(pool: &deadpool_postgres::Pool) -> Result<Vec<crate::DBEventSubscription>, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let rows = client.query(
+        "SELECT id, url, secret, event_types, active, created_at FROM event_subscriptions ORDER BY id",
+        &[]
+    ).await?;
+
+    Ok(rows.iter().map(row_to_event_subscription).collect())
+}
+
+pub async fn get_active_subscriptions_for_event(pool: &deadpool_postgres::Pool, event_type: &str) -> Result<Vec<crate::DBEventSubscription>, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let rows = client.query(
+        "SELECT id, url, secret, event_types, active, created_at FROM event_subscriptions
+         WHERE active AND $1 = ANY(event_types)",
+        &[&event_type]
+    ).await?;
+
+    Ok(rows.iter().map(row_to_event_subscription).collect())
+}
+
+pub async fn delete_event_subscription(pool: &deadpool_postgres::Pool, subscription_id: i64) -> Result<bool, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let deleted = client.execute("DELETE FROM event_subscriptions WHERE id = $1", &[&subscription_id]).await?;
+    Ok(deleted > 0)
+}
+
+// Logs an attempt. `next_attempt_at` schedules a retry of it; `retry_of` is the attempt this one retried, whose
+// schedule is cleared in the same transaction.
+#[allow(clippy::too_many_arguments)]
+pub async fn insert_event_delivery(
+    pool: &deadpool_postgres::Pool,
+    subscription_id: i64,
+    event: &crate::events::Event,
+    attempt: i32,
+    status_code: Option<i32>,
+    error: Option<&str>,
+    next_attempt_at: Option<chrono::DateTime<chrono::Utc>>,
+    retry_of: Option<i64>,
+) -> Result<(), anyhow::Error> {
+    let mut client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let payload = serde_json::to_value(event)?;
+    let succeeded = error.is_none() && status_code.map(|code| (200..300).contains(&code)).unwrap_or(false);
+    let transaction = client.transaction().await?;
+    transaction.execute(
+        "INSERT INTO event_deliveries (subscription_id, event_id, event_type, payload, attempt, status_code, error, succeeded, next_attempt_at)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+        &[&subscription_id, &event.id, &event.event_type, &payload, &attempt, &status_code, &error, &succeeded, &next_attempt_at]
+    ).await?;
+    if let Some(retry_of) = retry_of {
+        transaction.execute("UPDATE event_deliveries SET next_attempt_at = NULL WHERE id = $1", &[&retry_of]).await?;
+    }
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+// Failed attempts whose retry is due. Each is pushed `lease_secs` into the future so other polls leave it alone
+// while it is retried; if the retry is never logged, e.g. because the process died, it comes due again.
+pub async fn claim_due_event_retries(pool: &deadpool_postgres::Pool, lease_secs: i64, limit: i64) -> Result<Vec<crate::DBEventRetry>, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let rows = client.query(
+        "UPDATE event_deliveries d SET next_attempt_at = now() + make_interval(secs => $1::BIGINT)
+         FROM event_subscriptions s
+         WHERE s.id = d.subscription_id
+           AND d.id IN (
+               SELECT id FROM event_deliveries WHERE next_attempt_at <= now()
+               ORDER BY next_attempt_at LIMIT $2 FOR UPDATE SKIP LOCKED
+           )
+         RETURNING d.id AS delivery_id, d.attempt, d.payload,
+                   s.id, s.url, s.secret, s.event_types, s.active, s.created_at",
+        &[&lease_secs, &limit]
+    ).await?;
+
+    Ok(rows.iter().map(|row| crate::DBEventRetry {
+        delivery_id: row.get("delivery_id"),
+        attempt: row.get("attempt"),
+        payload: row.get("payload"),
+        subscription: row_to_event_subscription(row),
+    }).collect())
+}
+
+// Drops the retry of an attempt, e.g. because its subscription was deactivated
+pub async fn cancel_event_retry(pool: &deadpool_postgres::Pool, delivery_id: i64) -> Result<(), anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    client.execute("UPDATE event_deliveries SET next_attempt_at = NULL WHERE id = $1", &[&delivery_id]).await?;
+    Ok(())
+}
+
+pub async fn get_event_deliveries(pool: &deadpool_postgres::Pool, subscription_id: i64, limit: i64, offset: i64) -> Result<Vec<crate::DBEventDelivery>, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let rows = client.query(
+        "SELECT id, subscription_id, event_id, event_type, attempt, status_code, error, succeeded, created_at FROM event_deliveries
+         WHERE subscription_id = $1
+         ORDER BY created_at DESC, id DESC
+         LIMIT $2 OFFSET $3",
+        &[&subscription_id, &limit, &offset]
+    ).await?;
+
+    Ok(rows.iter().map(|row| crate::DBEventDelivery {
+        id: row.get("id"),
+        subscription_id: row.get("subscription_id"),
+        event_id: row.get("event_id"),
+        event_type: row.get("event_type"),
+        attempt: row.get("attempt"),
+        status_code: row.get("status_code"),
+        error: row.get("error"),
+        succeeded: row.get("succeeded"),
+        created_at: row.get("created_at"),
+    }).collect())
+}
+
+//      INBOUND WEBHOOKS RELATED
+//          ---     --  --
+pub async fn insert_webhook_event(
+    pool: &deadpool_postgres::Pool,
+    provider: &str,
+    path: &str,
+    headers: &serde_json::Value,
+    body: &[u8],
+    verified: bool,
+    verification_error: Option<&str>,
+) -> Result<i64, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    // Events that fail verification are kept for inspection but never handed to a handler
+    let status = if verified { "received" } else { "rejected" };
+    let row = client.query_one(
+        "INSERT INTO webhook_events (provider, path, headers, body, verified, verification_error, status)
+         VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id",
+        &[&provider, &path, &headers, &body, &verified, &verification_error, &status]
+    ).await?;
+
+    Ok(row.get("id"))
+}
+
+pub async fn mark_webhook_event_processed(pool: &deadpool_postgres::Pool, event_id: i64) -> Result<(), anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    client.execute(
+        "UPDATE webhook_events SET status = 'processed', attempts = attempts + 1, last_error = NULL, processed_at = now() WHERE id = $1",
+        &[&event_id]
+    ).await?;
+
+    Ok(())
+}
+
+pub async fn mark_webhook_event_dead_letter(pool: &deadpool_postgres::Pool, event_id: i64, error: &str) -> Result<(), anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    client.execute(
+        "UPDATE webhook_events SET status = 'dead_letter', attempts = attempts + 1, last_error = $2 WHERE id = $1",
+        &[&event_id, &error]
+    ).await?;
+
+    Ok(())
+}
+
+pub async fn list_webhook_events(
+    pool: &deadpool_postgres::Pool,
+    status: Option<&str>,
+    provider: Option<&str>,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<crate::DBWebhookEvent>, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let rows = client.query(
+        "SELECT id, provider, path, headers, body, verified, verification_error, status, attempts, last_error, received_at, processed_at
+         FROM webhook_events
+         WHERE ($1::TEXT IS NULL OR status = $1) AND ($2::TEXT IS NULL OR provider = $2)
+         ORDER BY received_at, id
+         LIMIT $3 OFFSET $4",
+        &[&status, &provider, &limit, &offset]
+    ).await?;
+
+    Ok(rows.iter().map(row_to_webhook_event).collect())
+}
+
+pub async fn get_webhook_event(pool: &deadpool_postgres::Pool, event_id: i64) -> Result<Option<crate::DBWebhookEvent>, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let row = client.query_opt(
+        "SELECT id, provider, path, headers, body, verified, verification_error, status, attempts, last_error, received_at, processed_at
+         FROM webhook_events WHERE id = $1",
+        &[&event_id]
+    ).await?;
+
+    Ok(row.as_ref().map(row_to_webhook_event))
+}
+
+//      PENDING WORK RELATED
+//          ---     --  --
+pub async fn insert_pending_reply(pool: &deadpool_postgres::Pool, reply: &crate::DBPendingReply) -> Result<i64, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let row = client.query_one(
+        "INSERT INTO pending_replies (user_id, chat_id, thread_id, assistant_id, text, send_at, deferred_from)
+         VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id",
+        &[&reply.user_id, &reply.chat_id, &reply.thread_id, &reply.assistant_id, &reply.text, &reply.send_at, &reply.deferred_from]
+    ).await?;
+
+    Ok(row.get("id"))
+}
+
+// Marks up to `limit` replies due at `now` as being sent by this process and returns them. A claim older than
+// ten minutes is taken to be from a process that died while sending, so that reply is claimed again.
+pub async fn claim_due_pending_replies(pool: &deadpool_postgres::Pool, now: chrono::DateTime<chrono::Utc>, limit: i64) -> Result<Vec<crate::DBPendingReply>, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let rows = client.query(
+        "UPDATE pending_replies SET claimed_at = $1
+         WHERE id IN (
+             SELECT id FROM pending_replies
+             WHERE send_at <= $1 AND (claimed_at IS NULL OR claimed_at < $1 - INTERVAL '10 minutes')
+             ORDER BY send_at
+             LIMIT $2
+             FOR UPDATE SKIP LOCKED
+         )
+         RETURNING id, user_id, chat_id, thread_id, assistant_id, text, send_at, deferred_from",
+        &[&now, &limit]
+    ).await?;
+
+    Ok(rows.iter().map(row_to_pending_reply).collect())
+}
+
+// Moves a claimed reply out of quiet hours, keeping the first time it was due in deferred_from
+pub async fn defer_pending_reply(pool: &deadpool_postgres::Pool, reply_id: i64, send_at: chrono::DateTime<chrono::Utc>) -> Result<(), anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    client.execute(
+        "UPDATE pending_replies SET deferred_from = COALESCE(deferred_from, send_at), send_at = $2, claimed_at = NULL WHERE id = $1",
+        &[&reply_id, &send_at]
+    ).await?;
+    Ok(())
+}
+
+pub async fn list_pending_replies(
+    pool: &deadpool_postgres::Pool,
+    user_id: Option<i64>,
+    deferred_only: bool,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<crate::DBPendingReply>, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let rows = client.query(
+        "SELECT id, user_id, chat_id, thread_id, assistant_id, text, send_at, deferred_from FROM pending_replies
+         WHERE ($1::BIGINT IS NULL OR user_id = $1) AND (NOT $2 OR deferred_from IS NOT NULL)
+         ORDER BY send_at
+         LIMIT $3 OFFSET $4",
+        &[&user_id, &deferred_only, &limit, &offset]
+    ).await?;
+
+    Ok(rows.iter().map(row_to_pending_reply).collect())
+}
+
+pub async fn delete_pending_reply(pool: &deadpool_postgres::Pool, reply_id: i64) -> Result<(), anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    client.execute("DELETE FROM pending_replies WHERE id = $1", &[&reply_id]).await?;
+    Ok(())
+}
+
+pub async fn upsert_pending_buffer(pool: &deadpool_postgres::Pool, buffer: &crate::DBPendingBuffer) -> Result<(), anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let messages = serde_json::to_value(&buffer.messages)?;
+    client.execute(
+        "INSERT INTO pending_buffers (user_id, chat_id, messages) VALUES ($1, $2, $3)
+         ON CONFLICT (user_id) DO UPDATE SET chat_id = EXCLUDED.chat_id, messages = EXCLUDED.messages, created_at = now()",
+        &[&buffer.user_id, &buffer.chat_id, &messages]
+    ).await?;
+
+    Ok(())
+}
+
+// Removes and returns every persisted buffer; they go straight back into memory
+pub async fn take_pending_buffers(pool: &deadpool_postgres::Pool) -> Result<Vec<crate::DBPendingBuffer>, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let rows = client.query("DELETE FROM pending_buffers RETURNING user_id, chat_id, messages", &[]).await?;
+
+    let mut buffers = Vec::with_capacity(rows.len());
+    for row in rows {
+        let messages: serde_json::Value = row.get("messages");
+        buffers.push(crate::DBPendingBuffer {
+            user_id: row.get("user_id"),
+            chat_id: row.get("chat_id"),
+            messages: serde_json::from_value(messages)?,
+        });
+    }
+    Ok(buffers)
+}
+
+// Threads with `assistant_id` whose last inbound message is older than `before`, and that have had no follow-up
+// since `before` either and fewer than `max_follow_ups` sent ones since the user last wrote, going by the
+// sender = 'user' row stored for each batch they send. Only active users (see crate::CONTACT_STATES) without a reply
+// already waiting in pending_replies. Longest dormant first.
+pub async fn get_dormant_threads(
+    pool: &deadpool_postgres::Pool,
+    assistant_id: &str,
+    before: chrono::DateTime<chrono::Utc>,
+    max_follow_ups: i64,
+    limit: i64,
+) -> Result<Vec<crate::DBDormantThread>, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let rows = client.query(
+        "WITH last_inbound AS (
+             SELECT t.user_id, t.thread_id, t.assistant_id, max(m.created_at) AS last_inbound_at
+             FROM threads t
+             JOIN messages m ON m.thread_id = t.thread_id AND m.sender = 'user'
+             WHERE t.assistant_id = $1
+             GROUP BY t.user_id, t.thread_id, t.assistant_id
+         )
+         SELECT li.user_id, li.thread_id, li.assistant_id, li.last_inbound_at, u.timezone,
+             count(f.id) FILTER (WHERE f.status = 'sent') AS follow_ups_sent
+         FROM last_inbound li
+         JOIN users u ON u.user_id = li.user_id
+         LEFT JOIN follow_ups f ON f.user_id = li.user_id AND f.created_at > li.last_inbound_at
+         WHERE li.last_inbound_at < $2
+           AND u.contact_state = 'active'
+           AND NOT EXISTS (SELECT 1 FROM pending_replies p WHERE p.user_id = li.user_id)
+         GROUP BY li.user_id, li.thread_id, li.assistant_id, li.last_inbound_at, u.timezone
+         HAVING count(f.id) FILTER (WHERE f.status = 'sent') < $3
+            AND COALESCE(max(f.created_at), li.last_inbound_at) < $2
+         ORDER BY li.last_inbound_at
+         LIMIT $4",
+        &[&assistant_id, &before, &max_follow_ups, &limit]
+    ).await?;
+    Ok(rows.iter().map(|row| crate::DBDormantThread {
+        user_id: row.get("user_id"),
+        thread_id: row.get("thread_id"),
+        assistant_id: row.get("assistant_id"),
+        last_inbound_at: row.get("last_inbound_at"),
+        timezone: row.get("timezone"),
+        follow_ups_sent: row.get("follow_ups_sent"),
+    }).collect())
+}
+
+// When the user last wrote on the thread, see get_dormant_threads
+pub async fn get_last_inbound_at(pool: &deadpool_postgres::Pool, thread_id: &str) -> Result<Option<chrono::DateTime<chrono::Utc>>, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let row = client.query_one(
+        "SELECT max(created_at) AS last_inbound_at FROM messages WHERE thread_id = $1 AND sender = 'user'",
+        &[&thread_id]
+    ).await?;
+    Ok(row.get("last_inbound_at"))
+}
+
+pub async fn insert_follow_up(
+    pool: &deadpool_postgres::Pool,
+    thread: &crate::DBDormantThread,
+    text: &str,
+    status: &str,
+    error: Option<&str>,
+) -> Result<(), anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    client.execute(
+        "INSERT INTO follow_ups (user_id, thread_id, assistant_id, text, status, error) VALUES ($1, $2, $3, $4, $5, $6)",
+        &[&thread.user_id, &thread.thread_id, &thread.assistant_id, &text, &status, &error]
+    ).await?;
+    Ok(())
+}
+
+// Sets users.contact_state, see crate::CONTACT_STATES. A block never replaces an opt-out, which has to outlast the
+// user unblocking the bot. Returns whether anything changed.
+pub async fn set_contact_state(pool: &deadpool_postgres::Pool, user_id: i64, state: &str) -> Result<bool, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let updated = client.execute(
+        "UPDATE users SET contact_state = $2, contact_state_at = now()
+         WHERE user_id = $1 AND contact_state <> $2 AND ($2 <> 'blocked' OR contact_state = 'active')",
+        &[&user_id, &state]
+    ).await?;
+    Ok(updated > 0)
+}
+
+// User ids matching a BroadcastSegment, with the parameters in the order of segment_params. Only active users (see
+// crate::CONTACT_STATES). A language_code of "pt" also matches "pt-br".
+const SEGMENT_QUERY: &str =
+    "WITH latest AS (
+         SELECT DISTINCT ON (user_id) user_id, interest, created_at FROM metrics ORDER BY user_id, id DESC
+     )
+     SELECT u.user_id FROM users u
+     LEFT JOIN latest m ON m.user_id = u.user_id
+     WHERE u.contact_state = 'active'
+       AND ($1::BIGINT[] IS NULL OR u.user_id = ANY($1))
+       AND ($2::INT IS NULL OR m.interest >= $2)
+       AND ($3::INT IS NULL OR m.interest <= $3)
+       AND ($4::INT IS NULL OR m.created_at >= now() - make_interval(days => $4))
+       AND ($5::INT IS NULL OR m.created_at IS NULL OR m.created_at < now() - make_interval(days => $5))
+       AND ($6::TEXT IS NULL OR lower(u.language_code) = lower($6) OR u.language_code ILIKE $6 || '-%')";
+
+fn segment_params(segment: &crate::BroadcastSegment) -> [&(dyn tokio_postgres::types::ToSql + Sync); 6] {
+    [
+        &segment.user_ids,
+        &segment.min_interest,
+        &segment.max_interest,
+        &segment.active_within_days,
+        &segment.inactive_for_days,
+        &segment.language_code,
+    ]
+}
+
+pub async fn count_segment(pool: &deadpool_postgres::Pool, segment: &crate::BroadcastSegment) -> Result<i64, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let row = client.query_one(&format!("SELECT count(*) AS count FROM ({}) segment", SEGMENT_QUERY), &segment_params(segment)).await?;
+    Ok(row.get("count"))
+}
+
+// Creates the broadcast with everyone currently in the segment as a pending recipient
+pub async fn insert_broadcast(pool: &deadpool_postgres::Pool, text: &str, segment: &crate::BroadcastSegment, created_by: &str) -> Result<crate::DBBroadcast, anyhow::Error> {
+    let mut client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let transaction = client.transaction().await?;
+    let row = transaction.query_one(
+        "INSERT INTO broadcasts (text, segment, created_by) VALUES ($1, $2, $3) RETURNING id",
+        &[&text, &serde_json::to_value(segment)?, &created_by]
+    ).await?;
+    let broadcast_id: i64 = row.get("id");
+    let mut params = segment_params(segment).to_vec();
+    params.push(&broadcast_id);
+    transaction.execute(
+        &format!("INSERT INTO broadcast_recipients (broadcast_id, user_id) SELECT $7, user_id FROM ({}) segment", SEGMENT_QUERY),
+        &params
+    ).await?;
+    transaction.commit().await?;
+    // Back to the pool before get_broadcast takes one
+    drop(client);
+
+    get_broadcast(pool, broadcast_id).await?.ok_or_else(|| anyhow::anyhow!("Broadcast {} vanished after insert", broadcast_id))
+}
+
+const BROADCAST_COLUMNS: &str =
+    "b.id, b.text, b.segment, b.status, b.created_by, b.created_at, b.started_at, b.finished_at,
+     (SELECT count(*) FROM broadcast_recipients r WHERE r.broadcast_id = b.id) AS recipients,
+     (SELECT count(*) FROM broadcast_recipients r WHERE r.broadcast_id = b.id AND r.status = 'pending') AS pending,
+     (SELECT count(*) FROM broadcast_recipients r WHERE r.broadcast_id = b.id AND r.status = 'sent') AS sent,
+     (SELECT count(*) FROM broadcast_recipients r WHERE r.broadcast_id = b.id AND r.status = 'failed') AS failed,
+     (SELECT count(*) FROM broadcast_recipients r WHERE r.broadcast_id = b.id AND r.status = 'unreachable') AS unreachable,
+     (SELECT count(*) FROM broadcast_recipients r WHERE r.broadcast_id = b.id AND r.status = 'opted_out') AS opted_out";
+
+pub async fn get_broadcast(pool: &deadpool_postgres::Pool, broadcast_id: i64) -> Result<Option<crate::DBBroadcast>, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let row = client.query_opt(&format!("SELECT {} FROM broadcasts b WHERE b.id = $1", BROADCAST_COLUMNS), &[&broadcast_id]).await?;
+    Ok(row.as_ref().map(row_to_broadcast))
+}
+
+// Newest first
+pub async fn list_broadcasts(pool: &deadpool_postgres::Pool, limit: i64, offset: i64) -> Result<Vec<crate::DBBroadcast>, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let rows = client.query(
+        &format!("SELECT {} FROM broadcasts b ORDER BY b.id DESC LIMIT $1 OFFSET $2", BROADCAST_COLUMNS),
+        &[&limit, &offset]
+    ).await?;
+    Ok(rows.iter().map(row_to_broadcast).collect())
+}
+
+// Stops a broadcast that hasn't finished; recipients not reached yet stay pending
+pub async fn cancel_broadcast(pool: &deadpool_postgres::Pool, broadcast_id: i64) -> Result<bool, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let updated = client.execute(
+        "UPDATE broadcasts SET status = 'cancelled', finished_at = now() WHERE id = $1 AND status IN ('queued', 'sending')",
+        &[&broadcast_id]
+    ).await?;
+    Ok(updated > 0)
+}
+
+// The oldest broadcast still to send, marked as sending
+pub async fn start_next_broadcast(pool: &deadpool_postgres::Pool) -> Result<Option<crate::DBBroadcast>, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let row = client.query_opt(
+        "UPDATE broadcasts SET status = 'sending', started_at = COALESCE(started_at, now())
+         WHERE id = (SELECT id FROM broadcasts WHERE status IN ('queued', 'sending') ORDER BY id LIMIT 1)
+         RETURNING id",
+        &[]
+    ).await?;
+    drop(client);
+    match row {
+        Some(row) => get_broadcast(pool, row.get("id")).await,
+        None => Ok(None),
+    }
+}
+
+// Marks a sending broadcast done; false if it was cancelled meanwhile
+// Recipients still sending are failed: whether they got the message is unknown, and they aren't sent it again
+pub async fn finish_broadcast(pool: &deadpool_postgres::Pool, broadcast_id: i64) -> Result<bool, anyhow::Error> {
+    let mut client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let transaction = client.transaction().await?;
+    transaction.execute(
+        "UPDATE broadcast_recipients SET status = 'failed', error = 'outcome not recorded, may have been delivered'
+         WHERE broadcast_id = $1 AND status = 'sending'",
+        &[&broadcast_id]
+    ).await?;
+    let updated = transaction.execute(
+        "UPDATE broadcasts SET status = 'done', finished_at = now() WHERE id = $1 AND status = 'sending'",
+        &[&broadcast_id]
+    ).await?;
+    transaction.commit().await?;
+    Ok(updated > 0)
+}
+
+pub async fn get_broadcast_status(pool: &deadpool_postgres::Pool, broadcast_id: i64) -> Result<Option<String>, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let row = client.query_opt("SELECT status FROM broadcasts WHERE id = $1", &[&broadcast_id]).await?;
+    Ok(row.map(|row| row.get("status")))
+}
+
+// (user_id, contact_state) of recipients not sent to yet
+pub async fn get_pending_broadcast_recipients(pool: &deadpool_postgres::Pool, broadcast_id: i64, limit: i64) -> Result<Vec<(i64, String)>, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let rows = client.query(
+        "SELECT r.user_id, COALESCE(u.contact_state, 'active') AS contact_state
+         FROM broadcast_recipients r
+         LEFT JOIN users u ON u.user_id = r.user_id
+         WHERE r.broadcast_id = $1 AND r.status = 'pending'
+         ORDER BY r.user_id
+         LIMIT $2",
+        &[&broadcast_id, &limit]
+    ).await?;
+    Ok(rows.iter().map(|row| (row.get("user_id"), row.get("contact_state"))).collect())
+}
+
+// Claims a pending recipient right before sending; false if they aren't pending any more
+pub async fn mark_broadcast_recipient_sending(pool: &deadpool_postgres::Pool, broadcast_id: i64, user_id: i64) -> Result<bool, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let updated = client.execute(
+        "UPDATE broadcast_recipients SET status = 'sending' WHERE broadcast_id = $1 AND user_id = $2 AND status = 'pending'",
+        &[&broadcast_id, &user_id]
+    ).await?;
+    Ok(updated > 0)
+}
+
+// Records how delivery to a recipient ended, after `attempts` tries
+pub async fn update_broadcast_recipient(
+    pool: &deadpool_postgres::Pool,
+    broadcast_id: i64,
+    user_id: i64,
+    status: &str,
+    error: Option<&str>,
+    attempts: i32,
+) -> Result<(), anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    client.execute(
+        "UPDATE broadcast_recipients SET status = $3, error = $4, attempts = attempts + $5,
+             sent_at = CASE WHEN $3 = 'sent' THEN now() ELSE sent_at END
+         WHERE broadcast_id = $1 AND user_id = $2",
+        &[&broadcast_id, &user_id, &status, &error, &attempts]
+    ).await?;
+    Ok(())
+}
+
+pub async fn list_broadcast_recipients(
+    pool: &deadpool_postgres::Pool,
+    broadcast_id: i64,
+    status: Option<&str>,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<crate::DBBroadcastRecipient>, anyhow::Error> {
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    let rows = client.query(
+        "SELECT broadcast_id, user_id, status, attempts, error, sent_at FROM broadcast_recipients
+         WHERE broadcast_id = $1 AND ($2::TEXT IS NULL OR status = $2)
+         ORDER BY user_id
+         LIMIT $3 OFFSET $4",
+        &[&broadcast_id, &status, &limit, &offset]
+    ).await?;
+    Ok(rows.iter().map(|row| crate::DBBroadcastRecipient {
+        broadcast_id: row.get("broadcast_id"),
+        user_id: row.get("user_id"),
+        status: row.get("status"),
+        attempts: row.get("attempts"),
+        error: row.get("error"),
+        sent_at: row.get("sent_at"),
+    }).collect())
+}
+
+fn row_to_broadcast(row: &tokio_postgres::Row) -> crate::DBBroadcast {
+    crate::DBBroadcast {
+        id: row.get("id"),
+        text: row.get("text"),
+        segment: serde_json::from_value(row.get("segment")).unwrap_or_default(),
+        status: row.get("status"),
+        created_by: row.get("created_by"),
+        recipients: row.get("recipients"),
+        pending: row.get("pending"),
+        sent: row.get("sent"),
+        failed: row.get("failed"),
+        unreachable: row.get("unreachable"),
+        opted_out: row.get("opted_out"),
+        created_at: row.get("created_at"),
+        started_at: row.get("started_at"),
+        finished_at: row.get("finished_at"),
+    }
+}
+
+fn row_to_pending_reply(row: &tokio_postgres::Row) -> crate::DBPendingReply {
+    crate::DBPendingReply {
+        id: row.get("id"),
+        user_id: row.get("user_id"),
+        chat_id: row.get("chat_id"),
+        thread_id: row.get("thread_id"),
+        assistant_id: row.get("assistant_id"),
+        text: row.get("text"),
+        send_at: row.get("send_at"),
+        deferred_from: row.get("deferred_from"),
+    }
+}
+
+fn row_to_webhook_event(row: &tokio_postgres::Row) -> crate::DBWebhookEvent {
+    crate::DBWebhookEvent {
+        id: row.get("id"),
+        provider: row.get("provider"),
+        path: row.get("path"),
+        headers: row.get("headers"),
+        body: row.get("body"),
+        verified: row.get("verified"),
+        verification_error: row.get("verification_error"),
+        status: row.get("status"),
+        attempts: row.get("attempts"),
+        last_error: row.get("last_error"),
+        received_at: row.get("received_at"),
+        processed_at: row.get("processed_at"),
+    }
+}
+
+fn row_to_event_subscription(row: &tokio_postgres::Row) -> crate::DBEventSubscription {
+    crate::DBEventSubscription {
+        id: row.get("id"),
+        url: row.get("url"),
+        secret: row.get("secret"),
+        event_types: row.get("event_types"),
+        active: row.get("active"),
+        created_at: row.get("created_at"),
+    }
+}
+
+fn row_to_user(row: &tokio_postgres::Row) -> crate::DBUser {
+    crate::DBUser {
+        id: row.get("user_id"),
+        first_name: row.get("first_name"),
+        last_name: row.get("last_name"),
+        username: row.get("username"),
+        language_code: row.get("language_code"),
+        timezone: row.get("timezone"),
+        timezone_source: row.get("timezone_source"),
+        contact_state: row.get("contact_state"),
+        contact_state_at: row.get("contact_state_at"),
+    }
+}
+
 
 // pub async fn get_thread_by_user_id(pool: deadpool_postgres::Pool, user_id: i64) -> Result<Option<String>, anyhow::Error> {
 //     // Get a client from the pool, handling the pool error explicitly
@@ -0,0 +1,1065 @@
+// src/database_sqlite.rs
+
+// database.rs on SQLite, for the sqlite feature without the postgres one. Same functions with the same behaviour;
+// lib.rs mounts this file as crate::database. rusqlite is synchronous, so every query runs through `interact` on
+// the pool's blocking threads, and SQLite having a single writer stands in for Postgres' row locks.
+
+use rusqlite::{params, OptionalExtension, TransactionBehavior};
+
+pub type Pool = deadpool_sqlite::Pool;
+
+// Embedded schema migrations, applied in order. migrations/sqlite/0001 is the Postgres schema up to 0014.
+const MIGRATIONS: &[(&str, &str)] = &[
+    ("0001_initial", include_str!("../migrations/sqlite/0001_initial.sql")),
+    ("0002_inbound_messages", include_str!("../migrations/sqlite/0002_inbound_messages.sql")),
+];
+
+const DEFAULT_PATH: &str = "webhooks_server.db";
+const BUSY_TIMEOUT_MS: u64 = 5000;
+
+// The database file is SQLITE_DATABASE_PATH, created if missing
+pub fn create_pool() -> Result<Pool, anyhow::Error> {
+    let path = std::env::var("SQLITE_DATABASE_PATH").unwrap_or_else(|_| DEFAULT_PATH.to_string());
+    Ok(deadpool_sqlite::Config::new(path).create_pool(deadpool_sqlite::Runtime::Tokio1)?)
+}
+
+// Runs `f` on a connection from the pool
+async fn interact<T, F>(pool: &Pool, f: F) -> Result<T, anyhow::Error>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut rusqlite::Connection) -> Result<T, anyhow::Error> + Send + 'static,
+{
+    let client = pool.get().await.map_err(|e| {
+        log::error!("Failed to get client from pool: {:?}", e);
+        anyhow::Error::new(e)
+    })?;
+
+    client.interact(move |connection| {
+        // Both are per connection; the busy timeout makes a second writer wait instead of failing
+        connection.pragma_update(None, "foreign_keys", true)?;
+        connection.busy_timeout(std::time::Duration::from_millis(BUSY_TIMEOUT_MS))?;
+        f(connection)
+    }).await.map_err(|e| anyhow::anyhow!("SQLite call failed: {}", e))?
+}
+
+pub async fn ping(pool: &Pool) -> Result<(), anyhow::Error> {
+    interact(pool, |connection| {
+        connection.query_row("SELECT 1", [], |_| Ok(()))?;
+        Ok(())
+    }).await
+}
+
+pub async fn run_migrations(pool: &Pool) -> Result<(), anyhow::Error> {
+    interact(pool, |connection| {
+        // Lets readers carry on while someone writes; stored in the file, so once is enough
+        connection.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (version TEXT PRIMARY KEY, applied_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f+00:00', 'now')))"
+        )?;
+
+        for (version, sql) in MIGRATIONS {
+            let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let applied = transaction.query_row("SELECT 1 FROM schema_migrations WHERE version = ?1", [version], |_| Ok(())).optional()?;
+            if applied.is_some() {
+                continue;
+            }
+            transaction.execute_batch(sql)?;
+            transaction.execute("INSERT INTO schema_migrations (version) VALUES (?1)", [version])?;
+            transaction.commit()?;
+            log::info!("Applied migration {}", version);
+        }
+        Ok(())
+    }).await
+}
+
+pub async fn insert_user(pool: Pool, user: crate::DBUser) -> Result<(), anyhow::Error> {
+    interact(&pool, move |connection| {
+        // Writing to the bot means they unblocked it; an opt-out stays until /start
+        connection.execute(
+            "INSERT INTO users (user_id, first_name, last_name, username, language_code) VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (user_id) DO UPDATE SET first_name = excluded.first_name, last_name = excluded.last_name, username = excluded.username,
+                 language_code = COALESCE(excluded.language_code, users.language_code),
+                 contact_state = CASE WHEN users.contact_state = 'blocked' THEN 'active' ELSE users.contact_state END,
+                 contact_state_at = CASE WHEN users.contact_state = 'blocked' THEN ?6 ELSE users.contact_state_at END",
+            params![user.id, user.first_name, user.last_name, user.username, user.language_code, chrono::Utc::now()]
+        )?;
+        Ok(())
+    }).await
+}
+
+// Sets the user's timezone unless the one they have came from a stronger source (see quiet_hours::TIMEZONE_SOURCES).
+// Returns whether anything changed.
+pub async fn set_user_timezone(pool: &Pool, user_id: i64, timezone: &str, source: &str) -> Result<bool, anyhow::Error> {
+    let (timezone, source) = (timezone.to_string(), source.to_string());
+    let sources = serde_json::to_value(crate::quiet_hours::TIMEZONE_SOURCES)?;
+    interact(pool, move |connection| {
+        let updated = connection.execute(
+            "UPDATE users SET timezone = ?2, timezone_source = ?3
+             WHERE user_id = ?1
+               AND (timezone IS NOT ?2 OR timezone_source IS NOT ?3)
+               AND COALESCE((SELECT key FROM json_each(?4) WHERE value = timezone_source), -1) <= (SELECT key FROM json_each(?4) WHERE value = ?3)",
+            params![user_id, timezone, source, sources]
+        )?;
+        Ok(updated > 0)
+    }).await
+}
+
+pub async fn insert_thread(pool: Pool, thread_id: &str, user_id: i64, openai_thread_id: &str, assistant_id: &str) -> Result<(), anyhow::Error> {
+    let (thread_id, openai_thread_id, assistant_id) = (thread_id.to_string(), openai_thread_id.to_string(), assistant_id.to_string());
+    interact(&pool, move |connection| {
+        connection.execute(
+            "INSERT INTO threads (thread_id, user_id, openai_thread_id, assistant_id) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (thread_id) DO UPDATE SET user_id = excluded.user_id, openai_thread_id = excluded.openai_thread_id, assistant_id = excluded.assistant_id",
+            params![thread_id, user_id, openai_thread_id, assistant_id]
+        )?;
+        Ok(())
+    }).await
+}
+
+pub async fn insert_message(pool: Pool, thread_id: &str, sender: &str, content: &str, message_type: &str, assistant_id: &str) -> Result<(), anyhow::Error> {
+    let values = [thread_id, sender, content, message_type, assistant_id].map(str::to_string);
+    interact(&pool, move |connection| {
+        connection.execute(
+            "INSERT INTO messages (thread_id, sender, content, message_type, assistant_id) VALUES (?1, ?2, ?3, ?4, ?5)",
+            values
+        )?;
+        Ok(())
+    }).await
+}
+
+pub async fn get_thread_by_user_id_and_assistant(pool: Pool, user_id: i64, assistant_id: &str) -> Result<Option<String>, anyhow::Error> {
+    let assistant_id = assistant_id.to_string();
+    interact(&pool, move |connection| {
+        Ok(connection.query_row(
+            "SELECT thread_id FROM threads WHERE user_id = ?1 AND assistant_id = ?2",
+            params![user_id, assistant_id],
+            |row| row.get("thread_id")
+        ).optional()?)
+    }).await
+}
+
+pub async fn insert_pre_processing_results(
+    pool: &Pool,
+    user_id: u64,
+    thread_id: &str,
+    interest: i32,
+    user_response_time: Option<i32>,
+    response_cue: Option<i32>,
+) -> Result<(), anyhow::Error> {
+    let thread_id = thread_id.to_string();
+    // Same defaults as database.rs
+    let response_cue = response_cue.unwrap_or(99999);
+    let user_response_time = user_response_time.unwrap_or(69);
+    interact(pool, move |connection| {
+        connection.execute(
+            "INSERT INTO metrics (user_id, thread_id, interest, user_response_time, response_cue) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![user_id as i64, thread_id, interest, user_response_time, response_cue]
+        )?;
+        Ok(())
+    }).await
+}
+
+
+//      DEDUPLICATION RELATED
+//          ---     --  --
+// Claims all of `keys` in one transaction, or none of them if any is completed or claimed within the last
+// `lease_secs`. Returns whether they were claimed.
+pub async fn claim_processed_updates(pool: &Pool, keys: &[String], lease_secs: i64) -> Result<bool, anyhow::Error> {
+    let keys = keys.to_vec();
+    interact(pool, move |connection| {
+        let now = chrono::Utc::now();
+        let lease_start = now - chrono::Duration::seconds(lease_secs);
+        // Immediate takes the write lock up front, so a concurrent claim waits for this one and then finds the keys taken
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        for key in &keys {
+            let claimed = transaction.query_row(
+                "INSERT INTO processed_updates (key, completed, processed_at) VALUES (?1, 0, ?2)
+                 ON CONFLICT (key) DO UPDATE SET processed_at = ?2
+                 WHERE NOT processed_updates.completed AND processed_updates.processed_at < ?3
+                 RETURNING key",
+                params![key, now, lease_start],
+                |_| Ok(())
+            ).optional()?;
+            if claimed.is_none() {
+                return Ok(false);
+            }
+        }
+        transaction.commit()?;
+        Ok(true)
+    }).await
+}
+
+pub async fn complete_processed_updates(pool: &Pool, keys: &[String]) -> Result<(), anyhow::Error> {
+    let keys = serde_json::to_value(keys)?;
+    interact(pool, move |connection| {
+        connection.execute(
+            "UPDATE processed_updates SET completed = 1, processed_at = ?2 WHERE key IN (SELECT value FROM json_each(?1))",
+            params![keys, chrono::Utc::now()]
+        )?;
+        Ok(())
+    }).await
+}
+
+// Gives up claims that were never completed, so a redelivery is handled
+pub async fn release_processed_updates(pool: &Pool, keys: &[String]) -> Result<(), anyhow::Error> {
+    let keys = serde_json::to_value(keys)?;
+    interact(pool, move |connection| {
+        connection.execute(
+            "DELETE FROM processed_updates WHERE key IN (SELECT value FROM json_each(?1)) AND NOT completed",
+            params![keys]
+        )?;
+        Ok(())
+    }).await
+}
+
+pub async fn delete_expired_processed_updates(pool: &Pool, ttl_hours: i64) -> Result<u64, anyhow::Error> {
+    interact(pool, move |connection| {
+        let deleted = connection.execute(
+            "DELETE FROM processed_updates WHERE processed_at < ?1",
+            params![chrono::Utc::now() - chrono::Duration::hours(ttl_hours)]
+        )?;
+        Ok(deleted as u64)
+    }).await
+}
+
+//      ADMIN API RELATED
+//          ---     --  --
+pub async fn search_users(pool: &Pool, search: Option<&str>, limit: i64, offset: i64) -> Result<Vec<crate::DBUser>, anyhow::Error> {
+    let search = search.map(str::to_string);
+    interact(pool, move |connection| {
+        // Matches on names/username, or on the exact user_id when the search term is numeric. LIKE ignores ASCII case.
+        let pattern = search.as_ref().map(|s| format!("%{}%", s));
+        let mut statement = connection.prepare(
+            "SELECT user_id, first_name, last_name, username, language_code, timezone, timezone_source, contact_state, contact_state_at FROM users
+             WHERE ?1 IS NULL
+                OR username LIKE ?1 OR first_name LIKE ?1 OR last_name LIKE ?1
+                OR CAST(user_id AS TEXT) = ?2
+             ORDER BY user_id
+             LIMIT ?3 OFFSET ?4"
+        )?;
+        let users = statement.query_map(params![pattern, search, limit, offset], row_to_user)?.collect::<Result<_, _>>()?;
+        Ok(users)
+    }).await
+}
+
+pub async fn get_user(pool: &Pool, user_id: i64) -> Result<Option<crate::DBUser>, anyhow::Error> {
+    interact(pool, move |connection| {
+        Ok(connection.query_row(
+            "SELECT user_id, first_name, last_name, username, language_code, timezone, timezone_source, contact_state, contact_state_at FROM users WHERE user_id = ?1",
+            params![user_id],
+            row_to_user
+        ).optional()?)
+    }).await
+}
+
+pub async fn get_threads_by_user_id(pool: &Pool, user_id: i64, assistant_id: Option<&str>) -> Result<Vec<crate::DBThread>, anyhow::Error> {
+    let assistant_id = assistant_id.map(str::to_string);
+    interact(pool, move |connection| {
+        let mut statement = connection.prepare(
+            "SELECT thread_id, user_id, openai_thread_id, assistant_id FROM threads
+             WHERE user_id = ?1 AND (?2 IS NULL OR assistant_id = ?2)
+             ORDER BY assistant_id"
+        )?;
+        let threads = statement.query_map(params![user_id, assistant_id], |row| Ok(crate::DBThread {
+            thread_id: row.get("thread_id")?,
+            user_id: row.get("user_id")?,
+            openai_thread_id: row.get("openai_thread_id")?,
+            assistant_id: row.get("assistant_id")?,
+        }))?.collect::<Result<_, _>>()?;
+        Ok(threads)
+    }).await
+}
+
+pub async fn get_messages_by_thread_id(pool: &Pool, thread_id: &str, limit: i64, offset: i64) -> Result<Vec<crate::DBMessage>, anyhow::Error> {
+    let thread_id = thread_id.to_string();
+    interact(pool, move |connection| {
+        let mut statement = connection.prepare(
+            "SELECT id, thread_id, sender, content, message_type, assistant_id, created_at FROM messages
+             WHERE thread_id = ?1
+             ORDER BY id
+             LIMIT ?2 OFFSET ?3"
+        )?;
+        let messages = statement.query_map(params![thread_id, limit, offset], |row| Ok(crate::DBMessage {
+            id: row.get("id")?,
+            thread_id: row.get("thread_id")?,
+            sender: row.get("sender")?,
+            content: row.get("content")?,
+            message_type: row.get("message_type")?,
+            assistant_id: row.get("assistant_id")?,
+            created_at: row.get("created_at")?,
+        }))?.collect::<Result<_, _>>()?;
+        Ok(messages)
+    }).await
+}
+
+pub async fn get_metrics_by_user_id(pool: &Pool, user_id: i64, limit: i64, offset: i64) -> Result<Vec<crate::DBMetric>, anyhow::Error> {
+    interact(pool, move |connection| {
+        let mut statement = connection.prepare(
+            "SELECT id, user_id, thread_id, interest, user_response_time, response_cue, created_at FROM metrics
+             WHERE user_id = ?1
+             ORDER BY id DESC
+             LIMIT ?2 OFFSET ?3"
+        )?;
+        let metrics = statement.query_map(params![user_id, limit, offset], |row| Ok(crate::DBMetric {
+            id: row.get("id")?,
+            user_id: row.get("user_id")?,
+            thread_id: row.get("thread_id")?,
+            interest: row.get("interest")?,
+            user_response_time: row.get("user_response_time")?,
+            response_cue: row.get("response_cue")?,
+            created_at: row.get("created_at")?,
+        }))?.collect::<Result<_, _>>()?;
+        Ok(metrics)
+    }).await
+}
+
+// Deletes the user along with their threads, messages, metrics and unsent replies. Returns false if the user didn't exist.
+pub async fn delete_user(pool: &Pool, user_id: i64) -> Result<bool, anyhow::Error> {
+    interact(pool, move |connection| {
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        transaction.execute(
+            "DELETE FROM messages WHERE thread_id IN (SELECT thread_id FROM threads WHERE user_id = ?1)",
+            params![user_id]
+        )?;
+        transaction.execute("DELETE FROM metrics WHERE user_id = ?1", params![user_id])?;
+        transaction.execute("DELETE FROM pending_replies WHERE user_id = ?1", params![user_id])?;
+        transaction.execute("DELETE FROM pending_buffers WHERE user_id = ?1", params![user_id])?;
+        transaction.execute("DELETE FROM follow_ups WHERE user_id = ?1", params![user_id])?;
+        transaction.execute("DELETE FROM broadcast_recipients WHERE user_id = ?1", params![user_id])?;
+        transaction.execute("DELETE FROM threads WHERE user_id = ?1", params![user_id])?;
+        let deleted = transaction.execute("DELETE FROM users WHERE user_id = ?1", params![user_id])?;
+        transaction.commit()?;
+        Ok(deleted > 0)
+    }).await
+}
+
+pub async fn insert_api_key(pool: &Pool, name: &str, key_hash: &str) -> Result<i64, anyhow::Error> {
+    let (name, key_hash) = (name.to_string(), key_hash.to_string());
+    interact(pool, move |connection| {
+        Ok(connection.query_row(
+            "INSERT INTO api_keys (name, key_hash) VALUES (?1, ?2) RETURNING id",
+            params![name, key_hash],
+            |row| row.get("id")
+        )?)
+    }).await
+}
+
+// Looks up a non-revoked key by its hash and bumps last_used_at in the same statement
+pub async fn get_api_key_by_hash(pool: &Pool, key_hash: &str) -> Result<Option<crate::DBApiKey>, anyhow::Error> {
+    let key_hash = key_hash.to_string();
+    interact(pool, move |connection| {
+        Ok(connection.query_row(
+            "UPDATE api_keys SET last_used_at = ?2 WHERE key_hash = ?1 AND revoked_at IS NULL RETURNING id, name",
+            params![key_hash, chrono::Utc::now()],
+            |row| Ok(crate::DBApiKey {
+                id: row.get("id")?,
+                name: row.get("name")?,
+            })
+        ).optional()?)
+    }).await
+}
+
+//      EVENT WEBHOOKS RELATED
+//          ---     --  --
+pub async fn insert_event_subscription(pool: &Pool, url: &str, secret: &str, event_types: &[String]) -> Result<crate::DBEventSubscription, anyhow::Error> {
+    let (url, secret) = (url.to_string(), secret.to_string());
+    let event_types = serde_json::to_value(event_types)?;
+    interact(pool, move |connection| {
+        Ok(connection.query_row(
+            "INSERT INTO event_subscriptions (url, secret, event_types) VALUES (?1, ?2, ?3)
+             RETURNING id, url, secret, event_types, active, created_at",
+            params![url, secret, event_types],
+            row_to_event_subscription
+        )?)
+    }).await
+}
+
+pub async fn get_event_subscriptions(pool: &Pool) -> Result<Vec<crate::DBEventSubscription>, anyhow::Error> {
+    interact(pool, |connection| {
+        let mut statement = connection.prepare(
+            "SELECT id, url, secret, event_types, active, created_at FROM event_subscriptions ORDER BY id"
+        )?;
+        let subscriptions = statement.query_map([], row_to_event_subscription)?.collect::<Result<_, _>>()?;
+        Ok(subscriptions)
+    }).await
+}
+
+pub async fn get_active_subscriptions_for_event(pool: &Pool, event_type: &str) -> Result<Vec<crate::DBEventSubscription>, anyhow::Error> {
+    let event_type = event_type.to_string();
+    interact(pool, move |connection| {
+        let mut statement = connection.prepare(
+            "SELECT id, url, secret, event_types, active, created_at FROM event_subscriptions
+             WHERE active AND EXISTS (SELECT 1 FROM json_each(event_types) WHERE value = ?1)"
+        )?;
+        let subscriptions = statement.query_map(params![event_type], row_to_event_subscription)?.collect::<Result<_, _>>()?;
+        Ok(subscriptions)
+    }).await
+}
+
+pub async fn delete_event_subscription(pool: &Pool, subscription_id: i64) -> Result<bool, anyhow::Error> {
+    interact(pool, move |connection| {
+        let deleted = connection.execute("DELETE FROM event_subscriptions WHERE id = ?1", params![subscription_id])?;
+        Ok(deleted > 0)
+    }).await
+}
+
+// Logs an attempt. `next_attempt_at` schedules a retry of it; `retry_of` is the attempt this one retried, whose
+// schedule is cleared in the same transaction.
+#[allow(clippy::too_many_arguments)]
+pub async fn insert_event_delivery(
+    pool: &Pool,
+    subscription_id: i64,
+    event: &crate::events::Event,
+    attempt: i32,
+    status_code: Option<i32>,
+    error: Option<&str>,
+    next_attempt_at: Option<chrono::DateTime<chrono::Utc>>,
+    retry_of: Option<i64>,
+) -> Result<(), anyhow::Error> {
+    let payload = serde_json::to_value(event)?;
+    let (event_id, event_type) = (event.id.clone(), event.event_type.clone());
+    let succeeded = error.is_none() && status_code.map(|code| (200..300).contains(&code)).unwrap_or(false);
+    let error = error.map(str::to_string);
+    interact(pool, move |connection| {
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        transaction.execute(
+            "INSERT INTO event_deliveries (subscription_id, event_id, event_type, payload, attempt, status_code, error, succeeded, next_attempt_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![subscription_id, event_id, event_type, payload, attempt, status_code, error, succeeded, next_attempt_at]
+        )?;
+        if let Some(retry_of) = retry_of {
+            transaction.execute("UPDATE event_deliveries SET next_attempt_at = NULL WHERE id = ?1", params![retry_of])?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }).await
+}
+
+// Failed attempts whose retry is due. Each is pushed `lease_secs` into the future so other polls leave it alone
+// while it is retried; if the retry is never logged, e.g. because the process died, it comes due again.
+pub async fn claim_due_event_retries(pool: &Pool, lease_secs: i64, limit: i64) -> Result<Vec<crate::DBEventRetry>, anyhow::Error> {
+    interact(pool, move |connection| {
+        let now = chrono::Utc::now();
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        // RETURNING can't see other tables in SQLite, so the subscriptions are read separately
+        let claimed: Vec<(i64, i32, serde_json::Value, i64)> = transaction.prepare(
+            "UPDATE event_deliveries SET next_attempt_at = ?1
+             WHERE id IN (
+                 SELECT id FROM event_deliveries WHERE next_attempt_at <= ?2
+                 ORDER BY next_attempt_at LIMIT ?3
+             )
+             RETURNING id, attempt, payload, subscription_id"
+        )?.query_map(
+            params![now + chrono::Duration::seconds(lease_secs), now, limit],
+            |row| Ok((row.get("id")?, row.get("attempt")?, row.get("payload")?, row.get("subscription_id")?))
+        )?.collect::<Result<_, _>>()?;
+
+        let mut retries = Vec::with_capacity(claimed.len());
+        for (delivery_id, attempt, payload, subscription_id) in claimed {
+            let subscription = transaction.query_row(
+                "SELECT id, url, secret, event_types, active, created_at FROM event_subscriptions WHERE id = ?1",
+                params![subscription_id],
+                row_to_event_subscription
+            )?;
+            retries.push(crate::DBEventRetry { delivery_id, attempt, payload, subscription });
+        }
+        transaction.commit()?;
+        Ok(retries)
+    }).await
+}
+
+// Drops the retry of an attempt, e.g. because its subscription was deactivated
+pub async fn cancel_event_retry(pool: &Pool, delivery_id: i64) -> Result<(), anyhow::Error> {
+    interact(pool, move |connection| {
+        connection.execute("UPDATE event_deliveries SET next_attempt_at = NULL WHERE id = ?1", params![delivery_id])?;
+        Ok(())
+    }).await
+}
+
+pub async fn get_event_deliveries(pool: &Pool, subscription_id: i64, limit: i64, offset: i64) -> Result<Vec<crate::DBEventDelivery>, anyhow::Error> {
+    interact(pool, move |connection| {
+        let mut statement = connection.prepare(
+            "SELECT id, subscription_id, event_id, event_type, attempt, status_code, error, succeeded, created_at FROM event_deliveries
+             WHERE subscription_id = ?1
+             ORDER BY created_at DESC, id DESC
+             LIMIT ?2 OFFSET ?3"
+        )?;
+        let deliveries = statement.query_map(params![subscription_id, limit, offset], |row| Ok(crate::DBEventDelivery {
+            id: row.get("id")?,
+            subscription_id: row.get("subscription_id")?,
+            event_id: row.get("event_id")?,
+            event_type: row.get("event_type")?,
+            attempt: row.get("attempt")?,
+            status_code: row.get("status_code")?,
+            error: row.get("error")?,
+            succeeded: row.get("succeeded")?,
+            created_at: row.get("created_at")?,
+        }))?.collect::<Result<_, _>>()?;
+        Ok(deliveries)
+    }).await
+}
+
+//      INBOUND WEBHOOKS RELATED
+//          ---     --  --
+pub async fn insert_webhook_event(
+    pool: &Pool,
+    provider: &str,
+    path: &str,
+    headers: &serde_json::Value,
+    body: &[u8],
+    verified: bool,
+    verification_error: Option<&str>,
+) -> Result<i64, anyhow::Error> {
+    let (provider, path, headers, body) = (provider.to_string(), path.to_string(), headers.clone(), body.to_vec());
+    let verification_error = verification_error.map(str::to_string);
+    // Events that fail verification are kept for inspection but never handed to a handler
+    let status = if verified { "received" } else { "rejected" };
+    interact(pool, move |connection| {
+        Ok(connection.query_row(
+            "INSERT INTO webhook_events (provider, path, headers, body, verified, verification_error, status)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) RETURNING id",
+            params![provider, path, headers, body, verified, verification_error, status],
+            |row| row.get("id")
+        )?)
+    }).await
+}
+
+pub async fn mark_webhook_event_processed(pool: &Pool, event_id: i64) -> Result<(), anyhow::Error> {
+    interact(pool, move |connection| {
+        connection.execute(
+            "UPDATE webhook_events SET status = 'processed', attempts = attempts + 1, last_error = NULL, processed_at = ?2 WHERE id = ?1",
+            params![event_id, chrono::Utc::now()]
+        )?;
+        Ok(())
+    }).await
+}
+
+pub async fn mark_webhook_event_dead_letter(pool: &Pool, event_id: i64, error: &str) -> Result<(), anyhow::Error> {
+    let error = error.to_string();
+    interact(pool, move |connection| {
+        connection.execute(
+            "UPDATE webhook_events SET status = 'dead_letter', attempts = attempts + 1, last_error = ?2 WHERE id = ?1",
+            params![event_id, error]
+        )?;
+        Ok(())
+    }).await
+}
+
+pub async fn list_webhook_events(
+    pool: &Pool,
+    status: Option<&str>,
+    provider: Option<&str>,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<crate::DBWebhookEvent>, anyhow::Error> {
+    let (status, provider) = (status.map(str::to_string), provider.map(str::to_string));
+    interact(pool, move |connection| {
+        let mut statement = connection.prepare(
+            "SELECT id, provider, path, headers, body, verified, verification_error, status, attempts, last_error, received_at, processed_at
+             FROM webhook_events
+             WHERE (?1 IS NULL OR status = ?1) AND (?2 IS NULL OR provider = ?2)
+             ORDER BY received_at, id
+             LIMIT ?3 OFFSET ?4"
+        )?;
+        let events = statement.query_map(params![status, provider, limit, offset], row_to_webhook_event)?.collect::<Result<_, _>>()?;
+        Ok(events)
+    }).await
+}
+
+pub async fn get_webhook_event(pool: &Pool, event_id: i64) -> Result<Option<crate::DBWebhookEvent>, anyhow::Error> {
+    interact(pool, move |connection| {
+        Ok(connection.query_row(
+            "SELECT id, provider, path, headers, body, verified, verification_error, status, attempts, last_error, received_at, processed_at
+             FROM webhook_events WHERE id = ?1",
+            params![event_id],
+            row_to_webhook_event
+        ).optional()?)
+    }).await
+}
+
+//      PENDING WORK RELATED
+//          ---     --  --
+pub async fn insert_pending_reply(pool: &Pool, reply: &crate::DBPendingReply) -> Result<i64, anyhow::Error> {
+    let reply = reply.clone();
+    interact(pool, move |connection| {
+        Ok(connection.query_row(
+            "INSERT INTO pending_replies (user_id, chat_id, thread_id, assistant_id, text, send_at, deferred_from)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) RETURNING id",
+            params![reply.user_id, reply.chat_id, reply.thread_id, reply.assistant_id, reply.text, reply.send_at, reply.deferred_from],
+            |row| row.get("id")
+        )?)
+    }).await
+}
+
+// Marks up to `limit` replies due at `now` as being sent by this process and returns them. A claim older than
+// ten minutes is taken to be from a process that died while sending, so that reply is claimed again.
+pub async fn claim_due_pending_replies(pool: &Pool, now: chrono::DateTime<chrono::Utc>, limit: i64) -> Result<Vec<crate::DBPendingReply>, anyhow::Error> {
+    interact(pool, move |connection| {
+        let mut statement = connection.prepare(
+            "UPDATE pending_replies SET claimed_at = ?1
+             WHERE id IN (
+                 SELECT id FROM pending_replies
+                 WHERE send_at <= ?1 AND (claimed_at IS NULL OR claimed_at < ?2)
+                 ORDER BY send_at
+                 LIMIT ?3
+             )
+             RETURNING id, user_id, chat_id, thread_id, assistant_id, text, send_at, deferred_from"
+        )?;
+        let replies = statement.query_map(params![now, now - chrono::Duration::minutes(10), limit], row_to_pending_reply)?.collect::<Result<_, _>>()?;
+        Ok(replies)
+    }).await
+}
+
+// Moves a claimed reply out of quiet hours, keeping the first time it was due in deferred_from
+pub async fn defer_pending_reply(pool: &Pool, reply_id: i64, send_at: chrono::DateTime<chrono::Utc>) -> Result<(), anyhow::Error> {
+    interact(pool, move |connection| {
+        connection.execute(
+            "UPDATE pending_replies SET deferred_from = COALESCE(deferred_from, send_at), send_at = ?2, claimed_at = NULL WHERE id = ?1",
+            params![reply_id, send_at]
+        )?;
+        Ok(())
+    }).await
+}
+
+pub async fn list_pending_replies(
+    pool: &Pool,
+    user_id: Option<i64>,
+    deferred_only: bool,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<crate::DBPendingReply>, anyhow::Error> {
+    interact(pool, move |connection| {
+        let mut statement = connection.prepare(
+            "SELECT id, user_id, chat_id, thread_id, assistant_id, text, send_at, deferred_from FROM pending_replies
+             WHERE (?1 IS NULL OR user_id = ?1) AND (NOT ?2 OR deferred_from IS NOT NULL)
+             ORDER BY send_at
+             LIMIT ?3 OFFSET ?4"
+        )?;
+        let replies = statement.query_map(params![user_id, deferred_only, limit, offset], row_to_pending_reply)?.collect::<Result<_, _>>()?;
+        Ok(replies)
+    }).await
+}
+
+pub async fn delete_pending_reply(pool: &Pool, reply_id: i64) -> Result<(), anyhow::Error> {
+    interact(pool, move |connection| {
+        connection.execute("DELETE FROM pending_replies WHERE id = ?1", params![reply_id])?;
+        Ok(())
+    }).await
+}
+
+pub async fn upsert_pending_buffer(pool: &Pool, buffer: &crate::DBPendingBuffer) -> Result<(), anyhow::Error> {
+    let messages = serde_json::to_value(&buffer.messages)?;
+    let (user_id, chat_id) = (buffer.user_id, buffer.chat_id);
+    interact(pool, move |connection| {
+        connection.execute(
+            "INSERT INTO pending_buffers (user_id, chat_id, messages) VALUES (?1, ?2, ?3)
+             ON CONFLICT (user_id) DO UPDATE SET chat_id = excluded.chat_id, messages = excluded.messages, created_at = excluded.created_at",
+            params![user_id, chat_id, messages]
+        )?;
+        Ok(())
+    }).await
+}
+
+// Removes and returns every persisted buffer; they go straight back into memory
+pub async fn take_pending_buffers(pool: &Pool) -> Result<Vec<crate::DBPendingBuffer>, anyhow::Error> {
+    let rows: Vec<(i64, i64, serde_json::Value)> = interact(pool, |connection| {
+        let mut statement = connection.prepare("DELETE FROM pending_buffers RETURNING user_id, chat_id, messages")?;
+        let rows = statement.query_map([], |row| Ok((row.get("user_id")?, row.get("chat_id")?, row.get("messages")?)))?.collect::<Result<_, _>>()?;
+        Ok(rows)
+    }).await?;
+
+    let mut buffers = Vec::with_capacity(rows.len());
+    for (user_id, chat_id, messages) in rows {
+        buffers.push(crate::DBPendingBuffer {
+            user_id,
+            chat_id,
+            messages: serde_json::from_value(messages)?,
+        });
+    }
+    Ok(buffers)
+}
+
+// Threads with `assistant_id` whose last inbound message is older than `before`, and that have had no follow-up
+// since `before` either and fewer than `max_follow_ups` sent ones since the user last wrote. See database.rs.
+pub async fn get_dormant_threads(
+    pool: &Pool,
+    assistant_id: &str,
+    before: chrono::DateTime<chrono::Utc>,
+    max_follow_ups: i64,
+    limit: i64,
+) -> Result<Vec<crate::DBDormantThread>, anyhow::Error> {
+    let assistant_id = assistant_id.to_string();
+    interact(pool, move |connection| {
+        let mut statement = connection.prepare(
+            "WITH last_inbound AS (
+                 SELECT t.user_id, t.thread_id, t.assistant_id, max(m.created_at) AS last_inbound_at
+                 FROM threads t
+                 JOIN messages m ON m.thread_id = t.thread_id AND m.sender = 'user'
+                 WHERE t.assistant_id = ?1
+                 GROUP BY t.user_id, t.thread_id, t.assistant_id
+             )
+             SELECT li.user_id, li.thread_id, li.assistant_id, li.last_inbound_at, u.timezone,
+                 count(f.id) FILTER (WHERE f.status = 'sent') AS follow_ups_sent
+             FROM last_inbound li
+             JOIN users u ON u.user_id = li.user_id
+             LEFT JOIN follow_ups f ON f.user_id = li.user_id AND f.created_at > li.last_inbound_at
+             WHERE li.last_inbound_at < ?2
+               AND u.contact_state = 'active'
+               AND NOT EXISTS (SELECT 1 FROM pending_replies p WHERE p.user_id = li.user_id)
+             GROUP BY li.user_id, li.thread_id, li.assistant_id, li.last_inbound_at, u.timezone
+             HAVING count(f.id) FILTER (WHERE f.status = 'sent') < ?3
+                AND COALESCE(max(f.created_at), li.last_inbound_at) < ?2
+             ORDER BY li.last_inbound_at
+             LIMIT ?4"
+        )?;
+        let threads = statement.query_map(params![assistant_id, before, max_follow_ups, limit], |row| Ok(crate::DBDormantThread {
+            user_id: row.get("user_id")?,
+            thread_id: row.get("thread_id")?,
+            assistant_id: row.get("assistant_id")?,
+            last_inbound_at: row.get("last_inbound_at")?,
+            timezone: row.get("timezone")?,
+            follow_ups_sent: row.get("follow_ups_sent")?,
+        }))?.collect::<Result<_, _>>()?;
+        Ok(threads)
+    }).await
+}
+
+// When the user last wrote on the thread, see get_dormant_threads
+pub async fn get_last_inbound_at(pool: &Pool, thread_id: &str) -> Result<Option<chrono::DateTime<chrono::Utc>>, anyhow::Error> {
+    let thread_id = thread_id.to_string();
+    interact(pool, move |connection| {
+        Ok(connection.query_row(
+            "SELECT max(created_at) AS last_inbound_at FROM messages WHERE thread_id = ?1 AND sender = 'user'",
+            params![thread_id],
+            |row| row.get("last_inbound_at")
+        )?)
+    }).await
+}
+
+pub async fn insert_follow_up(
+    pool: &Pool,
+    thread: &crate::DBDormantThread,
+    text: &str,
+    status: &str,
+    error: Option<&str>,
+) -> Result<(), anyhow::Error> {
+    let (user_id, thread_id, assistant_id) = (thread.user_id, thread.thread_id.clone(), thread.assistant_id.clone());
+    let (text, status, error) = (text.to_string(), status.to_string(), error.map(str::to_string));
+    interact(pool, move |connection| {
+        connection.execute(
+            "INSERT INTO follow_ups (user_id, thread_id, assistant_id, text, status, error) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![user_id, thread_id, assistant_id, text, status, error]
+        )?;
+        Ok(())
+    }).await
+}
+
+// Sets users.contact_state, see crate::CONTACT_STATES. A block never replaces an opt-out, which has to outlast the
+// user unblocking the bot. Returns whether anything changed.
+pub async fn set_contact_state(pool: &Pool, user_id: i64, state: &str) -> Result<bool, anyhow::Error> {
+    let state = state.to_string();
+    interact(pool, move |connection| {
+        let updated = connection.execute(
+            "UPDATE users SET contact_state = ?2, contact_state_at = ?3
+             WHERE user_id = ?1 AND contact_state <> ?2 AND (?2 <> 'blocked' OR contact_state = 'active')",
+            params![user_id, state, chrono::Utc::now()]
+        )?;
+        Ok(updated > 0)
+    }).await
+}
+
+// User ids matching a BroadcastSegment, with the parameters in the order of segment_params. Only active users (see
+// crate::CONTACT_STATES). A language_code of "pt" also matches "pt-br". LIKE ignores ASCII case.
+const SEGMENT_QUERY: &str =
+    "WITH latest AS (
+         SELECT m.user_id, m.interest, m.created_at FROM metrics m
+         WHERE m.id = (SELECT max(id) FROM metrics WHERE user_id = m.user_id)
+     )
+     SELECT u.user_id FROM users u
+     LEFT JOIN latest m ON m.user_id = u.user_id
+     WHERE u.contact_state = 'active'
+       AND (?1 IS NULL OR u.user_id IN (SELECT value FROM json_each(?1)))
+       AND (?2 IS NULL OR m.interest >= ?2)
+       AND (?3 IS NULL OR m.interest <= ?3)
+       AND (?4 IS NULL OR m.created_at >= ?4)
+       AND (?5 IS NULL OR m.created_at IS NULL OR m.created_at < ?5)
+       AND (?6 IS NULL OR lower(u.language_code) = lower(?6) OR u.language_code LIKE ?6 || '-%')";
+
+// The day counts become cutoff times here, SQLite having no intervals
+fn segment_params(segment: &crate::BroadcastSegment) -> Result<Vec<Box<dyn rusqlite::ToSql + Send>>, anyhow::Error> {
+    let now = chrono::Utc::now();
+    let days_ago = |days: Option<i32>| days.map(|days| now - chrono::Duration::days(days as i64));
+    Ok(vec![
+        Box::new(segment.user_ids.as_ref().map(serde_json::to_value).transpose()?),
+        Box::new(segment.min_interest),
+        Box::new(segment.max_interest),
+        Box::new(days_ago(segment.active_within_days)),
+        Box::new(days_ago(segment.inactive_for_days)),
+        Box::new(segment.language_code.clone()),
+    ])
+}
+
+pub async fn count_segment(pool: &Pool, segment: &crate::BroadcastSegment) -> Result<i64, anyhow::Error> {
+    let params = segment_params(segment)?;
+    interact(pool, move |connection| {
+        Ok(connection.query_row(
+            &format!("SELECT count(*) AS count FROM ({}) segment", SEGMENT_QUERY),
+            rusqlite::params_from_iter(params.iter()),
+            |row| row.get("count")
+        )?)
+    }).await
+}
+
+// Creates the broadcast with everyone currently in the segment as a pending recipient
+pub async fn insert_broadcast(pool: &Pool, text: &str, segment: &crate::BroadcastSegment, created_by: &str) -> Result<crate::DBBroadcast, anyhow::Error> {
+    let mut params = segment_params(segment)?;
+    let (text, created_by) = (text.to_string(), created_by.to_string());
+    let segment = serde_json::to_value(segment)?;
+    let broadcast_id = interact(pool, move |connection| {
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let broadcast_id: i64 = transaction.query_row(
+            "INSERT INTO broadcasts (text, segment, created_by) VALUES (?1, ?2, ?3) RETURNING id",
+            params![text, segment, created_by],
+            |row| row.get("id")
+        )?;
+        params.push(Box::new(broadcast_id));
+        transaction.execute(
+            &format!("INSERT INTO broadcast_recipients (broadcast_id, user_id) SELECT ?7, user_id FROM ({}) segment", SEGMENT_QUERY),
+            rusqlite::params_from_iter(params.iter())
+        )?;
+        transaction.commit()?;
+        Ok(broadcast_id)
+    }).await?;
+
+    get_broadcast(pool, broadcast_id).await?.ok_or_else(|| anyhow::anyhow!("Broadcast {} vanished after insert", broadcast_id))
+}
+
+const BROADCAST_COLUMNS: &str =
+    "b.id, b.text, b.segment, b.status, b.created_by, b.created_at, b.started_at, b.finished_at,
+     (SELECT count(*) FROM broadcast_recipients r WHERE r.broadcast_id = b.id) AS recipients,
+     (SELECT count(*) FROM broadcast_recipients r WHERE r.broadcast_id = b.id AND r.status = 'pending') AS pending,
+     (SELECT count(*) FROM broadcast_recipients r WHERE r.broadcast_id = b.id AND r.status = 'sent') AS sent,
+     (SELECT count(*) FROM broadcast_recipients r WHERE r.broadcast_id = b.id AND r.status = 'failed') AS failed,
+     (SELECT count(*) FROM broadcast_recipients r WHERE r.broadcast_id = b.id AND r.status = 'unreachable') AS unreachable,
+     (SELECT count(*) FROM broadcast_recipients r WHERE r.broadcast_id = b.id AND r.status = 'opted_out') AS opted_out";
+
+pub async fn get_broadcast(pool: &Pool, broadcast_id: i64) -> Result<Option<crate::DBBroadcast>, anyhow::Error> {
+    interact(pool, move |connection| {
+        Ok(connection.query_row(
+            &format!("SELECT {} FROM broadcasts b WHERE b.id = ?1", BROADCAST_COLUMNS),
+            params![broadcast_id],
+            row_to_broadcast
+        ).optional()?)
+    }).await
+}
+
+// Newest first
+pub async fn list_broadcasts(pool: &Pool, limit: i64, offset: i64) -> Result<Vec<crate::DBBroadcast>, anyhow::Error> {
+    interact(pool, move |connection| {
+        let mut statement = connection.prepare(&format!("SELECT {} FROM broadcasts b ORDER BY b.id DESC LIMIT ?1 OFFSET ?2", BROADCAST_COLUMNS))?;
+        let broadcasts = statement.query_map(params![limit, offset], row_to_broadcast)?.collect::<Result<_, _>>()?;
+        Ok(broadcasts)
+    }).await
+}
+
+// Stops a broadcast that hasn't finished; recipients not reached yet stay pending
+pub async fn cancel_broadcast(pool: &Pool, broadcast_id: i64) -> Result<bool, anyhow::Error> {
+    interact(pool, move |connection| {
+        let updated = connection.execute(
+            "UPDATE broadcasts SET status = 'cancelled', finished_at = ?2 WHERE id = ?1 AND status IN ('queued', 'sending')",
+            params![broadcast_id, chrono::Utc::now()]
+        )?;
+        Ok(updated > 0)
+    }).await
+}
+
+// The oldest broadcast still to send, marked as sending
+pub async fn start_next_broadcast(pool: &Pool) -> Result<Option<crate::DBBroadcast>, anyhow::Error> {
+    let broadcast_id: Option<i64> = interact(pool, |connection| {
+        Ok(connection.query_row(
+            "UPDATE broadcasts SET status = 'sending', started_at = COALESCE(started_at, ?1)
+             WHERE id = (SELECT id FROM broadcasts WHERE status IN ('queued', 'sending') ORDER BY id LIMIT 1)
+             RETURNING id",
+            params![chrono::Utc::now()],
+            |row| row.get("id")
+        ).optional()?)
+    }).await?;
+    match broadcast_id {
+        Some(broadcast_id) => get_broadcast(pool, broadcast_id).await,
+        None => Ok(None),
+    }
+}
+
+// Marks a sending broadcast done; false if it was cancelled meanwhile
+// Recipients still sending are failed: whether they got the message is unknown, and they aren't sent it again
+pub async fn finish_broadcast(pool: &Pool, broadcast_id: i64) -> Result<bool, anyhow::Error> {
+    interact(pool, move |connection| {
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        transaction.execute(
+            "UPDATE broadcast_recipients SET status = 'failed', error = 'outcome not recorded, may have been delivered'
+             WHERE broadcast_id = ?1 AND status = 'sending'",
+            params![broadcast_id]
+        )?;
+        let updated = transaction.execute(
+            "UPDATE broadcasts SET status = 'done', finished_at = ?2 WHERE id = ?1 AND status = 'sending'",
+            params![broadcast_id, chrono::Utc::now()]
+        )?;
+        transaction.commit()?;
+        Ok(updated > 0)
+    }).await
+}
+
+pub async fn get_broadcast_status(pool: &Pool, broadcast_id: i64) -> Result<Option<String>, anyhow::Error> {
+    interact(pool, move |connection| {
+        Ok(connection.query_row("SELECT status FROM broadcasts WHERE id = ?1", params![broadcast_id], |row| row.get("status")).optional()?)
+    }).await
+}
+
+// (user_id, contact_state) of recipients not sent to yet
+pub async fn get_pending_broadcast_recipients(pool: &Pool, broadcast_id: i64, limit: i64) -> Result<Vec<(i64, String)>, anyhow::Error> {
+    interact(pool, move |connection| {
+        let mut statement = connection.prepare(
+            "SELECT r.user_id, COALESCE(u.contact_state, 'active') AS contact_state
+             FROM broadcast_recipients r
+             LEFT JOIN users u ON u.user_id = r.user_id
+             WHERE r.broadcast_id = ?1 AND r.status = 'pending'
+             ORDER BY r.user_id
+             LIMIT ?2"
+        )?;
+        let recipients = statement.query_map(params![broadcast_id, limit], |row| Ok((row.get("user_id")?, row.get("contact_state")?)))?.collect::<Result<_, _>>()?;
+        Ok(recipients)
+    }).await
+}
+
+// Claims a pending recipient right before sending; false if they aren't pending any more
+pub async fn mark_broadcast_recipient_sending(pool: &Pool, broadcast_id: i64, user_id: i64) -> Result<bool, anyhow::Error> {
+    interact(pool, move |connection| {
+        let updated = connection.execute(
+            "UPDATE broadcast_recipients SET status = 'sending' WHERE broadcast_id = ?1 AND user_id = ?2 AND status = 'pending'",
+            params![broadcast_id, user_id]
+        )?;
+        Ok(updated > 0)
+    }).await
+}
+
+// Records how delivery to a recipient ended, after `attempts` tries
+pub async fn update_broadcast_recipient(
+    pool: &Pool,
+    broadcast_id: i64,
+    user_id: i64,
+    status: &str,
+    error: Option<&str>,
+    attempts: i32,
+) -> Result<(), anyhow::Error> {
+    let (status, error) = (status.to_string(), error.map(str::to_string));
+    interact(pool, move |connection| {
+        connection.execute(
+            "UPDATE broadcast_recipients SET status = ?3, error = ?4, attempts = attempts + ?5,
+                 sent_at = CASE WHEN ?3 = 'sent' THEN ?6 ELSE sent_at END
+             WHERE broadcast_id = ?1 AND user_id = ?2",
+            params![broadcast_id, user_id, status, error, attempts, chrono::Utc::now()]
+        )?;
+        Ok(())
+    }).await
+}
+
+pub async fn list_broadcast_recipients(
+    pool: &Pool,
+    broadcast_id: i64,
+    status: Option<&str>,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<crate::DBBroadcastRecipient>, anyhow::Error> {
+    let status = status.map(str::to_string);
+    interact(pool, move |connection| {
+        let mut statement = connection.prepare(
+            "SELECT broadcast_id, user_id, status, attempts, error, sent_at FROM broadcast_recipients
+             WHERE broadcast_id = ?1 AND (?2 IS NULL OR status = ?2)
+             ORDER BY user_id
+             LIMIT ?3 OFFSET ?4"
+        )?;
+        let recipients = statement.query_map(params![broadcast_id, status, limit, offset], |row| Ok(crate::DBBroadcastRecipient {
+            broadcast_id: row.get("broadcast_id")?,
+            user_id: row.get("user_id")?,
+            status: row.get("status")?,
+            attempts: row.get("attempts")?,
+            error: row.get("error")?,
+            sent_at: row.get("sent_at")?,
+        }))?.collect::<Result<_, _>>()?;
+        Ok(recipients)
+    }).await
+}
+
+fn row_to_broadcast(row: &rusqlite::Row) -> rusqlite::Result<crate::DBBroadcast> {
+    Ok(crate::DBBroadcast {
+        id: row.get("id")?,
+        text: row.get("text")?,
+        segment: serde_json::from_value(row.get("segment")?).unwrap_or_default(),
+        status: row.get("status")?,
+        created_by: row.get("created_by")?,
+        recipients: row.get("recipients")?,
+        pending: row.get("pending")?,
+        sent: row.get("sent")?,
+        failed: row.get("failed")?,
+        unreachable: row.get("unreachable")?,
+        opted_out: row.get("opted_out")?,
+        created_at: row.get("created_at")?,
+        started_at: row.get("started_at")?,
+        finished_at: row.get("finished_at")?,
+    })
+}
+
+fn row_to_pending_reply(row: &rusqlite::Row) -> rusqlite::Result<crate::DBPendingReply> {
+    Ok(crate::DBPendingReply {
+        id: row.get("id")?,
+        user_id: row.get("user_id")?,
+        chat_id: row.get("chat_id")?,
+        thread_id: row.get("thread_id")?,
+        assistant_id: row.get("assistant_id")?,
+        text: row.get("text")?,
+        send_at: row.get("send_at")?,
+        deferred_from: row.get("deferred_from")?,
+    })
+}
+
+fn row_to_webhook_event(row: &rusqlite::Row) -> rusqlite::Result<crate::DBWebhookEvent> {
+    Ok(crate::DBWebhookEvent {
+        id: row.get("id")?,
+        provider: row.get("provider")?,
+        path: row.get("path")?,
+        headers: row.get("headers")?,
+        body: row.get("body")?,
+        verified: row.get("verified")?,
+        verification_error: row.get("verification_error")?,
+        status: row.get("status")?,
+        attempts: row.get("attempts")?,
+        last_error: row.get("last_error")?,
+        received_at: row.get("received_at")?,
+        processed_at: row.get("processed_at")?,
+    })
+}
+
+fn row_to_event_subscription(row: &rusqlite::Row) -> rusqlite::Result<crate::DBEventSubscription> {
+    Ok(crate::DBEventSubscription {
+        id: row.get("id")?,
+        url: row.get("url")?,
+        secret: row.get("secret")?,
+        event_types: serde_json::from_value(row.get("event_types")?).unwrap_or_default(),
+        active: row.get("active")?,
+        created_at: row.get("created_at")?,
+    })
+}
+
+fn row_to_user(row: &rusqlite::Row) -> rusqlite::Result<crate::DBUser> {
+    Ok(crate::DBUser {
+        id: row.get("user_id")?,
+        first_name: row.get("first_name")?,
+        last_name: row.get("last_name")?,
+        username: row.get("username")?,
+        language_code: row.get("language_code")?,
+        timezone: row.get("timezone")?,
+        timezone_source: row.get("timezone_source")?,
+        contact_state: row.get("contact_state")?,
+        contact_state_at: row.get("contact_state_at")?,
+    })
+}
@@ -0,0 +1,507 @@
+// src/debounce.rs
+
+// Collects inbound items per key (a Telegram user) and starts a flush once the key has been quiet for
+// `quiet_period`, or `max_wait` after the first item of the batch, whichever comes first. A batch that reaches
+// `max_items` items or `max_size` (as measured by the size function, characters for messages) is flushed right away.
+// Each new item aborts the scheduled flush and schedules it again, but only while it is still waiting: once
+// started, a flush runs to the end and later items queue for the next one.
+//
+// A flush takes its items with `start_turn`, which also waits for the key's previous turn, so a key never has
+// two turns at once. Items stay with the turn until it calls `finish`; a turn dropped before that (an error, a
+// shutdown) leaves them for the next one. A failed turn can hand itself to `retry`, which schedules that next one
+// with exponential backoff, up to `max_retries` times in a row. A key's entry goes once it has nothing left.
+//
+// Once shutdown starts no new flush begins: waiting items stay put for `take_unflushed` to hand back.
+//
+// Only tokio::time is used for waiting, so a paused test clock drives it:
+//
+//     let buffer = Debouncer::new(DebounceConfig::from_env(), |text: &&str| text.len());
+//     buffer.push(1, "hi", async { ... });
+//     tokio::time::advance(Duration::from_secs(15)).await;
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct DebounceConfig {
+    pub quiet_period: Duration,
+    // Since the first item of the batch, however often new ones arrive
+    pub max_wait: Duration,
+    pub max_items: usize,
+    pub max_size: usize,
+    // Whether a new item cuts short a turn's Turn::wait, so its result can be thrown away and redone
+    pub cancel_on_new: bool,
+    // Before the first retry of a failed turn, doubling with each further one
+    pub retry_delay: Duration,
+    // Failed turns in a row after which the items wait for the key's next item instead
+    pub max_retries: u32,
+}
+
+impl DebounceConfig {
+    // BUFFER_QUIET_SECS (default 15), BUFFER_MAX_WAIT_SECS (default 120), BUFFER_MAX_MESSAGES (default 20),
+    // BUFFER_MAX_CHARS (default 4000), BUFFER_REGENERATE_ON_NEW_MESSAGE (default false), BUFFER_RETRY_SECS
+    // (default 30) and BUFFER_MAX_RETRIES (default 5)
+    pub fn from_env() -> Self {
+        let quiet_secs = std::env::var("BUFFER_QUIET_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(15);
+        let max_wait_secs = std::env::var("BUFFER_MAX_WAIT_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(120);
+        let retry_secs = std::env::var("BUFFER_RETRY_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(30);
+        DebounceConfig {
+            quiet_period: Duration::from_secs(quiet_secs),
+            max_wait: Duration::from_secs(max_wait_secs),
+            max_items: std::env::var("BUFFER_MAX_MESSAGES").ok().and_then(|v| v.parse().ok()).unwrap_or(20),
+            max_size: std::env::var("BUFFER_MAX_CHARS").ok().and_then(|v| v.parse().ok()).unwrap_or(4000),
+            cancel_on_new: std::env::var("BUFFER_REGENERATE_ON_NEW_MESSAGE").ok().and_then(|v| v.parse().ok()).unwrap_or(false),
+            retry_delay: Duration::from_secs(retry_secs),
+            max_retries: std::env::var("BUFFER_MAX_RETRIES").ok().and_then(|v| v.parse().ok()).unwrap_or(5),
+        }
+    }
+}
+
+struct Batch<T> {
+    // Waiting for the next turn
+    items: Vec<T>,
+    // When the wait for the next turn started, for max_wait
+    first_at: Instant,
+    // Taken by the current turn
+    in_flight: Vec<T>,
+    // The current turn has its result, so shutdown doesn't need to hand back in_flight
+    answered: bool,
+    // Turns in a row that ended in retry
+    failures: u32,
+    timer: Option<JoinHandle<()>>,
+    turns: Vec<JoinHandle<()>>,
+    processing: Arc<tokio::sync::Mutex<()>>,
+    arrived: Arc<Notify>,
+}
+
+impl<T> Default for Batch<T> {
+    fn default() -> Self {
+        Batch {
+            items: Vec::new(),
+            first_at: Instant::now(),
+            in_flight: Vec::new(),
+            answered: false,
+            failures: 0,
+            timer: None,
+            turns: Vec::new(),
+            processing: Arc::default(),
+            arrived: Arc::default(),
+        }
+    }
+}
+
+impl<T> Batch<T> {
+    fn is_waiting(&self) -> bool {
+        self.timer.as_ref().is_some_and(|timer| !timer.is_finished())
+    }
+
+    // Nothing waiting or in flight and nobody else after the key, as seen from the one turn still holding it
+    fn is_idle(&self) -> bool {
+        self.items.is_empty()
+            && self.in_flight.is_empty()
+            && !self.is_waiting()
+            && self.turns.iter().filter(|turn| !turn.is_finished()).count() <= 1
+            // The batch's own reference and the turn's guard
+            && Arc::strong_count(&self.processing) == 2
+    }
+}
+
+type Batches<K, T> = Arc<Mutex<HashMap<K, Batch<T>>>>;
+
+pub struct Debouncer<K, T> {
+    config: DebounceConfig,
+    size: fn(&T) -> usize,
+    batches: Batches<K, T>,
+}
+
+impl<K, T> Debouncer<K, T>
+where
+    K: Hash + Eq + Clone + Debug + Send + 'static,
+    T: Clone + Send + 'static,
+{
+    pub fn new(config: DebounceConfig, size: fn(&T) -> usize) -> Self {
+        Debouncer { config, size, batches: Arc::default() }
+    }
+
+    pub fn config(&self) -> &DebounceConfig {
+        &self.config
+    }
+
+    // Adds `item` and (re)schedules `flush` for when the batch is due. Returns the number of items waiting.
+    pub fn push<F>(&self, key: K, item: T, flush: F) -> usize
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut batches = self.batches.lock().unwrap();
+        let batch = batches.entry(key.clone()).or_default();
+        if !batch.is_waiting() {
+            batch.first_at = Instant::now();
+        }
+        batch.items.push(item);
+        batch.arrived.notify_waiters();
+
+        let size: usize = batch.items.iter().map(self.size).sum();
+        let max_wait_at = batch.first_at + self.config.max_wait;
+        let (due, cap) = if batch.items.len() >= self.config.max_items {
+            (Instant::now(), Some("message count"))
+        } else if size >= self.config.max_size {
+            (Instant::now(), Some("size"))
+        } else if max_wait_at <= Instant::now() + self.config.quiet_period {
+            (max_wait_at, Some("max wait"))
+        } else {
+            (Instant::now() + self.config.quiet_period, None)
+        };
+
+        let count = batch.items.len();
+        let log_key = key.clone();
+        let flush = async move {
+            if let Some(cap) = cap {
+                log::info!("Flushing batch for {:?} on its {} cap: {} items, size {}", log_key, cap, count, size);
+            }
+            flush.await;
+        };
+        self.schedule(key, batch, due, flush);
+        count
+    }
+
+    // Puts back items persisted at shutdown and flushes them right away
+    pub fn restore<F>(&self, key: K, items: Vec<T>, flush: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut batches = self.batches.lock().unwrap();
+        let batch = batches.entry(key.clone()).or_default();
+        batch.first_at = Instant::now();
+        batch.items.extend(items);
+        self.schedule(key, batch, Instant::now(), flush);
+    }
+
+    fn schedule<F>(&self, key: K, batch: &mut Batch<T>, due: Instant, flush: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        if let Some(previous) = batch.timer.take() {
+            previous.abort();
+        }
+        let batches = self.batches.clone();
+        batch.timer = Some(tokio::spawn(crate::logging::in_current_context(async move {
+            tokio::time::sleep_until(due).await;
+            // Left for take_unflushed, rather than starting work the grace period may not cover
+            if crate::shutdown::is_shutting_down() {
+                return;
+            }
+            // From here on the flush is out of reach of later items
+            if let Some(batch) = batches.lock().unwrap().get_mut(&key) {
+                batch.turns.retain(|turn| !turn.is_finished());
+                batch.turns.push(tokio::spawn(crate::logging::in_current_context(flush)));
+            }
+        })));
+    }
+
+    // Waits for the key's previous turn to end, then takes everything waiting, after whatever a failed
+    // turn left behind
+    pub async fn start_turn(&self, key: &K) -> Turn<K, T> {
+        let processing = self.batches.lock().unwrap().entry(key.clone()).or_default().processing.clone();
+        let processing = processing.lock_owned().await;
+
+        let mut batches = self.batches.lock().unwrap();
+        let batch = batches.entry(key.clone()).or_default();
+        let mut items = std::mem::take(&mut batch.in_flight);
+        items.append(&mut batch.items);
+        batch.in_flight = items.clone();
+        batch.answered = false;
+        Turn {
+            key: key.clone(),
+            items,
+            cancel_on_new: self.config.cancel_on_new,
+            arrived: batch.arrived.clone(),
+            batches: self.batches.clone(),
+            _processing: processing,
+        }
+    }
+
+    // Ends a failed turn and schedules `flush` to try its items again, after retry_delay doubled for each failure
+    // in a row. Returns when that is, or None once max_retries is used up and the items wait for the next push.
+    pub fn retry<F>(&self, turn: Turn<K, T>, flush: F) -> Option<Duration>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let key = turn.key.clone();
+        drop(turn);
+
+        let mut batches = self.batches.lock().unwrap();
+        let batch = batches.entry(key.clone()).or_default();
+        batch.failures += 1;
+        if batch.failures > self.config.max_retries {
+            return None;
+        }
+        let delay = self.config.retry_delay.saturating_mul(2u32.saturating_pow(batch.failures - 1));
+        // A flush is already on its way for newer items and takes these along
+        if !batch.is_waiting() {
+            self.schedule(key, batch, Instant::now() + delay, flush);
+        }
+        Some(delay)
+    }
+
+    // Whether a turn is still working towards its result. Waiting items and turns that already have theirs
+    // (see Turn::answered) don't count: take_unflushed hands the former back and the caller keeps the latter.
+    pub fn is_generating(&self) -> bool {
+        self.batches.lock().unwrap().values().any(|batch| !batch.answered && batch.turns.iter().any(|turn| !turn.is_finished()))
+    }
+
+    // Aborts every unfinished flush and hands back the items that still need one, for persisting at shutdown
+    pub fn take_unflushed(&self) -> Vec<(K, Vec<T>)> {
+        let mut batches = self.batches.lock().unwrap();
+        let mut unflushed = Vec::new();
+        for (key, batch) in batches.iter_mut() {
+            for flush in batch.timer.take().into_iter().chain(batch.turns.drain(..)) {
+                flush.abort();
+            }
+            let mut items = std::mem::take(&mut batch.in_flight);
+            if batch.answered {
+                items.clear();
+            }
+            items.append(&mut batch.items);
+            if !items.is_empty() {
+                unflushed.push((key.clone(), items));
+            }
+        }
+        unflushed
+    }
+
+    // (keys with items, total items), counting both waiting and in-flight items
+    pub fn stats(&self) -> (usize, usize) {
+        let batches = self.batches.lock().unwrap();
+        let count = |batch: &Batch<T>| batch.items.len() + batch.in_flight.len();
+        let keys = batches.values().filter(|batch| count(batch) > 0).count();
+        let items = batches.values().map(count).sum();
+        (keys, items)
+    }
+}
+
+// One flush's hold on a key, from start_turn until dropped
+pub struct Turn<K: Hash + Eq, T> {
+    key: K,
+    pub items: Vec<T>,
+    cancel_on_new: bool,
+    arrived: Arc<Notify>,
+    batches: Batches<K, T>,
+    _processing: tokio::sync::OwnedMutexGuard<()>,
+}
+
+impl<K: Hash + Eq, T> Turn<K, T> {
+    // The turn's result is ready; from here on its items count as handled at shutdown
+    pub fn answered(&self) {
+        if let Some(batch) = self.batches.lock().unwrap().get_mut(&self.key) {
+            batch.answered = true;
+        }
+    }
+
+    // Sleeps for `duration`, shutdown or not: an answered turn is persisted and aborted by the drain rather than
+    // sent early. With cancel_on_new, returns false instead as soon as an item is waiting for the next turn,
+    // including one that arrived before the wait.
+    pub async fn wait(&self, duration: Duration) -> bool {
+        if !self.cancel_on_new {
+            tokio::time::sleep(duration).await;
+            return true;
+        }
+        let arrived = self.arrived.notified();
+        tokio::pin!(arrived);
+        arrived.as_mut().enable();
+        if self.batches.lock().unwrap().get(&self.key).is_some_and(|batch| !batch.items.is_empty()) {
+            return false;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => true,
+            _ = arrived => false,
+        }
+    }
+
+    // Done with the items, whether or not the turn got to use them
+    pub fn finish(self) {
+        if let Some(batch) = self.batches.lock().unwrap().get_mut(&self.key) {
+            batch.in_flight.clear();
+            batch.answered = false;
+            batch.failures = 0;
+        }
+    }
+}
+
+impl<K: Hash + Eq, T> Drop for Turn<K, T> {
+    fn drop(&mut self) {
+        let mut batches = self.batches.lock().unwrap();
+        if batches.get(&self.key).is_some_and(|batch| batch.is_idle()) {
+            batches.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Flushed = Arc<Mutex<Vec<Vec<&'static str>>>>;
+
+    fn config() -> DebounceConfig {
+        DebounceConfig {
+            quiet_period: Duration::from_secs(15),
+            max_wait: Duration::from_secs(120),
+            max_items: 20,
+            max_size: 4000,
+            cancel_on_new: false,
+            retry_delay: Duration::from_secs(30),
+            max_retries: 2,
+        }
+    }
+
+    fn debouncer() -> Arc<Debouncer<u64, &'static str>> {
+        Arc::new(Debouncer::new(config(), |text: &&str| text.len()))
+    }
+
+    #[derive(Clone, Copy)]
+    enum Outcome {
+        Finish,
+        // Finishes after working on the items this long
+        Slow(Duration),
+        Fail,
+    }
+    use Outcome::*;
+
+    // Records the turn's items and ends it as `outcome` says
+    async fn flush(buffer: Arc<Debouncer<u64, &'static str>>, flushed: Flushed, outcome: Outcome) {
+        let turn = buffer.start_turn(&1).await;
+        if turn.items.is_empty() {
+            return;
+        }
+        flushed.lock().unwrap().push(turn.items.clone());
+        match outcome {
+            Finish => turn.finish(),
+            Slow(duration) => {
+                turn.wait(duration).await;
+                turn.finish();
+            }
+            Fail => {}
+        }
+    }
+
+    fn push(buffer: &Arc<Debouncer<u64, &'static str>>, flushed: &Flushed, item: &'static str, outcome: Outcome) {
+        buffer.push(1, item, flush(buffer.clone(), flushed.clone(), outcome));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn new_items_extend_the_quiet_period() {
+        let buffer = debouncer();
+        let flushed = Flushed::default();
+
+        push(&buffer, &flushed, "a", Finish);
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        push(&buffer, &flushed, "b", Finish);
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert!(flushed.lock().unwrap().is_empty());
+
+        tokio::time::sleep(Duration::from_secs(6)).await;
+        assert_eq!(*flushed.lock().unwrap(), vec![vec!["a", "b"]]);
+        // Nothing left for the key, so its entry is gone
+        assert!(buffer.batches.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn max_wait_caps_a_batch_that_keeps_growing() {
+        let buffer = debouncer();
+        let flushed = Flushed::default();
+
+        for item in ["0", "1", "2", "3", "4", "5", "6", "7", "8", "9", "10", "11"] {
+            push(&buffer, &flushed, item, Finish);
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        }
+        // 110 s after the first item plus the last one's quiet period would be 125 s
+        assert!(flushed.lock().unwrap().is_empty());
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(flushed.lock().unwrap().len(), 1);
+        assert_eq!(flushed.lock().unwrap()[0].len(), 12);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn items_during_a_turn_wait_for_the_next_one() {
+        let buffer = debouncer();
+        let flushed = Flushed::default();
+
+        push(&buffer, &flushed, "a", Slow(Duration::from_secs(60)));
+        tokio::time::sleep(Duration::from_secs(16)).await;
+        assert_eq!(*flushed.lock().unwrap(), vec![vec!["a"]]);
+
+        // "a" is in flight; the flush for "b" waits for its turn to end
+        push(&buffer, &flushed, "b", Finish);
+        tokio::time::sleep(Duration::from_secs(16)).await;
+        assert_eq!(*flushed.lock().unwrap(), vec![vec!["a"]]);
+        assert_eq!(buffer.stats(), (1, 2));
+
+        tokio::time::sleep(Duration::from_secs(45)).await;
+        assert_eq!(*flushed.lock().unwrap(), vec![vec!["a"], vec!["b"]]);
+        assert!(buffer.batches.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_failed_turn_hands_its_items_to_the_next() {
+        let buffer = debouncer();
+        let flushed = Flushed::default();
+
+        push(&buffer, &flushed, "a", Fail);
+        tokio::time::sleep(Duration::from_secs(16)).await;
+        assert_eq!(buffer.stats(), (1, 1));
+
+        push(&buffer, &flushed, "b", Finish);
+        tokio::time::sleep(Duration::from_secs(16)).await;
+        assert_eq!(*flushed.lock().unwrap(), vec![vec!["a"], vec!["a", "b"]]);
+        assert_eq!(buffer.stats(), (0, 0));
+        assert!(buffer.batches.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_failed_turn_is_retried_with_backoff() {
+        let buffer = debouncer();
+        let flushed = Flushed::default();
+        let failures = Arc::new(std::sync::atomic::AtomicU32::new(0));
+
+        // Fails and retries itself until there are no failures left to take
+        fn flaky(buffer: Arc<Debouncer<u64, &'static str>>, flushed: Flushed, failures: Arc<std::sync::atomic::AtomicU32>) -> std::pin::Pin<Box<dyn Future<Output = ()> + Send>> {
+            Box::pin(async move {
+                let turn = buffer.start_turn(&1).await;
+                flushed.lock().unwrap().push(turn.items.clone());
+                if failures.fetch_update(std::sync::atomic::Ordering::SeqCst, std::sync::atomic::Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() {
+                    let retry = flaky(buffer.clone(), flushed, failures);
+                    buffer.retry(turn, retry);
+                } else {
+                    turn.finish();
+                }
+            })
+        }
+
+        failures.store(2, std::sync::atomic::Ordering::SeqCst);
+        buffer.push(1, "a", flaky(buffer.clone(), flushed.clone(), failures.clone()));
+        // Fails at 15 s, again 30 s later, then 60 s after that it goes through
+        tokio::time::sleep(Duration::from_secs(44)).await;
+        assert_eq!(flushed.lock().unwrap().len(), 1);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(flushed.lock().unwrap().len(), 2);
+        tokio::time::sleep(Duration::from_secs(58)).await;
+        assert_eq!(flushed.lock().unwrap().len(), 2);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(*flushed.lock().unwrap(), vec![vec!["a"], vec!["a"], vec!["a"]]);
+        assert!(buffer.batches.lock().unwrap().is_empty());
+
+        // Past max_retries the items wait for the next push
+        failures.store(10, std::sync::atomic::Ordering::SeqCst);
+        flushed.lock().unwrap().clear();
+        buffer.push(1, "b", flaky(buffer.clone(), flushed.clone(), failures.clone()));
+        tokio::time::sleep(Duration::from_secs(600)).await;
+        assert_eq!(flushed.lock().unwrap().len(), 3);
+        assert_eq!(buffer.stats(), (1, 1));
+    }
+}
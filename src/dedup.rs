@@ -0,0 +1,65 @@
+// src/dedup.rs
+
+use tokio::time::{sleep, Duration};
+
+const DEFAULT_TTL_HOURS: i64 = 48;
+const CLEANUP_INTERVAL_SECS: u64 = 60 * 60;
+// How long a claim whose handler never finished (the process died) keeps redeliveries of its keys out
+const CLAIM_LEASE_SECS: i64 = 10 * 60;
+
+// Telegram stops retrying a webhook long before this, so anything older can be forgotten
+pub fn ttl_hours() -> i64 {
+    std::env::var("DEDUP_TTL_HOURS").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_TTL_HOURS)
+}
+
+pub fn update_key(update_id: i32) -> String {
+    format!("update:{}", update_id)
+}
+
+// The same message can come back in a different update, e.g. after the bot's offset was reset
+pub fn message_key(chat_id: i64, message_id: i64) -> String {
+    format!("message:{}:{}", chat_id, message_id)
+}
+
+// Claims the keys for the caller to handle; false if any of them is already handled or being handled. Fails open:
+// if the database is down we'd rather answer twice than never.
+pub async fn claim(pool: &crate::database::Pool, keys: &[String]) -> bool {
+    match crate::database::claim_processed_updates(pool, keys, CLAIM_LEASE_SECS).await {
+        Ok(true) => true,
+        Ok(false) => {
+            log::info!("dedup: skipping already processed {}", keys.join(", "));
+            false
+        }
+        Err(e) => {
+            log::error!("dedup: could not claim {}, processing anyway: {:?}", keys.join(", "), e);
+            true
+        }
+    }
+}
+
+// Called once the claimed keys were handled successfully; redeliveries are skipped until DEDUP_TTL_HOURS
+pub async fn mark_processed(pool: &crate::database::Pool, keys: &[String]) {
+    if let Err(e) = crate::database::complete_processed_updates(pool, keys).await {
+        log::error!("dedup: could not record {} as processed: {:?}", keys.join(", "), e);
+    }
+}
+
+// Called when handling failed, so a redelivery gets another go rather than waiting out the lease
+pub async fn release(pool: &crate::database::Pool, keys: &[String]) {
+    if let Err(e) = crate::database::release_processed_updates(pool, keys).await {
+        log::error!("dedup: could not release {}: {:?}", keys.join(", "), e);
+    }
+}
+
+pub fn spawn_cleanup(pool: crate::database::Pool) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            match crate::database::delete_expired_processed_updates(&pool, ttl_hours()).await {
+                Ok(deleted) if deleted > 0 => log::info!("dedup: pruned {} expired processed updates", deleted),
+                Ok(_) => {}
+                Err(e) => log::error!("dedup: failed to prune processed updates: {:?}", e),
+            }
+            sleep(Duration::from_secs(CLEANUP_INTERVAL_SECS)).await;
+        }
+    })
+}
@@ -0,0 +1,319 @@
+// src/events.rs
+
+use serde::{Deserialize, Serialize};
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+use tokio::time::Duration;
+
+pub const MESSAGE_RECEIVED: &str = "message.received";
+pub const MESSAGE_SENT: &str = "message.sent";
+pub const LEAD_SCORED: &str = "lead.scored";
+pub const HANDOFF_REQUESTED: &str = "handoff.requested";
+pub const ANALYSIS_COMPLETED: &str = "analysis.completed";
+
+pub const EVENT_TYPES: &[&str] = &[MESSAGE_RECEIVED, MESSAGE_SENT, LEAD_SCORED, HANDOFF_REQUESTED, ANALYSIS_COMPLETED];
+
+const MAX_DELIVERY_ATTEMPTS: i32 = 5;
+const DELIVERY_TIMEOUT_SECS: u64 = 10;
+// Retries are scheduled in event_deliveries and picked up by spawn_retries
+const RETRY_POLL_SECS: u64 = 1;
+const RETRIES_PER_POLL: i64 = 50;
+// A claimed retry that was never logged comes due again after this
+const RETRY_LEASE_SECS: i64 = 60;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Event {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub data: serde_json::Value,
+}
+
+impl Event {
+    pub fn new(event_type: &str, data: serde_json::Value) -> Self {
+        Event {
+            id: uuid::Uuid::new_v4().to_string(),
+            event_type: event_type.to_string(),
+            created_at: chrono::Utc::now(),
+            data,
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    // Shared by all deliveries so connections to subscriber endpoints are reused. No redirects: one could point
+    // anywhere, including at an address check_target refuses.
+    static ref CLIENT: reqwest::Client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(DELIVERY_TIMEOUT_SECS))
+        .redirect(reqwest::redirect::Policy::none())
+        .dns_resolver(std::sync::Arc::new(PublicOnlyResolver))
+        .build()
+        .expect("Failed to build the event delivery client");
+    // Every emitted event, for in-process listeners such as the simulate command
+    static ref LOCAL_EVENTS: tokio::sync::broadcast::Sender<Event> = tokio::sync::broadcast::channel(256).0;
+}
+
+pub fn subscribe_local() -> tokio::sync::broadcast::Receiver<Event> {
+    LOCAL_EVENTS.subscribe()
+}
+
+static LOCAL_ONLY: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+// Stops emit from delivering to webhook subscribers, so a simulated conversation never reaches their endpoints
+pub fn set_local_only() {
+    LOCAL_ONLY.store(true, std::sync::atomic::Ordering::SeqCst);
+}
+
+// Interest level at or above which a scored lead is announced to subscribers
+pub fn lead_score_threshold() -> i32 {
+    std::env::var("LEAD_SCORE_THRESHOLD").ok().and_then(|v| v.parse().ok()).unwrap_or(7)
+}
+
+// Receivers verify with HMAC-SHA256(secret, "{timestamp}.{body}") and compare against X-Webhook-Signature
+pub fn sign_payload(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+// Hosts from the comma-separated EVENT_WEBHOOK_ALLOWED_HOSTS, which subscriptions may use whatever they resolve to
+fn is_allowed_host(host: &str) -> bool {
+    std::env::var("EVENT_WEBHOOK_ALLOWED_HOSTS")
+        .unwrap_or_default()
+        .split(',')
+        .any(|allowed| !allowed.trim().is_empty() && allowed.trim().eq_ignore_ascii_case(host))
+}
+
+// Whether an address is on the public internet: not loopback, private (RFC 1918, fc00::/7), link-local (including
+// the 169.254.169.254 metadata service), shared (100.64.0.0/10), unspecified, broadcast, documentation or multicast
+pub fn is_public_ip(ip: std::net::IpAddr) -> bool {
+    match ip {
+        std::net::IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(a == 0
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        std::net::IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            // IPv4-mapped and NAT64 addresses reach the embedded IPv4 one
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return is_public_ip(v4.into());
+            }
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let [.., high, low] = segments;
+                return is_public_ip(std::net::Ipv4Addr::from((u32::from(high) << 16) | u32::from(low)).into());
+            }
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || (segments[0] & 0xfe00) == 0xfc00
+                || (segments[0] & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+// Where a subscription may send: an http(s) URL whose host is allowlisted or only has public addresses. Checked when
+// the subscription is created and before each delivery; PublicOnlyResolver checks again on connect.
+pub async fn check_target(url: &str) -> Result<(), String> {
+    let url = reqwest::Url::parse(url).map_err(|e| format!("url is invalid: {}", e))?;
+    if url.scheme() != "https" && url.scheme() != "http" {
+        return Err("url must be an http(s) URL".to_string());
+    }
+    let host = url.host_str().ok_or_else(|| "url has no host".to_string())?;
+    if is_allowed_host(host) {
+        return Ok(());
+    }
+    let addresses: Vec<std::net::IpAddr> = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) => vec![ip],
+        Err(_) => tokio::net::lookup_host((host, url.port_or_known_default().unwrap_or(443)))
+            .await
+            .map_err(|e| format!("{} does not resolve: {}", host, e))?
+            .map(|address| address.ip())
+            .collect(),
+    };
+    match addresses.iter().find(|ip| !is_public_ip(**ip)) {
+        _ if addresses.is_empty() => Err(format!("{} does not resolve", host)),
+        Some(ip) => Err(format!("{} is not a public address; add the host to EVENT_WEBHOOK_ALLOWED_HOSTS to allow it", ip)),
+        None => Ok(()),
+    }
+}
+
+// Leaves out non-public addresses on every lookup, so a host can't be repointed after passing check_target
+struct PublicOnlyResolver;
+
+impl reqwest::dns::Resolve for PublicOnlyResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let host = name.as_str();
+            let allowed = is_allowed_host(host);
+            let addresses: Vec<std::net::SocketAddr> = tokio::net::lookup_host((host, 0))
+                .await?
+                .filter(|address| allowed || is_public_ip(address.ip()))
+                .collect();
+            if addresses.is_empty() {
+                return Err(format!("{} has no public address", host).into());
+            }
+            Ok(Box::new(addresses.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+// Fire-and-forget: callers in the message pipeline never wait on subscriber endpoints
+pub fn emit(pool: &crate::database::Pool, event: Event) {
+    // Only fails when nobody is listening
+    let _ = LOCAL_EVENTS.send(event.clone());
+    if LOCAL_ONLY.load(std::sync::atomic::Ordering::SeqCst) {
+        return;
+    }
+    let pool = pool.clone();
+    tokio::spawn(crate::logging::in_current_context(async move {
+        if let Err(e) = dispatch(&pool, event).await {
+            log::error!("events: failed to dispatch event: {:?}", e);
+        }
+    }));
+}
+
+pub async fn dispatch(pool: &crate::database::Pool, event: Event) -> Result<(), anyhow::Error> {
+    let subscriptions = crate::database::get_active_subscriptions_for_event(pool, &event.event_type).await?;
+    if subscriptions.is_empty() {
+        return Ok(());
+    }
+    log::info!("events: dispatching {} {} to {} subscription(s)", event.event_type, event.id, subscriptions.len());
+
+    let body = serde_json::to_string(&event)?;
+    for subscription in subscriptions {
+        let pool = pool.clone();
+        let body = body.clone();
+        let event = event.clone();
+        tokio::spawn(crate::logging::in_current_context(async move {
+            deliver(&pool, &subscription, &event, &body, 1, None).await;
+        }));
+    }
+
+    Ok(())
+}
+
+// Sends failed deliveries again when their retry is due. Runs until shutdown; started once per process.
+pub fn spawn_retries(pool: crate::database::Pool) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        while !crate::shutdown::is_shutting_down() {
+            match crate::database::claim_due_event_retries(&pool, RETRY_LEASE_SECS, RETRIES_PER_POLL).await {
+                Ok(retries) => {
+                    for retry in retries {
+                        let pool = pool.clone();
+                        tokio::spawn(async move { retry_delivery(&pool, retry).await });
+                    }
+                }
+                Err(e) => log::error!("events: failed to load due retries: {:?}", e),
+            }
+            crate::shutdown::sleep_or_shutdown(Duration::from_secs(RETRY_POLL_SECS)).await;
+        }
+    })
+}
+
+async fn retry_delivery(pool: &crate::database::Pool, retry: crate::DBEventRetry) {
+    let event = match serde_json::from_value::<Event>(retry.payload) {
+        Ok(event) if retry.subscription.active => event,
+        result => {
+            if let Err(e) = result {
+                log::error!("events: dropping retry of delivery {}, unreadable payload: {:?}", retry.delivery_id, e);
+            } else {
+                log::info!("events: dropping retry of delivery {}, subscription {} is inactive", retry.delivery_id, retry.subscription.id);
+            }
+            if let Err(e) = crate::database::cancel_event_retry(pool, retry.delivery_id).await {
+                log::error!("events: failed to cancel retry of delivery {}: {:?}", retry.delivery_id, e);
+            }
+            return;
+        }
+    };
+    match serde_json::to_string(&event) {
+        Ok(body) => deliver(pool, &retry.subscription, &event, &body, retry.attempt + 1, Some(retry.delivery_id)).await,
+        Err(e) => log::error!("events: failed to serialize event {}: {:?}", event.id, e),
+    }
+}
+
+// One attempt, logged to event_deliveries. A failure schedules the next attempt with exponential backoff
+// (2s, 4s, 8s, ...) until MAX_DELIVERY_ATTEMPTS.
+async fn deliver(pool: &crate::database::Pool, subscription: &crate::DBEventSubscription, event: &Event, body: &str, attempt: i32, retry_of: Option<i64>) {
+    // A target that isn't allowed won't become allowed by retrying
+    let (status_code, error, retry) = match check_target(&subscription.url).await {
+        Ok(()) => {
+            let timestamp = chrono::Utc::now().timestamp();
+            let result = CLIENT.post(&subscription.url)
+                .header("Content-Type", "application/json")
+                .header("X-Webhook-Id", &event.id)
+                .header("X-Webhook-Event", &event.event_type)
+                .header("X-Webhook-Timestamp", timestamp.to_string())
+                .header("X-Webhook-Signature", sign_payload(&subscription.secret, timestamp, body))
+                .body(body.to_string())
+                .send()
+                .await;
+            match result {
+                Ok(response) if response.status().is_success() => (Some(response.status().as_u16() as i32), None, false),
+                Ok(response) => (Some(response.status().as_u16() as i32), Some(format!("Received non-2xx status code ({})", response.status())), true),
+                Err(e) => (None, Some(e.to_string()), true),
+            }
+        }
+        Err(e) => (None, Some(format!("Target not allowed: {}", e)), false),
+    };
+
+    let next_attempt_at = match &error {
+        Some(_) if retry && attempt < MAX_DELIVERY_ATTEMPTS => Some(chrono::Utc::now() + chrono::Duration::seconds(2i64.pow(attempt as u32))),
+        _ => None,
+    };
+    if let Err(e) = crate::database::insert_event_delivery(
+        pool,
+        subscription.id,
+        event,
+        attempt,
+        status_code,
+        error.as_deref(),
+        next_attempt_at,
+        retry_of,
+    ).await {
+        log::error!("events: failed to log delivery for subscription {}: {:?}", subscription.id, e);
+    }
+
+    match error {
+        None => log::info!("events: delivered {} {} to subscription {} on attempt {}", event.event_type, event.id, subscription.id, attempt),
+        Some(error) if next_attempt_at.is_some() => {
+            log::warn!("events: delivery of {} to subscription {} failed on attempt {}, retrying: {}", event.id, subscription.id, attempt, error);
+        }
+        Some(error) => {
+            log::error!("events: giving up on {} {} for subscription {} after {} attempts: {}", event.event_type, event.id, subscription.id, attempt, error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_public_addresses_are_public() {
+        for ip in ["8.8.8.8", "1.1.1.1", "2606:4700:4700::1111", "::ffff:8.8.8.8", "64:ff9b::808:808"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0",
+            "255.255.255.255", "224.0.0.1", "::1", "::", "fd00::1", "fe80::1", "::ffff:127.0.0.1", "64:ff9b::a00:1",
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn targets_must_be_public_http_urls() {
+        assert!(check_target("https://8.8.8.8/hook").await.is_ok());
+        for url in ["ftp://8.8.8.8/", "not a url", "http://127.0.0.1:8080/", "http://[::1]/", "http://169.254.169.254/latest", "http://localhost/"] {
+            assert!(check_target(url).await.is_err(), "{}", url);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+// src/follow_ups.rs
+
+// Re-engages users who stopped replying. Every FOLLOW_UP_POLL_SECS this looks for conversations with the Convo AI
+// whose user has been silent for FOLLOW_UP_AFTER_HOURS, asks the assistant for a follow-up on the same thread, and
+// sends it if the user's local time is within FOLLOW_UP_HOURS and outside quiet hours. A user gets at most
+// FOLLOW_UP_MAX_PER_USER of them, each another FOLLOW_UP_AFTER_HOURS after the last; their next message ends the
+// campaign and starts the count over. Only users whose contact_state is active get any (see crate::CONTACT_STATES).
+
+use chrono::Timelike;
+use tokio::time::Duration;
+
+// Follow-ups generated per poll at most; more dormant users wait for the next one
+const FOLLOW_UPS_PER_POLL: usize = 20;
+// Dormant threads looked at per poll, most of which may be outside their allowed hours
+const CANDIDATES_PER_POLL: i64 = 500;
+
+#[derive(Debug, Clone)]
+pub struct FollowUpConfig {
+    // Silence before the first follow-up, and between follow-ups
+    pub after: chrono::Duration,
+    // 0 turns follow-ups off
+    pub max_per_user: i64,
+    // Local start and end hour follow-ups may go out in; None for any hour outside quiet hours
+    pub allowed_hours: Option<(u32, u32)>,
+    pub poll_interval: Duration,
+    // Sent to the assistant on the user's thread; {hours} is replaced with the hours of silence
+    pub prompt: String,
+}
+
+impl FollowUpConfig {
+    // FOLLOW_UP_MAX_PER_USER (default 0, off), FOLLOW_UP_AFTER_HOURS (default 24), FOLLOW_UP_HOURS (default 10-20,
+    // or off), FOLLOW_UP_POLL_SECS (default 300) and FOLLOW_UP_PROMPT
+    pub fn from_env() -> Self {
+        let after_hours = std::env::var("FOLLOW_UP_AFTER_HOURS").ok().and_then(|v| v.parse().ok()).unwrap_or(24);
+        let poll_secs = std::env::var("FOLLOW_UP_POLL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(300);
+        let spec = std::env::var("FOLLOW_UP_HOURS").unwrap_or_else(|_| "10-20".to_string());
+        let allowed_hours = match crate::quiet_hours::parse_window(&spec) {
+            Some(window) => window,
+            None => {
+                log::warn!("Invalid FOLLOW_UP_HOURS {:?}, expected e.g. 10-20 or off; using 10-20", spec);
+                Some((10, 20))
+            }
+        };
+        FollowUpConfig {
+            after: chrono::Duration::hours(after_hours),
+            max_per_user: std::env::var("FOLLOW_UP_MAX_PER_USER").ok().and_then(|v| v.parse().ok()).unwrap_or(0),
+            allowed_hours,
+            poll_interval: Duration::from_secs(poll_secs),
+            prompt: std::env::var("FOLLOW_UP_PROMPT").unwrap_or_else(|_| {
+                "The user has not replied for {hours} hours. Write a short, friendly follow-up message that picks up \
+                 where the conversation left off, in the language of the conversation. Reply with the message only."
+                    .to_string()
+            }),
+        }
+    }
+
+    // Whether a follow-up may go out to a user in `timezone` at `at`
+    pub fn is_allowed(&self, at: chrono::DateTime<chrono::Utc>, timezone: chrono_tz::Tz) -> bool {
+        let quiet_hours = &crate::quiet_hours::QUIET_HOURS;
+        let hour = at.with_timezone(&timezone).hour();
+        !quiet_hours.is_quiet(at, timezone) && self.allowed_hours.is_none_or(|window| crate::quiet_hours::in_window(hour, window))
+    }
+}
+
+// Runs until shutdown; started once per process next to send_due_replies
+pub async fn run(pool: crate::database::Pool, bot: teloxide::Bot, openai_key: String, assistant_id: String) {
+    let config = FollowUpConfig::from_env();
+    if config.max_per_user <= 0 {
+        log::info!("follow_ups: off, FOLLOW_UP_MAX_PER_USER is 0");
+        return;
+    }
+    log::info!(
+        "follow_ups: up to {} per user after {} hours of silence, allowed hours {:?}",
+        config.max_per_user,
+        config.after.num_hours(),
+        config.allowed_hours,
+    );
+
+    while !crate::shutdown::is_shutting_down() {
+        let before = chrono::Utc::now() - config.after;
+        let threads = crate::database::get_dormant_threads(
+            &pool,
+            &assistant_id,
+            before,
+            config.max_per_user,
+            CANDIDATES_PER_POLL,
+        ).await;
+        match threads {
+            Ok(threads) => {
+                let mut generated = 0;
+                for thread in threads {
+                    if generated >= FOLLOW_UPS_PER_POLL || crate::shutdown::is_shutting_down() {
+                        break;
+                    }
+                    let timezone = crate::quiet_hours::QUIET_HOURS.timezone(thread.timezone.as_deref());
+                    if !config.is_allowed(chrono::Utc::now(), timezone) {
+                        continue;
+                    }
+                    generated += 1;
+                    // Private chats share the user's id
+                    let log_context = crate::logging::LogContext::for_chat(Some(thread.user_id), thread.user_id);
+                    crate::logging::with_context(log_context, send_follow_up(&pool, &bot, &openai_key, &config, &thread)).await;
+                }
+            }
+            Err(e) => log::error!("follow_ups: failed to find dormant threads: {:?}", e),
+        }
+        crate::shutdown::sleep_or_shutdown(config.poll_interval).await;
+    }
+}
+
+async fn send_follow_up(pool: &crate::database::Pool, bot: &teloxide::Bot, openai_key: &str, config: &FollowUpConfig, thread: &crate::DBDormantThread) {
+    let hours_silent = (chrono::Utc::now() - thread.last_inbound_at).num_hours();
+    log::info!(
+        "follow_ups: user_id {} silent for {} hours, {} follow-up(s) sent so far",
+        thread.user_id,
+        hours_silent,
+        thread.follow_ups_sent,
+    );
+
+    let prompt = format!("\n\nFollow-up request:\n{}", config.prompt.replace("{hours}", &hours_silent.to_string()));
+    let text = match crate::second_message_and_so_on(openai_key, &thread.thread_id, &prompt, &thread.assistant_id).await {
+        Ok(text) if text != crate::ASSISTANT_MESSAGE_FAILED => text,
+        Ok(_) => String::new(),
+        Err(e) => {
+            log::error!("follow_ups: failed to generate a follow-up for user_id {}: {:?}", thread.user_id, e);
+            record(pool, thread, "", "failed", Some(&e.to_string())).await;
+            return;
+        }
+    };
+    let chunks = crate::telegram::PACING.split(&text);
+    if chunks.is_empty() {
+        log::error!("follow_ups: assistant returned no follow-up for user_id {}", thread.user_id);
+        record(pool, thread, "", "failed", Some("no message from the assistant")).await;
+        return;
+    }
+
+    // The user may have written, or sent /stop, while the assistant was at it
+    if !crate::telegram::may_contact(pool, thread.user_id).await {
+        log::info!("follow_ups: user_id {} can no longer be contacted, dropping the follow-up", thread.user_id);
+        return;
+    }
+    match crate::database::get_last_inbound_at(pool, &thread.thread_id).await {
+        Ok(Some(last_inbound_at)) if last_inbound_at > thread.last_inbound_at => {
+            log::info!("follow_ups: user_id {} wrote in the meantime, dropping the follow-up", thread.user_id);
+            return;
+        }
+        Ok(_) => {}
+        Err(e) => log::warn!("follow_ups: failed to re-check last inbound message of user_id {}: {:?}", thread.user_id, e),
+    }
+
+    let chat_id = teloxide::types::ChatId(thread.user_id);
+    let mut sent = Ok(());
+    for chunk in chunks {
+        crate::telegram::show_typing(bot, chat_id, None, crate::telegram::PACING.typing_delay(&chunk)).await;
+        sent = teloxide::prelude::Requester::send_message(bot, chat_id, chunk).await.map(|_| ());
+        if sent.is_err() {
+            break;
+        }
+    }
+
+    match sent {
+        Ok(()) => {
+            log::info!("follow_ups: sent follow-up {} to user_id {}", thread.follow_ups_sent + 1, thread.user_id);
+            if let Err(e) = crate::database::insert_message(pool.clone(), &thread.thread_id, "assistant", &text, "follow_up", &thread.assistant_id).await {
+                log::error!("follow_ups: failed to log follow-up: {:?}", e);
+            }
+            crate::events::emit(pool, crate::events::Event::new(crate::events::MESSAGE_SENT, serde_json::json!({
+                "user_id": thread.user_id,
+                "chat_id": thread.user_id,
+                "thread_id": thread.thread_id,
+                "assistant_id": thread.assistant_id,
+                "text": text,
+                "follow_up": true,
+            })));
+            record(pool, thread, &text, "sent", None).await;
+        }
+        Err(e) if crate::telegram::is_blocked_error(&e) => {
+            log::info!("follow_ups: user_id {} blocked the bot, no more follow-ups", thread.user_id);
+            crate::telegram::note_send_error(pool, thread.user_id, &e).await;
+            record(pool, thread, &text, "blocked", None).await;
+        }
+        Err(e) => {
+            log::error!("follow_ups: failed to send follow-up to user_id {}: {:?}", thread.user_id, e);
+            record(pool, thread, &text, "failed", Some(&e.to_string())).await;
+        }
+    }
+}
+
+async fn record(pool: &crate::database::Pool, thread: &crate::DBDormantThread, text: &str, status: &str, error: Option<&str>) {
+    crate::metrics::FOLLOW_UPS.with_label_values(&[status]).inc();
+    if let Err(e) = crate::database::insert_follow_up(pool, thread, text, status, error).await {
+        log::error!("follow_ups: failed to record {} follow-up for user_id {}: {:?}", status, thread.user_id, e);
+    }
+}
@@ -0,0 +1,146 @@
+// src/health.rs
+
+use serde::Serialize;
+use std::sync::atomic::{AtomicU8, Ordering};
+use tokio::sync::Mutex;
+use tokio::time::{timeout, Duration, Instant};
+#[cfg(feature = "webhook-server")]
+use warp::{Filter, Reply};
+
+const CHECK_TIMEOUT_SECS: u64 = 5;
+const OPENAI_PROBE_CACHE_SECS: u64 = 30;
+
+// Telegram long polling state, reported by run_telegram_bot
+const TELEGRAM_DISABLED: u8 = 0;
+const TELEGRAM_ACTIVE: u8 = 1;
+const TELEGRAM_STOPPED: u8 = 2;
+
+static TELEGRAM_STATE: AtomicU8 = AtomicU8::new(TELEGRAM_DISABLED);
+
+lazy_static::lazy_static! {
+    // Last OpenAI probe, so scrapers hitting /readyz every few seconds don't turn into API traffic
+    static ref OPENAI_PROBE: Mutex<Option<(Instant, CheckResult)>> = Mutex::new(None);
+}
+
+pub fn set_telegram_polling(active: bool) {
+    TELEGRAM_STATE.store(if active { TELEGRAM_ACTIVE } else { TELEGRAM_STOPPED }, Ordering::SeqCst);
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct CheckResult {
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl CheckResult {
+    fn ok(detail: impl Into<String>) -> Self {
+        CheckResult { ok: true, detail: detail.into() }
+    }
+
+    fn failed(detail: impl Into<String>) -> Self {
+        CheckResult { ok: false, detail: detail.into() }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub database: CheckResult,
+    pub telegram: CheckResult,
+    pub openai: CheckResult,
+}
+
+async fn check_database(pool: &crate::database::Pool) -> CheckResult {
+    match timeout(Duration::from_secs(CHECK_TIMEOUT_SECS), crate::database::ping(pool)).await {
+        Ok(Ok(())) => CheckResult::ok("reachable"),
+        Ok(Err(e)) => CheckResult::failed(format!("{}", e)),
+        Err(_) => CheckResult::failed("timed out"),
+    }
+}
+
+fn check_telegram() -> CheckResult {
+    match TELEGRAM_STATE.load(Ordering::SeqCst) {
+        TELEGRAM_ACTIVE => CheckResult::ok("polling"),
+        TELEGRAM_STOPPED => CheckResult::failed("polling stopped"),
+        // Started with --webhooks-only, or built without the telegram feature
+        _ => CheckResult::ok("not running in this process"),
+    }
+}
+
+async fn check_openai() -> CheckResult {
+    let mut cached = OPENAI_PROBE.lock().await;
+    if let Some((checked_at, result)) = cached.as_ref() {
+        if checked_at.elapsed() < Duration::from_secs(OPENAI_PROBE_CACHE_SECS) {
+            return result.clone();
+        }
+    }
+
+    let result = match std::env::var("OPENAI_KEY") {
+        Err(_) => CheckResult::failed("OPENAI_KEY not set"),
+        Ok(openai_key) => {
+            let client = reqwest::Client::builder()
+                .timeout(Duration::from_secs(CHECK_TIMEOUT_SECS))
+                .build()
+                .unwrap_or_default();
+            match client.get(crate::openai_url("/models"))
+                .header("Authorization", format!("Bearer {}", openai_key))
+                .send()
+                .await
+            {
+                Ok(response) if response.status().is_success() => CheckResult::ok("reachable"),
+                Ok(response) => CheckResult::failed(format!("Received non-2xx status code ({})", response.status())),
+                Err(e) => CheckResult::failed(e.to_string()),
+            }
+        }
+    };
+
+    *cached = Some((Instant::now(), result.clone()));
+    result
+}
+
+pub async fn readiness(pool: &crate::database::Pool) -> Readiness {
+    let (database, openai) = tokio::join!(check_database(pool), check_openai());
+    let telegram = check_telegram();
+    Readiness {
+        ready: database.ok && telegram.ok && openai.ok,
+        database,
+        telegram,
+        openai,
+    }
+}
+
+// GET /healthz (process is up), GET /readyz (dependencies are usable), GET /metrics (Prometheus)
+#[cfg(feature = "webhook-server")]
+pub fn routes(pool: crate::database::Pool) -> warp::filters::BoxedFilter<(warp::reply::Response,)> {
+    let healthz = warp::path!("healthz")
+        .and(warp::get())
+        .map(|| warp::reply::json(&serde_json::json!({ "status": "ok" })).into_response());
+
+    let readyz = warp::path!("readyz")
+        .and(warp::get())
+        .and(warp::any().map(move || pool.clone()))
+        .then(|pool: crate::database::Pool| async move {
+            let readiness = readiness(&pool).await;
+            if !readiness.ready {
+                log::warn!("health: not ready: {:?}", readiness);
+            }
+            let status = if readiness.ready {
+                warp::http::StatusCode::OK
+            } else {
+                warp::http::StatusCode::SERVICE_UNAVAILABLE
+            };
+            warp::reply::with_status(warp::reply::json(&readiness), status).into_response()
+        });
+
+    let metrics = warp::path!("metrics")
+        .and(warp::get())
+        .map(|| match crate::metrics::render() {
+            Ok(body) => warp::reply::with_header(body, "content-type", "text/plain; version=0.0.4").into_response(),
+            Err(e) => {
+                log::error!("health: failed to render metrics: {:?}", e);
+                crate::admin_api::error_reply(warp::http::StatusCode::INTERNAL_SERVER_ERROR, "internal_error", "Internal server error").into_response()
+            }
+        });
+
+    healthz.or(readyz).unify().or(metrics).unify().boxed()
+}
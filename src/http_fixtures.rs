@@ -0,0 +1,316 @@
+// src/http_fixtures.rs
+
+// Record-and-replay for the OpenAI and Telegram traffic of lib.rs, telegram.rs and teloxide. A local proxy, one
+// port per API, takes the place of OPENAI_BASE_URL and TELEGRAM_API_URL (see openai_url and telegram_api_url):
+//
+// - record: forwards every call to the real APIs and appends the request/response pair to a JSON lines file.
+//   Authorization headers are never written; bot tokens and keys in paths and bodies are masked by
+//   redact::secrets. getUpdates polls that returned nothing are skipped.
+// - replay: answers from such a file without any network. Calls are matched on method and path, each in the
+//   order they were recorded, and getUpdates only hands out an update once as much time has passed since start
+//   as had when it was recorded, so buffering sees the same gaps between messages.
+//
+// In serve, set HTTP_FIXTURES_RECORD=<file> or HTTP_FIXTURES_REPLAY=<file>. From a test:
+//
+//     let fixtures = HttpFixtures::replay("fixtures/price_question.jsonl").await?;
+//     fixtures.install();
+//     ... run the bot ...
+//     assert!(fixtures.unplayed().is_empty());
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+use warp::Filter;
+
+// How long a replayed getUpdates holds when nothing is due, same as the long poll it stands in for
+const REPLAY_POLL_SECS: u64 = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Exchange {
+    // Since the proxy started
+    pub at_ms: u64,
+    // "openai" or "telegram"
+    pub api: String,
+    pub method: String,
+    // Secrets masked, so /bot[redacted]/sendMessage
+    pub path: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub query: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_content_type: Option<String>,
+    // For reading only, replay doesn't look at it
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub request_body: String,
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub body: String,
+    // Responses that aren't UTF-8 (file downloads) are stored hex encoded instead
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_hex: Option<String>,
+}
+
+impl Exchange {
+    fn key(&self) -> String {
+        format!("{} {} {}", self.api, self.method, self.path)
+    }
+
+    fn body_bytes(&self) -> Vec<u8> {
+        match &self.body_hex {
+            Some(hex) => hex::decode(hex).unwrap_or_default(),
+            None => self.body.clone().into_bytes(),
+        }
+    }
+}
+
+enum Mode {
+    Record { file: Mutex<std::fs::File>, upstreams: HashMap<&'static str, String>, client: reqwest::Client },
+    Replay { exchanges: Mutex<HashMap<String, VecDeque<Exchange>>> },
+}
+
+struct Shared {
+    mode: Mode,
+    started: Instant,
+}
+
+pub struct HttpFixtures {
+    openai_addr: SocketAddr,
+    telegram_addr: SocketAddr,
+    shared: Arc<Shared>,
+    shutdown: tokio_util::sync::CancellationToken,
+}
+
+struct Request {
+    api: &'static str,
+    method: String,
+    path: String,
+    query: String,
+    headers: Vec<(String, Vec<u8>)>,
+    body: warp::hyper::body::Bytes,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<String> {
+        self.headers.iter().find(|(header, _)| header == name).map(|(_, value)| String::from_utf8_lossy(value).to_string())
+    }
+}
+
+impl HttpFixtures {
+    // Forwards to wherever OPENAI_BASE_URL and TELEGRAM_API_URL point right now and writes to `path`, replacing it
+    pub async fn record(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let upstreams = HashMap::from([("openai", crate::openai_url("")), ("telegram", crate::telegram_api_url())]);
+        Ok(Self::start(Mode::Record { file: Mutex::new(file), upstreams, client: reqwest::Client::new() }).await)
+    }
+
+    pub async fn replay(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut exchanges: HashMap<String, VecDeque<Exchange>> = HashMap::new();
+        for (number, line) in contents.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            let exchange: Exchange = serde_json::from_str(line)
+                .map_err(|e| anyhow!("{} line {}: {}", path.display(), number + 1, e))?;
+            exchanges.entry(exchange.key()).or_default().push_back(exchange);
+        }
+        Ok(Self::start(Mode::Replay { exchanges: Mutex::new(exchanges) }).await)
+    }
+
+    async fn start(mode: Mode) -> Self {
+        let shared = Arc::new(Shared { mode, started: Instant::now() });
+        let shutdown = tokio_util::sync::CancellationToken::new();
+
+        // Separate ports rather than path prefixes, teloxide only takes a bare origin as its API URL
+        let serve = |api: &'static str| {
+            let shared = shared.clone();
+            let proxy = warp::method()
+                .and(warp::path::full())
+                .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+                .and(warp::header::headers_cloned())
+                .and(warp::body::bytes())
+                .then(move |method: warp::http::Method, path: warp::path::FullPath, query: String, headers: warp::http::HeaderMap, body| {
+                    let request = Request {
+                        api,
+                        method: method.to_string(),
+                        path: path.as_str().to_string(),
+                        query,
+                        headers: headers.iter().map(|(name, value)| (name.to_string(), value.as_bytes().to_vec())).collect(),
+                        body,
+                    };
+                    handle(shared.clone(), request)
+                });
+            let shutdown = shutdown.clone();
+            let (addr, server) = warp::serve(proxy).bind_with_graceful_shutdown(([127, 0, 0, 1], 0), async move { shutdown.cancelled().await });
+            tokio::spawn(server);
+            addr
+        };
+        let openai_addr = serve("openai");
+        let telegram_addr = serve("telegram");
+        log::info!(
+            "http_fixtures: {} OpenAI on {}, Telegram on {}",
+            if matches!(shared.mode, Mode::Record { .. }) { "recording" } else { "replaying" },
+            openai_addr,
+            telegram_addr
+        );
+
+        HttpFixtures { openai_addr, telegram_addr, shared, shutdown }
+    }
+
+    pub fn openai_url(&self) -> String {
+        format!("http://{}", self.openai_addr)
+    }
+
+    pub fn telegram_url(&self) -> String {
+        format!("http://{}", self.telegram_addr)
+    }
+
+    // Points OPENAI_BASE_URL and TELEGRAM_API_URL for the whole process at the proxy
+    pub fn install(&self) {
+        std::env::set_var("OPENAI_BASE_URL", self.openai_url());
+        std::env::set_var("TELEGRAM_API_URL", self.telegram_url());
+    }
+
+    // Recorded exchanges replay hasn't served yet, in recording order
+    pub fn unplayed(&self) -> Vec<Exchange> {
+        let Mode::Replay { exchanges } = &self.shared.mode else { return Vec::new() };
+        let mut unplayed: Vec<Exchange> = exchanges.lock().unwrap().values().flatten().cloned().collect();
+        unplayed.sort_by_key(|exchange| exchange.at_ms);
+        unplayed
+    }
+}
+
+impl Drop for HttpFixtures {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+// HTTP_FIXTURES_RECORD or HTTP_FIXTURES_REPLAY, already installed; keep it alive as long as the bot runs
+pub async fn from_env() -> Result<Option<HttpFixtures>> {
+    let fixtures = match (std::env::var("HTTP_FIXTURES_RECORD").ok(), std::env::var("HTTP_FIXTURES_REPLAY").ok()) {
+        (Some(_), Some(_)) => return Err(anyhow!("Set only one of HTTP_FIXTURES_RECORD and HTTP_FIXTURES_REPLAY")),
+        (Some(path), None) => HttpFixtures::record(PathBuf::from(path)).await?,
+        (None, Some(path)) => HttpFixtures::replay(PathBuf::from(path)).await?,
+        (None, None) => return Ok(None),
+    };
+    fixtures.install();
+    Ok(Some(fixtures))
+}
+
+// teloxide says GetUpdates, lib.rs getUpdates
+fn is_get_updates(path: &str) -> bool {
+    path.to_lowercase().ends_with("/getupdates")
+}
+
+async fn handle(shared: Arc<Shared>, request: Request) -> warp::http::Response<warp::hyper::Body> {
+    let result = match &shared.mode {
+        Mode::Record { file, upstreams, client } => record(&shared, file, upstreams, client, request).await,
+        Mode::Replay { exchanges } => replay(&shared, exchanges, request).await,
+    };
+    match result {
+        Ok((status, content_type, body)) => {
+            let mut response = warp::http::Response::builder().status(status);
+            if let Some(content_type) = content_type {
+                response = response.header("content-type", content_type);
+            }
+            response.body(body.into()).unwrap()
+        }
+        Err(e) => {
+            log::error!("http_fixtures: {:#}", e);
+            warp::http::Response::builder()
+                .status(502)
+                .header("content-type", "application/json")
+                .body(serde_json::json!({ "ok": false, "error_code": 502, "description": e.to_string(), "error": { "message": e.to_string() } }).to_string().into())
+                .unwrap()
+        }
+    }
+}
+
+async fn record(
+    shared: &Shared,
+    file: &Mutex<std::fs::File>,
+    upstreams: &HashMap<&'static str, String>,
+    client: &reqwest::Client,
+    request: Request,
+) -> Result<(u16, Option<String>, Vec<u8>)> {
+    let mut url = format!("{}{}", upstreams[request.api], request.path);
+    if !request.query.is_empty() {
+        url = format!("{}?{}", url, request.query);
+    }
+
+    let method = reqwest::Method::from_bytes(request.method.as_bytes())?;
+    let mut upstream_request = client.request(method, &url).body(request.body.clone());
+    // Without accept-encoding upstream answers uncompressed, since content-encoding isn't passed back
+    for (name, value) in &request.headers {
+        if !matches!(name.as_str(), "host" | "content-length" | "accept-encoding") {
+            upstream_request = upstream_request.header(name.as_str(), value.as_slice());
+        }
+    }
+    let response = upstream_request.send().await.with_context(|| format!("{} {} failed", request.method, crate::redact::secrets(&url)))?;
+    let status = response.status().as_u16();
+    let content_type = response.headers().get("content-type").and_then(|value| value.to_str().ok()).map(str::to_string);
+    let body = response.bytes().await?.to_vec();
+
+    // Empty long polls would be most of the file
+    let empty_poll = is_get_updates(&request.path)
+        && serde_json::from_slice::<serde_json::Value>(&body).is_ok_and(|value| value["result"].as_array().is_some_and(Vec::is_empty));
+    if !empty_poll {
+        let (body_text, body_hex) = match std::str::from_utf8(&body) {
+            Ok(text) => (crate::redact::secrets(text).to_string(), None),
+            Err(_) => (String::new(), Some(hex::encode(&body))),
+        };
+        let exchange = Exchange {
+            at_ms: shared.started.elapsed().as_millis() as u64,
+            api: request.api.to_string(),
+            method: request.method.clone(),
+            path: crate::redact::secrets(&request.path).to_string(),
+            query: crate::redact::secrets(&request.query).to_string(),
+            request_content_type: request.header("content-type"),
+            request_body: match std::str::from_utf8(&request.body) {
+                Ok(text) => crate::redact::secrets(text).to_string(),
+                Err(_) => format!("<{} bytes of binary data>", request.body.len()),
+            },
+            status,
+            content_type: content_type.clone(),
+            body: body_text,
+            body_hex,
+        };
+        let mut file = file.lock().unwrap();
+        writeln!(file, "{}", serde_json::to_string(&exchange)?)?;
+        file.flush()?;
+    }
+
+    Ok((status, content_type, body))
+}
+
+async fn replay(shared: &Shared, exchanges: &Mutex<HashMap<String, VecDeque<Exchange>>>, request: Request) -> Result<(u16, Option<String>, Vec<u8>)> {
+    let key = format!("{} {} {}", request.api, request.method, crate::redact::secrets(&request.path));
+    let long_poll = is_get_updates(&request.path);
+    let poll_deadline = Instant::now() + Duration::from_secs(REPLAY_POLL_SECS);
+
+    // Updates come out no earlier than they arrived in the recording
+    if long_poll {
+        let due = exchanges.lock().unwrap().get(&key).and_then(|queue| queue.front()).map(|exchange| shared.started + Duration::from_millis(exchange.at_ms));
+        match due {
+            Some(due) if due <= poll_deadline => tokio::time::sleep_until(due).await,
+            _ => {
+                tokio::time::sleep_until(poll_deadline).await;
+                return Ok((200, Some("application/json".to_string()), br#"{"ok":true,"result":[]}"#.to_vec()));
+            }
+        }
+    }
+
+    let exchange = exchanges
+        .lock()
+        .unwrap()
+        .get_mut(&key)
+        .and_then(VecDeque::pop_front)
+        .ok_or_else(|| anyhow!("no recorded response left for {}", key))?;
+    Ok((exchange.status, exchange.content_type.clone(), exchange.body_bytes()))
+}
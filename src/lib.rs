@@ -3,20 +3,64 @@
 use serde::{Deserialize, Serialize};
 use reqwest::Client;
 use std::env;
+#[cfg(feature = "audio")]
 use uuid::Uuid;
+#[cfg(feature = "audio")]
 use tokio::fs::File;
 //use tokio::io::AsyncReadExt;
+#[cfg(feature = "audio")]
 use tokio::io::AsyncWriteExt;
 use anyhow;
 //use reqwest::multipart;
+#[cfg(feature = "audio")]
 use anyhow::Context;
 use anyhow::Result;
+#[cfg(feature = "webhook-server")]
 pub mod webhooks;
+#[cfg(feature = "telegram")]
 pub mod telegram;
+#[cfg(feature = "postgres")]
 pub mod database;
+#[cfg(all(feature = "sqlite", not(feature = "postgres")))]
+#[path = "database_sqlite.rs"]
+pub mod database;
+#[cfg(feature = "webhook-server")]
+pub mod admin_api;
+pub mod events;
+#[cfg(feature = "webhook-server")]
+pub mod receivers;
+pub mod dedup;
+#[cfg(feature = "webhook-server")]
+pub mod listener;
+pub mod metrics;
+pub mod health;
+pub mod shutdown;
+pub mod logging;
+pub mod redact;
+pub mod debounce;
+pub mod pacing;
+pub mod quiet_hours;
+#[cfg(feature = "telegram")]
+pub mod follow_ups;
+#[cfg(feature = "telegram")]
+pub mod broadcasts;
+pub mod cli;
+#[cfg(feature = "test-support")]
+pub mod mock_openai;
+#[cfg(feature = "test-support")]
+pub mod mock_telegram;
+#[cfg(all(feature = "telegram", feature = "test-support"))]
+pub mod simulator;
+#[cfg(any(feature = "webhook-server", feature = "test-support"))]
+pub mod http_fixtures;
 use serde_json::Value;
 use rand::SeedableRng;
 
+// Users, threads, webhooks and pending work all live in crate::database: database.rs on Postgres, or
+// database_sqlite.rs when only the sqlite feature is on
+#[cfg(not(any(feature = "postgres", feature = "sqlite")))]
+compile_error!("A storage backend is required; build with the postgres or the sqlite feature");
+
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct WebhookPayload {
@@ -35,6 +79,19 @@ pub struct Message {
     pub voice: Option<Voice>,
 }
 
+impl Message {
+    // "audio", "voice" or "text". Transcribed audio and voice keep their audio/voice field next to the text.
+    pub fn kind(&self) -> &'static str {
+        if self.audio.is_some() {
+            "audio"
+        } else if self.voice.is_some() {
+            "voice"
+        } else {
+            "text"
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Audio {
     pub file_id: String,
@@ -80,6 +137,197 @@ pub struct DBUser {
     pub first_name: Option<String>,
     pub last_name: Option<String>,
     pub username: Option<String>,
+    pub language_code: Option<String>,
+    // IANA name, and where it came from: configured, phone or language (see quiet_hours)
+    pub timezone: Option<String>,
+    pub timezone_source: Option<String>,
+    // One of CONTACT_STATES, and since when
+    pub contact_state: String,
+    pub contact_state_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+// users.contact_state: whether the bot may message the user. Only active users get anything but a direct answer.
+pub const CONTACT_ACTIVE: &str = "active";
+// Sent /stop, or recorded through the admin API
+pub const CONTACT_OPTED_OUT: &str = "opted_out";
+// Telegram refused a message because they blocked the bot or deleted their account
+pub const CONTACT_BLOCKED: &str = "blocked";
+pub const CONTACT_STATES: &[&str] = &[CONTACT_ACTIVE, CONTACT_OPTED_OUT, CONTACT_BLOCKED];
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DBThread {
+    pub thread_id: String,
+    pub user_id: i64,
+    pub openai_thread_id: String,
+    pub assistant_id: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DBMessage {
+    pub id: i64,
+    pub thread_id: String,
+    pub sender: String,
+    pub content: String,
+    pub message_type: String,
+    pub assistant_id: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DBMetric {
+    pub id: i64,
+    pub user_id: i64,
+    pub thread_id: String,
+    pub interest: i32,
+    pub user_response_time: Option<i32>,
+    pub response_cue: Option<i32>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DBEventSubscription {
+    pub id: i64,
+    pub url: String,
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub event_types: Vec<String>,
+    pub active: bool,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+// A failed event delivery whose retry is due, see database::claim_due_event_retries
+#[derive(Debug, Clone)]
+pub struct DBEventRetry {
+    pub delivery_id: i64,
+    // The attempt that failed
+    pub attempt: i32,
+    pub payload: serde_json::Value,
+    pub subscription: DBEventSubscription,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DBEventDelivery {
+    pub id: i64,
+    pub subscription_id: i64,
+    pub event_id: String,
+    pub event_type: String,
+    pub attempt: i32,
+    pub status_code: Option<i32>,
+    pub error: Option<String>,
+    pub succeeded: bool,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DBWebhookEvent {
+    pub id: i64,
+    pub provider: String,
+    pub path: String,
+    pub headers: serde_json::Value,
+    #[serde(skip)]
+    pub body: Vec<u8>,
+    pub verified: bool,
+    pub verification_error: Option<String>,
+    pub status: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub received_at: chrono::DateTime<chrono::Utc>,
+    pub processed_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DBApiKey {
+    pub id: i64,
+    pub name: String,
+}
+
+// A reply that was generated but not yet sent when the process shut down, or that was deferred out of the
+// user's quiet hours
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DBPendingReply {
+    pub id: i64,
+    pub user_id: i64,
+    pub chat_id: i64,
+    pub thread_id: String,
+    pub assistant_id: String,
+    pub text: String,
+    pub send_at: chrono::DateTime<chrono::Utc>,
+    // When it would have been sent if not for quiet hours
+    pub deferred_from: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+// A debounce buffer that was never processed when the process shut down
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DBPendingBuffer {
+    pub user_id: i64,
+    pub chat_id: i64,
+    pub messages: Vec<Message>,
+}
+
+// A conversation whose user hasn't sent anything since `last_inbound_at`, with the follow-ups sent since
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DBDormantThread {
+    pub user_id: i64,
+    pub thread_id: String,
+    pub assistant_id: String,
+    pub last_inbound_at: chrono::DateTime<chrono::Utc>,
+    pub timezone: Option<String>,
+    pub follow_ups_sent: i64,
+}
+
+// Who a broadcast goes to; every criterion given must match. Interest and activity come from the user's latest
+// metrics row, which the bot writes for each batch of messages it analyses.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct BroadcastSegment {
+    pub user_ids: Option<Vec<i64>>,
+    pub min_interest: Option<i32>,
+    pub max_interest: Option<i32>,
+    // Wrote to the bot within the last N days
+    pub active_within_days: Option<i32>,
+    // Hasn't written to the bot for at least N days
+    pub inactive_for_days: Option<i32>,
+    pub language_code: Option<String>,
+}
+
+// A broadcast with its delivery counts so far
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DBBroadcast {
+    pub id: i64,
+    pub text: String,
+    pub segment: BroadcastSegment,
+    pub status: String,
+    pub created_by: String,
+    pub recipients: i64,
+    pub pending: i64,
+    pub sent: i64,
+    pub failed: i64,
+    pub unreachable: i64,
+    pub opted_out: i64,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub started_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub finished_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DBBroadcastRecipient {
+    pub broadcast_id: i64,
+    pub user_id: i64,
+    pub status: String,
+    pub attempts: i32,
+    pub error: Option<String>,
+    pub sent_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+// Every OpenAI call goes through here so OPENAI_BASE_URL can point them elsewhere, e.g. at mock_openai in tests
+pub fn openai_url(path: &str) -> String {
+    let base = env::var("OPENAI_BASE_URL").unwrap_or_else(|_| "https://api.openai.com/v1".to_string());
+    format!("{}{}", base.trim_end_matches('/'), path)
+}
+
+// Same for the Telegram Bot API and file downloads, e.g. at mock_telegram in tests. See also telegram::bot_from_env.
+pub fn telegram_api_url() -> String {
+    let base = env::var("TELEGRAM_API_URL").unwrap_or_else(|_| "https://api.telegram.org".to_string());
+    base.trim_end_matches('/').to_string()
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -127,7 +375,7 @@ async fn handle_text_message(bot_token: &str, chat_id: &u64, input_text: &str, o
     let response_text = call_openai_api(openai_key, input_text).await;
 
     let bot = Client::new();
-    bot.post(&format!("https://api.telegram.org/bot{}/sendMessage", bot_token))
+    bot.post(format!("{}/bot{}/sendMessage", telegram_api_url(), bot_token))
         .json(&serde_json::json!({
             "chat_id": chat_id,
             "text": response_text,
@@ -138,6 +386,7 @@ async fn handle_text_message(bot_token: &str, chat_id: &u64, input_text: &str, o
     Ok(())
 }
 
+#[cfg(feature = "audio")]
 async fn handle_audio_message(bot_token: &str, audio: &Audio, openai_key: &str) -> Result<String, anyhow::Error> {
     log::info!("Audio: step 2: In handle_audio_message fn");
 
@@ -147,7 +396,7 @@ async fn handle_audio_message(bot_token: &str, audio: &Audio, openai_key: &str)
     log::info!("Audio: step 2: In handle_audio_message. File_path is {file_path_on_telegram}");
 
     // Download the audio file from Telegram
-    let file_url = format!("https://api.telegram.org/file/bot{}/{}", bot_token, &file_path_on_telegram);
+    let file_url = format!("{}/file/bot{}/{}", telegram_api_url(), bot_token, &file_path_on_telegram);
     log::info!("Audio: step 3: about to download audio file");
     log::info!("file_url is {file_url}");
     let file_name = download_file(&file_url, &audio.file_id, audio.mime_type.as_deref()).await?;
@@ -155,11 +404,12 @@ async fn handle_audio_message(bot_token: &str, audio: &Audio, openai_key: &str)
     // Call OpenAI API to transcribe audio
     log::info!("Audio: step 4: about to transcribe the audio message");
     let transcription = transcribe_audio(openai_key, &file_name, audio.mime_type.as_deref()).await?;
-    log::info!("audio message transcribed to: {}", transcription);
+    log::info!("audio message transcribed to: {}", crate::redact::body(&transcription));
 
     // Return the transcription instead of sending it to Telegram
     Ok(transcription)
 }
+#[cfg(feature = "audio")]
 async fn handle_voice_message(bot_token: &str, voice: &Voice, openai_key: &str) -> Result<String, anyhow::Error> {
     log::info!("Voice: step 2: In handle_voice_message fn");
 
@@ -168,17 +418,27 @@ async fn handle_voice_message(bot_token: &str, voice: &Voice, openai_key: &str)
     log::info!("Voice: step 2: In handle_voice_message. got file path: {}", file_path_on_telegram);
 
     // Download the voice file from Telegram
-    let file_url = format!("https://api.telegram.org/file/bot{}/{}", bot_token, file_path_on_telegram);
+    let file_url = format!("{}/file/bot{}/{}", telegram_api_url(), bot_token, file_path_on_telegram);
     log::info!("Voice: step 3: about to download voice file");
     let file_name = download_file(&file_url, &voice.file_id, voice.mime_type.as_deref()).await?;
 
     // Call OpenAI API to transcribe voice
     log::info!("Voice: step 4: about to transcribe the voice message");
     let transcription = transcribe_audio(openai_key, &file_name, voice.mime_type.as_deref()).await?;
-    log::info!("voice message transcribed to: {}", transcription);
+    log::info!("voice message transcribed to: {}", crate::redact::body(&transcription));
 
     Ok(transcription)
 }
+
+// Without the audio feature, audio and voice messages fail like any other transcription error
+#[cfg(not(feature = "audio"))]
+async fn handle_audio_message(_bot_token: &str, _audio: &Audio, _openai_key: &str) -> Result<String, anyhow::Error> {
+    anyhow::bail!("Built without the audio feature, can't transcribe audio messages")
+}
+#[cfg(not(feature = "audio"))]
+async fn handle_voice_message(_bot_token: &str, _voice: &Voice, _openai_key: &str) -> Result<String, anyhow::Error> {
+    anyhow::bail!("Built without the audio feature, can't transcribe voice messages")
+}
 // async fn handle_audio_message(bot_token: &str, chat_id: &u64, audio: &Audio, openai_key: &str) -> Result<(), anyhow::Error> {
 //     log::info!("Audio: step 2: In handle_audio_message fn");
 
@@ -212,10 +472,11 @@ async fn handle_voice_message(bot_token: &str, voice: &Voice, openai_key: &str)
 
 
 
+#[cfg(feature = "audio")]
 async fn get_file(bot_token: &str, file_id: &str) -> Result<String> {
     log::info!("Audio: step 2 initializing. in get_file right now");
     let client = Client::new();
-    let res: Value = client.post(&format!("https://api.telegram.org/bot{}/getFile", bot_token))
+    let res: Value = client.post(format!("{}/bot{}/getFile", telegram_api_url(), bot_token))
         .form(&[("file_id", file_id)])
         .send()
         .await?
@@ -226,6 +487,7 @@ async fn get_file(bot_token: &str, file_id: &str) -> Result<String> {
 }
 
 
+#[cfg(feature = "audio")]
 async fn download_file(url: &str, file_id: &str, mime_type: Option<&str>) -> Result<String, anyhow::Error> {
     log::info!("Audio: step 3: in download_file fn");
     
@@ -316,6 +578,7 @@ async fn download_file(url: &str, file_id: &str, mime_type: Option<&str>) -> Res
 
 
 // use rs_openai::audio::Audio;
+#[cfg(feature = "audio")]
 async fn transcribe_audio(openai_key: &str, file_name: &str, mime_type: Option<&str>) -> Result<String, anyhow::Error> {
     log::info!("Audio: step 4: in transcribe_audio.");
     
@@ -337,9 +600,10 @@ async fn transcribe_audio(openai_key: &str, file_name: &str, mime_type: Option<&
         .part("file", file_part);
 
     log::info!("Audio: step 4: sending request to OpenAI for transcription");
+    let _transcription_timer = crate::metrics::TRANSCRIPTION_DURATION.start_timer();
 
     // Send the POST request to the OpenAI API endpoint
-    let response = client.post("https://api.openai.com/v1/audio/transcriptions")
+    let response = client.post(openai_url("/audio/transcriptions"))
         .header("Authorization", format!("Bearer {}", openai_key))
         .multipart(form)
         .send()
@@ -360,7 +624,7 @@ async fn transcribe_audio(openai_key: &str, file_name: &str, mime_type: Option<&
         .ok_or_else(|| anyhow::anyhow!("Transcription not found in response"))?
         .to_string();
 
-    log::info!("audio message transcribed to: {}", transcription);
+    log::info!("audio message transcribed to: {}", crate::redact::body(&transcription));
 
     Ok(transcription)
 }
@@ -370,7 +634,7 @@ async fn transcribe_audio(openai_key: &str, file_name: &str, mime_type: Option<&
 pub async fn call_openai_api(openai_key: &str, input: &str) -> String {
     let client = Client::new();
 
-    let response = match client.post("https://api.openai.com/v1/chat/completions")
+    let response = match client.post(openai_url("/chat/completions"))
         .header("Content-Type", "application/json")
         .header("Authorization", format!("Bearer {}", openai_key))
         .json(&serde_json::json!({
@@ -403,7 +667,7 @@ pub async fn call_openai_api(openai_key: &str, input: &str) -> String {
         }
     };
 
-    log::info!("OpenAI response: {:?}", response_json);
+    log::info!("OpenAI response: {}", crate::redact::body(&response_json));
 
     response_json["choices"][0]["message"]["content"].as_str().unwrap_or("").to_string()
 }
@@ -413,7 +677,7 @@ pub async fn create_openai_thread(openai_key: &str, initial_message: &str) -> an
     log::info!("Step 2 starting.In create_openai_thread rn. ");
     log::info!("Step 3 technically starting as well since we are using the message");
     log::info!("  in the json payload in the POST request to the url");
-    let response = client.post("https://api.openai.com/v1/threads")
+    let response = client.post(openai_url("/threads"))
         .header("Content-Type", "application/json")
         .header("Authorization", format!("Bearer {}", openai_key))
         .header("OpenAI-Beta", "assistants=v2")
@@ -437,6 +701,37 @@ pub async fn create_openai_thread(openai_key: &str, initial_message: &str) -> an
     Ok(thread_id)
 }
 
+lazy_static::lazy_static! {
+    static ref THREAD_RUNS: std::sync::Mutex<std::collections::HashMap<String, std::sync::Arc<tokio::sync::Mutex<()>>>> = std::sync::Mutex::new(std::collections::HashMap::new());
+}
+
+// OpenAI rejects a new message or run on a thread whose run is still going. Runs on a thread are taken one at a
+// time here, and a run still active on OpenAI's side (one we stopped polling, or one from before a restart) is
+// waited out first. Hold the guard until the run's reply has been read.
+pub async fn lock_thread_for_run(openai_key: &str, thread_id: &str) -> anyhow::Result<tokio::sync::OwnedMutexGuard<()>> {
+    let lock = THREAD_RUNS.lock().unwrap().entry(thread_id.to_string()).or_default().clone();
+    let guard = lock.lock_owned().await;
+
+    let client = reqwest::Client::new();
+    const MAX_RETRIES: u32 = 30;
+    for attempt in 0..MAX_RETRIES {
+        let response = client.get(openai_url(&format!("/threads/{}/runs?limit=1", thread_id)))
+            .header("Authorization", format!("Bearer {}", openai_key))
+            .header("OpenAI-Beta", "assistants=v2")
+            .send()
+            .await?;
+        let response_json: serde_json::Value = serde_json::from_str(&response.text().await?)?;
+        let latest = &response_json["data"][0];
+        let status = latest["status"].as_str().unwrap_or("");
+        if !matches!(status, "queued" | "in_progress" | "requires_action" | "cancelling") {
+            return Ok(guard);
+        }
+        log::warn!("Thread {} still has run {} {}, waiting... Attempt {}", thread_id, latest["id"], status, attempt + 1);
+        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+    }
+    Err(anyhow::anyhow!("Thread {} still has an active run", thread_id))
+}
+
 pub async fn create_run_on_thread(openai_key: &str, thread_id: &str, assistant_id: &str) -> anyhow::Result<String> {
     let client = reqwest::Client::new();
 
@@ -447,7 +742,7 @@ pub async fn create_run_on_thread(openai_key: &str, thread_id: &str, assistant_i
     log::info!("Now in step 4's function: create_run_on_thread");
     log::info!("create_run_on_thread payload: {}", json_payload);
 
-    let response = client.post(&format!("https://api.openai.com/v1/threads/{}/runs", thread_id))
+    let response = client.post(openai_url(&format!("/threads/{}/runs", thread_id)))
         .header("Content-Type", "application/json")
         .header("Authorization", format!("Bearer {}", openai_key))
         .header("OpenAI-Beta", "assistants=v2")
@@ -464,6 +759,8 @@ pub async fn create_run_on_thread(openai_key: &str, thread_id: &str, assistant_i
         .as_str()
         .ok_or_else(|| anyhow::anyhow!("Run ID not found in response"))?
         .to_string();
+    crate::metrics::RUNS_CREATED.inc();
+    crate::logging::set_run_id(&run_id);
     log::info!("Created new run with ID: {}", run_id);
     log::info!("Step 4 complete");
     Ok(run_id)
@@ -471,7 +768,7 @@ pub async fn create_run_on_thread(openai_key: &str, thread_id: &str, assistant_i
 
 pub async fn is_run_active(openai_key: &str, thread_id: &str, run_id: &str) -> anyhow::Result<bool> {
     let client = reqwest::Client::new();
-    let url = format!("https://api.openai.com/v1/threads/{}/runs/{}", thread_id, run_id);
+    let url = openai_url(&format!("/threads/{}/runs/{}", thread_id, run_id));
 
     log::info!("Step 5 initiating. Aka Checking run's status to see if it's done");
     log::info!("AKA GET https://api.openai.com/v1/threads/{thread_id}/runs/{run_id}");
@@ -500,7 +797,7 @@ pub async fn get_last_assistant_message(openai_key: &str, thread_id: &str) -> an
     log::info!("Step 6 initiating. Aka: Retrieve the assistant's response");
     log::info!("AKA: GET https://api.openai.com/v1/threads/{thread_id}/messages");
 
-    let response = client.get(&format!("https://api.openai.com/v1/threads/{}/messages", thread_id))
+    let response = client.get(openai_url(&format!("/threads/{}/messages", thread_id)))
         .header("Content-Type", "application/json")
         .header("Authorization", format!("Bearer {}", openai_key))
         .header("OpenAI-Beta", "assistants=v2") // Added the missing header
@@ -509,7 +806,7 @@ pub async fn get_last_assistant_message(openai_key: &str, thread_id: &str) -> an
 
     let response_text = response.text().await?;
     log::info!("Step 6 complete.");
-    log::info!("Received response from get_last_assistant_message: {}", response_text);
+    log::info!("Received response from get_last_assistant_message: {}", crate::redact::body(&response_text));
 
     let response_json: serde_json::Value = serde_json::from_str(&response_text)?;
 
@@ -522,7 +819,7 @@ pub async fn get_last_assistant_message(openai_key: &str, thread_id: &str) -> an
         msg["content"][0]["text"]["value"].as_str().unwrap().to_string()
     }).collect();
     if let Some(last_message) = assistant_texts.first() {
-        log::info!("The last message from the assistant is: {}", last_message);
+        log::info!("The last message from the assistant is: {}", crate::redact::body(&last_message));
         Ok(last_message.to_string())
     } else {
         log::error!("The assistant has not sent any messages.");
@@ -546,7 +843,12 @@ pub async fn get_last_assistant_message(openai_key: &str, thread_id: &str) -> an
 
 
 
+// What first_loop and second_message_and_so_on return when the run gave no message
+pub const ASSISTANT_MESSAGE_FAILED: &str = "Failed to retrieve the assistant's response. Please try again later.";
+
 pub async fn first_loop(openai_key: &str, thread_id: &str, assistant_id: &str) -> anyhow::Result<String> {
+    crate::logging::set_thread_id(thread_id);
+    let _run_guard = lock_thread_for_run(openai_key, thread_id).await?;
     log::info!("got to first_loop");
     // log::info!("Step 2 should be starting soon.");
     // log::info!("Since I am already adding the message to the json_payload in step 2,");
@@ -596,6 +898,7 @@ pub async fn first_loop(openai_key: &str, thread_id: &str, assistant_id: &str) -
         }
     };
 
+    let run_timer = crate::metrics::RUN_DURATION.start_timer();
     const MAX_RETRIES: u32 = 10;
     for attempt in 0..MAX_RETRIES {
         // Check if the run is active
@@ -611,6 +914,7 @@ pub async fn first_loop(openai_key: &str, thread_id: &str, assistant_id: &str) -
             break;
         }
     }
+    run_timer.observe_duration();
     log::info!("Step 6 should be starting soon");
     match get_last_assistant_message(openai_key, &thread_id).await {
         Ok(response) => {
@@ -619,7 +923,7 @@ pub async fn first_loop(openai_key: &str, thread_id: &str, assistant_id: &str) -
         },
         Err(e) => {
             log::error!("Failed to get the last assistant message: {}", e);
-            Ok("Failed to retrieve the assistant's response. Please try again later.".to_string())
+            Ok(ASSISTANT_MESSAGE_FAILED.to_string())
         }
     }
 
@@ -629,29 +933,11 @@ pub async fn first_loop(openai_key: &str, thread_id: &str, assistant_id: &str) -
 
 
 pub async fn second_message_and_so_on(openai_key: &str, thread_id: &str, text: &str, assistant_id: &str) -> anyhow::Result<String> {
+        crate::logging::set_thread_id(thread_id);
+        let _run_guard = lock_thread_for_run(openai_key, thread_id).await?;
     //step 3
         log::info!("since step 2 is already done, aka create the thread, we'll move on to step 3.");
-        let client = reqwest::Client::new();
-
-        let json_payload = serde_json::json!({
-            "role": "user",
-            "content": text
-        });
-
-        log::info!("Step 3 initializing: aka add a user's message to the thread");
-        log::info!("aka POST https://api.openai.com/v1/threads/{thread_id}/messages");
-
-        let response = client.post(&format!("https://api.openai.com/v1/threads/{}/messages", thread_id))
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", openai_key))
-        .header("OpenAI-Beta", "assistants=v2")
-        .json(&json_payload)
-        .send()
-        .await?;
-
-        let response_text = response.text().await?;
-        log::info!("Step 3 complete");
-        log::info!("Received response from add a user's message to the thread: {}", response_text);
+        post_user_message(openai_key, thread_id, text).await?;
 
     //step 4
         log::info!("Step 4 initializing. aka Run the assistant");
@@ -666,6 +952,7 @@ pub async fn second_message_and_so_on(openai_key: &str, thread_id: &str, text: &
             }
         };
     //step 5
+        let run_timer = crate::metrics::RUN_DURATION.start_timer();
         const MAX_RETRIES: u32 = 10;
         for attempt in 0..MAX_RETRIES {
             // Check if the run is active
@@ -683,6 +970,7 @@ pub async fn second_message_and_so_on(openai_key: &str, thread_id: &str, text: &
         }
     
     //step 6
+        run_timer.observe_duration();
         log::info!("Step 6 should be starting soon");
         match get_last_assistant_message(openai_key, &thread_id).await {
             Ok(response) => {
@@ -691,20 +979,54 @@ pub async fn second_message_and_so_on(openai_key: &str, thread_id: &str, text: &
             },
             Err(e) => {
                 log::error!("Failed to get the last assistant message: {}", e);
-                Ok("Failed to retrieve the assistant's response. Please try again later.".to_string())
+                Ok(ASSISTANT_MESSAGE_FAILED.to_string())
             }
         }
 }
 
 
+// Adds a user's message to the thread without running it, once no run is going on the thread
+pub async fn add_message_to_thread(openai_key: &str, thread_id: &str, text: &str) -> anyhow::Result<()> {
+    crate::logging::set_thread_id(thread_id);
+    let _run_guard = lock_thread_for_run(openai_key, thread_id).await?;
+    post_user_message(openai_key, thread_id, text).await
+}
+
+// Step 3 of second_message_and_so_on, for callers holding the thread's run lock
+async fn post_user_message(openai_key: &str, thread_id: &str, text: &str) -> anyhow::Result<()> {
+    let client = reqwest::Client::new();
+
+    let json_payload = serde_json::json!({
+        "role": "user",
+        "content": text
+    });
+
+    log::info!("Step 3 initializing: aka add a user's message to the thread");
+    log::info!("aka POST https://api.openai.com/v1/threads/{thread_id}/messages");
+
+    let response = client.post(openai_url(&format!("/threads/{}/messages", thread_id)))
+    .header("Content-Type", "application/json")
+    .header("Authorization", format!("Bearer {}", openai_key))
+    .header("OpenAI-Beta", "assistants=v2")
+    .json(&json_payload)
+    .send()
+    .await?;
+
+    let response_text = response.text().await?;
+    log::info!("Step 3 complete");
+    log::info!("Received response from add a user's message to the thread: {}", crate::redact::body(&response_text));
+    Ok(())
+}
+
+
 pub async fn send_next_message(thread_id: &str, text: &str) -> anyhow::Result<()> {
     let client = Client::new();
 
-    log::info!("message we're about to send: {}\n", text);
+    log::info!("message we're about to send: {}\n", crate::redact::body(text));
     log::info!("Step 3 initiating. AKA: Add a user's message to the thread");
     log::info!("AKA: POST https://api.openai.com/v1/threads/{thread_id}/messages");
 
-    let response = client.post(&format!("https://api.openai.com/v1/threads/{}/messages", thread_id))
+    let response = client.post(openai_url(&format!("/threads/{}/messages", thread_id)))
         .header("Content-Type", "application/json")
         .header("Authorization", "Bearer YOUR_OPEN_AI_KEY")
         .body(serde_json::json!({
@@ -717,7 +1039,7 @@ pub async fn send_next_message(thread_id: &str, text: &str) -> anyhow::Result<()
     
     log::info!("Step 3 complete");
     log::info!("response from POST https://api.openai.com/v1/threads/{thread_id}/messages:
-    {}", response_text);
+    {}", crate::redact::body(&response_text));
 
     Ok(())
 }
@@ -729,7 +1051,7 @@ pub async fn send_next_message(thread_id: &str, text: &str) -> anyhow::Result<()
 pub async fn pre_process_message(openai_key: &str, message: &str) -> Result<(String, String, String), anyhow::Error> {
     let client = reqwest::Client::new();
 
-    let response = client.post("https://api.openai.com/v1/chat/completions")
+    let response = client.post(openai_url("/chat/completions"))
         .header("Content-Type", "application/json")
         .header("Authorization", format!("Bearer {}", openai_key))
         .json(&serde_json::json!({
@@ -826,7 +1148,7 @@ fn parse_pre_processing_response(response: &str) -> Result<PreProcessingResult,
 // use teloxide::types::ChatId;
 
 // pub async fn summarize_conversation(
-//     pool: &deadpool_postgres::Pool, 
+//     pool: &crate::database::Pool, 
 //     message: &str, 
 //     openai_key: &str, 
 //     bot: &Bot, 
@@ -898,7 +1220,7 @@ fn parse_pre_processing_response(response: &str) -> Result<PreProcessingResult,
 
 // async fn process_text_message(
 //     bot: &Bot,
-//     pool: &deadpool_postgres::Pool,
+//     pool: &crate::database::Pool,
 //     message: &teloxide::prelude::Message,
 //     user_id: i64,
 //     text: &str,
@@ -932,7 +1254,7 @@ fn parse_pre_processing_response(response: &str) -> Result<PreProcessingResult,
 
 // async fn process_audio_message(
 //     bot: &Bot,
-//     pool: &deadpool_postgres::Pool,
+//     pool: &crate::database::Pool,
 //     message: &teloxide::prelude::Message,
 //     user_id: i64,
 //     audio: teloxide::types::Audio,
@@ -989,7 +1311,7 @@ fn parse_pre_processing_response(response: &str) -> Result<PreProcessingResult,
 
 // async fn process_voice_message(
 //     bot: &Bot,
-//     pool: &deadpool_postgres::Pool,
+//     pool: &crate::database::Pool,
 //     message: &teloxide::prelude::Message,
 //     user_id: i64,
 //     voice: teloxide::types::Voice,
@@ -1046,7 +1368,7 @@ fn parse_pre_processing_response(response: &str) -> Result<PreProcessingResult,
 
 // pub async fn users_second_message_window(
 //     bot: Bot,
-//     pool: deadpool_postgres::Pool,
+//     pool: crate::database::Pool,
 //     message: crate::Message,
 //     user_id: i64,
 //     wait_duration: tokio::time::Duration,
@@ -1121,7 +1443,7 @@ fn parse_pre_processing_response(response: &str) -> Result<PreProcessingResult,
 
 // pub async fn users_second_message_window(
 //     bot: Bot,
-//     pool: deadpool_postgres::Pool,
+//     pool: crate::database::Pool,
 //     message: teloxide::prelude::Message,
 //     user_id: i64,
 //     wait_duration: tokio::time::Duration,
@@ -1227,7 +1549,7 @@ fn parse_pre_processing_response(response: &str) -> Result<PreProcessingResult,
 
 // pub async fn users_second_message_window(
 //     bot: Bot,
-//     pool: deadpool_postgres::Pool,
+//     pool: crate::database::Pool,
 //     message: teloxide::prelude::Message,
 //     user_id: i64,
 //     wait_duration: tokio::time::Duration,
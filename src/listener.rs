@@ -0,0 +1,290 @@
+// src/listener.rs
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+#[cfg(feature = "tls")]
+use std::path::Path;
+#[cfg(feature = "tls")]
+use std::sync::{Arc, RwLock};
+#[cfg(feature = "tls")]
+use std::time::SystemTime;
+use tokio::net::TcpListener;
+use tokio::time::{sleep, Duration};
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls;
+use warp::Filter;
+
+const DEFAULT_CERT_PATH: &str = "/etc/letsencrypt/live/merivilla.com/fullchain.pem";
+const DEFAULT_KEY_PATH: &str = "/etc/letsencrypt/live/merivilla.com/privkey.pem";
+const DEFAULT_RELOAD_INTERVAL_SECS: u64 = 60;
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    pub reload_interval: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct ListenerConfig {
+    pub bind_addr: SocketAddr,
+    // None means plain HTTP, e.g. when running behind a reverse proxy that terminates TLS
+    pub tls: Option<TlsConfig>,
+    // Plain-HTTP listener that only redirects to https://
+    pub redirect_addr: Option<SocketAddr>,
+}
+
+impl ListenerConfig {
+    // WEBHOOK_TLS=false switches to plain HTTP on WEBHOOK_BIND_ADDR (default 0.0.0.0:8080).
+    // With TLS (the default) we bind 0.0.0.0:443 and redirect from HTTP_REDIRECT_BIND_ADDR (default 0.0.0.0:80, "off" to disable).
+    // Builds without the tls feature default to plain HTTP.
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        let tls_enabled = match std::env::var("WEBHOOK_TLS").unwrap_or_default().to_lowercase().as_str() {
+            "false" | "0" | "off" | "no" => false,
+            "" => cfg!(feature = "tls"),
+            _ => true,
+        };
+        if tls_enabled && !cfg!(feature = "tls") {
+            anyhow::bail!("WEBHOOK_TLS is on but this build has no tls feature");
+        }
+
+        let default_bind = if tls_enabled { "0.0.0.0:443" } else { "0.0.0.0:8080" };
+        let bind_addr = std::env::var("WEBHOOK_BIND_ADDR")
+            .unwrap_or_else(|_| default_bind.to_string())
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid WEBHOOK_BIND_ADDR: {}", e))?;
+
+        if !tls_enabled {
+            return Ok(ListenerConfig { bind_addr, tls: None, redirect_addr: None });
+        }
+
+        let reload_interval = std::env::var("TLS_RELOAD_INTERVAL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_RELOAD_INTERVAL_SECS);
+        let tls = TlsConfig {
+            cert_path: std::env::var("TLS_CERT_PATH").unwrap_or_else(|_| DEFAULT_CERT_PATH.to_string()).into(),
+            key_path: std::env::var("TLS_KEY_PATH").unwrap_or_else(|_| DEFAULT_KEY_PATH.to_string()).into(),
+            reload_interval: Duration::from_secs(reload_interval),
+        };
+
+        let redirect_addr = match std::env::var("HTTP_REDIRECT_BIND_ADDR") {
+            Ok(value) if value.is_empty() || value.eq_ignore_ascii_case("off") => None,
+            Ok(value) => Some(value.parse().map_err(|e| anyhow::anyhow!("Invalid HTTP_REDIRECT_BIND_ADDR: {}", e))?),
+            Err(_) => Some(SocketAddr::from(([0, 0, 0, 0], 80))),
+        };
+
+        Ok(ListenerConfig { bind_addr, tls: Some(tls), redirect_addr })
+    }
+
+    // Loads the certificate pair without binding anything, for check-config
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            load_certified_key(&tls.cert_path, &tls.key_path)?;
+        }
+        Ok(())
+    }
+}
+
+// Hands every new handshake whatever certificate is current. Connections already open keep the one they started with.
+#[cfg(feature = "tls")]
+#[derive(Debug)]
+struct ReloadingCertResolver {
+    current: RwLock<Arc<rustls::sign::CertifiedKey>>,
+}
+
+#[cfg(feature = "tls")]
+impl rustls::server::ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _client_hello: rustls::server::ClientHello) -> Option<Arc<rustls::sign::CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+#[cfg(feature = "tls")]
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<rustls::sign::CertifiedKey, anyhow::Error> {
+    let cert_pem = std::fs::read(cert_path).map_err(|e| anyhow::anyhow!("Failed to read {}: {}", cert_path.display(), e))?;
+    let certs = rustls_pemfile::certs(&mut cert_pem.as_slice()).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {}", cert_path.display());
+    }
+
+    let key_pem = std::fs::read(key_path).map_err(|e| anyhow::anyhow!("Failed to read {}: {}", key_path.display(), e))?;
+    let key = rustls_pemfile::private_key(&mut key_pem.as_slice())?
+        .ok_or_else(|| anyhow::anyhow!("No private key found in {}", key_path.display()))?;
+    let signing_key = rustls::crypto::ring::sign::any_supported_type(&key)?;
+
+    Ok(rustls::sign::CertifiedKey::new(certs, signing_key))
+}
+
+#[cfg(feature = "tls")]
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// Polls the PEM files and swaps the certificate in place when either changes (e.g. after a Let's Encrypt renewal).
+// Stops when `stop` is cancelled.
+#[cfg(feature = "tls")]
+fn spawn_cert_watcher(resolver: Arc<ReloadingCertResolver>, tls: TlsConfig, stop: tokio_util::sync::CancellationToken) {
+    tokio::spawn(async move {
+        let mut last_seen = (modified_at(&tls.cert_path), modified_at(&tls.key_path));
+        loop {
+            tokio::select! {
+                _ = sleep(tls.reload_interval) => {}
+                _ = stop.cancelled() => return,
+            }
+            let seen = (modified_at(&tls.cert_path), modified_at(&tls.key_path));
+            if seen == last_seen {
+                continue;
+            }
+            match load_certified_key(&tls.cert_path, &tls.key_path) {
+                Ok(key) => {
+                    *resolver.current.write().unwrap() = Arc::new(key);
+                    last_seen = seen;
+                    log::info!("listener: reloaded TLS certificate from {}", tls.cert_path.display());
+                }
+                // Renewals write cert and key separately, so a half-written pair is retried on the next tick
+                Err(e) => log::warn!("listener: certificate changed but could not be loaded yet, keeping the old one: {:?}", e),
+            }
+        }
+    });
+}
+
+pub async fn serve<S>(service: S, config: ListenerConfig) -> Result<(), anyhow::Error>
+where
+    S: warp::hyper::service::Service<warp::http::Request<warp::hyper::Body>, Response = warp::http::Response<warp::hyper::Body>, Error = std::convert::Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    let listener = TcpListener::bind(config.bind_addr).await?;
+
+    // The certificate watcher and redirect listener belong to this run: a supervisor restart calls serve again, so
+    // they stop whenever this returns, not only on shutdown
+    let shutdown = crate::shutdown::token();
+    let run = shutdown.child_token();
+    let _stop_run = run.clone().drop_guard();
+
+    #[cfg(feature = "tls")]
+    let acceptor = match &config.tls {
+        Some(tls) => {
+            let resolver = Arc::new(ReloadingCertResolver {
+                current: RwLock::new(Arc::new(load_certified_key(&tls.cert_path, &tls.key_path)?)),
+            });
+            spawn_cert_watcher(resolver.clone(), tls.clone(), run.clone());
+
+            let mut server_config = rustls::ServerConfig::builder()
+                .with_no_client_auth()
+                .with_cert_resolver(resolver);
+            server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+            Some(tokio_rustls::TlsAcceptor::from(Arc::new(server_config)))
+        }
+        None => None,
+    };
+    #[cfg(not(feature = "tls"))]
+    let acceptor: Option<()> = match config.tls {
+        Some(_) => anyhow::bail!("TLS is configured but this build has no tls feature"),
+        None => None,
+    };
+
+    if let Some(redirect_addr) = config.redirect_addr {
+        let https_port = config.bind_addr.port();
+        let run = run.clone();
+        match warp::serve(redirect_to_https(https_port))
+            .try_bind_with_graceful_shutdown(redirect_addr, async move { run.cancelled().await })
+        {
+            Ok((_, server)) => {
+                log::info!("listener: redirecting http://{} to https", redirect_addr);
+                tokio::spawn(server);
+            }
+            Err(e) => log::error!("listener: failed to bind redirect listener on {}: {:?}", redirect_addr, e),
+        }
+    }
+
+    log::info!(
+        "listener: serving {} on {}",
+        if acceptor.is_some() { "https" } else { "http" },
+        config.bind_addr
+    );
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::error!("listener: failed to accept connection: {:?}", e);
+                    sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            _ = shutdown.cancelled() => {
+                log::info!("listener: no longer accepting connections on {}", config.bind_addr);
+                return Ok(());
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let service = service.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let result = match acceptor {
+                #[cfg(feature = "tls")]
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(tls_stream) => serve_connection(tls_stream, service, shutdown).await,
+                    Err(e) => {
+                        log::debug!("listener: TLS handshake with {} failed: {:?}", peer, e);
+                        return;
+                    }
+                },
+                _ => serve_connection(stream, service, shutdown).await,
+            };
+            if let Err(e) = result {
+                log::debug!("listener: connection with {} ended with error: {:?}", peer, e);
+            }
+        });
+    }
+}
+
+// Lets in-flight requests finish on shutdown, then closes keep-alive connections
+async fn serve_connection<I, S>(io: I, service: S, shutdown: tokio_util::sync::CancellationToken) -> Result<(), warp::hyper::Error>
+where
+    I: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    S: warp::hyper::service::Service<warp::http::Request<warp::hyper::Body>, Response = warp::http::Response<warp::hyper::Body>, Error = std::convert::Infallible>
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    let connection = warp::hyper::server::conn::Http::new().serve_connection(io, service);
+    tokio::pin!(connection);
+    tokio::select! {
+        result = connection.as_mut() => result,
+        _ = shutdown.cancelled() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    }
+}
+
+fn redirect_to_https(https_port: u16) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    use warp::Reply;
+
+    warp::header::optional::<String>("host")
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .map(move |host: Option<String>, path: warp::path::FullPath, query: String| {
+            // Drop any port the client used for plain HTTP; an IPv6 host keeps its brackets
+            let host = match host.as_deref().map(str::parse::<warp::http::uri::Authority>) {
+                Some(Ok(authority)) if !authority.host().is_empty() => authority.host().to_string(),
+                _ => return warp::reply::with_status("Missing or invalid Host header", warp::http::StatusCode::BAD_REQUEST).into_response(),
+            };
+            let port = if https_port == 443 { String::new() } else { format!(":{}", https_port) };
+            let query = if query.is_empty() { query } else { format!("?{}", query) };
+            let location = format!("https://{}{}{}{}", host, port, path.as_str(), query);
+            warp::reply::with_status(
+                warp::reply::with_header(warp::reply(), "location", location),
+                warp::http::StatusCode::MOVED_PERMANENTLY,
+            )
+            .into_response()
+        })
+}
@@ -0,0 +1,192 @@
+// src/logging.rs
+
+use log4rs::append::console::ConsoleAppender;
+use log4rs::append::file::FileAppender;
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
+use log4rs::append::rolling_file::policy::compound::trigger::time::{TimeTrigger, TimeTriggerConfig, TimeTriggerInterval};
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::RollingFileAppender;
+use log4rs::append::Append;
+use log4rs::config::{Appender, Config, Root};
+use log4rs::encode::Encode;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::future::Future;
+use std::path::Path;
+
+// Who a log line is about. Set once per incoming message and carried through analysis, run creation and the reply.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LogContext {
+    pub correlation_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chat_id: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+}
+
+impl LogContext {
+    pub fn new() -> Self {
+        LogContext {
+            correlation_id: uuid::Uuid::new_v4().to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn for_chat(user_id: Option<i64>, chat_id: i64) -> Self {
+        LogContext {
+            user_id,
+            chat_id: Some(chat_id),
+            ..LogContext::new()
+        }
+    }
+}
+
+tokio::task_local! {
+    static CONTEXT: RefCell<LogContext>;
+}
+
+pub async fn with_context<F: Future>(context: LogContext, future: F) -> F::Output {
+    CONTEXT.scope(RefCell::new(context), future).await
+}
+
+// Task-locals don't follow tokio::spawn, so wrap spawned futures in this to keep the caller's context
+pub fn in_current_context<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    CONTEXT.scope(RefCell::new(current().unwrap_or_default()), future)
+}
+
+pub fn current() -> Option<LogContext> {
+    CONTEXT.try_with(|context| context.borrow().clone()).ok()
+}
+
+pub fn set_thread_id(thread_id: &str) {
+    let _ = CONTEXT.try_with(|context| context.borrow_mut().thread_id = Some(thread_id.to_string()));
+}
+
+pub fn set_run_id(run_id: &str) {
+    let _ = CONTEXT.try_with(|context| context.borrow_mut().run_id = Some(run_id.to_string()));
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    time: String,
+    level: &'a str,
+    target: &'a str,
+    message: String,
+    #[serde(flatten)]
+    context: Option<LogContext>,
+}
+
+// "{d} - {l} - {m}" like before, with the conversation context in between; or one JSON object per line.
+// Messages go through redact::redact first.
+#[derive(Debug)]
+pub struct ContextEncoder {
+    json: bool,
+}
+
+impl Encode for ContextEncoder {
+    fn encode(&self, w: &mut dyn log4rs::encode::Write, record: &log::Record) -> anyhow::Result<()> {
+        let time = chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%.f%:z").to_string();
+        let context = current();
+        let formatted = record.args().to_string();
+        let message = crate::redact::redact(&formatted);
+
+        if self.json {
+            let line = JsonLine {
+                time,
+                level: record.level().as_str(),
+                target: record.target(),
+                message: message.into_owned(),
+                context,
+            };
+            serde_json::to_writer(&mut *w, &line)?;
+            w.write_all(b"\n")?;
+            return Ok(());
+        }
+
+        let prefix = match context {
+            Some(context) => {
+                let mut fields = vec![format!("cid={}", context.correlation_id)];
+                if let Some(user_id) = context.user_id {
+                    fields.push(format!("user={}", user_id));
+                }
+                if let Some(chat_id) = context.chat_id {
+                    fields.push(format!("chat={}", chat_id));
+                }
+                if let Some(thread_id) = context.thread_id {
+                    fields.push(format!("thread={}", thread_id));
+                }
+                if let Some(run_id) = context.run_id {
+                    fields.push(format!("run={}", run_id));
+                }
+                format!("[{}] ", fields.join(" "))
+            }
+            None => String::new(),
+        };
+        writeln!(w, "{} - {} - {}{}", time, record.level(), prefix, message)?;
+        Ok(())
+    }
+}
+
+fn file_appender(path: &str, json: bool) -> Result<Box<dyn Append>, anyhow::Error> {
+    let encoder = Box::new(ContextEncoder { json });
+    let rotation = std::env::var("LOG_ROTATION").unwrap_or_else(|_| "none".to_string()).to_lowercase();
+    if rotation == "none" {
+        return Ok(Box::new(FileAppender::builder().encoder(encoder).build(path)?));
+    }
+
+    let trigger: Box<dyn log4rs::append::rolling_file::policy::compound::trigger::Trigger> = match rotation.as_str() {
+        "size" => {
+            let megabytes: u64 = std::env::var("LOG_ROTATE_SIZE_MB").ok().and_then(|v| v.parse().ok()).unwrap_or(50);
+            Box::new(SizeTrigger::new(megabytes * 1024 * 1024))
+        }
+        "hourly" | "daily" => Box::new(TimeTrigger::new(TimeTriggerConfig {
+            interval: if rotation == "hourly" { TimeTriggerInterval::Hour(1) } else { TimeTriggerInterval::Day(1) },
+            modulate: true,
+            max_random_delay: 0,
+        })),
+        other => anyhow::bail!("Unknown LOG_ROTATION {:?}, expected none, size, hourly or daily", other),
+    };
+    let keep: u32 = std::env::var("LOG_ROTATE_KEEP").ok().and_then(|v| v.parse().ok()).unwrap_or(7);
+    let roller = FixedWindowRoller::builder().build(&format!("{}.{{}}", path), keep)?;
+
+    Ok(Box::new(
+        RollingFileAppender::builder()
+            .encoder(encoder)
+            .build(path, Box::new(CompoundPolicy::new(trigger, Box::new(roller))))?,
+    ))
+}
+
+// LOG_FORMAT=text|json, LOG_OUTPUT=file|stdout|both, LOG_ROTATION=none|size|hourly|daily
+// (LOG_ROTATE_SIZE_MB, LOG_ROTATE_KEEP), LOG_FILE_PATH for the file
+pub fn init() -> Result<(), anyhow::Error> {
+    let json = std::env::var("LOG_FORMAT").map(|v| v.eq_ignore_ascii_case("json")).unwrap_or(false);
+    let output = std::env::var("LOG_OUTPUT").unwrap_or_else(|_| "file".to_string()).to_lowercase();
+    let log_file_path = std::env::var("LOG_FILE_PATH").unwrap_or_else(|_| "logs/webhooks_server.log".to_string());
+
+    let mut config = Config::builder();
+    let mut root = Root::builder();
+
+    if output == "file" || output == "both" {
+        if let Some(dir) = Path::new(&log_file_path).parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        config = config.appender(Appender::builder().build("logfile", file_appender(&log_file_path, json)?));
+        root = root.appender("logfile");
+    }
+    if output == "stdout" || output == "both" {
+        let stdout = ConsoleAppender::builder().encoder(Box::new(ContextEncoder { json })).build();
+        config = config.appender(Appender::builder().build("stdout", Box::new(stdout)));
+        root = root.appender("stdout");
+    }
+    if !matches!(output.as_str(), "file" | "stdout" | "both") {
+        anyhow::bail!("Unknown LOG_OUTPUT {:?}, expected file, stdout or both", output);
+    }
+
+    log4rs::init_config(config.build(root.build(log::LevelFilter::Info))?)?;
+    Ok(())
+}
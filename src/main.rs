@@ -1,79 +1,164 @@
 // src/main.rs
 
-use tokio::join;
 use dotenv::dotenv;
 use std::env;
+#[cfg(feature = "webhook-server")]
 use webhooks_server::webhooks::run_webhook_server;
+#[cfg(feature = "telegram")]
 use webhooks_server::telegram::run_telegram_bot;
+use webhooks_server::cli::Command;
 
 
 
-use std::fs;
 //use std::io::Write;
-use log::info;
-use log4rs::{
-    append::file::FileAppender, config::{Appender, Config, Root}, encode::pattern::PatternEncoder,
-};
 #[tokio::main]
 async fn main() {
     dotenv().ok(); // Load environment variables from .env file
-    
-    let log_file_path = env::var("LOG_FILE_PATH").unwrap_or_else(|_| "logs/webhooks_server.log".to_string());
 
-    // Ensure the logs directory exists
-    fs::create_dir_all("logs").expect("Failed to create logs directory");
+    let cli = <webhooks_server::cli::Cli as clap::Parser>::parse();
 
-    // Configure file logging
-    let logfile = log4rs::append::file::FileAppender::builder()
-        .encoder(Box::new(log4rs::encode::pattern::PatternEncoder::new("{d} - {l} - {m}{n}")))
-        .build(log_file_path)
-        .unwrap();
+    // Format, outputs and rotation come from LOG_* env vars, see logging::init
+    webhooks_server::logging::init().expect("Failed to initialize logging");
 
-    // Build the logger configuration
-    let config = log4rs::config::Config::builder()
-        .appender(log4rs::config::Appender::builder().build("logfile", Box::new(logfile)))
-        .build(log4rs::config::Root::builder().appender("logfile").build(log::LevelFilter::Info))
-        .unwrap();
+    log::info!("Logging started");
 
-    // Initialize logger
-    log4rs::init_config(config).unwrap();
+    let result = match cli.command.unwrap_or(Command::Serve(Default::default())) {
+        Command::Serve(args) => {
+            serve(!args.webhooks_only, !args.bot_only).await;
+            return;
+        }
+        Command::Migrate => webhooks_server::cli::migrate().await,
+        #[cfg(feature = "telegram")]
+        Command::Assistants(command) => webhooks_server::cli::assistants(command).await,
+        Command::Export(command) => webhooks_server::cli::export(command).await,
+        #[cfg(feature = "webhook-server")]
+        Command::ReplayWebhooks(args) => webhooks_server::cli::replay_webhooks(args).await,
+        #[cfg(feature = "webhook-server")]
+        Command::ApiKeys(command) => webhooks_server::cli::api_keys(command).await,
+        Command::CheckConfig => webhooks_server::cli::check_config().await,
+        #[cfg(all(feature = "telegram", feature = "test-support"))]
+        Command::Simulate(args) => webhooks_server::cli::simulate(args).await,
+    };
 
-    log::info!("Logging started");
+    if let Err(e) = result {
+        eprintln!("Error: {:#}", e);
+        std::process::exit(1);
+    }
+}
 
+async fn serve(run_bot: bool, run_webhooks: bool) {
     // Ensure environment variables are set
     let _openai_key = env::var("OPENAI_KEY").expect("OPENAI_KEY not set");
-    let _teloxide_token = env::var("TELOXIDE_TOKEN").expect("TELOXIDE_TOKEN not set");
+    if run_bot {
+        let _teloxide_token = env::var("TELOXIDE_TOKEN").expect("TELOXIDE_TOKEN not set");
+    }
 
-    // Create database config
-    let mut cfg = deadpool_postgres::Config::new();
-    cfg.host = Some(env::var("TELEGRAM_DATABASE_HOST").expect("TELEGRAM_DATABASE_HOST not set"));
-    cfg.port = Some(env::var("TELEGRAM_DATABASE_PORT").expect("TELEGRAM_DATABASE_PORT not set").parse().expect("Invalid port"));
-    cfg.user = Some(env::var("TELEGRAM_DATABASE_USER").expect("TELEGRAM_DATABASE_USER not set"));
-    cfg.password = Some(env::var("TELEGRAM_DATABASE_PASSWORD").expect("TELEGRAM_DATABASE_PASSWORD not set"));
-    cfg.dbname = Some(env::var("TELEGRAM_DATABASE_NAME").expect("TELEGRAM_DATABASE_NAME not set"));
-    cfg.manager = Some(deadpool_postgres::ManagerConfig { recycling_method: deadpool_postgres::RecyclingMethod::Fast });
+    // HTTP_FIXTURES_RECORD / HTTP_FIXTURES_REPLAY send OpenAI and Telegram traffic through http_fixtures
+    #[cfg(any(feature = "webhook-server", feature = "test-support"))]
+    let _http_fixtures = webhooks_server::http_fixtures::from_env().await.expect("Failed to start HTTP fixtures");
 
     // Create connection pool
-    let pool = cfg.create_pool(None, tokio_postgres::NoTls).unwrap();
-    
+    let pool = webhooks_server::database::create_pool().expect("Failed to create database pool");
+
     log::info!("Database connection pool created");
 
-    // Pass the pool to the webhook server and telegram bot
-    let webhook_server = {
-        let pool = pool.clone();
-        tokio::spawn(async move {
-            run_webhook_server(pool).await;
-            log::info!("Webhook server started");
-        })
-    };
+    // Bring the schema up to date before anything touches the database
+    webhooks_server::database::run_migrations(&pool).await.expect("Failed to run database migrations");
 
-    let telegram_bot = {
-        let pool = pool.clone();
-        tokio::spawn(async move {
-            run_telegram_bot(pool).await;
-            log::info!("Telegram bot started");
-        })
+    // Dedup cleanup lives for the whole process, not per bot restart
+    webhooks_server::dedup::spawn_cleanup(pool.clone());
+    // Outbound event deliveries that failed are retried from event_deliveries, including ones from before a restart
+    webhooks_server::events::spawn_retries(pool.clone());
+
+    // Pass the pool to the webhook server and telegram bot, each restarted by its supervisor if it dies
+    let mut subsystems: tokio::task::JoinSet<Result<(), anyhow::Error>> = tokio::task::JoinSet::new();
+    if run_webhooks {
+        #[cfg(feature = "webhook-server")]
+        subsystems.spawn(supervise("webhook_server", pool.clone(), run_webhook_server));
+        #[cfg(not(feature = "webhook-server"))]
+        log::warn!("Built without the webhook-server feature, not starting the webhook server");
+    }
+    if run_bot {
+        #[cfg(feature = "telegram")]
+        subsystems.spawn(supervise("telegram_bot", pool.clone(), run_telegram_bot));
+        #[cfg(not(feature = "telegram"))]
+        log::warn!("Built without the telegram feature, not starting the Telegram bot");
+    }
+
+    // Run until SIGTERM/Ctrl-C, or until a supervisor gives up on its subsystem
+    let exit_code = tokio::select! {
+        _ = webhooks_server::shutdown::wait_for_signal() => 0,
+        Some(result) = subsystems.join_next() => {
+            log::error!("Subsystem failed permanently, shutting down: {:?}", result);
+            1
+        }
     };
 
-    let _ = tokio::join!(webhook_server, telegram_bot);
-}
\ No newline at end of file
+    // Wind everything down within SHUTDOWN_GRACE_SECS
+    let grace_period = webhooks_server::shutdown::grace_period();
+    let deadline = tokio::time::Instant::now() + grace_period;
+    log::info!("Shutting down, grace period {:?}", grace_period);
+    webhooks_server::shutdown::trigger();
+
+    // Stop accepting webhooks and polling Telegram
+    if tokio::time::timeout_at(deadline, async { while subsystems.join_next().await.is_some() {} }).await.is_err() {
+        log::warn!("Webhook server or Telegram bot did not stop within the grace period");
+    }
+
+    // Replies being generated get until the deadline; open buffers and unsent replies are persisted for the next start
+    #[cfg(feature = "telegram")]
+    webhooks_server::telegram::drain(&pool, deadline).await;
+
+    pool.close();
+    log::info!("Shutdown complete");
+
+    // Non-zero so systemd restarts us
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+}
+
+// Restarts a subsystem whenever it panics or returns, with exponential backoff (1s, 2s, 4s, ... up to 60s).
+// Gives up after SUPERVISOR_MAX_RESTARTS failures in a row; a run lasting SUPERVISOR_STABLE_SECS resets the count.
+#[cfg(any(feature = "webhook-server", feature = "telegram"))]
+async fn supervise<F, Fut>(name: &'static str, pool: webhooks_server::database::Pool, run: F) -> Result<(), anyhow::Error>
+where
+    F: Fn(webhooks_server::database::Pool) -> Fut,
+    Fut: std::future::Future<Output = ()> + Send + 'static,
+{
+    let max_restarts: u32 = env::var("SUPERVISOR_MAX_RESTARTS").ok().and_then(|v| v.parse().ok()).unwrap_or(5);
+    let stable_after = tokio::time::Duration::from_secs(
+        env::var("SUPERVISOR_STABLE_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(300),
+    );
+    let mut failures = 0;
+
+    loop {
+        let started = tokio::time::Instant::now();
+        let result = tokio::spawn(run(pool.clone())).await;
+
+        if webhooks_server::shutdown::is_shutting_down() {
+            return Ok(());
+        }
+
+        match result {
+            Err(e) if e.is_panic() => log::error!("supervisor: {} panicked: {:?}", name, e),
+            _ => log::error!("supervisor: {} exited unexpectedly", name),
+        }
+
+        if started.elapsed() >= stable_after {
+            failures = 0;
+        }
+        failures += 1;
+        if failures > max_restarts {
+            anyhow::bail!("{} failed {} times in a row", name, failures);
+        }
+
+        // Doubles from 1s up to 60s; the shift can't overflow however high SUPERVISOR_MAX_RESTARTS is
+        let backoff = tokio::time::Duration::from_secs(1u64.checked_shl(failures - 1).unwrap_or(u64::MAX).min(60));
+        webhooks_server::metrics::SUBSYSTEM_RESTARTS.with_label_values(&[name]).inc();
+        log::warn!("supervisor: restarting {} in {:?} (restart {} of {})", name, backoff, failures, max_restarts);
+        if webhooks_server::shutdown::sleep_or_shutdown(backoff).await {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,75 @@
+// src/metrics.rs
+
+use prometheus::{Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+lazy_static::lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::new();
+
+    pub static ref MESSAGES_RECEIVED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("telegram_messages_received_total", "Telegram messages received, by message type"),
+        &["type"],
+    ).unwrap());
+
+    pub static ref RUNS_CREATED: IntCounter = register(IntCounter::new(
+        "openai_runs_created_total", "Assistant runs created on OpenAI threads",
+    ).unwrap());
+
+    // From run creation until the run is no longer active
+    pub static ref RUN_DURATION: Histogram = register(Histogram::with_opts(
+        HistogramOpts::new("openai_run_duration_seconds", "Time for an assistant run to complete")
+            .buckets(vec![0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0]),
+    ).unwrap());
+
+    pub static ref TRANSCRIPTION_DURATION: Histogram = register(Histogram::with_opts(
+        HistogramOpts::new("openai_transcription_duration_seconds", "Time to transcribe an audio or voice message")
+            .buckets(vec![0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0]),
+    ).unwrap());
+
+    pub static ref FOLLOW_UPS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("follow_ups_total", "Follow-ups to dormant users, by outcome: sent, failed or blocked"),
+        &["status"],
+    ).unwrap());
+
+    pub static ref BROADCAST_MESSAGES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("broadcast_messages_total", "Broadcast deliveries, by outcome: sent, failed, unreachable or opted_out"),
+        &["status"],
+    ).unwrap());
+
+    pub static ref SUBSYSTEM_RESTARTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("subsystem_restarts_total", "Times the supervisor restarted a subsystem after it died"),
+        &["subsystem"],
+    ).unwrap());
+
+    // Set at scrape time from the telegram message buffers
+    pub static ref BUFFERED_USERS: IntGauge = register(IntGauge::new(
+        "message_buffer_users", "Users with messages waiting in the debounce buffer",
+    ).unwrap());
+
+    pub static ref BUFFERED_MESSAGES: IntGauge = register(IntGauge::new(
+        "message_buffer_messages", "Messages waiting in the debounce buffer across all users",
+    ).unwrap());
+
+    pub static ref PENDING_DELAYED_REPLIES: IntGauge = register(IntGauge::new(
+        "pending_delayed_replies", "Generated replies waiting out their response cue before being sent",
+    ).unwrap());
+}
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(collector: T) -> T {
+    REGISTRY.register(Box::new(collector.clone())).expect("metric registered twice");
+    collector
+}
+
+// Prometheus text exposition format
+pub fn render() -> Result<String, anyhow::Error> {
+    #[cfg(feature = "telegram")]
+    {
+        let (users, messages) = crate::telegram::buffer_stats();
+        BUFFERED_USERS.set(users as i64);
+        BUFFERED_MESSAGES.set(messages as i64);
+        PENDING_DELAYED_REPLIES.set(crate::telegram::pending_reply_count() as i64);
+    }
+
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
@@ -0,0 +1,553 @@
+// src/mock_openai.rs
+
+// A local stand-in for the parts of the OpenAI API we call: threads, messages, runs, chat completions,
+// audio transcriptions, models and assistants. Start one, point OPENAI_BASE_URL at it with install(),
+// script what the assistants say and which calls fail, then inspect what was sent.
+//
+//     let openai = MockOpenAi::start().await;
+//     openai.install();
+//     openai.reply(ANALYZER_ID, &mock_openai::analysis(true, 8, Some(0)));
+//     openai.reply("asst_sales", "Hi! How can I help?");
+//     openai.fail_next(Endpoint::CreateRun, Failure::Status(500));
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::time::Duration;
+use warp::http::{Method, StatusCode};
+use warp::Filter;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    CreateThread,
+    CreateMessage,
+    ListMessages,
+    CreateRun,
+    GetRun,
+    ListRuns,
+    ChatCompletions,
+    AudioTranscriptions,
+    Models,
+    Assistants,
+}
+
+// What the next matching request gets instead of a normal answer. Delay answers normally, just late.
+#[derive(Debug, Clone)]
+pub enum Failure {
+    Status(u16),
+    Malformed,
+    Delay(Duration),
+}
+
+// How a run plays out: `in_progress_polls` GETs report in_progress, the next one reports `final_status`.
+// A completed run adds `reply` to the thread as the assistant's message.
+#[derive(Debug, Clone)]
+pub struct RunScript {
+    pub in_progress_polls: u32,
+    pub final_status: String,
+    pub reply: Option<String>,
+}
+
+impl RunScript {
+    pub fn completed(reply: &str) -> Self {
+        RunScript { in_progress_polls: 0, final_status: "completed".to_string(), reply: Some(reply.to_string()) }
+    }
+
+    pub fn failed() -> Self {
+        RunScript { in_progress_polls: 0, final_status: "failed".to_string(), reply: None }
+    }
+
+    pub fn after_polls(mut self, polls: u32) -> Self {
+        self.in_progress_polls = polls;
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub endpoint: Option<Endpoint>,
+    pub method: String,
+    pub path: String,
+    pub authorization: Option<String>,
+    // Null for bodies that aren't JSON, e.g. multipart transcription uploads
+    pub body: serde_json::Value,
+}
+
+#[derive(Debug, Clone)]
+pub struct MockMessage {
+    pub id: String,
+    pub role: String,
+    pub text: String,
+    pub run_id: Option<String>,
+    pub created_at: i64,
+}
+
+#[derive(Debug)]
+struct MockRun {
+    thread_id: String,
+    assistant_id: String,
+    status: String,
+    polls_left: u32,
+    script: RunScript,
+    created_at: i64,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    threads: HashMap<String, Vec<MockMessage>>,
+    runs: HashMap<String, MockRun>,
+    // Keyed by assistant id; None is for runs on any assistant without a script of its own
+    run_scripts: HashMap<Option<String>, VecDeque<RunScript>>,
+    default_run_scripts: HashMap<String, RunScript>,
+    completions: VecDeque<String>,
+    transcriptions: VecDeque<String>,
+    failures: HashMap<Endpoint, VecDeque<Failure>>,
+    requests: Vec<RecordedRequest>,
+    assistants: Vec<serde_json::Value>,
+}
+
+impl State {
+    fn id(&mut self, prefix: &str) -> String {
+        self.next_id += 1;
+        format!("{}_mock{}", prefix, self.next_id)
+    }
+
+    fn active_run_on(&self, thread_id: &str) -> Option<&str> {
+        self.runs
+            .iter()
+            .find(|(_, run)| run.thread_id == thread_id && matches!(run.status.as_str(), "queued" | "in_progress"))
+            .map(|(run_id, _)| run_id.as_str())
+    }
+
+    // A GET of the run, or a list with it first: moves it one poll along and adds its reply once completed
+    fn poll_run(&mut self, run_id: &str, now: i64) {
+        let message_id = self.id("msg");
+        let Some(run) = self.runs.get_mut(run_id) else {
+            return;
+        };
+
+        let mut reply = None;
+        if matches!(run.status.as_str(), "queued" | "in_progress") {
+            if run.polls_left > 0 {
+                run.polls_left -= 1;
+                run.status = "in_progress".to_string();
+            } else {
+                run.status = run.script.final_status.clone();
+                if run.status == "completed" {
+                    reply = run.script.reply.clone();
+                }
+            }
+        }
+
+        if let Some(text) = reply {
+            let thread_id = run.thread_id.clone();
+            let message = MockMessage { id: message_id, role: "assistant".to_string(), text, run_id: Some(run_id.to_string()), created_at: now };
+            self.threads.entry(thread_id).or_default().push(message);
+        }
+    }
+
+    fn next_run_script(&mut self, assistant_id: &str, thread_id: &str) -> RunScript {
+        for key in [Some(assistant_id.to_string()), None] {
+            if let Some(script) = self.run_scripts.get_mut(&key).and_then(|queue| queue.pop_front()) {
+                return script;
+            }
+        }
+        if let Some(script) = self.default_run_scripts.get(assistant_id) {
+            return script.clone();
+        }
+        // Unscripted runs echo the last user message so tests can still tell replies apart
+        let last_user_message = self
+            .threads
+            .get(thread_id)
+            .and_then(|messages| messages.iter().rev().find(|message| message.role == "user"))
+            .map(|message| message.text.clone())
+            .unwrap_or_default();
+        RunScript::completed(&format!("mock reply to: {}", last_user_message))
+    }
+}
+
+pub struct MockOpenAi {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    shutdown: tokio_util::sync::CancellationToken,
+}
+
+impl MockOpenAi {
+    // Binds 127.0.0.1 on a random port
+    pub async fn start() -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+        let shutdown = tokio_util::sync::CancellationToken::new();
+
+        let routes = {
+            let state = state.clone();
+            warp::path("v1")
+                .and(warp::path::tail())
+                .and(warp::method())
+                .and(warp::header::optional::<String>("authorization"))
+                .and(warp::body::bytes())
+                .then(move |tail: warp::path::Tail, method: Method, authorization: Option<String>, body: warp::hyper::body::Bytes| {
+                    handle(state.clone(), tail.as_str().to_string(), method, authorization, body)
+                })
+        };
+
+        let (addr, server) = {
+            let shutdown = shutdown.clone();
+            warp::serve(routes).bind_with_graceful_shutdown(([127, 0, 0, 1], 0), async move { shutdown.cancelled().await })
+        };
+        tokio::spawn(server);
+        log::debug!("mock_openai: listening on {}", addr);
+
+        MockOpenAi { addr, state, shutdown }
+    }
+
+    pub fn base_url(&self) -> String {
+        format!("http://{}/v1", self.addr)
+    }
+
+    // Sets OPENAI_BASE_URL for the whole process, plus OPENAI_KEY if it isn't set
+    pub fn install(&self) {
+        std::env::set_var("OPENAI_BASE_URL", self.base_url());
+        if std::env::var("OPENAI_KEY").is_err() {
+            std::env::set_var("OPENAI_KEY", "sk-mock");
+        }
+    }
+
+    // The next run of `assistant_id` completes with `text`
+    pub fn reply(&self, assistant_id: &str, text: &str) {
+        self.script_run(Some(assistant_id), RunScript::completed(text));
+    }
+
+    // Scripts the next run of `assistant_id`, or of any assistant without its own script when None
+    pub fn script_run(&self, assistant_id: Option<&str>, script: RunScript) {
+        let mut state = self.state.lock().unwrap();
+        state.run_scripts.entry(assistant_id.map(str::to_string)).or_default().push_back(script);
+    }
+
+    // What runs of `assistant_id` complete with once its scripted replies run out
+    pub fn default_reply(&self, assistant_id: &str, text: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.default_run_scripts.insert(assistant_id.to_string(), RunScript::completed(text));
+    }
+
+    pub fn chat_completion(&self, content: &str) {
+        self.state.lock().unwrap().completions.push_back(content.to_string());
+    }
+
+    pub fn transcription(&self, text: &str) {
+        self.state.lock().unwrap().transcriptions.push_back(text.to_string());
+    }
+
+    // Failures queue up per endpoint and are used one per request
+    pub fn fail_next(&self, endpoint: Endpoint, failure: Failure) {
+        self.state.lock().unwrap().failures.entry(endpoint).or_default().push_back(failure);
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    pub fn requests_to(&self, endpoint: Endpoint) -> Vec<RecordedRequest> {
+        self.requests().into_iter().filter(|request| request.endpoint == Some(endpoint)).collect()
+    }
+
+    // Oldest first
+    pub fn thread_messages(&self, thread_id: &str) -> Vec<MockMessage> {
+        self.state.lock().unwrap().threads.get(thread_id).cloned().unwrap_or_default()
+    }
+
+    pub fn thread_ids(&self) -> Vec<String> {
+        self.state.lock().unwrap().threads.keys().cloned().collect()
+    }
+
+    pub fn run_statuses(&self, thread_id: &str) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let mut runs: Vec<_> = state.runs.values().filter(|run| run.thread_id == thread_id).collect();
+        runs.sort_by_key(|run| run.created_at);
+        runs.into_iter().map(|run| run.status.clone()).collect()
+    }
+}
+
+impl Drop for MockOpenAi {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+// An Analyzing AI answer in the format parse_pre_processing_response expects
+pub fn analysis(qualified_to_respond: bool, interest_level: i32, respond_cue: Option<i32>) -> String {
+    format!(
+        "Qualified to Respond? {}\nInterest Level: {}\nRespond Cue: {}",
+        if qualified_to_respond { "yes" } else { "no" },
+        interest_level,
+        respond_cue.map(|cue| cue.to_string()).unwrap_or_else(|| "NA".to_string())
+    )
+}
+
+fn endpoint_for(method: &Method, segments: &[&str]) -> Option<Endpoint> {
+    match (method.as_str(), segments) {
+        ("POST", ["threads"]) => Some(Endpoint::CreateThread),
+        ("POST", ["threads", _, "messages"]) => Some(Endpoint::CreateMessage),
+        ("GET", ["threads", _, "messages"]) => Some(Endpoint::ListMessages),
+        ("POST", ["threads", _, "runs"]) => Some(Endpoint::CreateRun),
+        ("GET", ["threads", _, "runs", _]) => Some(Endpoint::GetRun),
+        ("GET", ["threads", _, "runs"]) => Some(Endpoint::ListRuns),
+        ("POST", ["chat", "completions"]) => Some(Endpoint::ChatCompletions),
+        ("POST", ["audio", "transcriptions"]) => Some(Endpoint::AudioTranscriptions),
+        ("GET", ["models"]) => Some(Endpoint::Models),
+        (_, ["assistants", ..]) => Some(Endpoint::Assistants),
+        _ => None,
+    }
+}
+
+fn json(status: StatusCode, value: serde_json::Value) -> warp::reply::Response {
+    warp::http::Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(value.to_string().into())
+        .unwrap()
+}
+
+fn openai_error(status: StatusCode, message: &str) -> warp::reply::Response {
+    json(status, serde_json::json!({
+        "error": { "message": message, "type": "invalid_request_error", "param": null, "code": null }
+    }))
+}
+
+fn message_json(thread_id: &str, message: &MockMessage) -> serde_json::Value {
+    serde_json::json!({
+        "id": message.id,
+        "object": "thread.message",
+        "created_at": message.created_at,
+        "thread_id": thread_id,
+        "role": message.role,
+        "content": [{ "type": "text", "text": { "value": message.text, "annotations": [] } }],
+        "run_id": message.run_id,
+    })
+}
+
+fn run_json(run_id: &str, run: &MockRun) -> serde_json::Value {
+    serde_json::json!({
+        "id": run_id,
+        "object": "thread.run",
+        "created_at": run.created_at,
+        "thread_id": run.thread_id,
+        "assistant_id": run.assistant_id,
+        "status": run.status,
+    })
+}
+
+// Accepts {"content": "..."} as well as the content-part array form
+fn content_text(content: &serde_json::Value) -> String {
+    match content {
+        serde_json::Value::String(text) => text.clone(),
+        serde_json::Value::Array(parts) => parts
+            .iter()
+            .filter_map(|part| part["text"].as_str().or_else(|| part["text"]["value"].as_str()))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+async fn handle(
+    state: Arc<Mutex<State>>,
+    path: String,
+    method: Method,
+    authorization: Option<String>,
+    body: warp::hyper::body::Bytes,
+) -> warp::reply::Response {
+    let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+    let endpoint = endpoint_for(&method, &segments);
+    let body_json: serde_json::Value = serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null);
+
+    let failure = {
+        let mut state = state.lock().unwrap();
+        state.requests.push(RecordedRequest {
+            endpoint,
+            method: method.to_string(),
+            path: format!("/v1/{}", path),
+            authorization,
+            body: body_json.clone(),
+        });
+        endpoint.and_then(|endpoint| state.failures.get_mut(&endpoint).and_then(|queue| queue.pop_front()))
+    };
+
+    match failure {
+        Some(Failure::Status(code)) => {
+            let status = StatusCode::from_u16(code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            return openai_error(status, "Injected failure");
+        }
+        Some(Failure::Malformed) => {
+            return warp::http::Response::builder()
+                .status(StatusCode::OK)
+                .header("content-type", "application/json")
+                .body("{\"id\": ".into())
+                .unwrap();
+        }
+        Some(Failure::Delay(delay)) => tokio::time::sleep(delay).await,
+        None => {}
+    }
+
+    let Some(endpoint) = endpoint else {
+        return openai_error(StatusCode::NOT_FOUND, &format!("Unknown endpoint {} /v1/{}", method, path));
+    };
+
+    let mut state = state.lock().unwrap();
+    let now = chrono::Utc::now().timestamp();
+
+    match endpoint {
+        Endpoint::CreateThread => {
+            let thread_id = state.id("thread");
+            let mut messages = Vec::new();
+            for message in body_json["messages"].as_array().cloned().unwrap_or_default() {
+                let id = state.id("msg");
+                messages.push(MockMessage {
+                    id,
+                    role: message["role"].as_str().unwrap_or("user").to_string(),
+                    text: content_text(&message["content"]),
+                    run_id: None,
+                    created_at: now,
+                });
+            }
+            state.threads.insert(thread_id.clone(), messages);
+            json(StatusCode::OK, serde_json::json!({ "id": thread_id, "object": "thread", "created_at": now }))
+        }
+        Endpoint::CreateMessage => {
+            let thread_id = segments[1].to_string();
+            if !state.threads.contains_key(&thread_id) {
+                return openai_error(StatusCode::NOT_FOUND, &format!("No thread found with id '{}'.", thread_id));
+            }
+            // Same rule as the real API: the thread is locked while a run is working on it
+            if let Some(run_id) = state.active_run_on(&thread_id) {
+                let message = format!("Can't add messages to {} while a run {} is active.", thread_id, run_id);
+                return openai_error(StatusCode::BAD_REQUEST, &message);
+            }
+            let message = MockMessage {
+                id: state.id("msg"),
+                role: body_json["role"].as_str().unwrap_or("user").to_string(),
+                text: content_text(&body_json["content"]),
+                run_id: None,
+                created_at: now,
+            };
+            let response = message_json(&thread_id, &message);
+            state.threads.get_mut(&thread_id).unwrap().push(message);
+            json(StatusCode::OK, response)
+        }
+        Endpoint::ListMessages => {
+            let thread_id = segments[1];
+            let Some(messages) = state.threads.get(thread_id) else {
+                return openai_error(StatusCode::NOT_FOUND, &format!("No thread found with id '{}'.", thread_id));
+            };
+            // Newest first, like the API's default order
+            let data: Vec<_> = messages.iter().rev().map(|message| message_json(thread_id, message)).collect();
+            json(StatusCode::OK, serde_json::json!({
+                "object": "list",
+                "data": data,
+                "first_id": data.first().map(|message| message["id"].clone()),
+                "last_id": data.last().map(|message| message["id"].clone()),
+                "has_more": false,
+            }))
+        }
+        Endpoint::CreateRun => {
+            let thread_id = segments[1].to_string();
+            if !state.threads.contains_key(&thread_id) {
+                return openai_error(StatusCode::NOT_FOUND, &format!("No thread found with id '{}'.", thread_id));
+            }
+            if let Some(run_id) = state.active_run_on(&thread_id) {
+                let message = format!("Thread {} already has an active run {}.", thread_id, run_id);
+                return openai_error(StatusCode::BAD_REQUEST, &message);
+            }
+            let Some(assistant_id) = body_json["assistant_id"].as_str().map(str::to_string) else {
+                return openai_error(StatusCode::BAD_REQUEST, "Missing required parameter: 'assistant_id'.");
+            };
+            let script = state.next_run_script(&assistant_id, &thread_id);
+            let run_id = state.id("run");
+            let run = MockRun {
+                thread_id,
+                assistant_id,
+                status: "queued".to_string(),
+                polls_left: script.in_progress_polls,
+                script,
+                created_at: now,
+            };
+            let response = run_json(&run_id, &run);
+            state.runs.insert(run_id, run);
+            json(StatusCode::OK, response)
+        }
+        Endpoint::GetRun => {
+            let (thread_id, run_id) = (segments[1], segments[3]);
+            if state.runs.get(run_id).is_none_or(|run| run.thread_id != thread_id) {
+                return openai_error(StatusCode::NOT_FOUND, &format!("No run found with id '{}'.", run_id));
+            }
+            state.poll_run(run_id, now);
+            json(StatusCode::OK, run_json(run_id, &state.runs[run_id]))
+        }
+        Endpoint::ListRuns => {
+            let thread_id = segments[1];
+            if !state.threads.contains_key(thread_id) {
+                return openai_error(StatusCode::NOT_FOUND, &format!("No thread found with id '{}'.", thread_id));
+            }
+            // Newest first, like the API; ids count up, so a longer one is newer
+            let mut run_ids: Vec<String> = state.runs.iter().filter(|(_, run)| run.thread_id == thread_id).map(|(run_id, _)| run_id.clone()).collect();
+            run_ids.sort_by(|a, b| (b.len(), b).cmp(&(a.len(), a)));
+            if let Some(newest) = run_ids.first() {
+                state.poll_run(newest, now);
+            }
+            let data: Vec<serde_json::Value> = run_ids.iter().map(|run_id| run_json(run_id, &state.runs[run_id])).collect();
+            json(StatusCode::OK, serde_json::json!({
+                "object": "list",
+                "data": data,
+                "first_id": data.first().map(|run| run["id"].clone()),
+                "last_id": data.last().map(|run| run["id"].clone()),
+                "has_more": false,
+            }))
+        }
+        Endpoint::ChatCompletions => {
+            let last_message = body_json["messages"].as_array().and_then(|messages| messages.last()).cloned().unwrap_or_default();
+            let content = state
+                .completions
+                .pop_front()
+                .unwrap_or_else(|| format!("mock completion for: {}", content_text(&last_message["content"])));
+            let id = state.id("chatcmpl");
+            json(StatusCode::OK, serde_json::json!({
+                "id": id,
+                "object": "chat.completion",
+                "created": now,
+                "model": body_json["model"],
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": content },
+                    "finish_reason": "stop",
+                }],
+            }))
+        }
+        Endpoint::AudioTranscriptions => {
+            let text = state.transcriptions.pop_front().unwrap_or_else(|| format!("mock transcription of {} bytes", body.len()));
+            json(StatusCode::OK, serde_json::json!({ "text": text }))
+        }
+        Endpoint::Models => json(StatusCode::OK, serde_json::json!({
+            "object": "list",
+            "data": [{ "id": "gpt-4o", "object": "model", "owned_by": "mock" }],
+        })),
+        Endpoint::Assistants => match (method.as_str(), &segments[1..]) {
+            ("GET", []) => json(StatusCode::OK, serde_json::json!({ "object": "list", "data": state.assistants })),
+            ("POST", []) => {
+                let assistant = serde_json::json!({
+                    "id": state.id("asst"),
+                    "object": "assistant",
+                    "created_at": now,
+                    "name": body_json["name"],
+                    "model": body_json["model"],
+                });
+                state.assistants.push(assistant.clone());
+                json(StatusCode::OK, assistant)
+            }
+            ("GET", [assistant_id]) => match state.assistants.iter().find(|assistant| assistant["id"] == *assistant_id) {
+                Some(assistant) => json(StatusCode::OK, assistant.clone()),
+                None => openai_error(StatusCode::NOT_FOUND, &format!("No assistant found with id '{}'.", assistant_id)),
+            },
+            _ => openai_error(StatusCode::NOT_FOUND, &format!("Unknown endpoint {} /v1/{}", method, path)),
+        },
+    }
+}
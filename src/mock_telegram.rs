@@ -0,0 +1,451 @@
+// src/mock_telegram.rs
+
+// A local stand-in for the Telegram Bot API: getMe, getUpdates (long polling), sendMessage, sendChatAction,
+// getFile, file downloads and the webhook methods. Tests push updates in as if users wrote to the bot and
+// assert on what the bot sent back. install() points TELEGRAM_API_URL (and so telegram::bot_from_env) at it.
+//
+//     let telegram = MockTelegram::start().await;
+//     telegram.install();
+//     telegram.push_text(42, "hi, how much is it?");
+//     let sent = telegram.wait_for_messages(1, Duration::from_secs(30)).await;
+//     assert_eq!(sent[0].chat_id, 42);
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::time::{Duration, Instant};
+use warp::http::StatusCode;
+use warp::Filter;
+
+pub const MOCK_BOT_TOKEN: &str = "123456789:mock-telegram-token-for-tests-only";
+const MOCK_BOT_ID: i64 = 123456789;
+
+// What the next call to a Bot API method gets instead of a normal answer
+#[derive(Debug, Clone)]
+pub enum Failure {
+    Error { code: u16, description: String },
+    RetryAfter(u64),
+    Delay(Duration),
+}
+
+impl Failure {
+    // What sendMessage answers once a user has blocked the bot
+    pub fn blocked() -> Self {
+        Failure::Error { code: 403, description: "Forbidden: bot was blocked by the user".to_string() }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordedCall {
+    pub method: String,
+    // Query, form, multipart text fields or JSON body, whichever the client used
+    pub params: serde_json::Value,
+}
+
+#[derive(Debug, Clone)]
+pub struct SentMessage {
+    pub message_id: i64,
+    pub chat_id: i64,
+    pub text: String,
+}
+
+struct MockFile {
+    file_path: String,
+    bytes: Vec<u8>,
+}
+
+#[derive(Default)]
+struct State {
+    next_update_id: i64,
+    next_message_id: i64,
+    updates: VecDeque<(i64, serde_json::Value)>,
+    files: HashMap<String, MockFile>,
+    calls: Vec<RecordedCall>,
+    sent: Vec<SentMessage>,
+    failures: HashMap<String, VecDeque<Failure>>,
+    webhook_url: Option<String>,
+}
+
+impl State {
+    fn message_id(&mut self) -> i64 {
+        self.next_message_id += 1;
+        self.next_message_id
+    }
+
+    fn push_message(&mut self, chat_id: i64, fields: serde_json::Value) -> i64 {
+        self.next_update_id += 1;
+        let update_id = self.next_update_id;
+        let mut message = serde_json::json!({
+            "message_id": self.message_id(),
+            "date": chrono::Utc::now().timestamp(),
+            "chat": { "id": chat_id, "type": "private", "first_name": "Test", "username": format!("user{}", chat_id) },
+            "from": { "id": chat_id, "is_bot": false, "first_name": "Test", "username": format!("user{}", chat_id) },
+        });
+        if let (Some(message), Some(fields)) = (message.as_object_mut(), fields.as_object()) {
+            message.extend(fields.clone());
+        }
+        self.updates.push_back((update_id, serde_json::json!({ "update_id": update_id, "message": message })));
+        update_id
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    // Wakes long-polling getUpdates calls and wait_for_messages
+    updates_pushed: Notify,
+    message_sent: Notify,
+}
+
+pub struct MockTelegram {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    shutdown: tokio_util::sync::CancellationToken,
+}
+
+impl MockTelegram {
+    // Binds 127.0.0.1 on a random port
+    pub async fn start() -> Self {
+        let shared = Arc::new(Shared {
+            // Message ids start at a random offset so a rerun against the same database isn't dropped by the
+            // processed-updates dedup, which is keyed on chat and message id
+            state: Mutex::new(State {
+                next_message_id: rand::Rng::gen_range(&mut rand::thread_rng(), 0..1_000_000_000),
+                ..State::default()
+            }),
+            updates_pushed: Notify::new(),
+            message_sent: Notify::new(),
+        });
+        let shutdown = tokio_util::sync::CancellationToken::new();
+
+        let api = {
+            let shared = shared.clone();
+            let shutdown = shutdown.clone();
+            warp::path!(String / String)
+                .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+                .and(warp::header::optional::<String>("content-type"))
+                .and(warp::body::bytes())
+                .then(move |bot: String, method: String, query: String, content_type: Option<String>, body: warp::hyper::body::Bytes| {
+                    let params = parse_params(&query, content_type.as_deref(), &body);
+                    handle_method(shared.clone(), shutdown.clone(), bot, method, params)
+                })
+        };
+        let files = {
+            let shared = shared.clone();
+            warp::path("file")
+                .and(warp::path::param::<String>())
+                .and(warp::path::tail())
+                .map(move |_bot: String, tail: warp::path::Tail| {
+                    let state = shared.state.lock().unwrap();
+                    match state.files.values().find(|file| file.file_path == tail.as_str()) {
+                        Some(file) => warp::http::Response::builder().status(StatusCode::OK).body(file.bytes.clone().into()).unwrap(),
+                        None => error_response(404, "Not Found"),
+                    }
+                })
+        };
+
+        let (addr, server) = {
+            let shutdown = shutdown.clone();
+            warp::serve(files.or(api).unify())
+                .bind_with_graceful_shutdown(([127, 0, 0, 1], 0), async move { shutdown.cancelled().await })
+        };
+        tokio::spawn(server);
+        log::debug!("mock_telegram: listening on {}", addr);
+
+        MockTelegram { addr, shared, shutdown }
+    }
+
+    pub fn api_url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    // Sets TELEGRAM_API_URL for the whole process, plus TELOXIDE_TOKEN if it isn't set
+    pub fn install(&self) {
+        std::env::set_var("TELEGRAM_API_URL", self.api_url());
+        if std::env::var("TELOXIDE_TOKEN").is_err() {
+            std::env::set_var("TELOXIDE_TOKEN", MOCK_BOT_TOKEN);
+        }
+    }
+
+    // A private-chat text message from user `chat_id`; returns the update id
+    pub fn push_text(&self, chat_id: i64, text: &str) -> i64 {
+        self.push_message(chat_id, serde_json::json!({ "text": text }))
+    }
+
+    // A voice note whose file can be fetched through getFile and downloaded
+    pub fn push_voice(&self, chat_id: i64, bytes: Vec<u8>, duration: u32) -> i64 {
+        let file_id = self.add_file("voice", "oga", bytes.clone());
+        self.push_message(chat_id, serde_json::json!({
+            "voice": {
+                "file_id": file_id,
+                "file_unique_id": format!("unique_{}", file_id),
+                "duration": duration,
+                "mime_type": "audio/ogg",
+                "file_size": bytes.len(),
+            }
+        }))
+    }
+
+    pub fn push_audio(&self, chat_id: i64, bytes: Vec<u8>, mime_type: &str, duration: u32) -> i64 {
+        let extension = mime_type.rsplit('/').next().unwrap_or("bin");
+        let file_id = self.add_file("music", extension, bytes.clone());
+        self.push_message(chat_id, serde_json::json!({
+            "audio": {
+                "file_id": file_id,
+                "file_unique_id": format!("unique_{}", file_id),
+                "duration": duration,
+                "mime_type": mime_type,
+                "file_size": bytes.len(),
+            }
+        }))
+    }
+
+    // Anything else: `fields` are merged into a message from `chat_id`
+    pub fn push_message(&self, chat_id: i64, fields: serde_json::Value) -> i64 {
+        let update_id = self.shared.state.lock().unwrap().push_message(chat_id, fields);
+        self.shared.updates_pushed.notify_waiters();
+        update_id
+    }
+
+    // Registers a downloadable file under <directory>/file_<n>.<extension>, like Telegram's paths, and returns its file_id
+    pub fn add_file(&self, directory: &str, extension: &str, bytes: Vec<u8>) -> String {
+        let mut state = self.shared.state.lock().unwrap();
+        let number = state.files.len() + 1;
+        let file_id = format!("{}_file_{}", directory, number);
+        let file_path = format!("{}/file_{}.{}", directory, number, extension);
+        state.files.insert(file_id.clone(), MockFile { file_path, bytes });
+        file_id
+    }
+
+    // Failures queue up per method name (e.g. "sendMessage") and are used one per call
+    pub fn fail_next(&self, method: &str, failure: Failure) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.failures.entry(method.to_lowercase()).or_default().push_back(failure);
+    }
+
+    pub fn sent_messages(&self) -> Vec<SentMessage> {
+        self.shared.state.lock().unwrap().sent.clone()
+    }
+
+    pub fn sent_to(&self, chat_id: i64) -> Vec<String> {
+        self.sent_messages().into_iter().filter(|message| message.chat_id == chat_id).map(|message| message.text).collect()
+    }
+
+    // Waits until at least `count` messages were sent, or the timeout passes; returns whatever was sent
+    pub async fn wait_for_messages(&self, count: usize, timeout: Duration) -> Vec<SentMessage> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let notified = self.shared.message_sent.notified();
+            let sent = self.sent_messages();
+            if sent.len() >= count || Instant::now() >= deadline {
+                return sent;
+            }
+            let _ = tokio::time::timeout_at(deadline, notified).await;
+        }
+    }
+
+    pub fn calls(&self) -> Vec<RecordedCall> {
+        self.shared.state.lock().unwrap().calls.clone()
+    }
+
+    pub fn calls_to(&self, method: &str) -> Vec<RecordedCall> {
+        self.calls().into_iter().filter(|call| call.method.eq_ignore_ascii_case(method)).collect()
+    }
+
+    // Updates pushed but not yet confirmed by a getUpdates offset
+    pub fn pending_updates(&self) -> usize {
+        self.shared.state.lock().unwrap().updates.len()
+    }
+
+    pub fn webhook_url(&self) -> Option<String> {
+        self.shared.state.lock().unwrap().webhook_url.clone()
+    }
+}
+
+impl Drop for MockTelegram {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+fn ok_response(result: serde_json::Value) -> warp::reply::Response {
+    let body = serde_json::json!({ "ok": true, "result": result });
+    warp::http::Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", "application/json")
+        .body(body.to_string().into())
+        .unwrap()
+}
+
+fn error_response(code: u16, description: &str) -> warp::reply::Response {
+    let body = serde_json::json!({ "ok": false, "error_code": code, "description": description });
+    warp::http::Response::builder()
+        .status(StatusCode::from_u16(code).unwrap_or(StatusCode::BAD_REQUEST))
+        .header("content-type", "application/json")
+        .body(body.to_string().into())
+        .unwrap()
+}
+
+// Query string, urlencoded form, multipart text fields or JSON, merged into one object
+fn parse_params(query: &str, content_type: Option<&str>, body: &[u8]) -> serde_json::Value {
+    let mut params = serde_json::Map::new();
+    let mut add_pairs = |encoded: &str| {
+        if let Ok(url) = reqwest::Url::parse(&format!("http://mock/?{}", encoded)) {
+            for (key, value) in url.query_pairs() {
+                params.insert(key.into_owned(), serde_json::Value::String(value.into_owned()));
+            }
+        }
+    };
+    add_pairs(query);
+
+    let content_type = content_type.unwrap_or_default();
+    if content_type.starts_with("application/x-www-form-urlencoded") {
+        add_pairs(&String::from_utf8_lossy(body));
+    } else if let Some(boundary) = content_type.split("boundary=").nth(1) {
+        for part in String::from_utf8_lossy(body).split(&format!("--{}", boundary.trim_matches('"'))) {
+            let Some((headers, value)) = part.split_once("\r\n\r\n") else { continue };
+            let Some(name) = headers.split("name=\"").nth(1).and_then(|rest| rest.split('"').next()) else { continue };
+            params.insert(name.to_string(), serde_json::Value::String(value.trim_end_matches("\r\n").to_string()));
+        }
+    } else if let Ok(serde_json::Value::Object(json)) = serde_json::from_slice(body) {
+        params.extend(json);
+    }
+
+    serde_json::Value::Object(params)
+}
+
+// Telegram accepts numbers and numeric strings alike
+fn int_param(params: &serde_json::Value, name: &str) -> Option<i64> {
+    match &params[name] {
+        serde_json::Value::Number(number) => number.as_i64(),
+        serde_json::Value::String(text) => text.parse().ok(),
+        _ => None,
+    }
+}
+
+fn bot_user() -> serde_json::Value {
+    serde_json::json!({ "id": MOCK_BOT_ID, "is_bot": true, "first_name": "Mock Bot", "username": "mock_bot" })
+}
+
+async fn handle_method(
+    shared: Arc<Shared>,
+    shutdown: tokio_util::sync::CancellationToken,
+    bot: String,
+    method: String,
+    params: serde_json::Value,
+) -> warp::reply::Response {
+    if !bot.starts_with("bot") {
+        return error_response(404, "Not Found");
+    }
+    let method_key = method.to_lowercase();
+
+    let failure = {
+        let mut state = shared.state.lock().unwrap();
+        state.calls.push(RecordedCall { method: method.clone(), params: params.clone() });
+        state.failures.get_mut(&method_key).and_then(|queue| queue.pop_front())
+    };
+    match failure {
+        Some(Failure::Error { code, description }) => return error_response(code, &description),
+        Some(Failure::RetryAfter(seconds)) => {
+            let body = serde_json::json!({
+                "ok": false,
+                "error_code": 429,
+                "description": format!("Too Many Requests: retry after {}", seconds),
+                "parameters": { "retry_after": seconds },
+            });
+            return warp::http::Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header("content-type", "application/json")
+                .body(body.to_string().into())
+                .unwrap();
+        }
+        Some(Failure::Delay(delay)) => tokio::time::sleep(delay).await,
+        None => {}
+    }
+
+    match method_key.as_str() {
+        "getme" => {
+            let mut me = bot_user();
+            me["can_join_groups"] = true.into();
+            me["can_read_all_group_messages"] = false.into();
+            me["supports_inline_queries"] = false.into();
+            ok_response(me)
+        }
+        "getupdates" => {
+            let offset = int_param(&params, "offset").unwrap_or(0);
+            let limit = int_param(&params, "limit").unwrap_or(100).clamp(1, 100) as usize;
+            let deadline = Instant::now() + Duration::from_secs(int_param(&params, "timeout").unwrap_or(0).max(0) as u64);
+            loop {
+                let notified = shared.updates_pushed.notified();
+                let updates: Vec<serde_json::Value> = {
+                    let mut state = shared.state.lock().unwrap();
+                    // An offset confirms every update before it, like the real API
+                    state.updates.retain(|(update_id, _)| *update_id >= offset);
+                    state.updates.iter().take(limit).map(|(_, update)| update.clone()).collect()
+                };
+                if !updates.is_empty() || Instant::now() >= deadline {
+                    return ok_response(serde_json::Value::Array(updates));
+                }
+                tokio::select! {
+                    _ = tokio::time::timeout_at(deadline, notified) => {}
+                    _ = shutdown.cancelled() => return ok_response(serde_json::json!([])),
+                }
+            }
+        }
+        "sendmessage" => {
+            let Some(chat_id) = int_param(&params, "chat_id") else {
+                return error_response(400, "Bad Request: chat_id is empty");
+            };
+            let text = params["text"].as_str().unwrap_or_default().to_string();
+            if text.is_empty() {
+                return error_response(400, "Bad Request: message text is empty");
+            }
+            if text.chars().count() > 4096 {
+                return error_response(400, "Bad Request: message is too long");
+            }
+            let message_id = {
+                let mut state = shared.state.lock().unwrap();
+                let message_id = state.message_id();
+                state.sent.push(SentMessage { message_id, chat_id, text: text.clone() });
+                message_id
+            };
+            shared.message_sent.notify_waiters();
+            ok_response(serde_json::json!({
+                "message_id": message_id,
+                "date": chrono::Utc::now().timestamp(),
+                "chat": { "id": chat_id, "type": "private", "first_name": "Test" },
+                "from": bot_user(),
+                "text": text,
+            }))
+        }
+        "sendchataction" => ok_response(serde_json::json!(true)),
+        "getfile" => {
+            let file_id = params["file_id"].as_str().unwrap_or_default().to_string();
+            let state = shared.state.lock().unwrap();
+            match state.files.get(&file_id) {
+                Some(file) => ok_response(serde_json::json!({
+                    "file_id": file_id,
+                    "file_unique_id": format!("unique_{}", file_id),
+                    "file_size": file.bytes.len(),
+                    "file_path": file.file_path,
+                })),
+                None => error_response(400, "Bad Request: invalid file_id"),
+            }
+        }
+        "setwebhook" => {
+            let url = params["url"].as_str().unwrap_or_default().to_string();
+            shared.state.lock().unwrap().webhook_url = if url.is_empty() { None } else { Some(url) };
+            ok_response(serde_json::json!(true))
+        }
+        "deletewebhook" => {
+            shared.state.lock().unwrap().webhook_url = None;
+            ok_response(serde_json::json!(true))
+        }
+        "getwebhookinfo" => {
+            let url = shared.state.lock().unwrap().webhook_url.clone().unwrap_or_default();
+            ok_response(serde_json::json!({ "url": url, "has_custom_certificate": false, "pending_update_count": 0 }))
+        }
+        // Everything else we don't model just succeeds
+        _ => ok_response(serde_json::json!(true)),
+    }
+}
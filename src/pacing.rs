@@ -0,0 +1,250 @@
+// src/pacing.rs
+
+// When and in what pieces a reply goes out, so it reads like someone answering from their phone: a wait before
+// starting that grows with the Analyzing AI's response cue and at night, then typing time for each chunk based on
+// its length. Long replies are split at paragraphs, then sentences, then words, and never past Telegram's limit.
+// Only computes; telegram.rs does the waiting and shows the typing indicator.
+
+use rand::Rng;
+use tokio::time::Duration;
+
+// Telegram rejects longer messages
+pub const TELEGRAM_MAX_MESSAGE_CHARS: usize = 4096;
+
+#[derive(Debug, Clone)]
+pub struct PacingConfig {
+    // Added to the response cue before the first chunk; the flat wait used to be cue + 30
+    pub base_delay: Duration,
+    pub chars_per_sec: f64,
+    // Cap on the typing time of one chunk
+    pub max_typing: Duration,
+    // Between night_start_hour and night_end_hour the wait before the first chunk is this much longer
+    pub night_factor: f64,
+    pub night_start_hour: u32,
+    pub night_end_hour: u32,
+    // Every delay is randomly up to this fraction shorter or longer
+    pub jitter: f64,
+    // Replies longer than this are split; chunks stay under it where the text allows
+    pub chunk_chars: usize,
+}
+
+impl PacingConfig {
+    // PACING_BASE_SECS (default 30), PACING_CHARS_PER_SEC (default 6), PACING_MAX_TYPING_SECS (default 60),
+    // PACING_NIGHT_FACTOR (default 2), PACING_NIGHT_START_HOUR (default 23), PACING_NIGHT_END_HOUR (default 7),
+    // PACING_JITTER (default 0.2) and PACING_CHUNK_CHARS (default 600, at most 4096)
+    pub fn from_env() -> Self {
+        let base_secs = std::env::var("PACING_BASE_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(30);
+        let max_typing_secs = std::env::var("PACING_MAX_TYPING_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(60);
+        let chunk_chars: usize = std::env::var("PACING_CHUNK_CHARS").ok().and_then(|v| v.parse().ok()).unwrap_or(600);
+        PacingConfig {
+            base_delay: Duration::from_secs(base_secs),
+            chars_per_sec: std::env::var("PACING_CHARS_PER_SEC").ok().and_then(|v| v.parse().ok()).unwrap_or(6.0),
+            max_typing: Duration::from_secs(max_typing_secs),
+            night_factor: std::env::var("PACING_NIGHT_FACTOR").ok().and_then(|v| v.parse().ok()).unwrap_or(2.0),
+            night_start_hour: std::env::var("PACING_NIGHT_START_HOUR").ok().and_then(|v| v.parse().ok()).unwrap_or(23),
+            night_end_hour: std::env::var("PACING_NIGHT_END_HOUR").ok().and_then(|v| v.parse().ok()).unwrap_or(7),
+            jitter: std::env::var("PACING_JITTER").ok().and_then(|v| v.parse().ok()).unwrap_or(0.2),
+            chunk_chars: chunk_chars.clamp(1, TELEGRAM_MAX_MESSAGE_CHARS),
+        }
+    }
+
+    fn is_night(&self, hour: u32) -> bool {
+        if self.night_start_hour <= self.night_end_hour {
+            (self.night_start_hour..self.night_end_hour).contains(&hour)
+        } else {
+            hour >= self.night_start_hour || hour < self.night_end_hour
+        }
+    }
+
+    fn jittered(&self, delay: Duration) -> Duration {
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return delay;
+        }
+        delay.mul_f64(rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter))
+    }
+
+    // Before starting to type the first chunk; `hour` is the user's local hour of the day
+    pub fn response_delay(&self, respond_cue: i32, hour: u32) -> Duration {
+        let delay = Duration::from_secs(respond_cue.max(0) as u64) + self.base_delay;
+        let delay = if self.is_night(hour) { delay.mul_f64(self.night_factor.max(0.0)) } else { delay };
+        self.jittered(delay)
+    }
+
+    // Shown as typing before a chunk goes out
+    pub fn typing_delay(&self, chunk: &str) -> Duration {
+        let secs = chunk.chars().count() as f64 / self.chars_per_sec.max(0.1);
+        self.jittered(Duration::from_secs_f64(secs).min(self.max_typing))
+    }
+
+    pub fn split(&self, text: &str) -> Vec<String> {
+        split_reply(text, self.chunk_chars)
+    }
+}
+
+// Splits `text` into chunks of at most `max_chars` characters, packing whole paragraphs, then sentences, then words
+// into each; only a single word longer than that is cut mid-word. Line breaks between sentences are kept. No
+// chunks for a blank text.
+pub fn split_reply(text: &str, max_chars: usize) -> Vec<String> {
+    let max_chars = max_chars.clamp(1, TELEGRAM_MAX_MESSAGE_CHARS);
+    let text = text.trim();
+    if text.is_empty() {
+        return Vec::new();
+    }
+    if text.chars().count() <= max_chars {
+        return vec![text.to_string()];
+    }
+
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    for paragraph in text.split("\n\n").map(str::trim).filter(|paragraph| !paragraph.is_empty()) {
+        for piece in pieces(paragraph, max_chars) {
+            if !current.is_empty() && current.chars().count() + piece.separator.len() + piece.text.chars().count() <= max_chars {
+                current.push_str(piece.separator);
+                current.push_str(&piece.text);
+            } else {
+                if !current.is_empty() {
+                    chunks.push(std::mem::take(&mut current));
+                }
+                current = piece.text;
+            }
+        }
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+struct Piece {
+    text: String,
+    // Joins it to the previous piece when both go in the same chunk
+    separator: &'static str,
+}
+
+// A paragraph as one piece if it fits, otherwise as sentences, and sentences that don't fit as runs of words
+fn pieces(paragraph: &str, max_chars: usize) -> Vec<Piece> {
+    if paragraph.chars().count() <= max_chars {
+        return vec![Piece { text: paragraph.to_string(), separator: "\n\n" }];
+    }
+
+    let mut pieces = Vec::new();
+    // The paragraph break before the first piece, then spaces, except where a sentence starts on a new line
+    let separator = |pieces: &Vec<Piece>, after_line_break: bool| match (pieces.is_empty(), after_line_break) {
+        (true, _) => "\n\n",
+        (false, true) => "\n",
+        (false, false) => " ",
+    };
+    for (sentence, after_line_break) in sentences(paragraph) {
+        if sentence.chars().count() <= max_chars {
+            pieces.push(Piece { text: sentence.to_string(), separator: separator(&pieces, after_line_break) });
+            continue;
+        }
+        let mut after_line_break = after_line_break;
+        let mut run = String::new();
+        for word in sentence.split_whitespace() {
+            let mut word: Vec<char> = word.chars().collect();
+            // Cut words too long for a chunk of their own
+            while word.len() > max_chars {
+                if !run.is_empty() {
+                    pieces.push(Piece { text: std::mem::take(&mut run), separator: separator(&pieces, after_line_break) });
+                    after_line_break = false;
+                }
+                let rest = word.split_off(max_chars);
+                pieces.push(Piece { text: word.into_iter().collect(), separator: separator(&pieces, after_line_break) });
+                after_line_break = false;
+                word = rest;
+            }
+            if !run.is_empty() && run.chars().count() + 1 + word.len() > max_chars {
+                pieces.push(Piece { text: std::mem::take(&mut run), separator: separator(&pieces, after_line_break) });
+                after_line_break = false;
+            }
+            if !run.is_empty() {
+                run.push(' ');
+            }
+            run.extend(word);
+        }
+        if !run.is_empty() {
+            pieces.push(Piece { text: run, separator: separator(&pieces, after_line_break) });
+        }
+    }
+    pieces
+}
+
+// Ends a sentence after ., ! or ? (and any closing quotes or brackets) followed by whitespace, or at a line break.
+// With each sentence, whether a line break came before it.
+fn sentences(paragraph: &str) -> Vec<(&str, bool)> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut after_line_break = false;
+    let mut chars = paragraph.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let end = match c {
+            '\n' => Some(i),
+            '.' | '!' | '?' => {
+                let mut end = i + c.len_utf8();
+                while let Some(&(j, next)) = chars.peek() {
+                    if !matches!(next, '.' | '!' | '?' | '"' | '\'' | ')' | ']' | '”' | '’') {
+                        break;
+                    }
+                    end = j + next.len_utf8();
+                    chars.next();
+                }
+                chars.peek().is_some_and(|&(_, next)| next.is_whitespace()).then_some(end)
+            }
+            _ => None,
+        };
+        if let Some(end) = end {
+            let sentence = paragraph[start..end].trim();
+            if !sentence.is_empty() {
+                sentences.push((sentence, after_line_break));
+                after_line_break = false;
+            }
+            after_line_break |= c == '\n';
+            start = end;
+        }
+    }
+    let rest = paragraph[start..].trim();
+    if !rest.is_empty() {
+        sentences.push((rest, after_line_break));
+    }
+    sentences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blank_text_has_no_chunks() {
+        assert!(split_reply("", 10).is_empty());
+        assert!(split_reply(" \n\n ", 10).is_empty());
+    }
+
+    #[test]
+    fn short_text_is_one_chunk() {
+        assert_eq!(split_reply("  Hi there!\nHow are you?  ", 100), vec!["Hi there!\nHow are you?"]);
+    }
+
+    #[test]
+    fn paragraphs_then_sentences_then_words() {
+        assert_eq!(split_reply("First one.\n\nSecond one.", 12), vec!["First one.", "Second one."]);
+        assert_eq!(split_reply("One. Two. Three is longer.", 12), vec!["One. Two.", "Three is", "longer."]);
+        assert_eq!(split_reply("abcdefghij klm", 4), vec!["abcd", "efgh", "ij", "klm"]);
+    }
+
+    #[test]
+    fn line_breaks_survive_a_split() {
+        let text = "Here is what we have:\n- a studio\n- a two bedroom\n\nLet me know.";
+        assert_eq!(split_reply(text, 40), vec!["Here is what we have:\n- a studio", "- a two bedroom\n\nLet me know."]);
+        assert_eq!(split_reply("Hi.\nSure thing. Call me", 16), vec!["Hi.\nSure thing.", "Call me"]);
+    }
+
+    #[test]
+    fn chunks_stay_within_the_limit() {
+        let text = "word ".repeat(300) + "\n\n" + &"x".repeat(50);
+        for chunk in split_reply(&text, 37) {
+            assert!(!chunk.is_empty() && chunk.chars().count() <= 37, "{:?}", chunk);
+        }
+    }
+}
@@ -0,0 +1,298 @@
+// src/quiet_hours.rs
+
+// Hours of the user's night in which nothing is sent to them unprompted: a reply whose response cue would land
+// there, or a proactive message, is deferred to the end of the window. Times are taken in the user's timezone
+// from the users row, which an admin can set and which is otherwise guessed from the phone number of a shared
+// contact or the Telegram language code, falling back to DEFAULT_TIMEZONE.
+
+use chrono::{DateTime, Duration, NaiveTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+
+lazy_static::lazy_static! {
+    pub static ref QUIET_HOURS: QuietHours = QuietHours::from_env();
+}
+
+// Where a users.timezone came from, weakest first; a guess never overwrites a stronger source
+pub const TIMEZONE_SOURCES: &[&str] = &["language", "phone", "configured"];
+
+#[derive(Debug, Clone)]
+pub struct QuietHours {
+    // Local start and end hour, e.g. (22, 8); None when turned off
+    pub window: Option<(u32, u32)>,
+    pub default_timezone: Tz,
+}
+
+impl QuietHours {
+    // QUIET_HOURS as "<start>-<end>" in local hours (default 22-8), or "off"; DEFAULT_TIMEZONE (default UTC) for
+    // users without one
+    pub fn from_env() -> Self {
+        let spec = std::env::var("QUIET_HOURS").unwrap_or_else(|_| "22-8".to_string());
+        let window = match parse_window(&spec) {
+            Some(window) => window,
+            None => {
+                log::warn!("Invalid QUIET_HOURS {:?}, expected e.g. 22-8 or off; using 22-8", spec);
+                Some((22, 8))
+            }
+        };
+        let default_timezone = std::env::var("DEFAULT_TIMEZONE").ok().and_then(|v| v.parse().ok()).unwrap_or(Tz::UTC);
+        QuietHours { window, default_timezone }
+    }
+
+    // The user's configured or inferred timezone, if it is a valid one, otherwise the default
+    pub fn timezone(&self, timezone: Option<&str>) -> Tz {
+        timezone.and_then(|name| name.parse().ok()).unwrap_or(self.default_timezone)
+    }
+
+    pub fn is_quiet(&self, at: DateTime<Utc>, timezone: Tz) -> bool {
+        let Some((start, end)) = self.window else {
+            return false;
+        };
+        in_window(at.with_timezone(&timezone).hour(), (start, end))
+    }
+
+    // `at` itself, or the end of the quiet hours it falls in
+    pub fn next_allowed(&self, at: DateTime<Utc>, timezone: Tz) -> DateTime<Utc> {
+        let Some((start, end)) = self.window else {
+            return at;
+        };
+        if !self.is_quiet(at, timezone) {
+            return at;
+        }
+        let local = at.with_timezone(&timezone);
+        // In the evening part of a window that wraps midnight, it ends tomorrow
+        let date = if start > end && local.hour() >= start { local.date_naive() + Duration::days(1) } else { local.date_naive() };
+        let end_local = date.and_time(NaiveTime::from_hms_opt(end, 0, 0).unwrap_or_default());
+        let end_at = timezone
+            .from_local_datetime(&end_local)
+            .earliest()
+            // The end hour was skipped by a DST change; an hour later exists
+            .or_else(|| timezone.from_local_datetime(&(end_local + Duration::hours(1))).earliest());
+        end_at.map_or(at, |end_at| end_at.with_timezone(&Utc))
+    }
+}
+
+// Whether `hour` is in [start, end), which wraps midnight when start > end
+pub fn in_window(hour: u32, (start, end): (u32, u32)) -> bool {
+    if start <= end {
+        (start..end).contains(&hour)
+    } else {
+        hour >= start || hour < end
+    }
+}
+
+// "<start>-<end>" in hours, or "off" for Some(None); None if invalid
+pub fn parse_window(spec: &str) -> Option<Option<(u32, u32)>> {
+    if spec.trim().eq_ignore_ascii_case("off") {
+        return Some(None);
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end): (u32, u32) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+    if start > 23 || end > 23 {
+        return None;
+    }
+    Some(if start == end { None } else { Some((start, end)) })
+}
+
+// Telegram's language_code, e.g. "de" or "pt-br". Only languages spoken mostly in one timezone, so plain "en"
+// or "es" stay unknown.
+pub fn infer_from_language(language_code: &str) -> Option<Tz> {
+    let code = language_code.to_ascii_lowercase().replace('_', "-");
+    let timezone = match code.as_str() {
+        "en-gb" => chrono_tz::Europe::London,
+        "pt-br" => chrono_tz::America::Sao_Paulo,
+        "es-mx" => chrono_tz::America::Mexico_City,
+        _ => match code.split('-').next().unwrap_or_default() {
+            "de" => chrono_tz::Europe::Berlin,
+            "fr" => chrono_tz::Europe::Paris,
+            "it" => chrono_tz::Europe::Rome,
+            "nl" => chrono_tz::Europe::Amsterdam,
+            "pl" => chrono_tz::Europe::Warsaw,
+            "uk" => chrono_tz::Europe::Kyiv,
+            "ru" => chrono_tz::Europe::Moscow,
+            "tr" => chrono_tz::Europe::Istanbul,
+            "cs" => chrono_tz::Europe::Prague,
+            "ro" => chrono_tz::Europe::Bucharest,
+            "el" => chrono_tz::Europe::Athens,
+            "sv" => chrono_tz::Europe::Stockholm,
+            "fi" => chrono_tz::Europe::Helsinki,
+            "he" => chrono_tz::Asia::Jerusalem,
+            "fa" => chrono_tz::Asia::Tehran,
+            "hi" => chrono_tz::Asia::Kolkata,
+            "th" => chrono_tz::Asia::Bangkok,
+            "vi" => chrono_tz::Asia::Ho_Chi_Minh,
+            "id" => chrono_tz::Asia::Jakarta,
+            "zh" => chrono_tz::Asia::Shanghai,
+            "ja" => chrono_tz::Asia::Tokyo,
+            "ko" => chrono_tz::Asia::Seoul,
+            _ => return None,
+        },
+    };
+    Some(timezone)
+}
+
+// From the country calling code of an international number ("+49 30 ...", "4930..."); for countries spanning
+// several timezones, the one most of the population lives in
+pub fn infer_from_phone(phone_number: &str) -> Option<Tz> {
+    let digits: String = phone_number.chars().filter(char::is_ascii_digit).collect();
+    const PREFIXES: &[(&str, Tz)] = &[
+        ("380", chrono_tz::Europe::Kyiv),
+        ("971", chrono_tz::Asia::Dubai),
+        ("972", chrono_tz::Asia::Jerusalem),
+        ("966", chrono_tz::Asia::Riyadh),
+        ("351", chrono_tz::Europe::Lisbon),
+        ("353", chrono_tz::Europe::Dublin),
+        ("358", chrono_tz::Europe::Helsinki),
+        ("420", chrono_tz::Europe::Prague),
+        ("1", chrono_tz::America::New_York),
+        ("7", chrono_tz::Europe::Moscow),
+        ("20", chrono_tz::Africa::Cairo),
+        ("27", chrono_tz::Africa::Johannesburg),
+        ("30", chrono_tz::Europe::Athens),
+        ("31", chrono_tz::Europe::Amsterdam),
+        ("32", chrono_tz::Europe::Brussels),
+        ("33", chrono_tz::Europe::Paris),
+        ("34", chrono_tz::Europe::Madrid),
+        ("39", chrono_tz::Europe::Rome),
+        ("40", chrono_tz::Europe::Bucharest),
+        ("41", chrono_tz::Europe::Zurich),
+        ("43", chrono_tz::Europe::Vienna),
+        ("44", chrono_tz::Europe::London),
+        ("45", chrono_tz::Europe::Copenhagen),
+        ("46", chrono_tz::Europe::Stockholm),
+        ("47", chrono_tz::Europe::Oslo),
+        ("48", chrono_tz::Europe::Warsaw),
+        ("49", chrono_tz::Europe::Berlin),
+        ("52", chrono_tz::America::Mexico_City),
+        ("54", chrono_tz::America::Argentina::Buenos_Aires),
+        ("55", chrono_tz::America::Sao_Paulo),
+        ("57", chrono_tz::America::Bogota),
+        ("61", chrono_tz::Australia::Sydney),
+        ("62", chrono_tz::Asia::Jakarta),
+        ("63", chrono_tz::Asia::Manila),
+        ("65", chrono_tz::Asia::Singapore),
+        ("66", chrono_tz::Asia::Bangkok),
+        ("81", chrono_tz::Asia::Tokyo),
+        ("82", chrono_tz::Asia::Seoul),
+        ("84", chrono_tz::Asia::Ho_Chi_Minh),
+        ("86", chrono_tz::Asia::Shanghai),
+        ("90", chrono_tz::Europe::Istanbul),
+        ("91", chrono_tz::Asia::Kolkata),
+    ];
+    PREFIXES.iter().find(|(prefix, _)| digits.starts_with(prefix)).map(|(_, timezone)| *timezone)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(text: &str) -> DateTime<Utc> {
+        text.parse().unwrap()
+    }
+
+    fn quiet_hours(window: (u32, u32)) -> QuietHours {
+        QuietHours { window: Some(window), default_timezone: Tz::UTC }
+    }
+
+    #[test]
+    fn windows_include_the_start_hour_but_not_the_end_hour() {
+        assert!(in_window(9, (9, 17)) && in_window(16, (9, 17)));
+        assert!(!in_window(17, (9, 17)) && !in_window(8, (9, 17)));
+    }
+
+    #[test]
+    fn windows_can_wrap_midnight() {
+        for hour in [22, 23, 0, 7] {
+            assert!(in_window(hour, (22, 8)), "{}", hour);
+        }
+        for hour in [8, 12, 21] {
+            assert!(!in_window(hour, (22, 8)), "{}", hour);
+        }
+    }
+
+    #[test]
+    fn parses_windows() {
+        assert_eq!(parse_window("22-8"), Some(Some((22, 8))));
+        assert_eq!(parse_window(" 0 - 6 "), Some(Some((0, 6))));
+        assert_eq!(parse_window("OFF"), Some(None));
+        // An empty window is as good as off
+        assert_eq!(parse_window("5-5"), Some(None));
+    }
+
+    #[test]
+    fn rejects_invalid_windows() {
+        for spec in ["", "22", "22-24", "-1-8", "ten-eight", "22-8-1", "22:00-08:00"] {
+            assert_eq!(parse_window(spec), None, "{:?}", spec);
+        }
+    }
+
+    #[test]
+    fn outside_quiet_hours_is_allowed_right_away() {
+        let at = utc("2024-06-10T12:00:00Z");
+        assert_eq!(quiet_hours((22, 8)).next_allowed(at, chrono_tz::Europe::Berlin), at);
+        let off = QuietHours { window: None, default_timezone: Tz::UTC };
+        assert_eq!(off.next_allowed(utc("2024-06-10T23:00:00Z"), Tz::UTC), utc("2024-06-10T23:00:00Z"));
+    }
+
+    #[test]
+    fn a_window_across_midnight_ends_the_next_morning() {
+        let quiet_hours = quiet_hours((22, 8));
+        let berlin = chrono_tz::Europe::Berlin;
+        // 23:30 in Berlin (UTC+2 in summer) waits for 08:00 the next day
+        assert_eq!(quiet_hours.next_allowed(utc("2024-06-10T21:30:00Z"), berlin), utc("2024-06-11T06:00:00Z"));
+        // 03:00 is already the morning part; it ends the same day
+        assert_eq!(quiet_hours.next_allowed(utc("2024-06-11T01:00:00Z"), berlin), utc("2024-06-11T06:00:00Z"));
+    }
+
+    #[test]
+    fn a_window_ending_in_a_dst_gap_ends_an_hour_later() {
+        // Berlin skips from 02:00 to 03:00 on 2024-03-31, so a window ending at 02:00 ends at 03:00 CEST
+        let at = utc("2024-03-31T00:00:00Z");
+        assert_eq!(quiet_hours((22, 2)).next_allowed(at, chrono_tz::Europe::Berlin), utc("2024-03-31T01:00:00Z"));
+    }
+
+    #[test]
+    fn falls_back_to_the_default_timezone() {
+        let quiet_hours = QuietHours { window: Some((22, 8)), default_timezone: chrono_tz::Asia::Tokyo };
+        assert_eq!(quiet_hours.timezone(Some("Europe/Berlin")), chrono_tz::Europe::Berlin);
+        assert_eq!(quiet_hours.timezone(Some("Mars/Olympus_Mons")), chrono_tz::Asia::Tokyo);
+        assert_eq!(quiet_hours.timezone(None), chrono_tz::Asia::Tokyo);
+    }
+
+    #[test]
+    fn infers_timezones_from_language_codes() {
+        assert_eq!(infer_from_language("de"), Some(chrono_tz::Europe::Berlin));
+        assert_eq!(infer_from_language("de-AT"), Some(chrono_tz::Europe::Berlin));
+        assert_eq!(infer_from_language("pt_BR"), Some(chrono_tz::America::Sao_Paulo));
+        assert_eq!(infer_from_language("en-GB"), Some(chrono_tz::Europe::London));
+        assert_eq!(infer_from_language("ES-MX"), Some(chrono_tz::America::Mexico_City));
+        // Spoken across many timezones
+        for code in ["en", "en-us", "es", "pt", "ar", ""] {
+            assert_eq!(infer_from_language(code), None, "{:?}", code);
+        }
+    }
+
+    #[test]
+    fn infers_timezones_from_calling_codes() {
+        assert_eq!(infer_from_phone("+1 (212) 555-0100"), Some(chrono_tz::America::New_York));
+        assert_eq!(infer_from_phone("+7 495 123-45-67"), Some(chrono_tz::Europe::Moscow));
+        assert_eq!(infer_from_phone("+44 20 7946 0958"), Some(chrono_tz::Europe::London));
+        assert_eq!(infer_from_phone("4930123456"), Some(chrono_tz::Europe::Berlin));
+        assert_eq!(infer_from_phone("+55 11 91234-5678"), Some(chrono_tz::America::Sao_Paulo));
+    }
+
+    #[test]
+    fn infers_timezones_from_three_digit_calling_codes() {
+        assert_eq!(infer_from_phone("+380 44 123 4567"), Some(chrono_tz::Europe::Kyiv));
+        assert_eq!(infer_from_phone("+971 4 123 4567"), Some(chrono_tz::Asia::Dubai));
+        assert_eq!(infer_from_phone("+972 2 123 4567"), Some(chrono_tz::Asia::Jerusalem));
+        assert_eq!(infer_from_phone("+351 21 123 4567"), Some(chrono_tz::Europe::Lisbon));
+        assert_eq!(infer_from_phone("+420 2 1234 5678"), Some(chrono_tz::Europe::Prague));
+    }
+
+    #[test]
+    fn unknown_or_local_numbers_have_no_timezone() {
+        for number in ["", "+999 123", "030 1234567", "0049 30 123456"] {
+            assert_eq!(infer_from_phone(number), None, "{:?}", number);
+        }
+    }
+}
@@ -0,0 +1,583 @@
+// src/receivers.rs
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+use serde::de::DeserializeOwned;
+use warp::Filter;
+use crate::admin_api::ApiError;
+
+const MAX_WEBHOOK_BODY_BYTES: u64 = 1024 * 1024;
+// Stored in place of credential headers in the webhook_events archive
+const REDACTED_HEADER: &str = "[redacted]";
+// Authorization and the headers of the built-in verifiers, masked again when archived events are read back since
+// events archived before masking on the way in still hold them
+const CREDENTIAL_HEADERS: &[&str] = &["authorization", "x-voner-secret", "x-hub-signature-256", "stripe-signature"];
+
+pub type HandlerFuture<'a> = Pin<Box<dyn Future<Output = Result<(), anyhow::Error>> + Send + 'a>>;
+
+#[derive(Debug)]
+pub enum VerificationError {
+    MissingHeader(&'static str),
+    Malformed(String),
+    InvalidSignature,
+    StaleTimestamp(i64),
+}
+
+impl std::fmt::Display for VerificationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerificationError::MissingHeader(header) => write!(f, "missing header {}", header),
+            VerificationError::Malformed(reason) => write!(f, "malformed signature: {}", reason),
+            VerificationError::InvalidSignature => write!(f, "signature does not match"),
+            VerificationError::StaleTimestamp(age) => write!(f, "timestamp is {} seconds away from now", age),
+        }
+    }
+}
+
+impl std::error::Error for VerificationError {}
+
+// How a provider proves a request came from them
+#[derive(Debug, Clone)]
+pub enum Verifier {
+    // Accept everything. Only for providers that can't sign their requests.
+    None,
+    // The header carries the shared secret as-is
+    SharedSecret { header: &'static str, secret: String },
+    // GitHub-style: header is `<prefix><hex HMAC-SHA256(secret, body)>`
+    HmacSha256 { header: &'static str, prefix: &'static str, secret: String },
+    // Stripe-style: header is `t=<unix ts>,v1=<hex HMAC-SHA256(secret, "{t}.{body}")>`, rejected outside the tolerance
+    TimestampedHmacSha256 { header: &'static str, secret: String, tolerance_secs: i64 },
+}
+
+impl Verifier {
+    pub fn github_style(secret: String) -> Self {
+        Verifier::HmacSha256 { header: "x-hub-signature-256", prefix: "sha256=", secret }
+    }
+
+    pub fn stripe_style(secret: String) -> Self {
+        Verifier::TimestampedHmacSha256 { header: "stripe-signature", secret, tolerance_secs: 300 }
+    }
+
+    // The header carrying the secret or signature, kept out of the webhook_events archive
+    pub fn header(&self) -> Option<&'static str> {
+        match self {
+            Verifier::None => None,
+            Verifier::SharedSecret { header, .. } | Verifier::HmacSha256 { header, .. } | Verifier::TimestampedHmacSha256 { header, .. } => Some(header),
+        }
+    }
+
+    pub fn verify(&self, headers: &warp::http::HeaderMap, body: &[u8]) -> Result<(), VerificationError> {
+        match self {
+            Verifier::None => Ok(()),
+            Verifier::SharedSecret { header, secret } => {
+                let value = header_str(headers, header)?;
+                // An empty secret would let an empty header through
+                if !secret.is_empty() && constant_time_eq(value.as_bytes(), secret.as_bytes()) {
+                    Ok(())
+                } else {
+                    Err(VerificationError::InvalidSignature)
+                }
+            }
+            Verifier::HmacSha256 { header, prefix, secret } => {
+                let value = header_str(headers, header)?;
+                let signature = value
+                    .strip_prefix(prefix)
+                    .ok_or_else(|| VerificationError::Malformed(format!("expected prefix {}", prefix)))?;
+                verify_hmac(secret, body, signature)
+            }
+            Verifier::TimestampedHmacSha256 { header, secret, tolerance_secs } => {
+                let value = header_str(headers, header)?;
+                let mut timestamp = None;
+                let mut signatures = Vec::new();
+                for part in value.split(',') {
+                    match part.trim().split_once('=') {
+                        Some(("t", t)) => timestamp = t.parse::<i64>().ok(),
+                        Some(("v1", signature)) => signatures.push(signature),
+                        _ => {}
+                    }
+                }
+                let timestamp = timestamp.ok_or_else(|| VerificationError::Malformed("missing t=".to_string()))?;
+                let age = chrono::Utc::now().timestamp() - timestamp;
+                if age.abs() > *tolerance_secs {
+                    return Err(VerificationError::StaleTimestamp(age));
+                }
+
+                let mut signed_payload = format!("{}.", timestamp).into_bytes();
+                signed_payload.extend_from_slice(body);
+                if signatures.iter().any(|signature| verify_hmac(secret, &signed_payload, signature).is_ok()) {
+                    Ok(())
+                } else {
+                    Err(VerificationError::InvalidSignature)
+                }
+            }
+        }
+    }
+}
+
+fn header_str<'a>(headers: &'a warp::http::HeaderMap, header: &'static str) -> Result<&'a str, VerificationError> {
+    headers
+        .get(header)
+        .ok_or(VerificationError::MissingHeader(header))?
+        .to_str()
+        .map_err(|_| VerificationError::Malformed(format!("{} is not valid ASCII", header)))
+}
+
+fn verify_hmac(secret: &str, payload: &[u8], hex_signature: &str) -> Result<(), VerificationError> {
+    let expected = hex::decode(hex_signature).map_err(|_| VerificationError::Malformed("signature is not hex".to_string()))?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(payload);
+    mac.verify_slice(&expected).map_err(|_| VerificationError::InvalidSignature)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// A webhook source: where it's mounted, how it's verified, what its payload looks like and what to do with it
+pub trait WebhookProvider: Send + Sync + 'static {
+    type Event: DeserializeOwned + Send;
+
+    // Stable name recorded in the webhook_events archive
+    fn name(&self) -> &'static str;
+
+    // Mounted at POST /webhooks/{path}
+    fn path(&self) -> &str;
+
+    fn verifier(&self) -> &Verifier;
+
+    fn parse(&self, body: &[u8]) -> Result<Self::Event, anyhow::Error> {
+        Ok(serde_json::from_slice(body)?)
+    }
+
+    fn handle(&self, event: Self::Event) -> HandlerFuture<'_>;
+}
+
+// Object-safe view of a provider so providers with different event types can share one registry
+pub trait ErasedProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn path(&self) -> &str;
+    fn verify(&self, headers: &warp::http::HeaderMap, body: &[u8]) -> Result<(), VerificationError>;
+    fn auth_header(&self) -> Option<&'static str>;
+    fn process<'a>(&'a self, body: &'a [u8]) -> HandlerFuture<'a>;
+}
+
+impl<P: WebhookProvider> ErasedProvider for P {
+    fn name(&self) -> &'static str {
+        WebhookProvider::name(self)
+    }
+
+    fn path(&self) -> &str {
+        WebhookProvider::path(self)
+    }
+
+    fn verify(&self, headers: &warp::http::HeaderMap, body: &[u8]) -> Result<(), VerificationError> {
+        self.verifier().verify(headers, body)
+    }
+
+    fn auth_header(&self) -> Option<&'static str> {
+        self.verifier().header()
+    }
+
+    fn process<'a>(&'a self, body: &'a [u8]) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            let event = self.parse(body)?;
+            self.handle(event).await
+        })
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct ReceiverRegistry {
+    providers: Vec<Arc<dyn ErasedProvider>>,
+}
+
+impl ReceiverRegistry {
+    pub fn new() -> Self {
+        ReceiverRegistry::default()
+    }
+
+    pub fn register<P: WebhookProvider>(mut self, provider: P) -> Self {
+        log::info!("receivers: registered {} at /webhooks/{}", WebhookProvider::name(&provider), WebhookProvider::path(&provider));
+        self.providers.push(Arc::new(provider));
+        self
+    }
+
+    pub fn find_by_path(&self, path: &str) -> Option<Arc<dyn ErasedProvider>> {
+        self.providers.iter().find(|p| p.path() == path).cloned()
+    }
+
+    pub fn find_by_name(&self, name: &str) -> Option<Arc<dyn ErasedProvider>> {
+        self.providers.iter().find(|p| p.name() == name).cloned()
+    }
+
+    pub fn provider_names(&self) -> Vec<&str> {
+        self.providers.iter().map(|p| p.name()).collect()
+    }
+
+    // POST /webhooks/{provider path}
+    pub fn routes(registry: Arc<ReceiverRegistry>, pool: crate::database::Pool) -> warp::filters::BoxedFilter<(warp::reply::Response,)> {
+        warp::path("webhooks")
+            .and(warp::path::tail())
+            .and(warp::post())
+            .and(warp::header::headers_cloned())
+            .and(warp::body::content_length_limit(MAX_WEBHOOK_BODY_BYTES))
+            .and(warp::body::bytes())
+            .and_then(move |tail: warp::path::Tail, headers: warp::http::HeaderMap, body: warp::hyper::body::Bytes| {
+                let registry = registry.clone();
+                let pool = pool.clone();
+                async move { receive(registry, pool, tail.as_str().trim_end_matches('/').to_string(), headers, body).await }
+            })
+            .boxed()
+    }
+}
+
+async fn receive(
+    registry: Arc<ReceiverRegistry>,
+    pool: crate::database::Pool,
+    path: String,
+    headers: warp::http::HeaderMap,
+    body: warp::hyper::body::Bytes,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    use warp::Reply;
+
+    let provider = registry
+        .find_by_path(&path)
+        .ok_or_else(|| warp::reject::custom(ApiError::not_found(format!("No webhook provider at /webhooks/{}", path))))?;
+
+    let verification = provider.verify(&headers, &body);
+    let verification_error = verification.as_ref().err().map(|e| e.to_string());
+
+    // Archive first so nothing is lost even if the handler fails or the process dies mid-way. Without an archive row
+    // the event could not be replayed, so it is refused and left to the provider to retry.
+    let archived_id = match crate::database::insert_webhook_event(
+        &pool,
+        provider.name(),
+        &path,
+        &headers_to_json(&headers, provider.auth_header()),
+        &body,
+        verification.is_ok(),
+        verification_error.as_deref(),
+    ).await {
+        Ok(id) => id,
+        Err(e) => {
+            log::error!("receivers: failed to archive {} webhook, refusing it: {:?}", provider.name(), e);
+            return Err(warp::reject::custom(ApiError::new(
+                warp::http::StatusCode::SERVICE_UNAVAILABLE,
+                "archive_unavailable",
+                "Could not store the webhook, try again later",
+            )));
+        }
+    };
+
+    if let Some(error) = verification_error {
+        log::warn!("receivers: rejected {} webhook: {}", provider.name(), error);
+        return Err(warp::reject::custom(ApiError::new(warp::http::StatusCode::UNAUTHORIZED, "invalid_signature", error)));
+    }
+
+    let result = provider.process(&body).await;
+    record_outcome(&pool, archived_id, &result).await;
+
+    match result {
+        Ok(()) => Ok(warp::reply::json(&serde_json::json!({ "status": "ok" })).into_response()),
+        Err(e) => {
+            log::error!("receivers: {} handler failed: {:?}", provider.name(), e);
+            Err(warp::reject::custom(ApiError::new(warp::http::StatusCode::UNPROCESSABLE_ENTITY, "handler_failed", e.to_string())))
+        }
+    }
+}
+
+async fn record_outcome(pool: &crate::database::Pool, event_id: i64, result: &Result<(), anyhow::Error>) {
+    let update = match result {
+        Ok(()) => crate::database::mark_webhook_event_processed(pool, event_id).await,
+        Err(e) => crate::database::mark_webhook_event_dead_letter(pool, event_id, &format!("{:#}", e)).await,
+    };
+    if let Err(e) = update {
+        log::error!("receivers: failed to record outcome of webhook event {}: {:?}", event_id, e);
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ReplayOutcome {
+    pub event_id: i64,
+    pub provider: String,
+    pub succeeded: bool,
+    pub error: Option<String>,
+}
+
+// Runs an archived event through the handler currently registered for its provider
+pub async fn replay_event(registry: &ReceiverRegistry, pool: &crate::database::Pool, event: &crate::DBWebhookEvent) -> ReplayOutcome {
+    let outcome = |error: Option<String>| ReplayOutcome {
+        event_id: event.id,
+        provider: event.provider.clone(),
+        succeeded: error.is_none(),
+        error,
+    };
+
+    if !event.verified {
+        return outcome(Some("event failed signature verification and cannot be replayed".to_string()));
+    }
+    let provider = match registry.find_by_name(&event.provider) {
+        Some(provider) => provider,
+        None => return outcome(Some(format!("no provider named {} is registered", event.provider))),
+    };
+
+    log::info!("receivers: replaying webhook event {} ({}, attempt {})", event.id, event.provider, event.attempts + 1);
+    let result = provider.process(&event.body).await;
+    record_outcome(pool, event.id, &result).await;
+    outcome(result.err().map(|e| format!("{:#}", e)))
+}
+
+// Replays every event in the given state (dead_letter, or received ones a crash never finished), oldest first
+pub async fn replay_events(registry: &ReceiverRegistry, pool: &crate::database::Pool, status: &str, provider: Option<&str>, limit: i64) -> Result<Vec<ReplayOutcome>, anyhow::Error> {
+    let events = crate::database::list_webhook_events(pool, Some(status), provider, limit, 0).await?;
+    let mut outcomes = Vec::with_capacity(events.len());
+    for event in &events {
+        outcomes.push(replay_event(registry, pool, event).await);
+    }
+    Ok(outcomes)
+}
+
+// Headers as archived in webhook_events, with Authorization and the provider's own secret or signature header masked
+pub fn headers_to_json(headers: &warp::http::HeaderMap, auth_header: Option<&str>) -> serde_json::Value {
+    let map = headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if *name == warp::http::header::AUTHORIZATION || Some(name.as_str()) == auth_header {
+                REDACTED_HEADER.to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.as_str().to_string(), serde_json::Value::String(value))
+        })
+        .collect::<serde_json::Map<_, _>>();
+    serde_json::Value::Object(map)
+}
+
+// Masks credential headers in an archived event's headers before they leave the server
+pub fn redact_archived_headers(headers: &mut serde_json::Value) {
+    if let Some(map) = headers.as_object_mut() {
+        for (name, value) in map.iter_mut() {
+            if CREDENTIAL_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+                *value = serde_json::Value::String(REDACTED_HEADER.to_string());
+            }
+        }
+    }
+}
+
+
+
+//--providers--//
+
+// Declarative provider for JSON payloads: name, path, verifier and a closure
+pub struct JsonProvider {
+    name: &'static str,
+    path: String,
+    verifier: Verifier,
+    handler: Arc<dyn Fn(serde_json::Value) -> HandlerFuture<'static> + Send + Sync>,
+}
+
+impl JsonProvider {
+    pub fn new<F, Fut>(name: &'static str, path: &str, verifier: Verifier, handler: F) -> Self
+    where
+        F: Fn(serde_json::Value) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), anyhow::Error>> + Send + 'static,
+    {
+        JsonProvider {
+            name,
+            path: path.to_string(),
+            verifier,
+            handler: Arc::new(move |event| Box::pin(handler(event))),
+        }
+    }
+}
+
+impl WebhookProvider for JsonProvider {
+    type Event = serde_json::Value;
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn verifier(&self) -> &Verifier {
+        &self.verifier
+    }
+
+    fn handle(&self, event: Self::Event) -> HandlerFuture<'_> {
+        (self.handler)(event)
+    }
+}
+
+// Voner delivery receipts
+#[derive(Debug, serde::Deserialize)]
+pub struct VonerMessageStatus {
+    pub message_id: Option<String>,
+    pub status: Option<String>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+pub struct VonerMessageStatusProvider {
+    verifier: Verifier,
+}
+
+impl WebhookProvider for VonerMessageStatusProvider {
+    type Event = VonerMessageStatus;
+
+    fn name(&self) -> &'static str {
+        "voner-message-status"
+    }
+
+    fn path(&self) -> &str {
+        "message-status"
+    }
+
+    fn verifier(&self) -> &Verifier {
+        &self.verifier
+    }
+
+    fn handle(&self, event: Self::Event) -> HandlerFuture<'_> {
+        Box::pin(async move {
+            log::info!("Received message status: {:?} for message {:?}", event.status, event.message_id);
+            Ok(())
+        })
+    }
+}
+
+// Voner signs with a shared secret header. Without one (or with an empty one) the Voner endpoints aren't mounted,
+// unless VONER_WEBHOOK_ALLOW_UNAUTHENTICATED=true opens them on purpose.
+fn voner_verifier() -> Option<Verifier> {
+    match std::env::var("VONER_WEBHOOK_SECRET") {
+        Ok(secret) if !secret.is_empty() => Some(Verifier::SharedSecret { header: "x-voner-secret", secret }),
+        _ if std::env::var("VONER_WEBHOOK_ALLOW_UNAUTHENTICATED").ok().and_then(|v| v.parse().ok()).unwrap_or(false) => {
+            log::warn!("VONER_WEBHOOK_SECRET not set and VONER_WEBHOOK_ALLOW_UNAUTHENTICATED=true; Voner webhooks are accepted unauthenticated");
+            Some(Verifier::None)
+        }
+        _ => {
+            log::warn!("VONER_WEBHOOK_SECRET not set; the Voner webhook endpoints are not mounted");
+            None
+        }
+    }
+}
+
+// Every provider the server knows about. New providers are added here.
+pub fn default_registry() -> ReceiverRegistry {
+    let mut registry = ReceiverRegistry::new();
+
+    if let Some(verifier) = voner_verifier() {
+        registry = registry
+            .register(VonerMessageStatusProvider { verifier: verifier.clone() })
+            .register(JsonProvider::new("voner-inbound-message", "inbound-message", verifier, |body| async move {
+                log::info!("Received inbound message: {}", crate::redact::body(&body));
+                Ok(())
+            }));
+    }
+
+    if let Ok(secret) = std::env::var("STRIPE_WEBHOOK_SECRET") {
+        registry = registry.register(JsonProvider::new("stripe", "stripe", Verifier::stripe_style(secret), |body| async move {
+            log::info!("Received stripe event {:?}", body["type"]);
+            Ok(())
+        }));
+    }
+
+    if let Ok(secret) = std::env::var("GITHUB_WEBHOOK_SECRET") {
+        registry = registry.register(JsonProvider::new("github", "github", Verifier::github_style(secret), |body| async move {
+            log::info!("Received github event for {:?}", body["repository"]["full_name"]);
+            Ok(())
+        }));
+    }
+
+    registry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = br#"{"id":"evt_1"}"#;
+
+    fn headers(header: &'static str, value: &str) -> warp::http::HeaderMap {
+        let mut headers = warp::http::HeaderMap::new();
+        headers.insert(header, value.parse().unwrap());
+        headers
+    }
+
+    fn hmac_hex(secret: &str, payload: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(payload);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn stripe_header(secret: &str, timestamp: i64, body: &[u8]) -> String {
+        let mut payload = format!("{}.", timestamp).into_bytes();
+        payload.extend_from_slice(body);
+        format!("t={},v1={}", timestamp, hmac_hex(secret, &payload))
+    }
+
+    #[test]
+    fn shared_secret() {
+        let verifier = Verifier::SharedSecret { header: "x-voner-secret", secret: "s3cret".to_string() };
+        assert!(verifier.verify(&headers("x-voner-secret", "s3cret"), BODY).is_ok());
+        assert!(matches!(verifier.verify(&headers("x-voner-secret", "s3cre7"), BODY), Err(VerificationError::InvalidSignature)));
+        assert!(matches!(verifier.verify(&headers("x-voner-secret", "s3cret-and-more"), BODY), Err(VerificationError::InvalidSignature)));
+        assert!(matches!(verifier.verify(&warp::http::HeaderMap::new(), BODY), Err(VerificationError::MissingHeader("x-voner-secret"))));
+        let mut non_ascii = warp::http::HeaderMap::new();
+        non_ascii.insert("x-voner-secret", warp::http::HeaderValue::from_bytes(b"s3cr\xe9t").unwrap());
+        assert!(matches!(verifier.verify(&non_ascii, BODY), Err(VerificationError::Malformed(_))));
+
+        // An empty secret matches nothing, not even an empty header
+        let empty = Verifier::SharedSecret { header: "x-voner-secret", secret: String::new() };
+        assert!(matches!(empty.verify(&headers("x-voner-secret", ""), BODY), Err(VerificationError::InvalidSignature)));
+    }
+
+    #[test]
+    fn hmac_sha256() {
+        let verifier = Verifier::github_style("s3cret".to_string());
+        let signature = format!("sha256={}", hmac_hex("s3cret", BODY));
+        assert!(verifier.verify(&headers("x-hub-signature-256", &signature), BODY).is_ok());
+        // Signed with another secret, or over another body
+        let other_secret = format!("sha256={}", hmac_hex("other", BODY));
+        assert!(matches!(verifier.verify(&headers("x-hub-signature-256", &other_secret), BODY), Err(VerificationError::InvalidSignature)));
+        assert!(matches!(verifier.verify(&headers("x-hub-signature-256", &signature), b"{}"), Err(VerificationError::InvalidSignature)));
+        assert!(matches!(verifier.verify(&warp::http::HeaderMap::new(), BODY), Err(VerificationError::MissingHeader("x-hub-signature-256"))));
+        // No prefix, or not hex
+        let unprefixed = hmac_hex("s3cret", BODY);
+        assert!(matches!(verifier.verify(&headers("x-hub-signature-256", &unprefixed), BODY), Err(VerificationError::Malformed(_))));
+        assert!(matches!(verifier.verify(&headers("x-hub-signature-256", "sha256=zz"), BODY), Err(VerificationError::Malformed(_))));
+    }
+
+    #[test]
+    fn timestamped_hmac_sha256() {
+        let verifier = Verifier::stripe_style("whsec_test".to_string());
+        let now = chrono::Utc::now().timestamp();
+        assert!(verifier.verify(&headers("stripe-signature", &stripe_header("whsec_test", now, BODY)), BODY).is_ok());
+        // Any of several v1 signatures may match, as during a secret rotation
+        let current = stripe_header("whsec_test", now, BODY);
+        let rotated = format!("{},{}", stripe_header("whsec_old", now, BODY), current.split_once(',').unwrap().1);
+        assert!(verifier.verify(&headers("stripe-signature", &rotated), BODY).is_ok());
+
+        let wrong = stripe_header("whsec_other", now, BODY);
+        assert!(matches!(verifier.verify(&headers("stripe-signature", &wrong), BODY), Err(VerificationError::InvalidSignature)));
+        // The timestamp is signed too
+        let signature = stripe_header("whsec_test", now, BODY);
+        let moved = signature.replacen(&format!("t={}", now), &format!("t={}", now - 1), 1);
+        assert!(matches!(verifier.verify(&headers("stripe-signature", &moved), BODY), Err(VerificationError::InvalidSignature)));
+        assert!(matches!(verifier.verify(&warp::http::HeaderMap::new(), BODY), Err(VerificationError::MissingHeader("stripe-signature"))));
+        assert!(matches!(verifier.verify(&headers("stripe-signature", "v1=abcd"), BODY), Err(VerificationError::Malformed(_))));
+        assert!(matches!(verifier.verify(&headers("stripe-signature", "t=soon,v1=abcd"), BODY), Err(VerificationError::Malformed(_))));
+        assert!(matches!(verifier.verify(&headers("stripe-signature", &format!("t={}", now)), BODY), Err(VerificationError::InvalidSignature)));
+
+        for timestamp in [now - 301, now + 301] {
+            let stale = stripe_header("whsec_test", timestamp, BODY);
+            assert!(matches!(verifier.verify(&headers("stripe-signature", &stale), BODY), Err(VerificationError::StaleTimestamp(_))));
+        }
+        let edge = stripe_header("whsec_test", now - 290, BODY);
+        assert!(verifier.verify(&headers("stripe-signature", &edge), BODY).is_ok());
+    }
+}
@@ -0,0 +1,59 @@
+// src/shutdown.rs
+
+use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+const DEFAULT_GRACE_SECS: u64 = 30;
+
+lazy_static::lazy_static! {
+    // Cancelled once when the process starts shutting down; every subsystem watches a clone
+    static ref SHUTDOWN: CancellationToken = CancellationToken::new();
+}
+
+pub fn token() -> CancellationToken {
+    SHUTDOWN.clone()
+}
+
+pub fn trigger() {
+    SHUTDOWN.cancel();
+}
+
+pub fn is_shutting_down() -> bool {
+    SHUTDOWN.is_cancelled()
+}
+
+// How long buffers and pending replies get to finish before whatever is left is persisted
+pub fn grace_period() -> Duration {
+    let secs = std::env::var("SHUTDOWN_GRACE_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_GRACE_SECS);
+    Duration::from_secs(secs)
+}
+
+// Resolves on SIGTERM (systemd stop) or Ctrl-C
+pub async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler");
+        tokio::select! {
+            _ = sigterm.recv() => log::info!("shutdown: received SIGTERM"),
+            _ = tokio::signal::ctrl_c() => log::info!("shutdown: received Ctrl-C"),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        log::info!("shutdown: received Ctrl-C");
+    }
+}
+
+// Sleeps like tokio::time::sleep but wakes early once shutdown starts, for background loops that should notice it
+// promptly. Returns true if the sleep was cut short.
+pub async fn sleep_or_shutdown(duration: Duration) -> bool {
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => false,
+        _ = SHUTDOWN.cancelled() => true,
+    }
+}
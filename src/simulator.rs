@@ -0,0 +1,269 @@
+// src/simulator.rs
+
+// Plays a scripted conversation through the real bot: run_telegram_bot polls a mock Telegram that we push the
+// transcript's messages into, buffering, analysis and the response cue run unchanged, and OpenAI is either the
+// real API or mock_openai. Runs on a paused tokio clock, so the 15 s buffer and the cue delays take no real time.
+//
+// Transcript format, one entry per line, # for comments:
+//
+//     +0s   Hi, is the apartment still available?
+//     +4s   how much is it per month?
+//     !analysis yes 8 5
+//     !reply It is! $1200 a month, utilities included.
+//     +3m   hello??
+//
+// Offsets are relative to the previous line (+5s, +2m, +1h, +1m30s). With --mock-openai, `!analysis <yes|no>
+// <interest level> <respond cue|NA>` scripts the Analyzing AI's next answer and `!reply <text>` the Convo AI's.
+
+use crate::mock_openai::MockOpenAi;
+use crate::mock_telegram::MockTelegram;
+use anyhow::{anyhow, bail, Result};
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use tokio::time::{Duration, Instant};
+
+// A paused clock jumps to the next timer as soon as the runtime is idle, including while a Postgres query is in
+// flight. So it is held until the runtime has gone QUIET of real time without waking, and at most MAX_HOLD, after
+// which slow real IO (the actual OpenAI API) is allowed to take virtual time.
+const QUIET: std::time::Duration = std::time::Duration::from_millis(5);
+const MAX_HOLD: std::time::Duration = std::time::Duration::from_millis(100);
+
+lazy_static::lazy_static! {
+    static ref LAST_WAKE: Mutex<std::time::Instant> = Mutex::new(std::time::Instant::now());
+    static ref RELEASED_AT: Mutex<Option<Instant>> = Mutex::new(None);
+    static ref CLOCK_MOVED: Notify = Notify::new();
+}
+
+#[derive(Debug, Clone)]
+pub enum Step {
+    Message(String),
+    Analysis(String),
+    Reply(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub after: Duration,
+    pub step: Step,
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub mock_openai: bool,
+    // Telegram user and chat id of the simulated user
+    pub user_id: i64,
+    // Stop once nothing happened for this long after the last line
+    pub settle: Duration,
+}
+
+pub fn parse_transcript(transcript: &str) -> Result<Vec<Line>> {
+    let mut lines = Vec::new();
+    for (number, raw) in transcript.lines().enumerate() {
+        let line = raw.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let parsed = if let Some(spec) = line.strip_prefix("!analysis") {
+            parse_analysis(spec).map(|analysis| Line { after: Duration::ZERO, step: Step::Analysis(analysis) })
+        } else if let Some(text) = line.strip_prefix("!reply") {
+            Ok(Line { after: Duration::ZERO, step: Step::Reply(text.trim().to_string()) })
+        } else if line.starts_with('+') {
+            let (offset, text) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            match (parse_offset(offset), text.trim()) {
+                (Err(e), _) => Err(e),
+                (Ok(_), "") => Err(anyhow!("message text is empty")),
+                (Ok(after), text) => Ok(Line { after, step: Step::Message(text.to_string()) }),
+            }
+        } else {
+            Err(anyhow!("expected \"+<offset> <message>\", \"!analysis ...\" or \"!reply ...\""))
+        };
+
+        lines.push(parsed.map_err(|e| anyhow!("line {}: {}", number + 1, e))?);
+    }
+    Ok(lines)
+}
+
+fn parse_offset(offset: &str) -> Result<Duration> {
+    let spec = offset.strip_prefix('+').unwrap_or(offset);
+    let mut seconds = 0;
+    let mut digits = String::new();
+    for c in spec.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let value: u64 = digits.parse().map_err(|_| anyhow!("invalid offset {:?}", offset))?;
+        digits.clear();
+        seconds += value * match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            _ => bail!("invalid offset {:?}, use e.g. +5s, +2m or +1m30s", offset),
+        };
+    }
+    // A bare number is seconds
+    if !digits.is_empty() {
+        seconds += digits.parse::<u64>()?;
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+// "yes 8 5" or "no 3 NA", written out the way the Analyzing AI answers
+fn parse_analysis(spec: &str) -> Result<String> {
+    let parts: Vec<&str> = spec.split_whitespace().collect();
+    let [qualified, interest_level, respond_cue] = parts[..] else {
+        bail!("expected !analysis <yes|no> <interest level> <respond cue|NA>");
+    };
+    let respond_cue = if respond_cue.eq_ignore_ascii_case("na") { None } else { Some(respond_cue.parse()?) };
+    Ok(crate::mock_openai::analysis(qualified.eq_ignore_ascii_case("yes"), interest_level.parse()?, respond_cue))
+}
+
+fn print_line(start: Instant, who: &str, text: &str) {
+    let elapsed = start.elapsed().as_secs();
+    let prefix = format!("[{:>3}:{:02}] {:>8}:", elapsed / 60, elapsed % 60, who);
+    let mut lines = text.lines();
+    println!("{} {}", prefix, lines.next().unwrap_or_default());
+    for line in lines {
+        println!("{} {}", " ".repeat(prefix.len()), line);
+    }
+}
+
+// Runtime hook, called every time the runtime thread wakes up
+fn runtime_woke() {
+    *LAST_WAKE.lock().unwrap() = std::time::Instant::now();
+    if let Some(released_at) = *RELEASED_AT.lock().unwrap() {
+        if Instant::now() > released_at {
+            CLOCK_MOVED.notify_one();
+        }
+    }
+}
+
+async fn hold_clock_during_io() {
+    loop {
+        // tokio doesn't auto-advance while a blocking task is running
+        let _ = tokio::task::spawn_blocking(|| {
+            let held = std::time::Instant::now();
+            while LAST_WAKE.lock().unwrap().elapsed() < QUIET && held.elapsed() < MAX_HOLD {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+        })
+        .await;
+
+        // Let it jump to the next timer, then hold it again
+        *RELEASED_AT.lock().unwrap() = Some(Instant::now());
+        CLOCK_MOVED.notified().await;
+        *RELEASED_AT.lock().unwrap() = None;
+    }
+}
+
+// Blocks the calling thread; runs the simulation on a runtime of its own with a paused clock
+pub fn run_on_virtual_clock(lines: Vec<Line>, options: Options) -> Result<()> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .on_thread_unpark(runtime_woke)
+        .build()?
+        .block_on(async {
+            tokio::spawn(hold_clock_during_io());
+            run(lines, options).await
+        })
+}
+
+// Needs a paused clock (see run_on_virtual_clock) and a database; point TELEGRAM_DATABASE_* (or SQLITE_DATABASE_PATH)
+// at a scratch one, since the simulated user, threads and messages are written there like any other. Events only go
+// to in-process listeners.
+pub async fn run(lines: Vec<Line>, options: Options) -> Result<()> {
+    let telegram = MockTelegram::start().await;
+    telegram.install();
+
+    let openai = if options.mock_openai {
+        let openai = MockOpenAi::start().await;
+        openai.install();
+        // Answer every batch right away unless the transcript says otherwise
+        openai.default_reply(crate::telegram::ANALYZING_AI_ID, &crate::mock_openai::analysis(true, 5, Some(0)));
+        Some(openai)
+    } else {
+        if std::env::var("OPENAI_KEY").is_err() {
+            bail!("OPENAI_KEY not set; pass --mock-openai to run without OpenAI");
+        }
+        if lines.iter().any(|line| !matches!(line.step, Step::Message(_))) {
+            bail!("!analysis and !reply lines need --mock-openai");
+        }
+        None
+    };
+
+    // Quiet hours go by the wall clock, which has nothing to do with the virtual one; leave them out unless asked for
+    if std::env::var("QUIET_HOURS").is_err() {
+        std::env::set_var("QUIET_HOURS", "off");
+    }
+
+    let pool = crate::database::create_pool()?;
+    crate::database::run_migrations(&pool).await?;
+
+    // The user's threads from an earlier run live in the database but not in this run's mock OpenAI
+    if openai.is_some() && crate::database::get_thread_by_user_id_and_assistant(pool.clone(), options.user_id, crate::telegram::ANALYZING_AI_ID).await?.is_some() {
+        bail!("user {} already has threads from an earlier run, which the mock OpenAI doesn't know; pick another --user-id", options.user_id);
+    }
+
+    // Only the simulated chat is ours: leave real users' pending work and webhook subscribers alone
+    crate::telegram::skip_pending_work();
+    crate::events::set_local_only();
+    let mut events = crate::events::subscribe_local();
+    tokio::spawn(crate::telegram::run_telegram_bot(pool.clone()));
+
+    let start = Instant::now();
+    let mut next_at = start;
+    let mut lines = lines.into_iter().peekable();
+    let mut replies = 0;
+    let mut idle_until = None;
+
+    loop {
+        let wake = match lines.peek() {
+            Some(line) => next_at + line.after,
+            None => *idle_until.get_or_insert_with(|| Instant::now() + options.settle),
+        };
+
+        tokio::select! {
+            event = events.recv() => {
+                let Ok(event) = event else { continue };
+                if event.event_type != crate::events::ANALYSIS_COMPLETED || event.data["chat_id"].as_i64() != Some(options.user_id) {
+                    continue;
+                }
+                print_line(start, "analyzer", event.data["analysis"].as_str().unwrap_or_default());
+                let decision = format!(
+                    "qualified={} interest={} respond_cue={}{}",
+                    event.data["qualified_to_respond"].as_str().unwrap_or_default(),
+                    event.data["interest_level"],
+                    event.data["respond_cue"],
+                    if event.data["respond_cue"].is_null() { ", no reply will be sent" } else { "" },
+                );
+                print_line(start, "decision", &decision);
+                idle_until = None;
+            }
+            sent = telegram.wait_for_messages(replies + 1, Duration::from_secs(24 * 60 * 60)) => {
+                for message in sent.iter().skip(replies) {
+                    print_line(start, "bot", &message.text);
+                }
+                replies = replies.max(sent.len());
+                idle_until = None;
+            }
+            _ = tokio::time::sleep_until(wake) => {
+                let Some(line) = lines.next() else { break };
+                next_at = wake;
+                match (line.step, &openai) {
+                    (Step::Message(text), _) => {
+                        print_line(start, "user", &text);
+                        telegram.push_text(options.user_id, &text);
+                    }
+                    (Step::Analysis(analysis), Some(openai)) => openai.reply(crate::telegram::ANALYZING_AI_ID, &analysis),
+                    (Step::Reply(text), Some(openai)) => openai.reply(crate::telegram::CONVO_AI_ID, &text),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    println!("Simulation ended after {}s of virtual time with {} bot message(s)", start.elapsed().as_secs(), replies);
+    Ok(())
+}
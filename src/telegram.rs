@@ -12,33 +12,71 @@ use crate::Message as CustomMessage; // Alias your Message type to avoid name co
 use crate::database::{insert_thread, insert_message};
 //use teloxide::types::{ChatKind};
 use tokio::time::{sleep, Duration};
-use tokio::sync::RwLock;
 
 
+// Reads every buffered batch and decides whether and when to answer
+pub const ANALYZING_AI_ID: &str = "asst_JjoQ4OUjIgdhTgA9fiAIeRQu";
+// Writes the replies users see
+pub const CONVO_AI_ID: &str = "asst_ybfxpPMxcuj7GZkwELR6sttt";
+
 // Global HashMap to store user_id to thread_id mappings
 lazy_static::lazy_static! {
     static ref USER_THREADS: Arc<Mutex<HashMap<u64, String>>> = Arc::new(Mutex::new(HashMap::new()));
 }
 
-// Define UserState to store message buffer and timer
-#[derive(Default)]
-struct UserState {
-    messages: Vec<crate::Message>,
-    timer: Option<tokio::task::JoinHandle<()>>,
+lazy_static::lazy_static! {
+    // Each user's messages since their last answer; the flush is respond_to_buffer
+    static ref BUFFERS: crate::debounce::Debouncer<u64, crate::Message> = crate::debounce::Debouncer::new(
+        crate::debounce::DebounceConfig::from_env(),
+        |message: &crate::Message| message.text.as_deref().map_or(0, |text| text.chars().count()),
+    );
+    pub(crate) static ref PACING: crate::pacing::PacingConfig = crate::pacing::PacingConfig::from_env();
+    // How far handle_buffered_messages got with a user's batch before it failed
+    static ref TURN_PROGRESS: std::sync::Mutex<HashMap<u64, TurnProgress>> = std::sync::Mutex::new(HashMap::new());
 }
-// Shared state to hold user states
-type SharedState = Arc<RwLock<HashMap<u64, UserState>>>;
 
-lazy_static::lazy_static! {
-    static ref USER_STATES: SharedState = Arc::new(RwLock::new(HashMap::new()));
+// A failed turn's batch comes back whole on its retry, maybe with newer messages after it. Each step of
+// handle_buffered_messages counts the batch's messages it is done with, so the retry picks up at the step that
+// failed and only the newer messages go through the steps already done: no AI runs on a message twice and no
+// row is inserted twice.
+#[derive(Default)]
+struct TurnProgress {
+    message_ids: Vec<u64>,
+    // Sent to the Analyzing AI, and analyzed by it; `analysis` is its latest response
+    analyzer_sent: usize,
+    analyzed: usize,
+    analysis: String,
+    // Analyzed messages whose events went out
+    announced: usize,
+    // Sent to the Convo AI with their analysis
+    convo_sent: usize,
+    // Logged in messages as the user's row, the analysis row and the pre-processing results
+    user_rows: usize,
+    analysis_rows: usize,
+    results_rows: usize,
 }
 
 
 
 
 
+// Answer to /stop, the last message an opted-out user gets
+const STOP_CONFIRMATION: &str = "You won't get any more messages from us. Send /start if you change your mind.";
+
+// teloxide::Bot::from_env, pointed at TELEGRAM_API_URL when that is set
+pub fn bot_from_env() -> teloxide::Bot {
+    let bot = teloxide::Bot::from_env();
+    match reqwest::Url::parse(&crate::telegram_api_url()) {
+        Ok(url) => bot.set_api_url(url),
+        Err(e) => {
+            log::error!("Invalid TELEGRAM_API_URL, using the default: {:?}", e);
+            bot
+        }
+    }
+}
+
 pub async fn get_file_path(file_id: &str, bot_token: &str) -> Result<String, anyhow::Error> {
-    let url = format!("https://api.telegram.org/bot{}/getFile?file_id={}", bot_token, file_id);
+    let url = format!("{}/bot{}/getFile?file_id={}", crate::telegram_api_url(), bot_token, file_id);
 
     let client = reqwest::Client::new();
     let response = client.get(&url).send().await?;
@@ -56,6 +94,19 @@ pub async fn get_file_path(file_id: &str, bot_token: &str) -> Result<String, any
     }
 }
 
+// Fetches an audio or voice message's file and transcribes it
+async fn transcribe(message: &mut CustomMessage, bot_token: &str, openai_key: &str) -> Result<String, anyhow::Error> {
+    if let Some(audio) = message.audio.as_mut() {
+        audio.file_path = Some(get_file_path(&audio.file_id, bot_token).await?);
+        handle_audio_message(bot_token, audio, openai_key).await
+    } else if let Some(voice) = message.voice.as_mut() {
+        voice.file_path = Some(get_file_path(&voice.file_id, bot_token).await?);
+        handle_voice_message(bot_token, voice, openai_key).await
+    } else {
+        Err(anyhow!("Message has neither audio nor voice"))
+    }
+}
+
 pub fn convert_teloxide_message_to_custom(message: teloxide::prelude::Message) -> CustomMessage {
     CustomMessage {
         message_id: message.id.0 as u64,
@@ -100,7 +151,7 @@ pub fn convert_teloxide_message_to_custom(message: teloxide::prelude::Message) -
 
 
 
-// pub async fn run_telegram_bot(pool: deadpool_postgres::Pool) {
+// pub async fn run_telegram_bot(pool: crate::database::Pool) {
 //     let bot = teloxide::Bot::from_env();
 //     log::info!("Bot started");
 //     let openai_key = std::env::var("OPENAI_KEY").expect("OPENAI_KEY not set");
@@ -272,20 +323,31 @@ pub fn convert_teloxide_message_to_custom(message: teloxide::prelude::Message) -
 //         }
 //     }).await;
 // }
-pub async fn run_telegram_bot(pool: deadpool_postgres::Pool) {
-    let bot = teloxide::Bot::from_env();
+pub async fn run_telegram_bot(pool: crate::database::Pool) {
+    let bot = bot_from_env();
     log::info!("Bot started");
     let openai_key = std::env::var("OPENAI_KEY").expect("OPENAI_KEY not set");
-    let assistant_id = "asst_ybfxpPMxcuj7GZkwELR6sttt".to_string();
+    let assistant_id = CONVO_AI_ID.to_string();
 
-    teloxide::repl(bot.clone(), move |message: teloxide::prelude::Message, bot: teloxide::Bot| {
+    restore_pending_work(&pool, &bot, &openai_key, &assistant_id).await;
+
+    let handler = move |update: Update, message: teloxide::prelude::Message, bot: teloxide::Bot| {
         let openai_key = openai_key.clone();
         let assistant_id = assistant_id.clone();
         let bot_token = std::env::var("TELOXIDE_TOKEN").expect("TELOXIDE_TOKEN not set");
 
         let pool = pool.clone();
 
-        async move {
+        // Every log line for this message, through analysis and the reply, carries these ids
+        let log_context = crate::logging::LogContext::for_chat(message.from().map(|user| user.id.0 as i64), message.chat.id.0);
+
+        crate::logging::with_context(log_context, async move {
+            // A restart or a redelivery can hand us messages we already took; never buffer the same one twice
+            let dedup_keys = [crate::dedup::update_key(update.id), crate::dedup::message_key(message.chat.id.0, message.id.0 as i64)];
+            if !crate::dedup::claim(&pool, &dedup_keys).await {
+                return teloxide::prelude::respond(());
+            }
+
             let result: anyhow::Result<()> = async {
                 let user_id = message.from()
                     .ok_or_else(|| anyhow::anyhow!("User not found in message"))
@@ -296,448 +358,119 @@ pub async fn run_telegram_bot(pool: deadpool_postgres::Pool) {
                     first_name: Some(message.from().unwrap().first_name.clone()), // value from Telegram API, always Some
                     last_name: Some(message.from().unwrap().last_name.clone().unwrap_or("N/A".to_string())), // convert None to "N/A"
                     username: Some(message.from().unwrap().username.clone().unwrap_or("N/A".to_string())), // convert None to "N/A"
+                    language_code: message.from().unwrap().language_code.clone(),
+                    timezone: None,
+                    timezone_source: None,
+                    contact_state: crate::CONTACT_ACTIVE.to_string(),
+                    contact_state_at: None,
                 };
+                let inferred_timezone = db_user.language_code.as_deref().and_then(crate::quiet_hours::infer_from_language);
 
                 if let Err(e) = crate::database::insert_user(pool.clone(), db_user).await {
                     log::error!("Failed to insert or update user: {:?}", e);
                 }
-
-                if let Some(text) = message.text() {
-                    log::info!("Received message: {}", text);
-                    let chat_id = message.chat.id;
-
-                    //gets write lock for USER_STATES so I can change it.
-                    let mut user_states = USER_STATES.write().await;
-                    //looks for entry in the USER_STATES hashmap corresponding to the user_id
-                    let user_state = user_states.entry(user_id as u64).or_default();
-
-                    // Add message to buffer
-                    user_state.messages.push(crate::telegram::convert_teloxide_message_to_custom(message.clone()));
-
-                    // If there's an existing timer, cancel it
-                    //IMPORTANT code comment explaining the code:
-                    //tokio::spawn spawns an asynch task that run async - ok we know this
-                    //then it RETURNS a JoinHandle. the "JoinHandle" is what allows us to control the spawned task
-                    //this "JoinHandle" is then assigned to user_state.timer in user_state.timer = Some(tokio::spawn...). 
-                    //So when run_telegram_bot() receives a new message, it goes through the logic and gets here:
-                    //if let Some(timer) = user_state.timer.take() {
-                    //     timer.abort();
-                    // }
-                    // user_state.timer is one of 2 things because of the code below: None or Some(JoinHandle<()>) .
-                    //if let Some(timer) = user_state.timer.take() {} first turns 
-                    //      user_state.timer to None, then says if
-                    //      user_state.timer WAS a Some(something) and not None(),
-                    //      aka before we did the take(), if user_state.timer
-                    //      was a Some(T) and not None(),
-                    //      let/set the new timer variable to something, aka JoinHandle<()>,
-                    //      and do the code in {}. So in this context, the abort() method
-                    //      is called on JoinHandle, which basically does what it says.
-                    if let Some(timer) = user_state.timer.take() {
-                        timer.abort();
-                        log::info!("Existing timer reset for user_id: {}", user_id);
+                // Only a first guess; set_user_timezone keeps a configured or phone-based one
+                if let Some(timezone) = inferred_timezone {
+                    if let Err(e) = crate::database::set_user_timezone(&pool, user_id, timezone.name(), "language").await {
+                        log::error!("Failed to set timezone from language for user_id {}: {:?}", user_id, e);
                     }
+                }
 
-                    // Start a new timer
-                    let bot_clone = bot.clone();
-                    let openai_key_clone = openai_key.clone();
-                    let assistant_id_clone = assistant_id.clone();
-                    let pool_clone = pool.clone();
-
-                    let zero_is_text_one_is_audio_two_is_voice = 0;
-
-                    log::info!("Starting 15-second timer for user_id: {}", user_id);
-                    user_state.timer = Some(tokio::spawn(async move {
-                        log::info!("Waiting for any new messages for user_id: {}", user_id);
-                        sleep(Duration::from_secs(15)).await;
-
-                        // Handle the collected messages after the timeout
-                        let result = handle_buffered_messages(user_id as u64, pool_clone, bot_clone, chat_id, openai_key_clone, assistant_id_clone, zero_is_text_one_is_audio_two_is_voice).await;
-
-                        match result {
-                            Ok((response_cue, convo_response_text, convo_thread_id)) => {
-                                let convo_response_text_clone = convo_response_text.clone();
-                                if let Some(cue) = response_cue {
-                                    // Now you can use `cue` as an `i32`
-                                    log::info!("in run_telegram_bot: just finished out of handle_buffered_messages.
-                                    respnonse cue timer initiating for {:?} seconds", &response_cue);
-                                    let timer = cue + 30;
-                                    sleep(Duration::from_secs(timer as u64)).await;
-                                    //once done sleeping, insert the message into database...
-                                    log::info!("in run_telegram_bot: inserting message into database");
-                                    if let Err(e) = crate::database::insert_message(
-                                        pool.clone(),
-                                        &convo_thread_id,
-                                        "assistant",
-                                        &convo_response_text,
-                                        "text",
-                                        &assistant_id,).await 
-                                    {
-                                        log::error!("run_telegram_bot: Failed to log Convo AI response: {:?}", e);
-                                    }
-                                    //and send the message
-                                    log::info!("sending convo response: {}", convo_response_text_clone);
-                                    bot.send_message(chat_id, convo_response_text).await.ok();
-                                    // Clear the user's message buffer
-                                    match clear_message_buffer(user_id as u64).await {
-                                        Ok(_) => {
-                                            // Successfully cleared the message buffer
-                                            log::info!("in run_telegram_bot: Message buffer cleared successfully.");
-                                        }
-                                        Err(e) => {
-                                            // Handle the error
-                                            log::info!("Error clearing message buffer: {:?}", e);
-                                        }
-                                    }
-                                } 
-                                else {
-                                    // Handle the case where `response_cue` is `None`
-                                    log::error!("in run_telegram_bot: No response cue available.");
-                                }
-                                // Use `convo_response_text` and `convo_thread_id` as needed
-                                //log::info!("convo Response text: {}", convo_response_text_clone);
-                                log::info!("run_telegram_bot: Thread ID: {}", convo_thread_id);
-                            }
-                            Err(e) => {
-                                // Handle the error
-                                log::info!("run_telegram_bot: Error handling buffered messages: {:?}", e);
+                // A contact the user shares of themselves tells us their country
+                if let Some(contact) = message.contact() {
+                    if contact.user_id.map(|id| id.0 as i64) == Some(user_id) {
+                        if let Some(timezone) = crate::quiet_hours::infer_from_phone(&contact.phone_number) {
+                            match crate::database::set_user_timezone(&pool, user_id, timezone.name(), "phone").await {
+                                Ok(true) => log::info!("Timezone of user_id {} set to {} from their phone number", user_id, timezone.name()),
+                                Ok(false) => {}
+                                Err(e) => log::error!("Failed to set timezone from phone for user_id {}: {:?}", user_id, e),
                             }
                         }
-
-                        //CHANGE - DONEEEE:
-                        //      make handle_buffered_messages return responce_cue, convo_response_text, convo_thread_id. 
-
-                        //ADD:
-                        //      timer with response_cue
-
-                        //      bot.send_message convo_response_text
-                        //      data insert message using thread id.
-                    }));
+                    }
+                    return Ok(());
                 }
 
-                //Handle audio messages
-                //removed for now until I can handle audio and voice messages properly
-                else if let Some(audio) = message.audio() {
-                    log::info!("Received audio message");
-                
-                    let mut custom_message = crate::telegram::convert_teloxide_message_to_custom(message.clone());
-                    let chat_id = message.chat.id;
-
-                    //get the file path
-                    //get transcription from handle_audio_message
-                    if let Some(ref mut custom_audio) = &mut custom_message.audio {
-                        match get_file_path(&custom_audio.file_id, &bot_token).await {
-                            Ok(file_path) => {
-                                custom_audio.file_path = Some(file_path);
-                                match handle_audio_message(&bot_token, &custom_audio, &openai_key).await {
-                                    Ok(transcription) => {
-                                        //create new CustomMessage with transcribed text so I can push it into the user_state
-                                        let transcribed_message = CustomMessage {
-                                            message_id: custom_message.message_id,
-                                            from: custom_message.from.clone(),
-                                            chat: custom_message.chat.clone(),
-                                            date: custom_message.date,
-                                            text: Some(transcription),
-                                            audio: None,
-                                            voice: None,
-                                        };
-
-                                        // Buffer the audio message
-                                        let mut user_states = USER_STATES.write().await;
-                                        let user_state = user_states.entry(user_id as u64).or_default();
-                                        user_state.messages.push(transcribed_message);
-                                        
-                                        // abort tokio::spawn'ed task if it exists. aka if 
-                                        //  one of the timers is still going, abort the tokio::spawn
-                                        if let Some(timer) = user_state.timer.take() {
-                                            timer.abort();
-                                        }
-
-                                        let bot_clone = bot.clone();
-                                        let openai_key_clone = openai_key.clone();
-                                        let assistant_id_clone = assistant_id.clone();
-                                        let pool_clone = pool.clone();
-
-                                        let zero_is_text_one_is_audio_two_is_voice = 1;
-
-
-                                    //IMPORTANT code comment explaining the code:
-                                    //tokio::spawn spawns an asynch task that run async - ok we know this
-                                    //then it RETURNS a JoinHandle. the "JoinHandle" is what allows us to control the spawned task
-                                    //this "JoinHandle" is then assigned to user_state.timer in user_state.timer = Some(tokio::spawn...). 
-                                    //So when run_telegram_bot() receives a new message, it goes through the logic and gets here:
-                                    //if let Some(timer) = user_state.timer.take() {
-                                    //     timer.abort();
-                                    // }
-                                    // user_state.timer is one of 2 things because of the code below: None or Some(JoinHandle<()>) .
-                                    //if let Some(timer) = user_state.timer.take() {} first turns 
-                                    //      user_state.timer to None, then says if
-                                    //      user_state.timer WAS a Some(something) and not None(),
-                                    //      aka before we did the take(), if user_state.timer
-                                    //      was a Some(T) and not None(),
-                                    //      let/set the new timer variable to something, aka JoinHandle<()>,
-                                    //      and do the code in {}. So in this context, the abort() method
-                                    //      is called on JoinHandle, which basically does what it says.
-
-                                        log::info!("Starting 15-second timer for user_id: {}", user_id);
-                                        user_state.timer = Some(tokio::spawn(async move {
-                                            log::info!("Waiting for any new messages for user_id: {}", user_id);
-                                            sleep(Duration::from_secs(15)).await;
-
-                                            // Handle the collected messages after the timeout
-                                            let result = 
-                                                handle_buffered_messages(
-                                                    user_id as u64, 
-                                                    pool_clone, 
-                                                    bot_clone, chat_id, 
-                                                    openai_key_clone, 
-                                                    assistant_id_clone,
-                                                    zero_is_text_one_is_audio_two_is_voice,
-                                                ).await;
-
-                                            match result {
-                                                Ok((response_cue, convo_response_text, convo_thread_id)) => {
-                                                    let convo_response_text_clone = convo_response_text.clone();
-                                                    if let Some(cue) = response_cue {
-                                                        // Now you can use `cue` as an `i32`
-                                                        log::info!("in run_telegram_bot: just finished out of handle_buffered_messages.
-                                                        respnonse cue timer initiating for {:?} seconds", &response_cue);
-                                                        let timer = cue + 30;
-                                                        sleep(Duration::from_secs(timer as u64)).await;
-                                                        //once done sleeping, insert the message into database...
-                                                        log::info!("in run_telegram_bot: inserting message into database");
-                                                        if let Err(e) = crate::database::insert_message(
-                                                            pool.clone(),
-                                                            &convo_thread_id,
-                                                            "assistant",
-                                                            &convo_response_text,
-                                                            "text",
-                                                            &assistant_id,).await 
-                                                        {
-                                                            log::error!("run_telegram_bot: Failed to log Convo AI response: {:?}", e);
-                                                            todo!("handle this error. not sure yet how");
-                                                        }
-                                                        //and send the message
-                                                        log::info!("run_telegarm_bot: sending convo response: {}", convo_response_text_clone);
-                                                        bot.send_message(chat_id, convo_response_text).await.ok();
-                                                        // Clear the user's message buffer
-                                                        match clear_message_buffer(user_id as u64).await {
-                                                            Ok(_) => {
-                                                                // Successfully cleared the message buffer
-                                                                log::info!("in run_telegram_bot: Message buffer cleared successfully.");
-                                                            }
-                                                            Err(e) => {
-                                                                // Handle the error
-                                                                log::info!("Error clearing message buffer: {:?}", e);
-                                                            }
-                                                        }
-                                                    } 
-                                                    else {
-                                                        // Handle the case where `response_cue` is `None`
-                                                        log::error!("in run_telegram_bot: No response cue available.");
-                                                        todo!("handle error where response cue is None. 
-                                                        aka if Analyzing AI fucked up. maybe try repeating the exact message?");
-                                                    }
-                                                    log::info!("run_telegram_bot: Thread ID: {}", convo_thread_id);
-                                                }
-                                                Err(e) => {
-                                                    // Handle the error
-                                                    log::info!("run_telegram_bot: Error handling buffered messages: {:?}", e);
-                                                    todo!("see if I can get the message from the database");
-                                                }
-                                            }
-                                        }));
-                                    },
-                                    Err(e) => {
-                                        log::error!("Failed to handle audio message: {:?}", e);
-                                        bot.send_message(chat_id, "Failed to process your audio message. Please try again later.").await?;
-                                    }
-                                }
-                            },
-                            Err(e) => {
-                                log::error!("Failed to retrieve file path: {:?}", e);
-                                bot.send_message(chat_id, "Failed to process your audio message. Please try again later.").await?;
-                            }
+                let message_type = if message.text().is_some() {
+                    "text"
+                } else if message.audio().is_some() {
+                    "audio"
+                } else if message.voice().is_some() {
+                    "voice"
+                } else {
+                    "other"
+                };
+                crate::metrics::MESSAGES_RECEIVED.with_label_values(&[message_type]).inc();
+                crate::events::emit(&pool, crate::events::Event::new(crate::events::MESSAGE_RECEIVED, serde_json::json!({
+                    "user_id": user_id,
+                    "chat_id": message.chat.id.0,
+                    "message_id": message.id.0,
+                    "message_type": message_type,
+                    "text": message.text(),
+                })));
+
+                // /stop opts out of every message the user didn't just ask for, and /start opts back in
+                let command = message.text().map(|text| text.trim().split('@').next().unwrap_or_default().to_ascii_lowercase());
+                match command.as_deref() {
+                    Some("/stop") => {
+                        match crate::database::set_contact_state(&pool, user_id, crate::CONTACT_OPTED_OUT).await {
+                            Ok(_) => log::info!("user_id {} opted out", user_id),
+                            Err(e) => log::error!("Failed to opt out user_id {}: {:?}", user_id, e),
                         }
+                        bot.send_message(message.chat.id, STOP_CONFIRMATION).await?;
+                        return Ok(());
                     }
+                    Some("/start") => match crate::database::set_contact_state(&pool, user_id, crate::CONTACT_ACTIVE).await {
+                        Ok(true) => log::info!("user_id {} opted back in", user_id),
+                        Ok(false) => {}
+                        Err(e) => log::error!("Failed to opt in user_id {}: {:?}", user_id, e),
+                    },
+                    _ => {}
                 }
-                else if let Some(voice) = message.voice() {
-                    log::info!("Received audio message");
-                
-                    let mut custom_message = crate::telegram::convert_teloxide_message_to_custom(message.clone());
-                    let chat_id = message.chat.id;
-
-                    //get the file path
-                    //get transcription from handle_audio_message
-                    if let Some(ref mut custom_voice) = &mut custom_message.voice {
-                        match get_file_path(&custom_voice.file_id, &bot_token).await {
-                            Ok(file_path) => {
-                                custom_voice.file_path = Some(file_path);
-                                match handle_voice_message(&bot_token, &custom_voice, &openai_key).await {
-                                    Ok(transcription) => {
-                                        //create new CustomMessage with transcribed text so I can push it into the user_state
-                                        let transcribed_message = CustomMessage {
-                                            message_id: custom_message.message_id,
-                                            from: custom_message.from.clone(),
-                                            chat: custom_message.chat.clone(),
-                                            date: custom_message.date,
-                                            text: Some(transcription),
-                                            audio: None,
-                                            voice: None,
-                                        };
-
-                                        // Buffer the audio message
-                                        let mut user_states = USER_STATES.write().await;
-                                        let user_state = user_states.entry(user_id as u64).or_default();
-                                        user_state.messages.push(transcribed_message);
-                                        
-                                        // abort tokio::spawn'ed task if it exists. aka if 
-                                        //  one of the timers is still going, abort the tokio::spawn
-                                        if let Some(timer) = user_state.timer.take() {
-                                            timer.abort();
-                                        }
-
-                                        let bot_clone = bot.clone();
-                                        let openai_key_clone = openai_key.clone();
-                                        let assistant_id_clone = assistant_id.clone();
-                                        let pool_clone = pool.clone();
-
-                                        let zero_is_text_one_is_audio_two_is_voice = 2;
-
-
-                                    //IMPORTANT code comment explaining the code:
-                                    //tokio::spawn spawns an asynch task that run async - ok we know this
-                                    //then it RETURNS a JoinHandle. the "JoinHandle" is what allows us to control the spawned task
-                                    //this "JoinHandle" is then assigned to user_state.timer in user_state.timer = Some(tokio::spawn...). 
-                                    //So when run_telegram_bot() receives a new message, it goes through the logic and gets here:
-                                    //if let Some(timer) = user_state.timer.take() {
-                                    //     timer.abort();
-                                    // }
-                                    // user_state.timer is one of 2 things because of the code below: None or Some(JoinHandle<()>) .
-                                    //if let Some(timer) = user_state.timer.take() {} first turns 
-                                    //      user_state.timer to None, then says if
-                                    //      user_state.timer WAS a Some(something) and not None(),
-                                    //      aka before we did the take(), if user_state.timer
-                                    //      was a Some(T) and not None(),
-                                    //      let/set the new timer variable to something, aka JoinHandle<()>,
-                                    //      and do the code in {}. So in this context, the abort() method
-                                    //      is called on JoinHandle, which basically does what it says.
-
-                                        log::info!("Starting 15-second timer for user_id: {}", user_id);
-                                        user_state.timer = Some(tokio::spawn(async move {
-                                            log::info!("Waiting for any new messages for user_id: {}", user_id);
-                                            sleep(Duration::from_secs(15)).await;
-
-                                            // Handle the collected messages after the timeout
-                                            let result = 
-                                                handle_buffered_messages(
-                                                    user_id as u64, 
-                                                    pool_clone, 
-                                                    bot_clone, chat_id, 
-                                                    openai_key_clone, 
-                                                    assistant_id_clone,
-                                                    zero_is_text_one_is_audio_two_is_voice,
-                                                ).await;
-
-                                            match result {
-                                                Ok((response_cue, convo_response_text, convo_thread_id)) => {
-                                                    let convo_response_text_clone = convo_response_text.clone();
-                                                    if let Some(cue) = response_cue {
-                                                        // Now you can use `cue` as an `i32`
-                                                        log::info!("in run_telegram_bot: just finished out of handle_buffered_messages.
-                                                        respnonse cue timer initiating for {:?} seconds", &response_cue);
-                                                        let timer = cue + 30;
-                                                        sleep(Duration::from_secs(timer as u64)).await;
-                                                        //once done sleeping, insert the message into database...
-                                                        log::info!("in run_telegram_bot: inserting message into database");
-                                                        if let Err(e) = crate::database::insert_message(
-                                                            pool.clone(),
-                                                            &convo_thread_id,
-                                                            "assistant",
-                                                            &convo_response_text,
-                                                            "text",
-                                                            &assistant_id,).await 
-                                                        {
-                                                            log::error!("run_telegram_bot: Failed to log Convo AI response for voice message: {:?}", e);
-                                                            todo!("handle this error. not sure yet how");
-                                                        }
-                                                        //and send the message
-                                                        log::info!("run_telegarm_bot: sending convo response: {}", convo_response_text_clone);
-                                                        bot.send_message(chat_id, convo_response_text).await.ok();
-                                                        // Clear the user's message buffer
-                                                        match clear_message_buffer(user_id as u64).await {
-                                                            Ok(_) => {
-                                                                // Successfully cleared the message buffer
-                                                                log::info!("in run_telegram_bot: Message buffer cleared successfully.");
-                                                            }
-                                                            Err(e) => {
-                                                                // Handle the error
-                                                                log::info!("Error clearing message buffer: {:?}", e);
-                                                            }
-                                                        }
-                                                    } 
-                                                    else {
-                                                        // Handle the case where `response_cue` is `None`
-                                                        log::error!("in run_telegram_bot: No response cue available for voice message.");
-                                                        todo!("handle error where response cue is None. 
-                                                        aka if Analyzing AI fucked up. maybe try repeating the exact message?");
-                                                    }
-                                                    log::info!("run_telegram_bot: Thread ID: {}", convo_thread_id);
-                                                }
-                                                Err(e) => {
-                                                    // Handle the error
-                                                    log::info!("run_telegram_bot: Error handling buffered messages for voice message: {:?}", e);
-                                                    todo!("see if I can get the message from the database");
-                                                }
-                                            }
-                                        }));
-                                    },
-                                    Err(e) => {
-                                        log::error!("Failed to handle voice message: {:?}", e);
-                                        bot.send_message(chat_id, "Failed to process your voice message. Please try again later.").await?;
-                                    }
-                                }
-                            },
-                            Err(e) => {
-                                log::error!("Failed to retrieve file path: {:?}", e);
-                                bot.send_message(chat_id, "Failed to process your voice message. Please try again later.").await?;
-                            }
-                        }
+                // Every reply waits out a response cue, so an opted-out user gets none until /start
+                if let Some(user) = crate::database::get_user(&pool, user_id).await? {
+                    if user.contact_state == crate::CONTACT_OPTED_OUT {
+                        log::info!("Not answering user_id {}, who opted out", user_id);
+                        return Ok(());
                     }
                 }
 
-                // // Handle voice messages
-                // else if let Some(voice) = message.voice() {
-                //     log::info!("Received voice message");
-                
-                //     let mut custom_message = crate::telegram::convert_teloxide_message_to_custom(message.clone());
-                //     let chat_id = message.chat.id;
-
-                //     // Buffer the voice message
-                //     let mut user_states = USER_STATES.write().await;
-                //     let user_state = user_states.entry(user_id as u64).or_default();
-                //     user_state.messages.push(custom_message);
-                    
-                //     // Reset the timer if it's active
-                //     if let Some(timer) = user_state.timer.take() {
-                //         timer.abort();
-                //     }
-
-                //     let bot_clone = bot.clone();
-                //     let openai_key_clone = openai_key.clone();
-                //     let assistant_id_clone = assistant_id.clone();
-                //     let pool_clone = pool.clone();
-
-                //     user_state.timer = Some(tokio::spawn(async move {
-                //         sleep(Duration::from_secs(15)).await;
-
-                //         // Handle the collected messages after the timeout
-                //         let (response_cue, convo_response_text, convo_thread_id) = handle_buffered_messages(user_id as u64, pool_clone, bot_clone, chat_id, openai_key_clone, assistant_id_clone).await;
-                //     }));
-                // }
+                let chat_id = message.chat.id;
+                let mut custom_message = crate::telegram::convert_teloxide_message_to_custom(message.clone());
+                if let Some(text) = message.text() {
+                    log::info!("Received message: {}", crate::redact::body(text));
+                } else if custom_message.audio.is_some() || custom_message.voice.is_some() {
+                    let kind = custom_message.kind();
+                    log::info!("Received {} message", kind);
+                    // Buffered as its transcription; the audio/voice field stays so the item keeps its type
+                    match transcribe(&mut custom_message, &bot_token, &openai_key).await {
+                        Ok(transcription) => custom_message.text = Some(transcription),
+                        Err(e) => {
+                            log::error!("Failed to handle {} message: {:?}", kind, e);
+                            bot.send_message(chat_id, format!("Failed to process your {} message. Please try again later.", kind)).await?;
+                            return Ok(());
+                        }
+                    }
+                } else {
+                    return Ok(());
+                }
 
+                let batch_size = BUFFERS.push(user_id as u64, custom_message, respond_to_buffer(
+                    user_id as u64,
+                    pool.clone(),
+                    bot.clone(),
+                    chat_id,
+                    openai_key.clone(),
+                    assistant_id.clone(),
+                ));
+                log::info!("Buffered message {} for user_id: {}, waiting {:?} for more", batch_size, user_id, BUFFERS.config().quiet_period);
                 Ok(())
             }.await;
 
+            if result.is_ok() {
+                crate::dedup::mark_processed(&pool, &dedup_keys).await;
+            } else {
+                crate::dedup::release(&pool, &dedup_keys).await;
+            }
             if let Err(error) = result {
                 match &error.downcast_ref::<teloxide::RequestError>() {
                     Some(teloxide::RequestError::RetryAfter(duration)) => {
@@ -757,136 +490,613 @@ pub async fn run_telegram_bot(pool: deadpool_postgres::Pool) {
             }
 
             teloxide::prelude::respond(())
+        })
+    };
+
+    // Same setup as teloxide::repl minus its own Ctrl-C handler, so polling stops when crate::shutdown says so
+    let mut dispatcher = teloxide::dispatching::Dispatcher::builder(bot.clone(), Update::filter_message().endpoint(handler))
+        .default_handler(|_| Box::pin(async {}))
+        .build();
+    let shutdown_token = dispatcher.shutdown_token();
+    tokio::spawn(async move {
+        crate::shutdown::token().cancelled().await;
+        log::info!("Stopping Telegram polling");
+        if let Ok(stopped) = shutdown_token.shutdown() {
+            stopped.await;
+        }
+    });
+
+    crate::health::set_telegram_polling(true);
+    dispatcher.dispatch().await;
+    crate::health::set_telegram_polling(false);
+
+    if crate::shutdown::is_shutting_down() {
+        log::info!("Telegram polling stopped");
+    } else {
+        log::error!("Telegram polling stopped unexpectedly");
+    }
+}
+
+lazy_static::lazy_static! {
+    // Replies waiting out their response cue, keyed by registration
+    static ref PENDING_REPLIES: std::sync::Mutex<HashMap<u64, crate::DBPendingReply>> = std::sync::Mutex::new(HashMap::new());
+}
+static NEXT_PENDING_REPLY: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+// Tracks a generated reply until it has been sent, so shutdown can persist it if it never goes out.
+// Deregisters on drop, which also covers the reply task being aborted by a newer message.
+struct PendingReply {
+    key: u64,
+}
+
+impl PendingReply {
+    fn register(user_id: u64, chat_id: teloxide::types::ChatId, thread_id: &str, assistant_id: &str, text: &str, delay: Duration) -> Self {
+        let key = NEXT_PENDING_REPLY.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        PENDING_REPLIES.lock().unwrap().insert(key, crate::DBPendingReply {
+            id: 0,
+            user_id: user_id as i64,
+            chat_id: chat_id.0,
+            thread_id: thread_id.to_string(),
+            assistant_id: assistant_id.to_string(),
+            text: text.to_string(),
+            send_at: chrono::Utc::now() + chrono::Duration::from_std(delay).unwrap_or_default(),
+            deferred_from: None,
+        });
+        PendingReply { key }
+    }
+
+    // What is still to be sent once part of a split reply went out
+    fn remaining(&self, text: &str) {
+        if let Some(reply) = PENDING_REPLIES.lock().unwrap().get_mut(&self.key) {
+            reply.text = text.to_string();
+        }
+    }
+}
+
+impl Drop for PendingReply {
+    fn drop(&mut self) {
+        PENDING_REPLIES.lock().unwrap().remove(&self.key);
+    }
+}
+
+pub fn pending_reply_count() -> usize {
+    PENDING_REPLIES.lock().unwrap().len()
+}
+
+// Runs after polling has stopped. Debounce windows and response cues keep their timing: this only waits for
+// turns still generating a reply, then writes the open buffers and unsent replies to the database, where
+// restore_pending_work and send_due_replies pick them up on the next start.
+pub async fn drain(pool: &crate::database::Pool, deadline: tokio::time::Instant) {
+    loop {
+        if !BUFFERS.is_generating() {
+            break;
+        }
+        if tokio::time::Instant::now() >= deadline {
+            log::warn!("drain: grace period over, persisting unfinished work");
+            break;
+        }
+        sleep(Duration::from_millis(200)).await;
+    }
+
+    // Back to back, so a turn can't get further with a reply between it being copied and the turn being aborted
+    let replies: Vec<crate::DBPendingReply> = PENDING_REPLIES.lock().unwrap().values().cloned().collect();
+    let unflushed = BUFFERS.take_unflushed();
+
+    for reply in &replies {
+        match crate::database::insert_pending_reply(pool, reply).await {
+            Ok(id) => log::info!("drain: persisted pending reply {} for user_id: {}", id, reply.user_id),
+            Err(e) => log::error!("drain: failed to persist pending reply for user_id {}: {:?}", reply.user_id, e),
+        }
+    }
+
+    // Messages already answered by a pending reply aren't handed back, only what came after them
+    for (user_id, messages) in unflushed {
+        let buffer = crate::DBPendingBuffer {
+            user_id: user_id as i64,
+            chat_id: messages[0].chat.id as i64,
+            messages,
+        };
+        match crate::database::upsert_pending_buffer(pool, &buffer).await {
+            Ok(()) => log::info!("drain: persisted {} buffered message(s) for user_id: {}", buffer.messages.len(), user_id),
+            Err(e) => log::error!("drain: failed to persist buffer for user_id {}: {:?}", user_id, e),
+        }
+    }
+}
+
+static RESTORED_PENDING_WORK: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+// For the simulate command: its bot shares the real database, whose pending buffers, replies, follow-ups and
+// broadcasts belong to real users and must not be sent to the mock
+pub fn skip_pending_work() {
+    RESTORED_PENDING_WORK.store(true, std::sync::atomic::Ordering::SeqCst);
+}
+
+// Picks up the buffers the previous process persisted in drain and starts send_due_replies for the replies, the
+// follow-up campaign and the broadcast sender. Only once per process: they keep running across a supervisor
+// restart of the bot.
+async fn restore_pending_work(pool: &crate::database::Pool, bot: &teloxide::Bot, openai_key: &str, assistant_id: &str) {
+    if RESTORED_PENDING_WORK.swap(true, std::sync::atomic::Ordering::SeqCst) {
+        return;
+    }
+
+    tokio::spawn(send_due_replies(pool.clone(), bot.clone()));
+    tokio::spawn(crate::follow_ups::run(pool.clone(), bot.clone(), openai_key.to_string(), assistant_id.to_string()));
+    tokio::spawn(crate::broadcasts::run(pool.clone(), bot.clone()));
+
+    match crate::database::take_pending_buffers(pool).await {
+        Ok(buffers) => {
+            for buffer in buffers {
+                log::info!("restore: {} buffered message(s) for user_id {}", buffer.messages.len(), buffer.user_id);
+                let user_id = buffer.user_id as u64;
+                let chat_id = teloxide::types::ChatId(buffer.chat_id);
+                let log_context = crate::logging::LogContext::for_chat(Some(buffer.user_id), buffer.chat_id);
+                let flush = crate::logging::with_context(log_context, respond_to_buffer(
+                    user_id,
+                    pool.clone(),
+                    bot.clone(),
+                    chat_id,
+                    openai_key.to_string(),
+                    assistant_id.to_string(),
+                ));
+                BUFFERS.restore(user_id, buffer.messages, flush);
+            }
         }
-    }).await;
+        Err(e) => log::error!("restore: failed to load pending buffers: {:?}", e),
+    }
+}
+
+// Sends replies from pending_replies as they come due: ones persisted at shutdown and ones deferred out of quiet
+// hours. One that comes due inside the user's quiet hours anyway (persisted just before they began) is deferred again.
+async fn send_due_replies(pool: crate::database::Pool, bot: teloxide::Bot) {
+    let poll_secs = std::env::var("PENDING_REPLY_POLL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(30);
+    let quiet_hours = &crate::quiet_hours::QUIET_HOURS;
+    while !crate::shutdown::is_shutting_down() {
+        let replies = match crate::database::claim_due_pending_replies(&pool, chrono::Utc::now(), 50).await {
+            Ok(replies) => replies,
+            Err(e) => {
+                log::error!("send_due_replies: failed to claim due replies: {:?}", e);
+                Vec::new()
+            }
+        };
+        for reply in replies {
+            let log_context = crate::logging::LogContext::for_chat(Some(reply.user_id), reply.chat_id);
+            crate::logging::with_context(log_context, async {
+                let user = crate::database::get_user(&pool, reply.user_id).await.ok().flatten();
+                if let Some(user) = user.as_ref().filter(|user| user.contact_state != crate::CONTACT_ACTIVE) {
+                    log::info!("send_due_replies: user_id {} is {}, dropping pending reply {}", reply.user_id, user.contact_state, reply.id);
+                    if let Err(e) = crate::database::delete_pending_reply(&pool, reply.id).await {
+                        log::error!("send_due_replies: failed to delete pending reply {}: {:?}", reply.id, e);
+                    }
+                    return;
+                }
+                let timezone = quiet_hours.timezone(user.as_ref().and_then(|user| user.timezone.as_deref()));
+                let allowed_at = quiet_hours.next_allowed(chrono::Utc::now(), timezone);
+                if allowed_at > chrono::Utc::now() {
+                    log::info!("send_due_replies: pending reply {} falls in quiet hours ({}), deferred to {}", reply.id, timezone, allowed_at);
+                    if let Err(e) = crate::database::defer_pending_reply(&pool, reply.id, allowed_at).await {
+                        log::error!("send_due_replies: failed to defer pending reply {}: {:?}", reply.id, e);
+                    }
+                    return;
+                }
+                log::info!("send_due_replies: sending pending reply {} due at {}", reply.id, reply.send_at);
+                send_pending_reply(&pool, &bot, &reply).await;
+            }).await;
+        }
+        crate::shutdown::sleep_or_shutdown(Duration::from_secs(poll_secs)).await;
+    }
+}
+
+async fn send_pending_reply(pool: &crate::database::Pool, bot: &teloxide::Bot, reply: &crate::DBPendingReply) {
+    if let Err(e) = crate::database::insert_message(pool.clone(), &reply.thread_id, "assistant", &reply.text, "text", &reply.assistant_id).await {
+        log::error!("restore: failed to log pending reply {}: {:?}", reply.id, e);
+    }
+    // Already overdue, so the chunks go out back to back
+    let mut sent = Ok(());
+    for chunk in PACING.split(&reply.text) {
+        sent = bot.send_message(teloxide::types::ChatId(reply.chat_id), chunk).await.map(|_| ());
+        if sent.is_err() {
+            break;
+        }
+    }
+    match sent {
+        Ok(()) => {
+            crate::events::emit(pool, crate::events::Event::new(crate::events::MESSAGE_SENT, serde_json::json!({
+                "user_id": reply.user_id,
+                "chat_id": reply.chat_id,
+                "thread_id": reply.thread_id,
+                "assistant_id": reply.assistant_id,
+                "text": reply.text,
+            })));
+            if let Err(e) = crate::database::delete_pending_reply(pool, reply.id).await {
+                log::error!("restore: failed to delete sent pending reply {}: {:?}", reply.id, e);
+            }
+        }
+        Err(e) => {
+            log::error!("restore: failed to send pending reply {}: {:?}", reply.id, e);
+            note_send_error(pool, reply.user_id, &e).await;
+        }
+    }
 }
-async fn handle_buffered_messages(
+
+// Whether Telegram refused a message because the user blocked the bot or deleted their account
+pub(crate) fn is_blocked_error(error: &teloxide::RequestError) -> bool {
+    matches!(error, teloxide::RequestError::Api(teloxide::ApiError::BotBlocked | teloxide::ApiError::UserDeactivated))
+}
+
+// After a failed send: a user who blocked the bot gets nothing more until they write again
+pub(crate) async fn note_send_error(pool: &crate::database::Pool, user_id: i64, error: &teloxide::RequestError) {
+    if !is_blocked_error(error) {
+        return;
+    }
+    match crate::database::set_contact_state(pool, user_id, crate::CONTACT_BLOCKED).await {
+        Ok(true) => log::info!("user_id {} blocked the bot or deleted their account", user_id),
+        Ok(false) => {}
+        Err(e) => log::error!("Failed to mark user_id {} as blocked: {:?}", user_id, e),
+    }
+}
+
+// Whether the user may get a message they didn't just ask for. False when unsure.
+pub(crate) async fn may_contact(pool: &crate::database::Pool, user_id: i64) -> bool {
+    match crate::database::get_user(pool, user_id).await {
+        Ok(user) => user.is_none_or(|user| user.contact_state == crate::CONTACT_ACTIVE),
+        Err(e) => {
+            log::error!("Failed to look up contact state of user_id {}: {:?}", user_id, e);
+            false
+        }
+    }
+}
+
+// The flush for BUFFERS: processes a user's buffered messages and sends the reply after its response cue.
+// Messages that arrive meanwhile wait for the user's next turn. With BUFFER_REGENERATE_ON_NEW_MESSAGE they
+// cancel a reply still waiting out its cue instead, and the next turn answers them with this turn's messages
+// already on the threads.
+async fn respond_to_buffer(
+    user_id: u64,
+    pool: crate::database::Pool,
+    bot: teloxide::Bot,
+    chat_id: teloxide::types::ChatId,
+    openai_key: String,
+    assistant_id: String,
+) {
+    let turn = BUFFERS.start_turn(&user_id).await;
+    if turn.items.is_empty() {
+        // Already taken by the turn this one waited for
+        return;
+    }
+
+    // On error the turn ends unfinished, so its messages are retried on their own or with the next ones
+    let result = handle_buffered_messages(user_id, pool.clone(), chat_id, openai_key.clone(), assistant_id.clone(), &turn.items).await;
+    let (response_cue, convo_response_text, convo_thread_id) = match result {
+        Ok(result) => result,
+        Err(e) => {
+            log::error!("respond_to_buffer: Error handling buffered messages: {:?}", e);
+            let retry = respond_to_buffer_boxed(user_id, pool.clone(), bot.clone(), chat_id, openai_key, assistant_id);
+            match BUFFERS.retry(turn, crate::logging::in_current_context(retry)) {
+                Some(delay) => log::info!("respond_to_buffer: Retrying the messages of user_id {} in {:?}", user_id, delay),
+                None => log::warn!("respond_to_buffer: Giving up retrying the messages of user_id {} until they write again", user_id),
+            }
+            return;
+        }
+    };
+
+    let Some(cue) = response_cue else {
+        log::error!("respond_to_buffer: No response cue available.");
+        turn.finish();
+        return;
+    };
+    let quiet_hours = &crate::quiet_hours::QUIET_HOURS;
+    let user = crate::database::get_user(&pool, user_id as i64).await.ok().flatten();
+    if let Some(user) = user.as_ref().filter(|user| user.contact_state != crate::CONTACT_ACTIVE) {
+        log::info!("respond_to_buffer: user_id {} is {}, not replying", user_id, user.contact_state);
+        turn.finish();
+        return;
+    }
+    let timezone = quiet_hours.timezone(user.as_ref().and_then(|user| user.timezone.as_deref()));
+
+    let chunks = PACING.split(&convo_response_text);
+    if chunks.is_empty() {
+        log::error!("respond_to_buffer: Convo AI returned an empty reply for user_id {}", user_id);
+        turn.finish();
+        return;
+    }
+    let typing: Vec<Duration> = chunks.iter().map(|chunk| PACING.typing_delay(chunk)).collect();
+    let delay = PACING.response_delay(cue, chrono::Timelike::hour(&chrono::Utc::now().with_timezone(&timezone)));
+
+    // A reply that would land in the user's quiet hours waits in pending_replies for send_due_replies instead
+    let send_at = chrono::Utc::now() + chrono::Duration::from_std(delay + typing[0]).unwrap_or_default();
+    let allowed_at = quiet_hours.next_allowed(send_at, timezone);
+    if allowed_at > send_at {
+        let reply = crate::DBPendingReply {
+            id: 0,
+            user_id: user_id as i64,
+            chat_id: chat_id.0,
+            thread_id: convo_thread_id.clone(),
+            assistant_id: assistant_id.clone(),
+            text: convo_response_text.clone(),
+            send_at: allowed_at,
+            deferred_from: Some(send_at),
+        };
+        match crate::database::insert_pending_reply(&pool, &reply).await {
+            Ok(id) => {
+                log::info!("respond_to_buffer: Reply {} to user_id {} falls in quiet hours ({}), deferred to {}", id, user_id, timezone, allowed_at);
+                turn.finish();
+                return;
+            }
+            Err(e) => log::error!("respond_to_buffer: Failed to defer reply out of quiet hours, sending it now: {:?}", e),
+        }
+    }
+
+    let pending_reply = PendingReply::register(user_id, chat_id, &convo_thread_id, &assistant_id, &convo_response_text, delay + typing[0]);
+    turn.answered();
+    log::info!("respond_to_buffer: Replying to user_id: {} in {} chunk(s), starting in {:?}", user_id, chunks.len(), delay);
+
+    // Only until the first chunk goes out can a new message still cancel the reply
+    if !turn.wait(delay).await || !show_typing(&bot, chat_id, Some(&turn), typing[0]).await {
+        log::info!("respond_to_buffer: New message from user_id: {}, discarding the pending reply to answer it as well", user_id);
+        turn.finish();
+        return;
+    }
+
+    // They may have sent /stop while the reply waited
+    if !may_contact(&pool, user_id as i64).await {
+        log::info!("respond_to_buffer: user_id {} can no longer be contacted, discarding the reply", user_id);
+        turn.finish();
+        return;
+    }
+
+    if let Err(e) = crate::database::insert_message(pool.clone(), &convo_thread_id, "assistant", &convo_response_text, "text", &assistant_id).await {
+        log::error!("respond_to_buffer: Failed to log Convo AI response: {:?}", e);
+    }
+    let mut sent_all = true;
+    for (i, chunk) in chunks.iter().enumerate() {
+        if i > 0 {
+            show_typing(&bot, chat_id, None, typing[i]).await;
+        }
+        if let Err(e) = bot.send_message(chat_id, chunk.clone()).await {
+            log::error!("respond_to_buffer: Failed to send chunk {} of {} to user_id {}: {:?}", i + 1, chunks.len(), user_id, e);
+            note_send_error(&pool, user_id as i64, &e).await;
+            sent_all = false;
+            break;
+        }
+        pending_reply.remaining(&chunks[i + 1..].join("\n\n"));
+    }
+    if sent_all {
+        crate::events::emit(&pool, crate::events::Event::new(crate::events::MESSAGE_SENT, serde_json::json!({
+            "user_id": user_id,
+            "chat_id": chat_id.0,
+            "thread_id": convo_thread_id,
+            "assistant_id": assistant_id,
+            "text": convo_response_text,
+        })));
+    }
+    turn.finish();
+    log::info!("respond_to_buffer: Turn finished for user_id: {}", user_id);
+}
+
+// For respond_to_buffer's retry of itself: a named type breaks the cycle in proving its future Send
+fn respond_to_buffer_boxed(
     user_id: u64,
-    pool: deadpool_postgres::Pool,
+    pool: crate::database::Pool,
     bot: teloxide::Bot,
     chat_id: teloxide::types::ChatId,
     openai_key: String,
     assistant_id: String,
-    zero_is_text_one_is_audio_two_is_voice: i32,
+) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>> {
+    Box::pin(respond_to_buffer(user_id, pool, bot, chat_id, openai_key, assistant_id))
+}
+
+// Telegram shows the typing indicator for about 5 seconds
+const TYPING_REFRESH: Duration = Duration::from_secs(4);
+
+// Waits `duration` with the typing indicator on; false if a new message cut the turn's wait short. Without a turn
+// it ends early on shutdown; a turn's reply is persisted by the drain instead.
+pub(crate) async fn show_typing(bot: &teloxide::Bot, chat_id: teloxide::types::ChatId, turn: Option<&crate::debounce::Turn<u64, crate::Message>>, duration: Duration) -> bool {
+    let deadline = tokio::time::Instant::now() + duration;
+    while turn.is_some() || !crate::shutdown::is_shutting_down() {
+        let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        if let Err(e) = bot.send_chat_action(chat_id, teloxide::types::ChatAction::Typing).await {
+            log::warn!("Failed to show typing to chat {}: {:?}", chat_id, e);
+        }
+        match turn {
+            Some(turn) if !turn.wait(remaining.min(TYPING_REFRESH)).await => return false,
+            Some(_) => {}
+            None => {
+                crate::shutdown::sleep_or_shutdown(remaining.min(TYPING_REFRESH)).await;
+            }
+        }
+    }
+    true
+}
+
+// (users with buffered messages, total buffered messages), for /metrics
+pub fn buffer_stats() -> (usize, usize) {
+    BUFFERS.stats()
+}
+
+pub async fn handle_buffered_messages(
+    user_id: u64,
+    pool: crate::database::Pool,
+    chat_id: teloxide::types::ChatId,
+    openai_key: String,
+    assistant_id: String,
+    messages: &[crate::Message],
+) -> Result<(Option<i32>, String, String), anyhow::Error> {
+    if messages.is_empty() {
+        return Err(anyhow::anyhow!("No buffered messages for user_id: {}", user_id));
+    }
+
+    // Picks up a failed turn of the same batch, or starts over
+    let message_ids: Vec<u64> = messages.iter().map(|message| message.message_id).collect();
+    let mut progress = TURN_PROGRESS.lock().unwrap().remove(&user_id)
+        .filter(|progress| message_ids.starts_with(&progress.message_ids))
+        .unwrap_or_default();
+    if progress.analyzer_sent > 0 {
+        log::info!("handle_buffered_messages: Resuming the failed turn of user_id {} ({} of {} messages analyzed)", user_id, progress.analyzed, messages.len());
+    }
+    progress.message_ids = message_ids;
+
+    let result = process_buffered_messages(user_id, &pool, chat_id, &openai_key, &assistant_id, messages, &mut progress).await;
+    if result.is_err() {
+        TURN_PROGRESS.lock().unwrap().insert(user_id, progress);
+    }
+    result
+}
+
+// The steps of handle_buffered_messages, each skipped for the messages `progress` says it is done with
+async fn process_buffered_messages(
+    user_id: u64,
+    pool: &crate::database::Pool,
+    chat_id: teloxide::types::ChatId,
+    openai_key: &str,
+    assistant_id: &str,
+    messages: &[crate::Message],
+    progress: &mut TurnProgress,
 ) -> Result<(Option<i32>, String, String), anyhow::Error> {
-    log::info!("about to acquire the write lock for USER_STATES");
-    let mut user_states = USER_STATES.write().await;
-    log::info!("acquired the write lock for USER_STATES");
     //  TODO: get user's message linked to the same assistant. because if we intercept the same uer's message but going to another assistant, 
     //      we dont want to concatenate THAT message too
-    if let Some(user_state) = user_states.get_mut(&user_id) {
-        log::info!("In handle_buffered_messages. Processing buffered messages for user_id: {}", user_id);
+    log::info!("In handle_buffered_messages. Processing {} buffered messages for user_id: {}", messages.len(), user_id);
 
-        // Concatenate all messages into a single string
-        let concatenated_messages: String = user_state
-            .messages
+    // Concatenate the messages from `from` on into a single string
+    let concatenated_from = |from: usize| -> String {
+        messages[from..]
             .iter()
             .map(|message| message.text.clone().unwrap_or_default())
             .collect::<Vec<_>>()
-            .join("\n");
-
-        //removed 07/23/24 - because I dont want to clear message buffer until the telegram bot actually sends the message. 
-        //      this is in case a message comes within the 2nd timer.
-        // // Clear the user's message buffer
-        // user_state.messages.clear();
-        log::info!("User message buffer cleared for user_id: {}", user_id);
-
-        // Step 1: Pre-process the concatenated message with Analyzing AI.
-        // Goal is to get response from Analyzing AI
-        let analyzing_ai_id = "asst_JjoQ4OUjIgdhTgA9fiAIeRQu";
-        // Step 1a: Send message to Analyzing AI to get/create a thread.
-        let (analyzing_thread_id, is_new_thread) = crate::telegram::get_or_create_thread(&pool, user_id as i64, analyzing_ai_id, &openai_key, &concatenated_messages).await?;
+            .join("\n")
+    };
+    let all = messages.len();
+
+    // Step 1: Pre-process the concatenated message with Analyzing AI.
+    // Goal is to get response from Analyzing AI
+    let analyzing_ai_id = ANALYZING_AI_ID;
+    if progress.analyzed < all {
+        // Step 1a: Send message to Analyzing AI to get/create a thread. A new thread starts with the messages.
+        let (analyzing_thread_id, is_new_thread) = crate::telegram::get_or_create_thread(pool, user_id as i64, analyzing_ai_id, openai_key, &concatenated_from(progress.analyzer_sent)).await?;
         log::info!("in handle_buffed_messages. just got the new thread {}. is it new? {}", &analyzing_thread_id, &is_new_thread);
+        if is_new_thread {
+            progress.analyzer_sent = all;
+        } else if progress.analyzer_sent < all {
+            crate::add_message_to_thread(openai_key, &analyzing_thread_id, &concatenated_from(progress.analyzer_sent)).await?;
+            progress.analyzer_sent = all;
+        }
         // Step 1b: Run thread and receive response from Analyzing AI
-        let response_text = if is_new_thread {
-            crate::first_loop(&openai_key, &analyzing_thread_id, analyzing_ai_id).await?
-        } else {
-            crate::second_message_and_so_on(&openai_key, &analyzing_thread_id, &concatenated_messages, analyzing_ai_id).await?
-        };
-
+        progress.analysis = crate::first_loop(openai_key, &analyzing_thread_id, analyzing_ai_id).await?;
+        progress.analyzed = all;
         log::info!("handle_buffered_messages: finished step 1b. ran thread and received response from Analyzing AI");
-
-
-        // Step 2: Parse the Analyzing AI response
-        let parsed_results = crate::parse_pre_processing_response(&response_text)?;
-
-        // Step 4: Combine the original user message and parsed information into a final message
-        let final_message = format!(
-            "\n\nPre-processing results:\nQualified to Respond? {}\nInterest Level: {}\nRespond Cue: {:?}\nOriginal message:\n{}",
-            parsed_results.qualified_to_respond,
-            parsed_results.interest_level,
-            parsed_results.respond_cue,
-            concatenated_messages,
-        );
-
-        // Step 5: Process with Convo AI. Goal is to get response from Convo AI
-        // Step 5a: Sending final_message to assistant's endpoint to get/create a thread
-        let (convo_thread_id, is_new_thread) = crate::telegram::get_or_create_thread(&pool, user_id as i64, &assistant_id, &openai_key, &final_message).await?;
-
-        // Step 5b: Insert user's concatenated message into the database.
-        if zero_is_text_one_is_audio_two_is_voice == 0 {
-            crate::database::insert_message(
-                pool.clone(),
-                &convo_thread_id,
-                "assistant",
-                &response_text,
-                "text",
-                analyzing_ai_id,
-            ).await?;
-        }
-        else if zero_is_text_one_is_audio_two_is_voice == 1 {
-            crate::database::insert_message(
-                pool.clone(),
-                &convo_thread_id,
-                "assistant",
-                &response_text,
-                "audio",
-                analyzing_ai_id,
-            ).await?;
-        }
-        else if zero_is_text_one_is_audio_two_is_voice == 2 {
-            crate::database::insert_message(
-                pool.clone(),
-                &convo_thread_id,
-                "assistant",
-                &response_text,
-                "voice",
-                analyzing_ai_id,
-            ).await?;
+    }
+    let response_text = progress.analysis.clone();
+
+    // Step 2: Parse the Analyzing AI response
+    let parsed_results = crate::parse_pre_processing_response(&response_text)?;
+
+    if progress.announced < progress.analyzed {
+        let analyzed_messages = concatenated_from(progress.announced);
+        crate::events::emit(pool, crate::events::Event::new(crate::events::ANALYSIS_COMPLETED, serde_json::json!({
+            "user_id": user_id,
+            "chat_id": chat_id.0,
+            "message": analyzed_messages,
+            "analysis": response_text,
+            "qualified_to_respond": parsed_results.qualified_to_respond,
+            "interest_level": parsed_results.interest_level,
+            "respond_cue": parsed_results.respond_cue,
+        })));
+
+        // Let subscribers know about hot leads, and about conversations the Analyzing AI won't answer
+        if parsed_results.interest_level >= crate::events::lead_score_threshold() {
+            crate::events::emit(pool, crate::events::Event::new(crate::events::LEAD_SCORED, serde_json::json!({
+                "user_id": user_id,
+                "chat_id": chat_id.0,
+                "interest_level": parsed_results.interest_level,
+                "respond_cue": parsed_results.respond_cue,
+                "qualified_to_respond": parsed_results.qualified_to_respond,
+            })));
         }
-        else {
-            log::error!("handle_buffered_messages: received message type that is not text, audio, voice.
-            zero_is_text_one_is_audio_two_is_voice = {}", &zero_is_text_one_is_audio_two_is_voice);
-            bot.send_message(chat_id, "could not figure out what message type you sent. can you please try again with text, voice, or audio").await.ok();
+        if parsed_results.qualified_to_respond.eq_ignore_ascii_case("no") {
+            crate::events::emit(pool, crate::events::Event::new(crate::events::HANDOFF_REQUESTED, serde_json::json!({
+                "user_id": user_id,
+                "chat_id": chat_id.0,
+                "interest_level": parsed_results.interest_level,
+                "message": analyzed_messages,
+            })));
         }
+        progress.announced = progress.analyzed;
+    }
+
+    // Step 4: Combine the original user message and parsed information into a final message
+    let final_message = format!(
+        "\n\nPre-processing results:\nQualified to Respond? {}\nInterest Level: {}\nRespond Cue: {:?}\nOriginal message:\n{}",
+        parsed_results.qualified_to_respond,
+        parsed_results.interest_level,
+        parsed_results.respond_cue,
+        concatenated_from(progress.convo_sent),
+    );
+
+    // Step 5: Process with Convo AI. Goal is to get response from Convo AI
+    // Step 5a: Sending final_message to assistant's endpoint to get/create a thread, or onto the thread there is
+    let (convo_thread_id, is_new_thread) = crate::telegram::get_or_create_thread(pool, user_id as i64, assistant_id, openai_key, &final_message).await?;
+    if is_new_thread {
+        progress.convo_sent = all;
+    } else if progress.convo_sent < all {
+        crate::add_message_to_thread(openai_key, &convo_thread_id, &final_message).await?;
+        progress.convo_sent = all;
+    }
 
-        // Step 3: Insert parsed variables into the database.
+    // Step 5b: Insert what the user sent and the analysis of it into the database, typed after what the user
+    //      sent: text, audio or voice, or mixed when the batch had more than one kind. The user's row is what
+    //      follow-ups go by to tell when they last wrote.
+    let message_type = |from: usize| match messages[from..].iter().map(crate::Message::kind).collect::<std::collections::HashSet<_>>() {
+        kinds if kinds.len() == 1 => messages[from].kind(),
+        _ => "mixed",
+    };
+    if progress.user_rows < all {
+        crate::database::insert_message(
+            pool.clone(),
+            &convo_thread_id,
+            "user",
+            &concatenated_from(progress.user_rows),
+            message_type(progress.user_rows),
+            assistant_id,
+        ).await?;
+        progress.user_rows = all;
+    }
+    if progress.analysis_rows < progress.analyzed {
+        crate::database::insert_message(
+            pool.clone(),
+            &convo_thread_id,
+            "assistant",
+            &response_text,
+            message_type(progress.analysis_rows),
+            analyzing_ai_id,
+        ).await?;
+        progress.analysis_rows = progress.analyzed;
+    }
+
+    // Step 3: Insert parsed variables into the database.
+    if progress.results_rows < progress.analyzed {
         crate::database::insert_pre_processing_results(
-            &pool,
+            pool,
             user_id,
             &convo_thread_id,
             parsed_results.interest_level,
             None,
             parsed_results.respond_cue,
         ).await?;
-
-        // Step 5c: Run thread and receive response from Convo AI
-        let convo_response_text = if is_new_thread {
-            crate::first_loop(&openai_key, &convo_thread_id, &assistant_id).await?
-        } else {
-            crate::second_message_and_so_on(&openai_key, &convo_thread_id, &final_message, &assistant_id).await?
-        };
-
-        // Return the response cue, Convo AI response, and Convo thread ID
-        return Ok((parsed_results.respond_cue, convo_response_text, convo_thread_id));
+        progress.results_rows = progress.analyzed;
     }
 
-    Err(anyhow::anyhow!("User state not found"))
+    // Step 5c: Run thread and receive response from Convo AI. final_message is on the thread already.
+    let convo_response_text = crate::first_loop(openai_key, &convo_thread_id, assistant_id).await?;
+
+    // Return the response cue, Convo AI response, and Convo thread ID
+    Ok((parsed_results.respond_cue, convo_response_text, convo_thread_id))
 }
 //Replaced with above on 07/23/24 - because I want it to return response cue, convo AI response, and convo thread ID
 // async fn handle_buffered_messages(
 //     user_id: u64,
-//     pool: deadpool_postgres::Pool,
+//     pool: crate::database::Pool,
 //     bot: teloxide::Bot,
 //     chat_id: teloxide::types::ChatId,
 //     openai_key: String,
@@ -940,7 +1150,7 @@ async fn handle_buffered_messages(
 //     }
 // }
 
-// pub async fn run_telegram_bot(pool: deadpool_postgres::Pool) {
+// pub async fn run_telegram_bot(pool: crate::database::Pool) {
 //     let bot = Bot::from_env();
 //     log::info!("Bot started");
 //     let openai_key = env::var("OPENAI_KEY").expect("OPENAI_KEY not set");
@@ -1150,7 +1360,7 @@ async fn handle_buffered_messages(
 //     }).await;
 // }
 
-pub async fn get_or_create_thread(pool: &deadpool_postgres::Pool, user_id: i64, assistant_id: &str, openai_key: &str, initial_message: &str) -> Result<(String, bool), anyhow::Error> {
+pub async fn get_or_create_thread(pool: &crate::database::Pool, user_id: i64, assistant_id: &str, openai_key: &str, initial_message: &str) -> Result<(String, bool), anyhow::Error> {
     let existing_thread_id = crate::database::get_thread_by_user_id_and_assistant(pool.clone(), user_id, assistant_id).await?;
     match existing_thread_id {
         Some(thread_id) => Ok((thread_id, false)),
@@ -1162,24 +1372,11 @@ pub async fn get_or_create_thread(pool: &deadpool_postgres::Pool, user_id: i64,
     }
 }
 
-async fn clear_message_buffer(user_id: u64) -> Result<(), anyhow::Error> {
-    log::info!("about to acquire the write lock for USER_STATES");
-    let mut user_states = USER_STATES.write().await; 
-    log::info!("acquired the write lock for USER_STATES");
-    //  TODO: get user's message linked to the same assistant. because if we intercept the same uer's message but going to another assistant, 
-    //      we dont want to concatenate THAT message too
-    if let Some(user_state) = user_states.get_mut(&user_id) {
-        log::info!("In handle_buffered_messages. Processing buffered messages for user_id: {}", user_id);
-        // Clear the user's message buffer
-        user_state.messages.clear();
-    }
-    Ok(())
-}
 
 
 // async fn handle_buffered_messages(
 //     user_id: u64,
-//     pool: deadpool_postgres::Pool,
+//     pool: crate::database::Pool,
 //     bot: teloxide::Bot,
 //     chat_id: teloxide::types::ChatId,
 //     openai_key: String,
@@ -1282,7 +1479,7 @@ async fn clear_message_buffer(user_id: u64) -> Result<(), anyhow::Error> {
 
 // async fn handle_text_message_logic(
 //     message: teloxide::prelude::Message,
-//     pool: deadpool_postgres::Pool,
+//     pool: crate::database::Pool,
 //     bot: teloxide::Bot,
 //     user_id: i64,
 //     chat_id: teloxide::types::ChatId,
@@ -1352,7 +1549,7 @@ async fn clear_message_buffer(user_id: u64) -> Result<(), anyhow::Error> {
 
 // async fn handle_audio_message_logic(
 //     message: teloxide::prelude::Message, 
-//     pool: deadpool_postgres::Pool,
+//     pool: crate::database::Pool,
 //     bot: teloxide::Bot, 
 //     user_id: i64, 
 //     chat_id: teloxide::types::ChatId, 
@@ -1414,7 +1611,7 @@ async fn clear_message_buffer(user_id: u64) -> Result<(), anyhow::Error> {
 
 // async fn handle_voice_message_logic(
 //     message: teloxide::prelude::Message,
-//     pool: deadpool_postgres::Pool, 
+//     pool: crate::database::Pool, 
 //     bot: teloxide::Bot, 
 //     user_id: i64, 
 //     chat_id: teloxide::types::ChatId, 
@@ -1473,7 +1670,7 @@ async fn clear_message_buffer(user_id: u64) -> Result<(), anyhow::Error> {
 
 //     Ok(())
 // }
-// async fn get_or_create_thread(pool: &deadpool_postgres::Pool, user_id: i64, assistant_id: &str, openai_key: &str, initial_message: &str) -> Result<String, anyhow::Error> {
+// async fn get_or_create_thread(pool: &crate::database::Pool, user_id: i64, assistant_id: &str, openai_key: &str, initial_message: &str) -> Result<String, anyhow::Error> {
 //     let existing_thread_id = crate::database::get_thread_by_user_id_and_assistant(pool.clone(), user_id, assistant_id).await?;
 //     let thread_id = match existing_thread_id {
 //         Some(thread_id) => thread_id,
@@ -1497,7 +1694,7 @@ async fn clear_message_buffer(user_id: u64) -> Result<(), anyhow::Error> {
 
 
 
-// pub async fn run_telegram_bot(pool: deadpool_postgres::Pool) {
+// pub async fn run_telegram_bot(pool: crate::database::Pool) {
 //     let bot = Bot::from_env();
 //     log::info!("Bot started");
 //     let openai_key = env::var("OPENAI_KEY").expect("OPENAI_KEY not set");
@@ -1769,7 +1966,7 @@ async fn clear_message_buffer(user_id: u64) -> Result<(), anyhow::Error> {
 
 
 
-// pub async fn run_telegram_bot(pool: deadpool_postgres::Pool) {
+// pub async fn run_telegram_bot(pool: crate::database::Pool) {
 //     let bot = Bot::from_env();
 //     log::info!("Bot started");
 //     let openai_key = env::var("OPENAI_KEY").expect("OPENAI_KEY not set");
@@ -2051,7 +2248,7 @@ async fn clear_message_buffer(user_id: u64) -> Result<(), anyhow::Error> {
 
 
 
-// pub async fn run_telegram_bot(pool: deadpool_postgres::Pool) {
+// pub async fn run_telegram_bot(pool: crate::database::Pool) {
 //     let bot = Bot::from_env();
 //     log::info!("Bot started");
 //     let openai_key = env::var("OPENAI_KEY").expect("OPENAI_KEY not set");
@@ -2523,12 +2720,18 @@ async fn clear_message_buffer(user_id: u64) -> Result<(), anyhow::Error> {
 
 
 
-async fn list_assistants() -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
-    let api_key = env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY not set");
+// The assistant helpers used to read OPENAI_API_KEY only; the rest of the app uses OPENAI_KEY
+fn assistants_api_key() -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(env::var("OPENAI_API_KEY").or_else(|_| env::var("OPENAI_KEY")).map_err(|_| "OPENAI_KEY not set")?)
+}
+
+pub async fn list_assistants() -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let api_key = assistants_api_key()?;
     let client = reqwest::Client::new();
 
-    let resp = client.get("https://api.openai.com/v1/assistants")
+    let resp = client.get(crate::openai_url("/assistants"))
         .header("Authorization", format!("Bearer {}", api_key))
+        .header("OpenAI-Beta", "assistants=v2")
         .send()
         .await?;
 
@@ -2546,12 +2749,13 @@ async fn list_assistants() -> Result<String, Box<dyn std::error::Error + Send +
     Ok(assistant_list_str)
 }
 
-async fn create_assistant(name: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
-    let api_key = env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY not set");
+pub async fn create_assistant(name: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let api_key = assistants_api_key()?;
     let client = reqwest::Client::new();
     //WHY ARE WE USING V1
-    let response = client.post("https://api.openai.com/v1/assistants")
+    let response = client.post(crate::openai_url("/assistants"))
         .header("Authorization", format!("Bearer {}", api_key))
+        .header("OpenAI-Beta", "assistants=v2")
         .json(&serde_json::json!({
             "name": name
         }))
@@ -2564,4 +2768,23 @@ async fn create_assistant(name: &str) -> Result<String, Box<dyn std::error::Erro
     let assistant_name = response["name"].as_str().unwrap_or("");
 
     Ok(format!("Assistant created: ID: {}, Name: {}", assistant_id, assistant_name))
+}
+
+pub async fn get_assistant(assistant_id: &str) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
+    let api_key = assistants_api_key()?;
+    let client = reqwest::Client::new();
+
+    let response = client.get(crate::openai_url(&format!("/assistants/{}", assistant_id)))
+        .header("Authorization", format!("Bearer {}", api_key))
+        .header("OpenAI-Beta", "assistants=v2")
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        return Err(format!("Received non-200 status code ({}) from OpenAI: {}", status, text).into());
+    }
+
+    Ok(response.json::<serde_json::Value>().await?)
 }
\ No newline at end of file
@@ -1,11 +1,6 @@
 // src/webhooks.rs
 
-use teloxide::payloads::DeleteMyCommandsSetters;
 use warp::Filter;
-use crate::{WebhookPayload, handle_message_handler};
-use std::env;
-use teloxide::types::Message as TeloxideMessage;
-use crate::telegram::convert_teloxide_message_to_custom;
 use crate::Message as CustomMessage;
 
 
@@ -15,7 +10,7 @@ use std::sync::Arc;
 use tokio::net::TcpListener;
 use std::fs::File;
 
-// pub async fn run_webhook_server(pool: deadpool_postgres::Pool) {
+// pub async fn run_webhook_server(pool: crate::database::Pool) {
 //     let openai_key = env::var("OPENAI_KEY").expect("OPENAI_KEY not set");
 
 //     // POST /webhook
@@ -47,52 +42,18 @@ use std::fs::File;
 
 
 
-pub async fn run_webhook_server(pool: deadpool_postgres::Pool) {
+pub async fn run_webhook_server(pool: crate::database::Pool) {
     log::info!("IS CODE GETTING HEREEEEEEEEEEEEEEE");
-    let openai_key = env::var("OPENAI_KEY").expect("OPENAI_KEY not set");
+    // Telegram updates come in by long polling in telegram.rs; this server has no Telegram webhook route
 
-    // POST /webhook
-    let webhook_route = warp::path("webhook")
-        .and(warp::post())
-        .and(warp::body::json())
-        .and_then(move |payload: WebhookPayload| {
-            let openai_key = openai_key.clone();
-            async move {
-                log::info!("Webhook called with payload: {:?}", payload);
-                if let Some(ref message) = payload.message {
-                    handle_message_handler(message.clone(), openai_key).await;
-                }
-                Ok::<_, warp::Rejection>(warp::reply::json(&"OK"))
-            }
-        });
 
 
 
 
-
-//--voner webhooks filters --//
-
-
-
-    let inbound_message = warp::path("webhooks")
-        .and(warp::path("inbound-message"))
-        .and(warp::post())
-        .and(warp::body::json())
-        .map(|body: serde_json::Value| {
-            println!("Received inbound message: {:?}", body);
-            warp::reply::json(&body)
-    });
-    // Define the message status filter
-    let message_status = warp::path("webhooks")
-        .and(warp::path("message-status"))
-        .and(warp::post())
-        .and(warp::body::json())
-        .map(|body: serde_json::Value| {
-            println!("Received message status: {:?}", body);
-            warp::reply::json(&body)
-    });
-
-//--^^voner webhooks filters ^^--//
+//--provider webhooks--//
+    // Voner and any other signed providers live in receivers.rs, mounted under POST /webhooks/{path}
+    let receiver_registry = Arc::new(crate::receivers::default_registry());
+    let provider_webhooks = crate::receivers::ReceiverRegistry::routes(receiver_registry.clone(), pool.clone());
 
 
 
@@ -112,31 +73,59 @@ pub async fn run_webhook_server(pool: deadpool_postgres::Pool) {
             //             .and_then(handle_request)
             //             .recover(handle_rejection);
         
-        let routes = html_route.or(message_status);
-
-    // Load SSL keys and certs
-        let cert_path = "/etc/letsencrypt/live/merivilla.com/fullchain.pem";
-        let key_path = "/etc/letsencrypt/live/merivilla.com/privkey.pem";
-
+        let routes = html_route
+            .or(crate::health::routes(pool.clone()))
+            .or(provider_webhooks)
+            .or(crate::admin_api::routes(pool.clone(), receiver_registry))
+            .recover(handle_rejection);
+
+    // Bind addresses, TLS and the HTTP redirect come from env, see listener::ListenerConfig
+    let listener_config = match crate::listener::ListenerConfig::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("Invalid listener configuration: {:?}", e);
+            return;
+        }
+    };
 
     log::info!("Starting the server...");
 
-    warp::serve(routes)
-        .tls()
-        .cert_path(cert_path)
-        .key_path(key_path)
-        .run(([0, 0, 0, 0], 443))
-        .await;
+    if let Err(e) = crate::listener::serve(warp::service(routes), listener_config).await {
+        log::error!("Webhook server stopped: {:?}", e);
+    }
 
 
 
 
 }
-//un code comment this if you just want to see if a request comes in
+// Turns every rejection into a JSON error body: {"error": {"code": "...", "message": "..."}}
     async fn handle_rejection(err: warp::Rejection) -> Result<impl warp::Reply, std::convert::Infallible> {
-        log::error!("Request was rejected: {:?}", err);
-        Ok(warp::reply::with_status("Internal Server Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR))
+        use warp::http::StatusCode;
+
+        let (status, code, message) = if let Some(api_error) = err.find::<crate::admin_api::ApiError>() {
+            (api_error.status, api_error.code, api_error.message.clone())
+        } else if err.is_not_found() {
+            (StatusCode::NOT_FOUND, "not_found", "Not found".to_string())
+        } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+            (StatusCode::BAD_REQUEST, "invalid_query", e.to_string())
+        } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+            (StatusCode::BAD_REQUEST, "invalid_body", e.to_string())
+        } else if let Some(e) = err.find::<warp::reject::MissingHeader>() {
+            (StatusCode::BAD_REQUEST, "missing_header", e.to_string())
+        } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+            (StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", "Method not allowed".to_string())
+        } else if err.find::<warp::reject::UnsupportedMediaType>().is_some() {
+            (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type", "Unsupported media type".to_string())
+        } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+            (StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", "Payload too large".to_string())
+        } else {
+            log::error!("Request was rejected: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "internal_error", "Internal server error".to_string())
+        };
+
+        Ok(crate::admin_api::error_reply(status, code, &message))
     }
+//un code comment this if you just want to see if a request comes in
     async fn handle_request(method: warp::http::Method, path: warp::filters::path::FullPath, body: serde_json::Value) -> Result<impl warp::Reply, warp::Rejection> {
         log::info!("Received a request: method: {:?}, path: {:?}, body: {:?}", method, path, body);
         Ok("Hello, World!")
@@ -0,0 +1,44 @@
+// tests/mock_conversation.rs
+
+// Conversation turns against the local OpenAI and Telegram mocks: the user's message starts a thread, the
+// assistant's run completes, and the reply reaches the user's chat. Both mocks set process-wide env vars, so
+// everything runs in one test.
+
+use teloxide::prelude::Requester;
+use webhooks_server::mock_openai::MockOpenAi;
+use webhooks_server::mock_telegram::MockTelegram;
+
+const ASSISTANT_ID: &str = "asst_test";
+const CHAT_ID: i64 = 4242;
+
+#[tokio::test]
+async fn conversation_turns_through_mocks() {
+    let openai = MockOpenAi::start().await;
+    openai.install();
+    let telegram = MockTelegram::start().await;
+    telegram.install();
+    let openai_key = std::env::var("OPENAI_KEY").unwrap();
+    let bot = webhooks_server::telegram::bot_from_env();
+
+    // First message: the thread is created with it and the assistant runs on it
+    openai.reply(ASSISTANT_ID, "Hi! How can I help?");
+    let thread_id = webhooks_server::create_openai_thread(&openai_key, "Hello").await.unwrap();
+    let reply = webhooks_server::first_loop(&openai_key, &thread_id, ASSISTANT_ID).await.unwrap();
+    assert_eq!(reply, "Hi! How can I help?");
+    bot.send_message(teloxide::types::ChatId(CHAT_ID), reply).await.unwrap();
+
+    // Second message goes onto the same thread
+    openai.reply(ASSISTANT_ID, "It costs 10 euros.");
+    let reply = webhooks_server::second_message_and_so_on(&openai_key, &thread_id, "How much is it?", ASSISTANT_ID).await.unwrap();
+    assert_eq!(reply, "It costs 10 euros.");
+    bot.send_message(teloxide::types::ChatId(CHAT_ID), reply).await.unwrap();
+
+    let roles_and_texts: Vec<(String, String)> = openai.thread_messages(&thread_id).into_iter().map(|m| (m.role, m.text)).collect();
+    assert_eq!(roles_and_texts, vec![
+        ("user".to_string(), "Hello".to_string()),
+        ("assistant".to_string(), "Hi! How can I help?".to_string()),
+        ("user".to_string(), "How much is it?".to_string()),
+        ("assistant".to_string(), "It costs 10 euros.".to_string()),
+    ]);
+    assert_eq!(telegram.sent_to(CHAT_ID), vec!["Hi! How can I help?", "It costs 10 euros."]);
+}